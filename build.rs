@@ -26,13 +26,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
     let git_version = String::from_utf8(git_describe_output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_VERSION={}", git_version);
-    let bin_dir = "/opt/host-insight-client";
-    println!("cargo:rustc-env=BIN_DIR={}", bin_dir);
     let conf_dir = "/etc/opt/host-insight-client";
     println!("cargo:rustc-env=CONF_DIR={}", conf_dir);
+    let ca_file = "/etc/ssl/certs/ca-certificates.crt";
+    println!("cargo:rustc-env=CA_FILE={}", ca_file);
+    let run_dir = "/tmp/host-insight";
+    println!("cargo:rustc-env=RUN_DIR={}", run_dir);
     // Build proto
     let mut config = prost_build::Config::new();
     config.protoc_arg("--experimental_allow_proto3_optional");
+    // For the JSON debug tap
+    config.type_attribute(".", "#[derive(serde_derive::Serialize)]");
     tonic_build::configure().compile_with_config(
         config,
         &[
@@ -0,0 +1,44 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Arbitrary DBC files, e.g. a broken file pushed with FetchResource. A
+// file that parses is linted and its messages are decoded, as the client
+// does when it loads the file.
+
+#![no_main]
+
+use lib::decode::{decode_signals, lint, parse_dbc};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(dbc) = parse_dbc("fuzz.dbc", text) else {
+        return;
+    };
+    lint(&dbc);
+    for message in dbc.messages() {
+        for frame in [[0x00; 64], [0xFF; 64], [0x5A; 64]] {
+            for len in [0, 1, 8, 64] {
+                decode_signals(message, &frame[..len], &dbc, false);
+                decode_signals(message, &frame[..len], &dbc, true);
+            }
+        }
+    }
+});
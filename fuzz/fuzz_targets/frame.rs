@@ -0,0 +1,75 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Arbitrary frame payloads decoded with a DBC that covers both byte
+// orders, signed, unsigned and float signals, value descriptions and
+// multiplexing. The first byte selects the message and the enum
+// encoding, the rest is the frame data.
+
+#![no_main]
+
+use can_dbc::DBC;
+use lib::decode::{decode_signals, parse_dbc};
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+const DBC_TEXT: &str = r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 256 Engine: 8 ECU
+ SG_ Speed : 0|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+ SG_ Temp : 16|8@1+ (1,-40) [-40|210] "C" Vector__XXX
+ SG_ Torque : 31|12@0- (1,-125) [-125|125] "%" Vector__XXX
+ SG_ State : 40|2@1+ (1,0) [0|3] "" Vector__XXX
+ SG_ Raw : 0|64@1+ (1,0) [0|0] "" Vector__XXX
+
+BO_ 257 Mux: 8 ECU
+ SG_ Selector M : 0|8@1+ (1,0) [0|255] "" Vector__XXX
+ SG_ Low m0 : 8|32@1- (1,0) [0|0] "" Vector__XXX
+ SG_ High m1 : 8|32@1+ (1,0) [0|0] "" Vector__XXX
+ SG_ Level m1 : 47|16@0- (0.5,-10.5) [0|0] "" Vector__XXX
+
+BO_ 258 Floats: 64 ECU
+ SG_ Single : 0|32@1- (1,0) [0|0] "" Vector__XXX
+ SG_ Double : 32|64@1- (2,1) [0|0] "" Vector__XXX
+ SG_ Wide : 511|64@0- (1,0) [0|0] "" Vector__XXX
+
+VAL_ 256 State 0 "Off" 1 "On" 2 "Error" ;
+
+SIG_VALTYPE_ 258 Single : 1;
+SIG_VALTYPE_ 258 Double : 2;
+"#;
+
+fn dbc() -> &'static DBC {
+    static DBC: OnceLock<DBC> = OnceLock::new();
+    DBC.get_or_init(|| parse_dbc("fuzz.dbc", DBC_TEXT).unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((selector, frame)) = data.split_first() else {
+        return;
+    };
+    let dbc = dbc();
+    let message = &dbc.messages()[*selector as usize % dbc.messages().len()];
+    decode_signals(message, frame, dbc, selector & 0x80 != 0);
+});
@@ -0,0 +1,35 @@
+#!/bin/sh
+
+# Copyright (C) 2023  Host Mobility AB
+
+# This file is part of HOST Insight Client
+
+# HOST Insight Client is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License as published by
+# the Free Software Foundation, either version 3 of the License, or
+# (at your option) any later version.
+
+# HOST Insight Client is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU General Public License for more details.
+
+# You should have received a copy of the GNU General Public License
+# along with this program; if not, write to the Free Software Foundation,
+# Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+# Build the size-focused profile and fail if the binary exceeds the size
+# budget for the smallest gateways. Extra arguments are passed to cargo,
+# e.g. --target=armv7-unknown-linux-gnueabihf.
+
+budget=${SIZE_BUDGET:-5000000}
+
+cargo build --profile release-small --no-default-features "$@" || exit 1
+
+binary=$(find target -path "*/release-small/host-insight-client" -type f | head -n 1)
+size=$(wc -c < "$binary")
+echo "$binary: $size bytes, budget $budget bytes"
+if [ "$size" -gt "$budget" ]; then
+  echo "The binary exceeds the size budget"
+  exit 1
+fi
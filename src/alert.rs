@@ -0,0 +1,392 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use lazy_static::lazy_static;
+use lib::{
+    cache, conf_dir, history,
+    host_insight::{
+        agent_client::AgentClient, Alert, AlertDefinition, AlertDefinitions, Comparison,
+    },
+    CONFIG,
+};
+use prost::Message;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::time::{sleep_until, Instant as TokioInstant};
+use tonic::transport::Channel;
+
+// History attached to a triggered alert if its definition has no window
+const DEFAULT_HISTORY_WINDOW: Duration = Duration::from_secs(10);
+
+struct AlertState {
+    definition: AlertDefinition,
+    pending_since: Option<Instant>,
+    active: bool,
+}
+
+lazy_static! {
+    static ref ALERTS: Mutex<Vec<AlertState>> = Mutex::new(load_alert_definitions());
+    // New definitions are checked against the cached values right away
+    static ref INSTALLED: Notify = Notify::new();
+}
+
+fn alert_definitions_path() -> PathBuf {
+    PathBuf::from(format!("{}/alerts.pb", conf_dir()))
+}
+
+fn new_alert_states(definitions: Vec<AlertDefinition>) -> Vec<AlertState> {
+    definitions
+        .into_iter()
+        .map(|definition| AlertState {
+            definition,
+            pending_since: None,
+            active: false,
+        })
+        .collect()
+}
+
+// Alert definitions installed by the server are kept across restarts
+fn load_alert_definitions() -> Vec<AlertState> {
+    match fs::read(alert_definitions_path()) {
+        Ok(buf) => match AlertDefinitions::decode(buf.as_slice()) {
+            Ok(msg) => new_alert_states(msg.definitions),
+            Err(e) => {
+                eprintln!("Failed to decode stored alert definitions: {e}");
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+// Replace all alert definitions with the ones pushed by the server
+pub async fn install_alert_definitions(definitions: AlertDefinitions) {
+    if let Err(e) = fs::write(alert_definitions_path(), definitions.encode_to_vec()) {
+        eprintln!("Failed to store alert definitions: {e}");
+    }
+    println!(
+        "Installing {} alert definitions",
+        definitions.definitions.len()
+    );
+    *ALERTS.lock().await = new_alert_states(definitions.definitions);
+    INSTALLED.notify_one();
+}
+
+fn is_triggered(d: &AlertDefinition, value: f64) -> bool {
+    match Comparison::from_i32(d.comparison) {
+        Some(Comparison::GreaterThan) => value > d.threshold,
+        Some(Comparison::GreaterOrEqual) => value >= d.threshold,
+        Some(Comparison::LessThan) => value < d.threshold,
+        Some(Comparison::LessOrEqual) => value <= d.threshold,
+        Some(Comparison::Equal) => value == d.threshold,
+        Some(Comparison::NotEqual) => value != d.threshold,
+        None => false,
+    }
+}
+
+// An active alert is only cleared once the value has moved back past
+// the threshold by more than the hysteresis.
+fn is_cleared(d: &AlertDefinition, value: f64) -> bool {
+    match Comparison::from_i32(d.comparison) {
+        Some(Comparison::GreaterThan) | Some(Comparison::GreaterOrEqual) => {
+            value < d.threshold - d.hysteresis
+        }
+        Some(Comparison::LessThan) | Some(Comparison::LessOrEqual) => {
+            value > d.threshold + d.hysteresis
+        }
+        Some(Comparison::Equal) => (value - d.threshold).abs() > d.hysteresis,
+        Some(Comparison::NotEqual) => (value - d.threshold).abs() <= d.hysteresis,
+        None => true,
+    }
+}
+
+// Check a new value of the signal of an alert. Returns the alert to
+// send if the alert was triggered or cleared. The value must hold for
+// the duration of the definition, measured from the first value that
+// triggers it, before the alert is triggered.
+fn evaluate(alert: &mut AlertState, value: f64, at: Instant) -> Option<Alert> {
+    let d = &alert.definition;
+    if !alert.active {
+        if !is_triggered(d, value) {
+            alert.pending_since = None;
+            return None;
+        }
+        let since = *alert.pending_since.get_or_insert(at);
+        if at.saturating_duration_since(since) < Duration::from_millis(d.duration_ms as u64) {
+            return None;
+        }
+        alert.active = true;
+        alert.pending_since = None;
+        Some(new_alert(d, value, true))
+    } else if is_cleared(d, value) {
+        alert.active = false;
+        Some(new_alert(d, value, false))
+    } else {
+        None
+    }
+}
+
+// When the earliest pending alert is triggered if its value holds
+fn next_deadline(alerts: &[AlertState]) -> Option<Instant> {
+    alerts
+        .iter()
+        .filter_map(|a| {
+            let since = a.pending_since?;
+            Some(since + Duration::from_millis(a.definition.duration_ms as u64))
+        })
+        .min()
+}
+
+// The history of the signal to attach to a triggered alert
+fn history_window(d: &AlertDefinition) -> Duration {
+    match d.history_window_ms {
+        0 => DEFAULT_HISTORY_WINDOW,
+        ms => Duration::from_millis(ms as u64),
+    }
+}
+
+// Evaluate an alert and collect it with its history window if it was
+// triggered or cleared
+fn check(alert: &mut AlertState, value: f64, at: Instant, changed: &mut Vec<(Alert, Duration)>) {
+    if let Some(a) = evaluate(alert, value, at) {
+        changed.push((a, history_window(&alert.definition)));
+    }
+}
+
+// Check all alerts against the cached values, e.g. when a pending alert
+// is due or updates were missed
+async fn evaluate_cached(alerts: &mut [AlertState], now: Instant) -> Vec<(Alert, Duration)> {
+    let mut changed = Vec::new();
+    for alert in alerts.iter_mut() {
+        let value = match cache::get_any(&alert.definition.signal).await {
+            Some(v) => cache::numeric(&v.value),
+            None => None,
+        };
+        if let Some(value) = value {
+            check(alert, value, now, &mut changed);
+        }
+    }
+    changed
+}
+
+// Alerts are evaluated on every update of their signals, so that a
+// condition is seen even if it holds only briefly
+pub async fn alert_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut updates = cache::subscribe();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(send_alerts(channel, rx));
+
+    let mut changed = evaluate_cached(&mut ALERTS.lock().await, Instant::now()).await;
+    loop {
+        for (alert, window) in changed.drain(..) {
+            // Attach the recent history of the signal, if it is recorded
+            if alert.active && CONFIG.history.is_some() {
+                history::request_upload(vec![alert.signal.clone()], window).await;
+            }
+            let _ = tx.send(alert);
+        }
+
+        let deadline = next_deadline(&ALERTS.lock().await);
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    let value = match cache::numeric(&update.value) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    let mut alerts = ALERTS.lock().await;
+                    for alert in alerts.iter_mut() {
+                        if alert.definition.signal == update.name {
+                            check(alert, value, update.at, &mut changed);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    eprintln!("Alerts missed {n} signal updates");
+                    changed = evaluate_cached(&mut ALERTS.lock().await, Instant::now()).await;
+                }
+                Err(RecvError::Closed) => return Err("Signal updates ended".into()),
+            },
+            _ = sleep_until(TokioInstant::from_std(deadline.unwrap_or_else(Instant::now))),
+                if deadline.is_some() =>
+            {
+                changed = evaluate_cached(&mut ALERTS.lock().await, Instant::now()).await;
+            }
+            _ = INSTALLED.notified() => {
+                changed = evaluate_cached(&mut ALERTS.lock().await, Instant::now()).await;
+            }
+        }
+    }
+}
+
+// Send alerts in the order they were triggered and cleared, so that
+// evaluation goes on while the server is unreachable
+async fn send_alerts(channel: Channel, mut alerts: mpsc::UnboundedReceiver<Alert>) {
+    while let Some(alert) = alerts.recv().await {
+        send_alert(channel.clone(), alert).await;
+    }
+}
+
+fn new_alert(d: &AlertDefinition, value: f64, active: bool) -> Alert {
+    Alert {
+        id: d.id.clone(),
+        signal: d.signal.clone(),
+        value,
+        time_stamp: history::unix_millis(SystemTime::now()),
+        active,
+    }
+}
+
+// Alerts are sent immediately, bypassing any batching
+async fn send_alert(channel: Channel, alert: Alert) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendAlert", &alert).await;
+        let response = client.send_alert(alert.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(
+        comparison: Comparison,
+        threshold: f64,
+        hysteresis: f64,
+        duration_ms: u32,
+    ) -> AlertState {
+        new_alert_states(vec![AlertDefinition {
+            id: "a".to_string(),
+            signal: "EngineTemp".to_string(),
+            comparison: comparison as i32,
+            threshold,
+            hysteresis,
+            duration_ms,
+            history_window_ms: 0,
+        }])
+        .remove(0)
+    }
+
+    fn ms(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn thresholds() {
+        // A value that does not trigger the alert, then one that does
+        let cases = [
+            (Comparison::GreaterThan, 100.0, 100.5),
+            (Comparison::GreaterOrEqual, 99.5, 100.0),
+            (Comparison::LessThan, 100.0, 99.5),
+            (Comparison::LessOrEqual, 100.5, 100.0),
+            (Comparison::Equal, 99.0, 100.0),
+            (Comparison::NotEqual, 100.0, 101.0),
+        ];
+        for (comparison, below, above) in cases {
+            let mut alert = state(comparison, 100.0, 0.0, 0);
+            let now = Instant::now();
+            assert!(evaluate(&mut alert, below, now).is_none(), "{comparison:?}");
+            let triggered = evaluate(&mut alert, above, now).unwrap();
+            assert!(triggered.active, "{comparison:?}");
+            assert_eq!(triggered.value, above);
+        }
+        let mut alert = state(Comparison::GreaterThan, 100.0, 0.0, 0);
+        alert.definition.comparison = -1;
+        assert!(evaluate(&mut alert, 1e9, Instant::now()).is_none());
+    }
+
+    // An active alert is only cleared once the value has moved back past
+    // the threshold by more than the hysteresis
+    #[test]
+    fn hysteresis() {
+        let mut alert = state(Comparison::GreaterThan, 100.0, 5.0, 0);
+        let now = Instant::now();
+        assert!(evaluate(&mut alert, 101.0, now).unwrap().active);
+        assert!(evaluate(&mut alert, 99.0, now).is_none());
+        assert!(evaluate(&mut alert, 95.0, now).is_none());
+        let cleared = evaluate(&mut alert, 94.9, now).unwrap();
+        assert!(!cleared.active);
+        assert!(evaluate(&mut alert, 99.0, now).is_none());
+
+        let mut alert = state(Comparison::LessThan, 10.0, 2.0, 0);
+        assert!(evaluate(&mut alert, 9.0, now).unwrap().active);
+        assert!(evaluate(&mut alert, 12.0, now).is_none());
+        assert!(!evaluate(&mut alert, 12.1, now).unwrap().active);
+
+        let mut alert = state(Comparison::Equal, 3.0, 0.5, 0);
+        assert!(evaluate(&mut alert, 3.0, now).unwrap().active);
+        assert!(evaluate(&mut alert, 3.4, now).is_none());
+        assert!(!evaluate(&mut alert, 3.6, now).unwrap().active);
+    }
+
+    // The condition must hold for the whole duration, and a value that
+    // briefly returns below the threshold starts it over
+    #[test]
+    fn duration() {
+        let mut alert = state(Comparison::GreaterThan, 100.0, 0.0, 500);
+        let start = Instant::now();
+        assert!(evaluate(&mut alert, 101.0, start).is_none());
+        assert_eq!(
+            next_deadline(std::slice::from_ref(&alert)),
+            Some(ms(start, 500))
+        );
+        assert!(evaluate(&mut alert, 101.0, ms(start, 300)).is_none());
+        assert!(evaluate(&mut alert, 99.0, ms(start, 400)).is_none());
+        assert_eq!(next_deadline(std::slice::from_ref(&alert)), None);
+        assert!(evaluate(&mut alert, 101.0, ms(start, 600)).is_none());
+        assert!(evaluate(&mut alert, 101.0, ms(start, 1000)).is_none());
+        let triggered = evaluate(&mut alert, 102.0, ms(start, 1100)).unwrap();
+        assert!(triggered.active);
+        assert_eq!(triggered.value, 102.0);
+        assert_eq!(next_deadline(std::slice::from_ref(&alert)), None);
+    }
+
+    // A spike between two updates that are far apart is seen, as every
+    // update is evaluated
+    #[test]
+    fn short_condition_without_duration() {
+        let mut alert = state(Comparison::GreaterThan, 100.0, 0.0, 0);
+        let start = Instant::now();
+        assert!(evaluate(&mut alert, 50.0, start).is_none());
+        assert!(evaluate(&mut alert, 150.0, ms(start, 1)).unwrap().active);
+        assert!(!evaluate(&mut alert, 50.0, ms(start, 2)).unwrap().active);
+    }
+
+    #[test]
+    fn history_window_defaults() {
+        let mut alert = state(Comparison::GreaterThan, 100.0, 0.0, 0);
+        assert_eq!(history_window(&alert.definition), DEFAULT_HISTORY_WINDOW);
+        alert.definition.history_window_ms = 30_000;
+        assert_eq!(history_window(&alert.definition), Duration::from_secs(30));
+    }
+}
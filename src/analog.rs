@@ -0,0 +1,88 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Analog outputs, e.g. PWM duty cycles and DAC values, that are set
+// through a sysfs attribute such as
+// /sys/class/pwm/pwmchip0/pwm0/duty_cycle or
+// /sys/bus/iio/devices/iio:device0/out_voltage0_raw. Setpoints are in
+// the unit of the attribute and clamped to the configured range.
+
+use lib::{error::ClientError, AnalogOutPort, CONFIG};
+use std::fs;
+use std::io;
+
+fn port(external_name: &str) -> Option<&'static AnalogOutPort> {
+    CONFIG
+        .analog_out
+        .as_ref()?
+        .ports
+        .iter()
+        .find(|p| p.external_name == external_name)
+}
+
+pub fn is_analog_out(external_name: &str) -> bool {
+    port(external_name).is_some()
+}
+
+fn write_value(p: &AnalogOutPort, value: f64) -> io::Result<()> {
+    // Most attributes only accept integers
+    let text = if value.fract() == 0.0 {
+        format!("{}", value as i64)
+    } else {
+        value.to_string()
+    };
+    fs::write(&p.path, text)
+}
+
+// Set an analog out and return the setpoint after clamping
+pub fn set_analog_out(external_name: &str, setpoint: f64) -> Result<f64, ClientError> {
+    let p =
+        port(external_name).ok_or_else(|| ClientError::UnknownOutput(external_name.to_string()))?;
+    if !setpoint.is_finite() {
+        return Err(ClientError::AnalogOut {
+            name: external_name.to_string(),
+            source: io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("setpoint {setpoint} is not a number"),
+            ),
+        });
+    }
+    // validate_config makes sure that min <= max, which clamp relies on
+    let value = setpoint.clamp(p.min, p.max);
+    if value != setpoint {
+        eprintln!("Clamping setpoint {setpoint} of {external_name} to {value}");
+    }
+    write_value(p, value).map_err(|source| ClientError::AnalogOut {
+        name: external_name.to_string(),
+        source,
+    })?;
+    Ok(value)
+}
+
+// Set every analog out to its default. A port that fails is logged and
+// skipped so that the others still get their defaults.
+pub fn set_all_analog_out_to_defaults() {
+    for p in CONFIG.analog_out.iter().flat_map(|a| &a.ports) {
+        if let Err(e) = write_value(p, p.default) {
+            eprintln!(
+                "Failed to set analog out {} to its default value: {e}",
+                p.external_name
+            );
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Attention check of a lone worker, e.g. at a machine in a remote
+// location. The check runs locally, so that it keeps working without a
+// connection: every interval the output, e.g. a buzzer, is activated
+// until the worker activates the input. A check that is not acknowledged
+// within the window is escalated, with the alarm output and in the
+// heartbeat status, and stays escalated until it is acknowledged. Each
+// step is sent to the server as an audit event once it can be reached.
+
+use super::gpio::set_digital_out;
+use super::local_override;
+use super::net::{clear_status, handle_send_result, intercept, set_status};
+use super::tap;
+use lazy_static::lazy_static;
+use lib::{
+    history,
+    host_insight::{agent_client::AgentClient, AttentionEvent, AttentionEventKind},
+    StatusCodes, CONFIG,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref EVENT_QUEUE: Mutex<Vec<AttentionEvent>> = Mutex::new(Vec::new());
+    // Rising edges per digital input, to acknowledge a check by a new
+    // activation rather than by an input that is stuck active
+    static ref ACTIVATIONS: std::sync::Mutex<HashMap<String, u64>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+// Called on each rising edge of a digital input, also while the digital
+// inputs are paused
+pub fn rising_edge(name: &str) {
+    *ACTIVATIONS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default() += 1;
+}
+
+pub fn activations(name: &str) -> u64 {
+    ACTIVATIONS
+        .lock()
+        .unwrap()
+        .get(name)
+        .copied()
+        .unwrap_or_default()
+}
+
+async fn audit(kind: AttentionEventKind, response: Duration) {
+    EVENT_QUEUE.lock().await.push(AttentionEvent {
+        time_stamp: history::unix_millis(SystemTime::now()),
+        kind: kind as i32,
+        response_ms: response.as_millis() as u32,
+    });
+}
+
+fn set(output: &str, active: bool) {
+    if let Err(e) = set_digital_out(output, active) {
+        eprintln!("Attention check failed to set {output}: {e}");
+    }
+}
+
+pub async fn attention_monitor() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.attention.as_ref().unwrap();
+    let window = Duration::from_secs(config.window_s);
+    loop {
+        sleep(Duration::from_secs(config.interval_s)).await;
+        // The outputs are kept in their defaults during a local override
+        if local_override::is_active().await {
+            continue;
+        }
+
+        let started = Instant::now();
+        let count = activations(&config.input);
+        audit(AttentionEventKind::AttentionCheck, Duration::ZERO).await;
+        set(&config.output, true);
+
+        let mut escalated = false;
+        while activations(&config.input) == count {
+            if !escalated && started.elapsed() >= window {
+                eprintln!(
+                    "Attention check not acknowledged within {} s",
+                    window.as_secs()
+                );
+                escalated = true;
+                audit(AttentionEventKind::AttentionMissed, started.elapsed()).await;
+                set_status(StatusCodes::AttentionMissed).await;
+                if let Some(alarm) = &config.alarm {
+                    set(alarm, true);
+                }
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+
+        audit(AttentionEventKind::AttentionAcknowledged, started.elapsed()).await;
+        set(&config.output, false);
+        if escalated {
+            println!("Attention check acknowledged");
+            clear_status(StatusCodes::AttentionMissed).await;
+            if let Some(alarm) = &config.alarm {
+                set(alarm, false);
+            }
+        }
+    }
+}
+
+pub async fn attention_event_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    loop {
+        let events: Vec<AttentionEvent> = EVENT_QUEUE.lock().await.drain(..).collect();
+        for event in events {
+            let mut retry_sleep_s = CONFIG.time.sleep_min_s;
+            loop {
+                tap::record("SendAttentionEvent", &event).await;
+                let response = client.send_attention_event(event.clone()).await;
+                if handle_send_result(response, &mut retry_sleep_s)
+                    .await
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Backpressure from the sender to the producers. When the backlog of CAN
+// messages waiting to be sent grows past the thresholds of the
+// configured degradation ladder, e.g. while the server is unreachable,
+// the producers reduce the data instead of filling up memory and spool:
+// changes of a signal are sent at most every min_interval_ms, changes
+// within the deadband are left out and periodic signals are sampled less
+// often. A level is left when the backlog has shrunk to half of its
+// threshold.
+//
+// The server can also ask for less data to protect the backend, with a
+// rate hint in its replies. Only the given percentage of the changes of
+// each signal and of the periodic samples are then sent, until the hint
+// expires.
+//
+// While the client sheds load because of CPU use or event loop lag, the
+// limits of the load_shedding config apply on top of the backlog level
+// and the best effort signals are left out.
+//
+// The rate policy of the current geographic situation, e.g. parked or in
+// a depot zone, overrides the limits of the backlog level that it sets.
+
+use lazy_static::lazy_static;
+use lib::{BackpressureLevel, RatePolicy, CONFIG};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+static SHEDDING: AtomicBool = AtomicBool::new(false);
+
+const DEFAULT_HINT_DURATION_S: u64 = 600;
+
+lazy_static! {
+    static ref LEVEL: Mutex<Option<usize>> = Mutex::new(None);
+    // Percentage of the data to send and when the hint expires
+    static ref SERVER_HINT: Mutex<Option<(u32, Instant)>> = Mutex::new(None);
+    static ref GEO_POLICY: Mutex<Option<RatePolicy>> = Mutex::new(None);
+}
+
+fn levels() -> &'static [BackpressureLevel] {
+    CONFIG.backpressure.as_deref().unwrap_or_default()
+}
+
+// Select the level for the backlog. The levels are ordered by threshold.
+fn select(levels: &[BackpressureLevel], backlog: usize, current: Option<usize>) -> Option<usize> {
+    let reached = levels.iter().rposition(|l| backlog >= l.backlog);
+    match current {
+        // Stay until the backlog has shrunk to half the threshold
+        Some(c) if reached < Some(c) && backlog >= levels[c].backlog / 2 => Some(c),
+        _ => reached,
+    }
+}
+
+pub async fn update(backlog: usize) {
+    if levels().is_empty() {
+        return;
+    }
+    let mut level = LEVEL.lock().await;
+    let selected = select(levels(), backlog, *level);
+    if selected != *level {
+        match selected {
+            Some(l) => println!("Backlog of {backlog} messages, degrading to level {l}"),
+            None => println!("Backlog of {backlog} messages, no longer degrading"),
+        }
+        *level = selected;
+    }
+}
+
+pub async fn current() -> Option<BackpressureLevel> {
+    let mut level = (*LEVEL.lock().await).map(|l| levels()[l].clone());
+    if let Some(policy) = GEO_POLICY.lock().await.as_ref() {
+        level = Some(with_policy(level, policy));
+    }
+    if !is_shedding() {
+        return level;
+    }
+    let shedding = shedding_level();
+    Some(match level {
+        Some(level) => stricter(&level, &shedding),
+        None => shedding,
+    })
+}
+
+// The limits that a rate policy sets take the place of those of the
+// level, so that e.g. a zone can raise a rate that the backlog limits
+fn with_policy(level: Option<BackpressureLevel>, policy: &RatePolicy) -> BackpressureLevel {
+    let level = level.unwrap_or(BackpressureLevel {
+        backlog: 0,
+        min_interval_ms: None,
+        deadband_percent: None,
+        periodic_factor: None,
+    });
+    BackpressureLevel {
+        backlog: level.backlog,
+        min_interval_ms: policy.min_interval_ms.or(level.min_interval_ms),
+        deadband_percent: policy.deadband_percent.or(level.deadband_percent),
+        periodic_factor: policy.periodic_factor.or(level.periodic_factor),
+    }
+}
+
+// Apply the rate policy of the geographic situation, or none
+pub async fn set_geo_policy(policy: Option<RatePolicy>) {
+    *GEO_POLICY.lock().await = policy;
+}
+
+pub fn set_shedding(shedding: bool) {
+    SHEDDING.store(shedding, Ordering::Relaxed);
+}
+
+pub fn is_shedding() -> bool {
+    SHEDDING.load(Ordering::Relaxed)
+}
+
+// Whether a signal is left out while shedding load
+pub fn is_shed(name: &str) -> bool {
+    is_shedding()
+        && CONFIG
+            .load_shedding
+            .as_ref()
+            .and_then(|c| c.best_effort.as_ref())
+            .is_some_and(|signals| signals.iter().any(|s| s == name))
+}
+
+fn shedding_level() -> BackpressureLevel {
+    let config = CONFIG.load_shedding.as_ref();
+    BackpressureLevel {
+        backlog: 0,
+        min_interval_ms: config.and_then(|c| c.min_interval_ms),
+        deadband_percent: config.and_then(|c| c.deadband_percent),
+        periodic_factor: config.and_then(|c| c.periodic_factor),
+    }
+}
+
+// The stricter of each limit of two levels
+fn stricter(a: &BackpressureLevel, b: &BackpressureLevel) -> BackpressureLevel {
+    fn max<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(if a > b { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+    BackpressureLevel {
+        backlog: a.backlog.max(b.backlog),
+        min_interval_ms: max(a.min_interval_ms, b.min_interval_ms),
+        deadband_percent: max(a.deadband_percent, b.deadband_percent),
+        periodic_factor: max(a.periodic_factor, b.periodic_factor),
+    }
+}
+
+// Apply a rate hint from the server. A rate of 100 percent or more
+// lifts the hint.
+pub async fn set_server_hint(rate_percent: u32, duration_s: u32) {
+    let mut hint = SERVER_HINT.lock().await;
+    if rate_percent >= 100 {
+        if hint.take().is_some() {
+            println!("Server rate hint lifted");
+        }
+        return;
+    }
+    let duration_s = match duration_s {
+        0 => DEFAULT_HINT_DURATION_S,
+        s => s as u64,
+    };
+    if !matches!(*hint, Some((rate, _)) if rate == rate_percent) {
+        println!("Server asks for {rate_percent}% of the data for {duration_s} s");
+    }
+    *hint = Some((
+        rate_percent,
+        Instant::now() + Duration::from_secs(duration_s),
+    ));
+}
+
+// Percentage of the data to send, if the server has asked for less
+pub async fn server_rate() -> Option<u32> {
+    let mut hint = SERVER_HINT.lock().await;
+    match *hint {
+        Some((rate, until)) if Instant::now() < until => Some(rate),
+        Some(_) => {
+            println!("Server rate hint expired");
+            *hint = None;
+            None
+        }
+        None => None,
+    }
+}
+
+// Whether the nth (counting from 1) sample should be sent at the given
+// rate. The kept samples are spread evenly.
+pub fn keep_sample(n: u64, rate_percent: u32) -> bool {
+    let rate = rate_percent as u64;
+    n * rate / 100 != (n - 1) * rate / 100
+}
+
+// Whether a changed value should be left out. The deadband is a percentage
+// of the range of the signal or, if the range is unknown, of the last sent
+// value.
+pub fn is_throttled(
+    level: &BackpressureLevel,
+    last_sent: Option<&(Instant, Option<f64>)>,
+    value: Option<f64>,
+    range: f64,
+) -> bool {
+    let (sent, sent_value) = match last_sent {
+        Some(last_sent) => last_sent,
+        None => return false,
+    };
+    if let Some(min_interval_ms) = level.min_interval_ms {
+        if sent.elapsed() < Duration::from_millis(min_interval_ms) {
+            return true;
+        }
+    }
+    match (level.deadband_percent, value, sent_value) {
+        (Some(deadband), Some(value), Some(sent_value)) => {
+            let scale = if range > 0.0 { range } else { sent_value.abs() };
+            (value - sent_value).abs() <= scale * deadband / 100.0
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(backlog: usize) -> BackpressureLevel {
+        BackpressureLevel {
+            backlog,
+            min_interval_ms: None,
+            deadband_percent: None,
+            periodic_factor: None,
+        }
+    }
+
+    #[test]
+    fn select_uses_hysteresis() {
+        let levels = [level(100), level(1000)];
+        assert_eq!(select(&levels, 50, None), None);
+        assert_eq!(select(&levels, 100, None), Some(0));
+        assert_eq!(select(&levels, 1500, Some(0)), Some(1));
+        // Stay until the backlog is down to half the threshold
+        assert_eq!(select(&levels, 600, Some(1)), Some(1));
+        assert_eq!(select(&levels, 400, Some(1)), Some(0));
+        assert_eq!(select(&levels, 49, Some(0)), None);
+    }
+
+    #[test]
+    fn deadband_is_relative_to_range() {
+        let level = BackpressureLevel {
+            deadband_percent: Some(1.0),
+            ..level(0)
+        };
+        let sent = (Instant::now(), Some(50.0));
+        assert!(is_throttled(&level, Some(&sent), Some(50.5), 100.0));
+        assert!(!is_throttled(&level, Some(&sent), Some(51.5), 100.0));
+        // Without a range, relative to the last sent value
+        assert!(is_throttled(&level, Some(&sent), Some(50.4), 0.0));
+        assert!(!is_throttled(&level, Some(&sent), Some(50.6), 0.0));
+        assert!(!is_throttled(&level, None, Some(50.0), 100.0));
+    }
+
+    #[test]
+    fn keeps_samples_at_rate() {
+        let kept = |rate| (1..=100).filter(|n| keep_sample(*n, rate)).count();
+        assert_eq!(kept(50), 50);
+        assert_eq!(kept(10), 10);
+        assert_eq!(kept(0), 0);
+        assert!(keep_sample(2, 50));
+        assert!(!keep_sample(3, 50));
+    }
+
+    #[test]
+    fn stricter_takes_the_larger_limits() {
+        let a = BackpressureLevel {
+            min_interval_ms: Some(100),
+            deadband_percent: Some(2.0),
+            ..level(1000)
+        };
+        let b = BackpressureLevel {
+            min_interval_ms: Some(500),
+            periodic_factor: Some(4),
+            ..level(0)
+        };
+        let s = stricter(&a, &b);
+        assert_eq!(s.min_interval_ms, Some(500));
+        assert_eq!(s.deadband_percent, Some(2.0));
+        assert_eq!(s.periodic_factor, Some(4));
+    }
+
+    #[test]
+    fn zone_policy_raises_the_rate() {
+        let backlog = BackpressureLevel {
+            min_interval_ms: Some(10_000),
+            deadband_percent: Some(2.0),
+            ..level(1000)
+        };
+        let zone = RatePolicy {
+            min_interval_ms: Some(100),
+            ..Default::default()
+        };
+        let l = with_policy(Some(backlog), &zone);
+        assert_eq!(l.min_interval_ms, Some(100));
+        assert_eq!(l.deadband_percent, Some(2.0));
+        assert_eq!(l.backlog, 1000);
+
+        let l = with_policy(None, &zone);
+        assert_eq!(l.min_interval_ms, Some(100));
+        assert_eq!(l.periodic_factor, None);
+    }
+
+    #[test]
+    fn min_interval_throttles_recent_signals() {
+        let level = BackpressureLevel {
+            min_interval_ms: Some(60_000),
+            ..level(0)
+        };
+        let sent = (Instant::now(), None);
+        assert!(is_throttled(&level, Some(&sent), None, 0.0));
+    }
+}
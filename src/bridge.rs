@@ -0,0 +1,122 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Filtered forwarding of frames between CAN ports, so that the device can
+// replace a simple CAN bridge. Only allowlisted IDs are forwarded, and
+// each ID at most max_rate_hz. A forwarded frame is also seen by the
+// other sockets on the destination port, so frames that a bridge in the
+// opposite direction just forwarded are not sent back.
+
+use super::fdstore;
+use lazy_static::lazy_static;
+use lib::{BridgeConfig, MIN_BRIDGE_RATE_HZ};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tokio_socketcan::{CANFilter, CANSocket};
+
+const ECHO_WINDOW: Duration = Duration::from_millis(100);
+// Pause after a read error, e.g. while the source port recovers from bus
+// off, so that the bridge does not spin until the port is back
+const READ_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+type FrameKey = (String, u32, Vec<u8>);
+
+lazy_static! {
+    // Frames forwarded to a port, and when
+    static ref FORWARDED: Mutex<HashMap<FrameKey, Instant>> = Mutex::new(HashMap::new());
+}
+
+pub async fn bridge(config: &BridgeConfig) -> Result<(), Box<dyn Error>> {
+    let rx = fdstore::open_can(&config.from, &fdstore::bridge_socket_name(config))?;
+    let filters = config
+        .ids
+        .iter()
+        .map(|id| CANFilter::new(*id, libc::CAN_EFF_MASK))
+        .collect::<Result<Vec<_>, _>>()?;
+    rx.set_filter(&filters)?;
+    let tx = CANSocket::open(&config.to)?;
+
+    let min_interval = config
+        .max_rate_hz
+        .map(|hz| Duration::from_secs_f64(1.0 / hz.max(MIN_BRIDGE_RATE_HZ)));
+    let mut last_forwarded: HashMap<u32, Instant> = HashMap::new();
+    println!(
+        "Bridging {:?} from {} to {}",
+        config.ids, config.from, config.to
+    );
+
+    // Errors on opening the ports above are config problems, while read
+    // errors pass, e.g. when the port comes back after a bus off. Only the
+    // first error of a run is logged.
+    let mut read_failing = false;
+    loop {
+        let frame = match rx.read_frame().await {
+            Ok(frame) => {
+                if read_failing {
+                    println!("Bridging from {} again", config.from);
+                    read_failing = false;
+                }
+                frame
+            }
+            Err(e) => {
+                if !read_failing {
+                    eprintln!("Failed to read from {} for bridging: {e}", config.from);
+                    read_failing = true;
+                }
+                sleep(READ_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let now = Instant::now();
+
+        if is_echo(&config.from, frame.id(), frame.data(), now).await {
+            continue;
+        }
+        if let (Some(min_interval), Some(last)) = (min_interval, last_forwarded.get(&frame.id())) {
+            if now.duration_since(*last) < min_interval {
+                continue;
+            }
+        }
+        last_forwarded.insert(frame.id(), now);
+
+        FORWARDED
+            .lock()
+            .await
+            .insert((config.to.clone(), frame.id(), frame.data().to_vec()), now);
+        if let Err(e) = tx.write_frame(frame)?.await {
+            eprintln!(
+                "Failed to forward frame {:#x} to {}: {}",
+                frame.id(),
+                config.to,
+                e
+            );
+        }
+    }
+}
+
+// Whether the frame was just forwarded to the port by a bridge
+async fn is_echo(port: &str, id: u32, data: &[u8], now: Instant) -> bool {
+    let mut forwarded = FORWARDED.lock().await;
+    forwarded.retain(|_, sent| now.duration_since(*sent) < ECHO_WINDOW);
+    forwarded
+        .remove(&(port.to_string(), id, data.to_vec()))
+        .is_some()
+}
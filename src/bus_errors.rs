@@ -0,0 +1,331 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Reporting of CAN bus errors. The controller signals e.g. missing
+// acknowledgements, error counters and state changes with error frames,
+// which are otherwise not received, so that a failing bus looks like an
+// idle one. Error frames can arrive at the bit rate of the bus, so
+// repeats are coalesced and reported at most once per interval, while
+// state changes are reported at once. Repeats that are still unreported
+// when a burst stops are reported after the interval.
+
+use super::log_level::debug;
+use super::net::{handle_send_result, intercept};
+use super::subsystem;
+use super::tap;
+use super::utils::stream_batch;
+use futures::stream::StreamExt;
+use lazy_static::lazy_static;
+use lib::{
+    history,
+    host_insight::{agent_client::AgentClient, CanBusError, CanControllerState, Subsystem},
+    CanPort, CONFIG,
+};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tokio_socketcan::CANSocket;
+use tonic::transport::Channel;
+use tonic::Request;
+
+// Error classes of an error frame, from linux/can/error.h
+const CAN_ERR_CRTL: u32 = 0x04;
+const CAN_ERR_BUSOFF: u32 = 0x40;
+const CAN_ERR_RESTARTED: u32 = 0x100;
+const CAN_ERR_CNT: u32 = 0x200;
+const CAN_ERR_MASK: u32 = 0x1fffffff;
+const CLASSES: [(u32, &str); 9] = [
+    (0x01, "tx_timeout"),
+    (0x02, "lost_arbitration"),
+    (CAN_ERR_CRTL, "controller"),
+    (0x08, "protocol"),
+    (0x10, "transceiver"),
+    (0x20, "ack"),
+    (CAN_ERR_BUSOFF, "bus_off"),
+    (0x80, "bus_error"),
+    (CAN_ERR_RESTARTED, "restarted"),
+];
+
+// Controller status in data[1] of a controller error
+const CAN_ERR_CRTL_RX_WARNING: u8 = 0x04;
+const CAN_ERR_CRTL_TX_WARNING: u8 = 0x08;
+const CAN_ERR_CRTL_RX_PASSIVE: u8 = 0x10;
+const CAN_ERR_CRTL_TX_PASSIVE: u8 = 0x20;
+const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_ERRORS_TO_SEND: usize = 100;
+const MAX_QUEUED_ERRORS: usize = 1000;
+
+lazy_static! {
+    static ref BUS_ERROR_QUEUE: Mutex<VecDeque<CanBusError>> = Mutex::new(VecDeque::new());
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG
+        .can
+        .as_ref()
+        .is_some_and(|c| c.bus_errors == Some(true))
+}
+
+fn classes(errors: u32) -> Vec<String> {
+    CLASSES
+        .iter()
+        .filter(|(class, _)| errors & class != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+// The controller state after an error frame
+fn controller_state(errors: u32, data: &[u8], state: CanControllerState) -> CanControllerState {
+    let status = data.get(1).copied().unwrap_or(0);
+    if errors & CAN_ERR_BUSOFF != 0 {
+        CanControllerState::CanStateBusOff
+    } else if errors & CAN_ERR_CRTL != 0 && status != 0 {
+        if status & (CAN_ERR_CRTL_RX_PASSIVE | CAN_ERR_CRTL_TX_PASSIVE) != 0 {
+            CanControllerState::CanStateErrorPassive
+        } else if status & (CAN_ERR_CRTL_RX_WARNING | CAN_ERR_CRTL_TX_WARNING) != 0 {
+            CanControllerState::CanStateErrorWarning
+        } else if status & CAN_ERR_CRTL_ACTIVE != 0 {
+            CanControllerState::CanStateErrorActive
+        } else {
+            state
+        }
+    } else if errors & CAN_ERR_RESTARTED != 0 {
+        CanControllerState::CanStateErrorActive
+    } else {
+        state
+    }
+}
+
+// The error frames of one port
+struct Tracker {
+    bus: String,
+    state: CanControllerState,
+    // When and which errors were last reported
+    reported: Option<(Instant, u32)>,
+    // Error frames since the last report, the last of which is pending
+    // while it repeats the report
+    count: u32,
+    pending: Option<(u32, Vec<u8>)>,
+}
+
+impl Tracker {
+    fn new(bus: &str) -> Tracker {
+        Tracker {
+            bus: bus.to_string(),
+            state: CanControllerState::CanStateUnknown,
+            reported: None,
+            count: 0,
+            pending: None,
+        }
+    }
+
+    // Handle an error frame. Returns a report unless it repeats the last
+    // one within the interval.
+    fn handle(&mut self, errors: u32, data: &[u8], now: Instant) -> Option<CanBusError> {
+        let state = controller_state(errors, data, self.state);
+        let changed = state != self.state;
+        self.state = state;
+        self.count += 1;
+        match self.reported {
+            Some((at, last)) if !changed && last == errors && now - at < REPORT_INTERVAL => {
+                self.pending = Some((errors, data.to_vec()));
+                None
+            }
+            _ => Some(self.report(errors, data, now)),
+        }
+    }
+
+    // Report the pending error frames once the interval has passed
+    // without another report
+    fn flush(&mut self, now: Instant) -> Option<CanBusError> {
+        match self.reported {
+            Some((at, _)) if now - at < REPORT_INTERVAL => None,
+            _ => {
+                let (errors, data) = self.pending.take()?;
+                Some(self.report(errors, &data, now))
+            }
+        }
+    }
+
+    fn report(&mut self, errors: u32, data: &[u8], now: Instant) -> CanBusError {
+        self.reported = Some((now, errors));
+        self.pending = None;
+        let counters = errors & CAN_ERR_CNT != 0 && data.len() == 8;
+        let report = CanBusError {
+            bus: self.bus.clone(),
+            time_stamp: history::unix_millis(SystemTime::now()),
+            errors: classes(errors),
+            state: self.state as i32,
+            tx_error_count: counters.then(|| data[6].into()),
+            rx_error_count: counters.then(|| data[7].into()),
+            data: data.to_vec(),
+            count: self.count,
+        };
+        self.count = 0;
+        report
+    }
+}
+
+pub async fn bus_error_monitor(port: &CanPort) -> Result<(), Box<dyn Error>> {
+    let mut socket = CANSocket::open(&port.name)?;
+    // Only the error frames are read from the socket
+    socket.filter_drop_all()?;
+    socket.set_error_filter(CAN_ERR_MASK)?;
+    eprintln!("Start reading bus errors from {}", &port.name);
+
+    let mut tracker = Tracker::new(&port.name);
+    let mut flush = interval(REPORT_INTERVAL);
+    flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let state = tracker.state;
+        let report = tokio::select! {
+            frame = socket.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(_)) => continue,
+                    None => break,
+                };
+                if !subsystem::is_enabled(Subsystem::Can).await {
+                    continue;
+                }
+                tracker.handle(frame.err(), frame.data(), Instant::now())
+            }
+            _ = flush.tick() => tracker.flush(Instant::now()),
+        };
+        let report = match report {
+            Some(report) => report,
+            None => continue,
+        };
+        if tracker.state != state {
+            eprintln!("{} is {}", port.name, tracker.state.as_str_name());
+        }
+        queue_report(report).await;
+    }
+    Ok(())
+}
+
+// Queue a report for sending, dropping the oldest if the queue is full
+pub async fn queue_report(report: CanBusError) {
+    let mut queue = BUS_ERROR_QUEUE.lock().await;
+    if queue.len() >= MAX_QUEUED_ERRORS {
+        queue.pop_front();
+    }
+    queue.push_back(report);
+}
+
+pub async fn bus_error_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    loop {
+        let errors: Vec<_> = {
+            let mut queue = BUS_ERROR_QUEUE.lock().await;
+            let n = queue.len().min(MAX_ERRORS_TO_SEND);
+            queue.drain(..n).collect()
+        };
+        if errors.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+        debug!("Sending {} CAN bus errors", errors.len());
+
+        let errors = Arc::new(errors);
+        let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+        loop {
+            tap::record_all("SendCanBusErrors", errors.as_slice()).await;
+            let request = Request::new(stream_batch(&errors));
+            let response = client.send_can_bus_errors(request).await;
+            if handle_send_result(response, &mut retry_sleep_s)
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_states() {
+        let unknown = CanControllerState::CanStateUnknown;
+        let passive = [0, CAN_ERR_CRTL_TX_PASSIVE, 0, 0, 0, 0, 0, 0];
+        let state = controller_state(CAN_ERR_CRTL, &passive, unknown);
+        assert_eq!(state, CanControllerState::CanStateErrorPassive);
+        // An ACK error does not change the state
+        let state = controller_state(0x20, &[0; 8], state);
+        assert_eq!(state, CanControllerState::CanStateErrorPassive);
+        let state = controller_state(CAN_ERR_BUSOFF, &[0; 8], state);
+        assert_eq!(state, CanControllerState::CanStateBusOff);
+        let state = controller_state(CAN_ERR_RESTARTED, &[0; 8], state);
+        assert_eq!(state, CanControllerState::CanStateErrorActive);
+    }
+
+    #[test]
+    fn repeats_are_coalesced() {
+        let t0 = Instant::now();
+        let mut tracker = Tracker::new("can0");
+        let ack = 0x20 | CAN_ERR_CNT;
+        let data = [0, 0, 0, 0, 0, 0, 128, 0];
+
+        let report = tracker.handle(ack, &data, t0).unwrap();
+        assert_eq!(report.errors, ["ack"]);
+        assert_eq!(report.tx_error_count, Some(128));
+        assert_eq!(report.count, 1);
+        assert!(tracker.handle(ack, &data, t0).is_none());
+        assert!(tracker.handle(ack, &data, t0).is_none());
+
+        // A state change is reported at once
+        let warning = [0, CAN_ERR_CRTL_TX_WARNING, 0, 0, 0, 0, 96, 0];
+        let report = tracker.handle(ack | CAN_ERR_CRTL, &warning, t0).unwrap();
+        assert_eq!(
+            report.state,
+            CanControllerState::CanStateErrorWarning as i32
+        );
+        assert_eq!(report.count, 3);
+
+        let report = tracker.handle(ack | CAN_ERR_CRTL, &warning, t0 + REPORT_INTERVAL);
+        assert_eq!(report.unwrap().count, 1);
+    }
+
+    #[test]
+    fn stopped_bursts_are_flushed() {
+        let t0 = Instant::now();
+        let mut tracker = Tracker::new("can0");
+        let ack = 0x20 | CAN_ERR_CNT;
+        let data = [0, 0, 0, 0, 0, 0, 128, 0];
+        assert!(tracker.flush(t0).is_none());
+
+        assert_eq!(tracker.handle(ack, &data, t0).unwrap().count, 1);
+        let last = [0, 0, 0, 0, 0, 0, 130, 0];
+        assert!(tracker.handle(ack, &data, t0).is_none());
+        assert!(tracker.handle(ack, &last, t0).is_none());
+        // The burst stops, and the repeats are reported after the interval
+        assert!(tracker.flush(t0 + REPORT_INTERVAL / 2).is_none());
+        let report = tracker.flush(t0 + REPORT_INTERVAL).unwrap();
+        assert_eq!(report.count, 2);
+        assert_eq!(report.tx_error_count, Some(130));
+        assert!(tracker.flush(t0 + REPORT_INTERVAL * 3).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Recovery of CAN ports from bus-off. A controller that has gone bus
+// off stops receiving until it is restarted, which the kernel only does
+// with restart_ms and not all drivers support. The state of a port with
+// bus_off_backoff_ms is polled, and a port that is bus off is reported,
+// and set up and brought up again after the backoff. The backoff doubles
+// while the port goes bus off again soon after each recovery.
+
+use super::bus_errors;
+use super::can::bring_up;
+use lib::{
+    history,
+    host_insight::{CanBusError, CanControllerState},
+    CanPort, CONFIG,
+};
+use serde_json::Value;
+use std::error::Error;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::sleep;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// A port that stays up this long after a recovery is back to the
+// configured backoff
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub fn is_enabled_for(port: &CanPort) -> bool {
+    port.bus_off_backoff_ms.is_some()
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG
+        .can
+        .iter()
+        .flat_map(|c| c.ports.iter().flatten())
+        .any(is_enabled_for)
+}
+
+// The controller state in the output of ip -details -json link show
+fn parse_state(json: &str) -> CanControllerState {
+    let links: Value = serde_json::from_str(json).unwrap_or_default();
+    let state = links
+        .get(0)
+        .and_then(|l| l.pointer("/linkinfo/info_data/state"))
+        .and_then(Value::as_str);
+    match state {
+        Some("ERROR-ACTIVE") => CanControllerState::CanStateErrorActive,
+        Some("ERROR-WARNING") => CanControllerState::CanStateErrorWarning,
+        Some("ERROR-PASSIVE") => CanControllerState::CanStateErrorPassive,
+        Some("BUS-OFF") => CanControllerState::CanStateBusOff,
+        _ => CanControllerState::CanStateUnknown,
+    }
+}
+
+async fn controller_state(interface: &str) -> CanControllerState {
+    let interface = interface.to_string();
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new("ip")
+            .args(["-details", "-json", "link", "show", "dev", &interface])
+            .output()
+    })
+    .await;
+    match output {
+        Ok(Ok(output)) if output.status.success() => {
+            parse_state(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => CanControllerState::CanStateUnknown,
+    }
+}
+
+fn next_backoff(backoff: Duration, initial: Duration, recovered: Option<Instant>) -> Duration {
+    match recovered {
+        Some(at) if at.elapsed() < STABLE_AFTER => (backoff * 2).min(MAX_BACKOFF.max(initial)),
+        _ => initial,
+    }
+}
+
+async fn report(port: &CanPort, errors: &str, state: CanControllerState) {
+    bus_errors::queue_report(CanBusError {
+        bus: port.name.clone(),
+        time_stamp: history::unix_millis(SystemTime::now()),
+        errors: vec![errors.to_string()],
+        state: state as i32,
+        count: 1,
+        ..Default::default()
+    })
+    .await;
+}
+
+pub async fn bus_off_monitor(port: &CanPort) -> Result<(), Box<dyn Error>> {
+    let initial = Duration::from_millis(port.bus_off_backoff_ms.unwrap());
+    let mut backoff = initial;
+    let mut recovered: Option<Instant> = None;
+    loop {
+        sleep(POLL_INTERVAL).await;
+        if controller_state(&port.name).await != CanControllerState::CanStateBusOff {
+            continue;
+        }
+        backoff = next_backoff(backoff, initial, recovered);
+        eprintln!(
+            "{} is bus off, bringing it up again in {} ms",
+            port.name,
+            backoff.as_millis()
+        );
+        // The error frames already report it when bus errors are reported
+        if !bus_errors::is_enabled() {
+            report(port, "bus_off", CanControllerState::CanStateBusOff).await;
+        }
+        sleep(backoff).await;
+
+        // The kernel may have restarted the controller in the meantime
+        if controller_state(&port.name).await == CanControllerState::CanStateBusOff {
+            let port = port.clone();
+            tokio::task::spawn_blocking(move || bring_up(&port)).await?;
+        }
+        recovered = Some(Instant::now());
+        let state = controller_state(&port.name).await;
+        report(port, "restarted", state).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states() {
+        let json = r#"[{"ifname":"can0","linkinfo":{"info_kind":"can",
+            "info_data":{"state":"BUS-OFF","restart_ms":0}}}]"#;
+        assert_eq!(parse_state(json), CanControllerState::CanStateBusOff);
+        let json = r#"[{"ifname":"can0","linkinfo":{"info_data":{"state":"ERROR-ACTIVE"}}}]"#;
+        assert_eq!(parse_state(json), CanControllerState::CanStateErrorActive);
+        // A port that is down has no state
+        let json = r#"[{"ifname":"can0","linkinfo":{"info_kind":"can"}}]"#;
+        assert_eq!(parse_state(json), CanControllerState::CanStateUnknown);
+    }
+
+    #[test]
+    fn backoff_doubles_while_unstable() {
+        let initial = Duration::from_millis(500);
+        assert_eq!(next_backoff(initial, initial, None), initial);
+        let now = Some(Instant::now());
+        let backoff = next_backoff(initial, initial, now);
+        assert_eq!(backoff, Duration::from_secs(1));
+        assert_eq!(next_backoff(MAX_BACKOFF, initial, now), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Process-wide cache of the latest value of every signal and input,
+// keyed by source (e.g. a CAN bus or "digital_in") and name. The values
+// are stored by name first, so that a signal can be looked up by name
+// alone, e.g. for composites, without a scan of the cache.
+
+use super::history;
+use super::host_insight::can_signal;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, RwLock};
+
+pub const DIGITAL_IN_SOURCE: &str = "digital_in";
+// Updates buffered for a subscriber that falls behind
+const UPDATE_CHANNEL_CAPACITY: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedValue {
+    pub value: can_signal::Value,
+    pub unit: String,
+    pub updated: SystemTime,
+    // The same on the monotonic clock, for measuring intervals
+    pub updated_at: Instant,
+    // When the value was last changed or refreshed
+    pub reported: Instant,
+}
+
+// A value stored in the cache, as seen by subscribers
+#[derive(Clone, Debug)]
+pub struct Update {
+    pub name: String,
+    pub value: can_signal::Value,
+    pub at: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Freshness {
+    Changed,
+    // Unchanged, but not reported for longer than the max age
+    Stale,
+    Unchanged,
+}
+
+lazy_static! {
+    // By name, then by source
+    static ref LAST_VALUES: RwLock<HashMap<String, HashMap<String, CachedValue>>> =
+        RwLock::new(HashMap::new());
+    static ref UPDATES: broadcast::Sender<Update> = broadcast::channel(UPDATE_CHANNEL_CAPACITY).0;
+}
+
+// Receive every value stored from now on, e.g. to evaluate conditions
+// that may hold for less time than a polling interval
+pub fn subscribe() -> broadcast::Receiver<Update> {
+    UPDATES.subscribe()
+}
+
+// Store the latest value of a signal. Returns true if the value
+// differs from the previously cached one.
+pub async fn update(source: &str, name: &str, unit: &str, value: can_signal::Value) -> bool {
+    update_with_max_age(source, name, unit, value, None).await != Freshness::Unchanged
+}
+
+// Store the latest value of a signal. An unchanged value is reported as
+// stale once it has not been changed or refreshed for max_age, so that
+// it can be sent again.
+pub async fn update_with_max_age(
+    source: &str,
+    name: &str,
+    unit: &str,
+    value: can_signal::Value,
+    max_age: Option<Duration>,
+) -> Freshness {
+    let now = SystemTime::now();
+    let now_at = Instant::now();
+    history::record(source, name, &value, now).await;
+
+    let mut map = LAST_VALUES.write().await;
+    let values = map.entry(name.to_string()).or_default();
+    let previous = values.get(source);
+    let freshness = match previous {
+        Some(previous) if previous.value != value => Freshness::Changed,
+        Some(previous) => match max_age {
+            Some(max_age) if now_at.duration_since(previous.reported) >= max_age => {
+                Freshness::Stale
+            }
+            _ => Freshness::Unchanged,
+        },
+        None => Freshness::Changed,
+    };
+    let reported = match (freshness, previous) {
+        (Freshness::Unchanged, Some(previous)) => previous.reported,
+        _ => now_at,
+    };
+    // Only copied if anyone is subscribed
+    let update = (UPDATES.receiver_count() > 0).then(|| Update {
+        name: name.to_string(),
+        value: value.clone(),
+        at: now_at,
+    });
+    values.insert(
+        source.to_string(),
+        CachedValue {
+            value,
+            unit: unit.to_string(),
+            updated: now,
+            updated_at: now_at,
+            reported,
+        },
+    );
+    drop(map);
+    if let Some(update) = update {
+        let _ = UPDATES.send(update);
+    }
+    freshness
+}
+
+pub async fn get(source: &str, name: &str) -> Option<CachedValue> {
+    LAST_VALUES.read().await.get(name)?.get(source).cloned()
+}
+
+// Get the latest value of a signal from any source
+pub async fn get_any(name: &str) -> Option<CachedValue> {
+    LAST_VALUES
+        .read()
+        .await
+        .get(name)?
+        .values()
+        .max_by_key(|v| v.updated_at)
+        .cloned()
+}
+
+// Get a copy of all cached values as (source, name, value)
+pub async fn snapshot() -> Vec<(String, String, CachedValue)> {
+    LAST_VALUES
+        .read()
+        .await
+        .iter()
+        .flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |(source, v)| (source.clone(), name.clone(), v.clone()))
+        })
+        .collect()
+}
+
+// Get the value as a number, if it is numeric
+pub fn numeric(value: &can_signal::Value) -> Option<f64> {
+    match value {
+        can_signal::Value::ValF64(v) => Some(*v),
+        can_signal::Value::ValI64(v) => Some(*v as f64),
+        can_signal::Value::ValU64(v) => Some(*v as f64),
+        can_signal::Value::ValStr(_) => None,
+    }
+}
@@ -16,57 +16,137 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
+use super::backpressure;
+use super::can_trace;
+use super::composite;
+use super::dbc::{shared_dbc, DBC_RETRY_INTERVAL};
+use super::decode_cache::DecodeCache;
+use super::fdstore;
+use super::intrusion;
+use super::j1939::{self, J1939Id, Packet};
+use super::log_level::debug;
 use super::net::{handle_send_result, intercept};
-use async_std::sync::Mutex;
-use can_dbc::{ByteOrder, MultiplexIndicator, SignalExtendedValueType};
-use futures::{stream, stream::StreamExt};
+use super::periodic::periodic_signals;
+use super::raw_can;
+use super::redundancy;
+use super::routing;
+use super::scrub;
+use super::spool;
+use super::stats;
+use super::subsystem::is_enabled;
+use super::tap;
+use super::transport;
+use super::utils::stream_batch;
 use lazy_static::lazy_static;
 use lib::{
-    host_insight::{agent_client::AgentClient, can_signal, CanMessage, CanSignal},
-    CanPort, ExitCodes, CONFIG, CONF_DIR,
+    cache::{self, Freshness},
+    decode::decode_signals,
+    error::ClientError,
+    history,
+    host_insight::{agent_client::AgentClient, CanMessage, CanSignal, Subsystem},
+    signal_name, CanPort, EnumEncoding, CONFIG,
 };
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
-use std::time::Duration;
-use tokio::time::sleep;
-use tokio_socketcan::CANSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
 use tonic::transport::Channel;
 use tonic::Request;
 
+const MAX_MSG_TO_SEND: usize = 100;
+const DEFAULT_DECODE_CACHE: usize = 1024;
+const DEFAULT_DATA_BITRATE: u32 = 2000000;
+// Pause after a read error, so that an error that persists, e.g. while
+// the interface is down, does not keep the task spinning
+const READ_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+// How often held-back values are checked while no frames arrive
+const HELD_BACK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// A queued message and when its frame was received and when it was queued
+struct QueuedMessage {
+    message: CanMessage,
+    received: Instant,
+    queued: Instant,
+}
+
 lazy_static! {
-    static ref CAN_MSG_QUEUE: Mutex<Vec<CanMessage>> = Mutex::new(Vec::new());
+    static ref CAN_MSG_QUEUE: Mutex<Vec<QueuedMessage>> = Mutex::new(Vec::new());
 }
 
-fn load_dbc_file(s: &str) -> Result<can_dbc::DBC, Box<dyn Error>> {
-    let path = PathBuf::from(format!("{}/{}", CONF_DIR, s));
-    let mut f = fs::File::open(path)?;
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)?;
-    let dbc = can_dbc::DBC::from_slice(&buffer).expect("Failed to parse dbc file");
-    Ok(dbc)
+// CAN messages sent since the last heartbeat
+static SENT_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+// Number of CAN messages sent since the last call, for the heartbeat
+pub fn take_sent_count() -> u64 {
+    SENT_MESSAGES.swap(0, Ordering::Relaxed)
 }
 
-// Checks if the last signal value sent is equal to supllied signal and value
-fn is_can_signal_duplicate(
-    map: &HashMap<String, Option<can_signal::Value>>,
-    name: &str,
-    val: &Option<can_signal::Value>,
-) -> bool {
-    if let Some(last_sent) = map.get_key_value(name) {
-        if Some(last_sent.1) == Some(val) {
-            return true;
+// Age of the oldest CAN message that has not been sent yet. Spooled
+// messages are older than the ones in memory, and are counted from when
+// they were spooled.
+pub async fn oldest_unsent_age() -> Option<Duration> {
+    if spool::is_enabled() {
+        if let Some(millis) = spool::oldest_segment_millis(spool::CAN_SPOOL) {
+            let age = history::unix_millis(SystemTime::now()).saturating_sub(millis);
+            return Some(Duration::from_millis(age.max(0) as u64));
         }
     }
-    false
+    CAN_MSG_QUEUE
+        .lock()
+        .await
+        .first()
+        .map(|queued| queued.received.elapsed())
 }
 
-pub async fn can_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
-    const MAX_MSG_TO_SEND: usize = 100;
+// Correct the time stamps of the queued messages after a step of the
+// wall clock
+pub async fn restamp_queue(correct: impl Fn(i64) -> Option<i64>) {
+    for queued in CAN_MSG_QUEUE.lock().await.iter_mut() {
+        restamp_message(&mut queued.message, &correct);
+    }
+}
+
+pub fn restamp_message(message: &mut CanMessage, correct: impl Fn(i64) -> Option<i64>) -> bool {
+    match message.time_stamp.and_then(correct) {
+        Some(time_stamp) => {
+            message.time_stamp = Some(time_stamp);
+            true
+        }
+        None => false,
+    }
+}
 
+pub async fn can_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
     loop {
+        let mut backlog = CAN_MSG_QUEUE.lock().await.len();
+        if spool::is_enabled() {
+            backlog += spool::segment_count(spool::CAN_SPOOL) * MAX_MSG_TO_SEND;
+        }
+        backpressure::update(backlog).await;
+
+        // Spooled messages are older than the ones in memory
+        if spool::is_enabled() {
+            if let Some(segment) = spool::oldest_segment(spool::CAN_SPOOL) {
+                match spool::read_segment::<CanMessage>(&segment) {
+                    Ok(messages) => {
+                        let count = messages.len() as u64;
+                        send_can_message_stream(channel.clone(), messages).await;
+                        SENT_MESSAGES.fetch_add(count, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        eprintln!("Quarantining spool segment {:?}: {}", segment, e);
+                        spool::quarantine_segment(&segment);
+                        continue;
+                    }
+                }
+                spool::remove_segment(&segment);
+                continue;
+            }
+        }
+
         let mut vec = Vec::new();
 
         let mut req_map = CAN_MSG_QUEUE.lock().await;
@@ -86,379 +166,491 @@ pub async fn can_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
             drop(req_map);
         }
 
-        send_can_message_stream(channel.clone(), vec).await;
+        debug!(
+            "Sending {} CAN messages, {} left in the queue",
+            vec.len(),
+            len - vec.len()
+        );
+        let sent = Instant::now();
+        let timings: Vec<_> = vec.iter().map(|q| (q.received, q.queued)).collect();
+        send_can_message_stream(
+            channel.clone(),
+            vec.into_iter().map(|q| q.message).collect(),
+        )
+        .await;
+        SENT_MESSAGES.fetch_add(timings.len() as u64, Ordering::Relaxed);
+        stats::record_latencies(&timings, sent, Instant::now()).await;
     }
 }
 
 pub async fn can_monitor(port: &CanPort) -> Result<(), Box<dyn Error>> {
-    let dbc = load_dbc_file(CONFIG.can.as_ref().unwrap().dbc_file.as_ref().unwrap())
-        .unwrap_or_else(|_| std::process::exit(ExitCodes::Enoent as i32));
-
-    let mut map = HashMap::new();
-    let mut prev_map = HashMap::new();
-    for message in dbc.messages() {
-        map.insert(message.message_id().0, message);
+    let socket_rx =
+        fdstore::open_can(&port.name, &port.name).map_err(|source| ClientError::Can {
+            port: port.name.clone(),
+            source,
+        })?;
+    if port.fd == Some(true) {
+        socket_rx.enable_fd().map_err(|source| ClientError::Can {
+            port: port.name.clone(),
+            source,
+        })?;
     }
 
+    // A broken DBC file should not stop the other ports or the digital
+    // I/O, so keep retrying until a fixed file is in place. The failure
+    // is reported to the server by the DBC lint. Meanwhile, the frames
+    // are sent raw if the port allows it.
+    let dbc_file = CONFIG.can.as_ref().unwrap().dbc_file.as_ref().unwrap();
+    let dbc = loop {
+        match shared_dbc(dbc_file).await {
+            Ok(dbc) => break dbc,
+            Err(e) if raw_can::is_allowed_for(port) => {
+                eprintln!(
+                    "{}: could not load {}, sending raw frames: {}",
+                    port.name, dbc_file, e
+                );
+                let raw = raw_can::read_frames(port, &socket_rx);
+                if let Ok(result) = timeout(DBC_RETRY_INTERVAL, raw).await {
+                    result?;
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: could not load {}: {}", port.name, dbc_file, e);
+                sleep(DBC_RETRY_INTERVAL).await;
+            }
+        }
+    };
+
     let mut msg_map = HashMap::new();
     for message in dbc.messages() {
         msg_map.insert(message.message_id().0, message);
     }
 
-    let mut socket_rx = CANSocket::open(&port.name.clone())?;
+    // J1939 messages are looked up by PGN and source address
+    let j1939 = port.j1939 == Some(true);
+    let j1939_messages = j1939::Messages::new(&dbc);
+    let mut transport = j1939::Reassembler::new();
+    let find_message = |id: u32| {
+        if j1939 {
+            j1939_messages.get(&j1939::parse_id(id))
+        } else {
+            msg_map.get(&id).copied()
+        }
+    };
+
+    // Unchanged signals are sent again after this time
+    let max_age = CONFIG
+        .can
+        .as_ref()
+        .unwrap()
+        .resend_unchanged_s
+        .map(Duration::from_secs);
+
+    // These signals are sent by the periodic reporter instead
+    let periodic_signals = periodic_signals();
+
+    let raw_enums = CONFIG.can.as_ref().unwrap().enums == Some(EnumEncoding::Raw);
+
+    // Frames often repeat the same payload, which is then decoded once
+    let mut decode_cache = DecodeCache::new(
+        CONFIG
+            .can
+            .as_ref()
+            .unwrap()
+            .decode_cache
+            .unwrap_or(DEFAULT_DECODE_CACHE),
+    );
+
+    // Without sending, signals are only decoded into the value cache,
+    // e.g. for heartbeats, alerts and the history
+    let send = CONFIG.can.as_ref().unwrap().send.unwrap_or(true);
+
+    // When and what was last sent of each signal, for backpressure
+    let mut last_sent: HashMap<String, (Instant, Option<f64>)> = HashMap::new();
+    // Signals whose latest change was held back, and so differ from what
+    // was last sent even if the cache says they are unchanged, with the
+    // raw value of the change
+    let mut held_back: HashMap<String, Option<u64>> = HashMap::new();
+    // Changes per signal, for the server rate hint
+    let mut changes: HashMap<String, u64> = HashMap::new();
+
+    // Ports of a redundancy group are reported as the group
+    let group = redundancy::group_of(port);
+    let bus = group.map_or(&port.name, |g| &g.name);
+
     eprintln!("Start reading from {}", &port.name);
     if let Some(bitrate) = &port.bitrate {
         eprintln!("Bitrate: {bitrate}");
     }
+    if let Some(data_bitrate) = &port.data_bitrate {
+        eprintln!("Data bitrate: {data_bitrate}");
+    }
 
-    while let Some(frame) = socket_rx.next().await {
-        if let Some(message) = msg_map.get_key_value(&frame.as_ref().unwrap().id()) {
-            if frame.as_ref().unwrap().id() == message.1.message_id().0 {
-                let data = frame.as_ref().unwrap().data();
-                let mut can_signals: Vec<CanSignal> = Vec::new();
-
-                let mut multiplex_val = 0;
-
-                for signal in message.1.signals() {
-                    let can_signal_value =
-                        match get_can_signal_value(message.1.message_id(), data, signal, &dbc) {
-                            Some(val) => Some(val),
-                            // FIXME: Report an error to the server instead of just skipping the signal
-                            None => continue,
-                        };
-
-                    let signal_unit = if str::is_empty(signal.unit()) {
-                        match can_signal_value {
-                            Some(can_signal::Value::ValStr(_)) => "enum".to_string(),
-                            _ => "N/A".to_string(),
-                        }
-                    } else {
-                        signal.unit().clone()
-                    };
-                    // If the signal is a multiplexor, store the value of that signal.
-                    if is_multiplexor(signal) {
-                        if let Some(can_signal::Value::ValU64(val)) = can_signal_value.clone() {
-                            multiplex_val = val;
-                        }
-                        continue;
+    let mut read_failing = false;
+    loop {
+        // Held-back values are sent once the backpressure has ended, also
+        // when their frames have stopped coming
+        let frame = if held_back.is_empty() {
+            socket_rx.read_fd_frame().await
+        } else {
+            match timeout(HELD_BACK_CHECK_INTERVAL, socket_rx.read_fd_frame()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    if backpressure::current().await.is_none()
+                        && backpressure::server_rate().await.is_none()
+                    {
+                        flush_held_back(bus, &mut held_back, &mut last_sent, send).await;
                     }
+                    continue;
+                }
+            }
+        };
+        let received = Instant::now();
+        if !is_enabled(Subsystem::Can).await {
+            continue;
+        }
+        // Read errors, e.g. while the interface is going down, are
+        // skipped like frames of unknown messages. Only the first of a
+        // run is logged.
+        let f = match &frame {
+            Ok(f) => {
+                if read_failing {
+                    println!("Reading from {} again", port.name);
+                    read_failing = false;
+                }
+                f
+            }
+            Err(e) => {
+                if !read_failing {
+                    eprintln!("Failed to read from {}: {e}", port.name);
+                    read_failing = true;
+                }
+                sleep(READ_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        stats::record_frame(&port.name, f.id()).await;
+        if can_trace::is_enabled() {
+            can_trace::trace(&port.name, || {
+                let message = find_message(f.id());
+                let decoded = message
+                    .map(|m| decode_signals(m, f.data(), &dbc, raw_enums))
+                    .unwrap_or_default();
+                let signals: Vec<_> = message
+                    .iter()
+                    .flat_map(|m| {
+                        decoded.iter().map(|d| {
+                            let signal = &m.signals()[d.index];
+                            (signal_name(signal.name()), &d.value)
+                        })
+                    })
+                    .collect();
+                can_trace::format_frame(&port.name, f.id(), &f.flags(), f.data(), &signals)
+            })
+            .await;
+        }
+        if intrusion::is_enabled() {
+            let expected_dlc = find_message(f.id()).map(|m| *m.message_size() as usize);
+            intrusion::inspect(&port.name, f.id(), f.data().len(), expected_dlc).await;
+        }
+        if let Some(group) = group {
+            if redundancy::is_duplicate(group, &port.name, f.id(), f.data()).await {
+                continue;
+            }
+        }
+        // J1939 transport protocol frames are decoded once the whole
+        // message has been received
+        let reassembled;
+        let (message, data) = if j1939 {
+            let id = j1939::parse_id(f.id());
+            match transport.handle(&id, f.data(), received) {
+                Packet::Single => (j1939_messages.get(&id), f.data()),
+                Packet::Consumed => continue,
+                Packet::Complete(pgn, data) => {
+                    reassembled = data;
+                    let id = J1939Id { pgn, ..id };
+                    (j1939_messages.get(&id), reassembled.as_slice())
+                }
+            }
+        } else {
+            (msg_map.get(&f.id()).copied(), f.data())
+        };
+        let message = match message {
+            Some(message) => message,
+            None => continue,
+        };
+        let pressure = backpressure::current().await;
+        let server_rate = backpressure::server_rate().await;
+        if pressure.is_none() && server_rate.is_none() && !held_back.is_empty() {
+            flush_held_back(bus, &mut held_back, &mut last_sent, send).await;
+        }
+        let mut can_signals: Vec<CanSignal> = Vec::new();
+
+        let id = message.message_id().0;
+        let decoded = match decode_cache.get(id, data) {
+            Some(decoded) => decoded,
+            None => {
+                let decoded = Arc::new(decode_signals(message, data, &dbc, raw_enums));
+                decode_cache.insert(id, data, decoded.clone());
+                decoded
+            }
+        };
 
-                    // If the value is a multiplexed signal
-                    // Check if the multiplex signal value matches the multiplexor value of this signal
-                    // Else continue and discard the signal
-                    // FIXME: This is dependent on that the multipexor signal is parsed firs in the for-loop.
-                    // otherwise the multiplex_val variable will be 0
-                    if is_multiplexed(signal) {
-                        if let Some(can_signal::Value::ValU64(_)) = can_signal_value.clone() {
-                            if multiplex_val != get_multiplex_val(signal) {
+        for d in decoded.iter() {
+            let signal = &message.signals()[d.index];
+            let name = signal_name(signal.name());
+            let signal_unit = d.unit.clone();
+            let can_signal_value = d.value.clone();
+            let raw = d.raw;
+
+            let mut can_signal: CanSignal = CanSignal {
+                signal_name: name.to_string(),
+                unit: signal_unit,
+                value: can_signal_value.clone(),
+                raw,
+                refresh: false,
+            };
+            if periodic_signals.contains(name) {
+                if let Some(value) = can_signal_value {
+                    cache::update(bus, name, &can_signal.unit, value).await;
+                }
+                continue;
+            }
+            if let Some(value) = can_signal_value {
+                let number = cache::numeric(&value);
+                let freshness =
+                    match cache::update_with_max_age(bus, name, &can_signal.unit, value, max_age)
+                        .await
+                    {
+                        Freshness::Unchanged if held_back.contains_key(name) => Freshness::Changed,
+                        freshness => freshness,
+                    };
+                match freshness {
+                    Freshness::Changed => {
+                        if let Some(level) = &pressure {
+                            let range = signal.max - signal.min;
+                            let last = last_sent.get(name);
+                            if backpressure::is_throttled(level, last, number, range) {
+                                held_back.insert(name.to_string(), raw);
+                                continue;
+                            }
+                        }
+                        if let Some(rate) = server_rate {
+                            let n = changes.entry(name.to_string()).or_default();
+                            *n += 1;
+                            if !backpressure::keep_sample(*n, rate) {
+                                held_back.insert(name.to_string(), raw);
                                 continue;
                             }
                         }
                     }
-
-                    let can_signal: CanSignal = CanSignal {
-                        signal_name: signal.name().clone(),
-                        unit: signal_unit,
-                        value: can_signal_value.clone(),
-                    };
-                    if is_can_signal_duplicate(&prev_map, signal.name(), &can_signal_value) {
-                        continue;
-                    }
-                    *prev_map
-                        .entry(signal.name().clone())
-                        .or_insert_with(|| can_signal_value.clone()) = can_signal_value.clone();
-                    can_signals.push(can_signal);
+                    Freshness::Stale => can_signal.refresh = true,
+                    Freshness::Unchanged => continue,
                 }
-
-                if can_signals.is_empty() {
+                if backpressure::is_shed(name) {
+                    held_back.insert(name.to_string(), raw);
                     continue;
                 }
-
-                let can_message: CanMessage = CanMessage {
-                    bus: port.name.clone(),
-                    time_stamp: None, // The tokio_socketcan library currently lacks support for timestamps, but see https://github.com/socketcan-rs/socketcan-rs/issues/22
-                    signal: can_signals.clone(),
-                };
-                let mut req_map = CAN_MSG_QUEUE.lock().await;
-
-                req_map.push(can_message);
+                held_back.remove(name);
+                last_sent.insert(name.to_string(), (Instant::now(), number));
             }
+            can_signals.push(can_signal);
         }
-    }
-    Ok(())
-}
-
-pub fn setup_can(ports: &Vec<CanPort>) {
-    let default_bitrate = "500000";
-    let default_listen_only_state = "on";
 
-    for p in ports {
-        let interface = &p.name;
+        composite::check_composites(message.signals().iter().map(|s| signal_name(s.name()))).await;
 
-        let bitrate = if let Some(b) = p.bitrate {
-            b.to_string()
-        } else {
-            default_bitrate.to_string()
-        };
-
-        // ip link set INTERFACE down
-        let mut process = std::process::Command::new("ip")
-            .arg("link")
-            .arg("set")
-            .arg(interface)
-            .arg("down")
-            .spawn()
-            .expect("Failed to run ip command.");
-        match process.wait() {
-            Ok(_) => eprintln!("Interface {} is down", &interface),
-            Err(e) => panic!("Error: {}", e),
+        if can_signals.is_empty() || !send {
+            continue;
         }
 
-        // ip link set up INTERFACE type can bitrate BITRATE listen-only {ON/OFF}
-        let listen_only_state = match p.listen_only {
-            Some(true) => "on",
-            Some(false) => "off",
-            None => default_listen_only_state,
+        let can_message: CanMessage = CanMessage {
+            bus: bus.clone(),
+            time_stamp: Some(history::unix_millis(f.received())),
+            signal: can_signals.clone(),
+            composite: String::new(),
         };
-
-        let mut process = std::process::Command::new("ip")
-            .arg("link")
-            .arg("set")
-            .arg("up")
-            .arg(interface)
-            .arg("type")
-            .arg("can")
-            .arg("bitrate")
-            .arg(bitrate)
-            .arg("listen-only")
-            .arg(listen_only_state)
-            .spawn()
-            .expect("Failed to run ip command.");
-        match process.wait() {
-            Ok(_) => eprintln!("Interface {} is up", &interface),
-            Err(e) => panic!("Error: {}", e),
-        }
+        queue_received_can_message(can_message, received).await;
     }
 }
 
-// Get the can signal value based on the message ID, the data part of
-// the frame, the signal, and extra metadata contained in the DBC
-// file.
-// The following can_signal::can_signal::Value types can be returned:
-//   can_signal::Value::ValF64, ValStr, ValI64, ValU64
-fn get_can_signal_value(
-    id: &can_dbc::MessageId,
-    d: &[u8],
-    s: &can_dbc::Signal,
-    dbc: &can_dbc::DBC,
-) -> Option<can_signal::Value> {
-    let mut frame_data: [u8; 8] = [0; 8];
-    if *s.byte_order() == ByteOrder::LittleEndian {
-        for (index, value) in d.iter().enumerate() {
-            frame_data[index] = *value;
-        }
+// Send the latest values of the signals that were held back, now that
+// the backpressure has ended, instead of waiting for their next change
+async fn flush_held_back(
+    bus: &str,
+    held_back: &mut HashMap<String, Option<u64>>,
+    last_sent: &mut HashMap<String, (Instant, Option<f64>)>,
+    send: bool,
+) {
+    let mut signals = Vec::new();
+    let mut latest = None;
+    for (name, raw) in held_back.drain() {
+        let cached = match cache::get(bus, &name).await {
+            Some(cached) => cached,
+            None => continue,
+        };
+        last_sent.insert(
+            name.clone(),
+            (Instant::now(), cache::numeric(&cached.value)),
+        );
+        latest = latest.max(Some(cached.updated));
+        signals.push(CanSignal {
+            signal_name: name,
+            unit: cached.unit,
+            value: Some(cached.value),
+            raw,
+            refresh: false,
+        });
     }
-
-    let frame_value: u64 = if *s.byte_order() == ByteOrder::LittleEndian {
-        u64::from_le_bytes(frame_data)
-    } else {
-        u64::from_be_bytes(frame_data)
-    };
-
-    let signal_value = get_signal_value(frame_value, *s.start_bit(), *s.signal_size());
-
-    match get_signal_value_type(s, dbc, id) {
-        Some(SignalValueType::Float) => get_float(signal_value, *s.factor(), *s.offset()),
-        Some(SignalValueType::Signed) => {
-            get_signed_number(signal_value, *s.signal_size(), *s.factor(), *s.offset())
-        }
-        Some(SignalValueType::Unsigned) => {
-            get_unsigned_number(signal_value, *s.factor(), *s.offset())
-        }
-        Some(SignalValueType::Double) => get_double(signal_value, *s.factor(), *s.offset()),
-        // FIXME: IMPLEMENT BOOL
-        Some(SignalValueType::String) => get_string(signal_value, dbc, id, s),
-        _ => None,
+    if signals.is_empty() || !send {
+        return;
     }
+    queue_can_message(CanMessage {
+        bus: bus.to_string(),
+        time_stamp: latest.map(history::unix_millis),
+        signal: signals,
+        composite: String::new(),
+    })
+    .await;
 }
 
-fn is_multiplexor(s: &can_dbc::Signal) -> bool {
-    match s.multiplexer_indicator() {
-        MultiplexIndicator::Multiplexor => true,
-        MultiplexIndicator::MultiplexedSignal(_val) => false,
-        MultiplexIndicator::MultiplexorAndMultiplexedSignal(_val) => false,
-        MultiplexIndicator::Plain => false,
-    }
+// Add a message to the send queue. If the queue has grown beyond its
+// limit, e.g. while the server is unreachable, the oldest messages are
+// moved to the spool.
+pub async fn queue_can_message(can_message: CanMessage) {
+    queue_received_can_message(can_message, Instant::now()).await;
 }
 
-fn is_multiplexed(s: &can_dbc::Signal) -> bool {
-    match s.multiplexer_indicator() {
-        MultiplexIndicator::Multiplexor => false,
-        MultiplexIndicator::MultiplexedSignal(_val) => true,
-        MultiplexIndicator::MultiplexorAndMultiplexedSignal(_val) => false,
-        MultiplexIndicator::Plain => false,
+// Add a message decoded from a frame received at the given time
+async fn queue_received_can_message(mut can_message: CanMessage, received: Instant) {
+    let had_signals = !can_message.signal.is_empty();
+    scrub::scrub_message(&mut can_message);
+    if had_signals && can_message.signal.is_empty() {
+        return;
+    }
+    routing::route_can_message(&can_message).await;
+    let mut req_map = CAN_MSG_QUEUE.lock().await;
+    req_map.push(QueuedMessage {
+        message: can_message,
+        received,
+        queued: Instant::now(),
+    });
+
+    if spool::should_spool(req_map.len()) {
+        let n = req_map.len().min(MAX_MSG_TO_SEND);
+        let oldest: Vec<QueuedMessage> = req_map.drain(..n).collect();
+        drop(req_map);
+        let messages = oldest.iter().map(|q| q.message.clone()).collect();
+        let result = spool::write_segment(spool::CAN_SPOOL, messages).await;
+        if result.is_err() {
+            // Ahead of the messages queued meanwhile
+            CAN_MSG_QUEUE.lock().await.splice(..0, oldest);
+        }
+        spool::report_write(spool::CAN_SPOOL, result).await;
     }
 }
 
-fn get_multiplex_val(s: &can_dbc::Signal) -> u64 {
-    match s.multiplexer_indicator() {
-        MultiplexIndicator::Multiplexor => 0,
-        MultiplexIndicator::MultiplexedSignal(val) => *val,
-        MultiplexIndicator::MultiplexorAndMultiplexedSignal(val) => *val,
-        MultiplexIndicator::Plain => 0,
+// Set up the CAN interfaces with ip link. Not all drivers support all
+// parameters, e.g. some USB adapters lack termination control or a
+// configurable sample point, so an unsupported optional parameter is
+// logged and skipped rather than leaving the interface down.
+pub fn setup_can(ports: &Vec<CanPort>) {
+    for p in ports {
+        let interface = &p.name;
+
+        // Reconfiguring would drop the frames buffered during a restart,
+        // so a stored socket is only replaced if the settings changed
+        if fdstore::is_current(p) {
+            eprintln!("Keeping the configuration of {interface} from before the restart");
+            continue;
+        }
+        bring_up(p);
     }
 }
 
-#[derive(Debug)]
-enum SignalValueType {
-    Float,
-    Signed,
-    Unsigned,
-    Double,
-    // Bool,  UNIMPLEMENTED
-    String,
-}
+// Set up a port with ip link and bring it up
+pub fn bring_up(p: &CanPort) {
+    let default_bitrate = "500000";
+    let default_listen_only_state = "on";
+    let interface = &p.name;
+
+    let bitrate = if let Some(b) = p.bitrate {
+        b.to_string()
+    } else {
+        default_bitrate.to_string()
+    };
 
-fn get_signal_value_type(
-    s: &can_dbc::Signal,
-    dbc: &can_dbc::DBC,
-    id: &can_dbc::MessageId,
-) -> Option<SignalValueType> {
-    let val_desc = dbc.value_descriptions_for_signal(*id, s.name());
-    if val_desc.is_some() {
-        return Some(SignalValueType::String);
+    // ip link set INTERFACE down
+    if ip_link(&[interface, "down"]) {
+        eprintln!("Interface {} is down", &interface);
     }
 
-    let mut value_type_extended: Option<can_dbc::SignalExtendedValueType> =
-        Some(can_dbc::SignalExtendedValueType::SignedOrUnsignedInteger);
+    // CAN FD is enabled together with the bitrates, with bit rate
+    // switching to the data bitrate
+    let data_bitrate = p.data_bitrate.unwrap_or(DEFAULT_DATA_BITRATE).to_string();
+    let mut timing = vec![interface.as_str(), "type", "can", "bitrate", &bitrate];
+    if p.fd == Some(true) {
+        timing.extend(["dbitrate", &data_bitrate, "fd", "on"]);
+    }
 
-    for elem in dbc.signal_extended_value_type_list() {
-        if elem.signal_name() == s.name() {
-            value_type_extended = Some(*elem.signal_extended_value_type());
-            break;
+    // The sample point is calculated together with the bitrate
+    let mut bitrate_set = false;
+    if let Some(sample_point) = p.sample_point {
+        let sample_point = sample_point.to_string();
+        bitrate_set = ip_link(&[&timing[..], &["sample-point", &sample_point]].concat());
+        if !bitrate_set {
+            eprintln!("Ignoring unsupported sample point on {interface}");
         }
     }
-    match value_type_extended {
-        Some(SignalExtendedValueType::IEEEfloat32Bit) => Some(SignalValueType::Float),
-        Some(SignalExtendedValueType::IEEEdouble64bit) => Some(SignalValueType::Double),
-        Some(SignalExtendedValueType::SignedOrUnsignedInteger) => match *s.value_type() {
-            can_dbc::ValueType::Unsigned => Some(SignalValueType::Unsigned),
-            can_dbc::ValueType::Signed => Some(SignalValueType::Signed),
-        },
-        _ => None,
+    if !bitrate_set && !ip_link(&timing) {
+        eprintln!("Failed to set the bitrate of {interface}");
     }
-}
 
-fn get_string(
-    signal_value: u64,
-    dbc: &can_dbc::DBC,
-    id: &can_dbc::MessageId,
-    s: &can_dbc::Signal,
-) -> Option<can_signal::Value> {
-    let val_desc = dbc.value_descriptions_for_signal(*id, s.name());
-
-    if let Some(desc) = val_desc {
-        for elem in desc {
-            if *elem.a() == signal_value as f64 {
-                return Some(can_signal::Value::ValStr(elem.b().to_string()));
-            }
-        }
-        // Signal exists in value description but key could not be found
-        return Some(can_signal::Value::ValStr(signal_value.to_string()));
+    let listen_only_state = match p.listen_only {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => default_listen_only_state,
+    };
+    let mut options = vec![("listen-only", listen_only_state.to_string())];
+    if let Some(restart_ms) = p.restart_ms {
+        options.push(("restart-ms", restart_ms.to_string()));
     }
-    None
-}
-
-fn get_float(
-    signal_value: u64,
-    signal_factor: f64,
-    signal_offset: f64,
-) -> Option<can_signal::Value> {
-    Some(can_signal::Value::ValF64(
-        f32::from_bits(signal_value as u32) as f64 * signal_factor + signal_offset,
-    ))
-}
-
-fn get_double(
-    signal_value: u64,
-    signal_factor: f64,
-    signal_offset: f64,
-) -> Option<can_signal::Value> {
-    Some(can_signal::Value::ValF64(
-        f64::from_bits(signal_value) * signal_factor + signal_offset,
-    ))
-}
-
-fn get_unsigned_number(
-    signal_value: u64,
-    signal_factor: f64,
-    signal_offset: f64,
-) -> Option<can_signal::Value> {
-    if is_float(signal_factor) || is_float(signal_offset) {
-        return Some(can_signal::Value::ValF64(
-            signal_value as f64 * signal_factor + signal_offset,
-        ));
+    if let Some(termination) = p.termination {
+        options.push(("termination", termination.to_string()));
     }
-    Some(can_signal::Value::ValU64(
-        signal_value * signal_factor as u64 + signal_offset as u64,
-    ))
-}
-
-fn get_signed_number(
-    signal_value: u64,
-    signal_length: u64,
-    signal_factor: f64,
-    signal_offset: f64,
-) -> Option<can_signal::Value> {
-    let signed_mask = 1 << (signal_length - 1);
-    let is_negative = (signed_mask & signal_value) != 0;
-
-    let max_val: u64 = 0xFFFFFFFFFFFFFFFF;
-    let two_compliment_64 = (max_val << signal_length) | signal_value;
-
-    if is_negative {
-        if is_float(signal_factor) || is_float(signal_offset) {
-            return Some(can_signal::Value::ValF64(
-                ((two_compliment_64) as i64) as f64 * signal_factor + signal_offset,
-            ));
+    for (option, value) in options {
+        if !ip_link(&[interface, "type", "can", option, &value]) {
+            eprintln!("Ignoring unsupported {option} {value} on {interface}");
         }
-
-        return Some(can_signal::Value::ValI64(
-            two_compliment_64 as i64 * signal_factor as i64 + signal_offset as i64,
-        ));
     }
 
-    if is_float(signal_factor) || is_float(signal_offset) {
-        return Some(can_signal::Value::ValF64(
-            signal_value as f64 * signal_factor + signal_offset,
-        ));
+    // ip link set up INTERFACE
+    if ip_link(&["up", interface]) {
+        eprintln!("Interface {} is up", &interface);
+    } else {
+        eprintln!("Failed to bring up {interface}");
     }
-
-    Some(can_signal::Value::ValI64(
-        signal_value as i64 * signal_factor as i64 + signal_offset as i64,
-    ))
 }
 
-fn is_float(f: f64) -> bool {
-    f != f as i64 as f64
-}
-
-fn get_signal_value(frame_value: u64, start_bit: u64, signal_size: u64) -> u64 {
-    if signal_size == 64 {
-        return frame_value;
+// Run ip link set with the given arguments and return true on success
+fn ip_link(args: &[&str]) -> bool {
+    let status = std::process::Command::new("ip")
+        .arg("link")
+        .arg("set")
+        .args(args)
+        .status();
+    match status {
+        Ok(status) => status.success(),
+        Err(e) => panic!("Failed to run ip command: {}", e),
     }
-
-    let bit_mask: u64 = 2u64.pow(signal_size as u32) - 1;
-    (frame_value >> start_bit) & bit_mask
 }
 
-#[allow(dead_code)]
 async fn send_can_message(channel: Channel, can_message: CanMessage) {
     let mut client = AgentClient::with_interceptor(channel, intercept);
 
     let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
     loop {
         let request = Request::new(can_message.clone());
+        tap::record("SendCanMessage", &can_message).await;
         let response = client.send_can_message(request).await;
         if handle_send_result(response, &mut retry_sleep_s)
             .await
@@ -469,20 +661,100 @@ async fn send_can_message(channel: Channel, can_message: CanMessage) {
     }
 }
 
-async fn send_can_message_stream(channel: Channel, can_messages: Vec<CanMessage>) {
-    let mut client = AgentClient::with_interceptor(channel, intercept);
+pub async fn send_can_message_stream(channel: Channel, can_messages: Vec<CanMessage>) {
+    let mut client = AgentClient::with_interceptor(channel.clone(), intercept);
 
+    let can_messages = Arc::new(can_messages);
     let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
     loop {
+        if !transport::use_streams().await {
+            for can_message in can_messages.iter() {
+                send_can_message(channel.clone(), can_message.clone()).await;
+            }
+            break;
+        }
+
         //Create request of type CanMessage. The latter is defined in host_insight.proto
-        let request = Request::new(stream::iter(can_messages.clone()));
+        let request = Request::new(stream_batch(&can_messages));
 
+        tap::record_all("SendCanMessageStream", can_messages.as_slice()).await;
         let response = client.send_can_message_stream(request).await;
-        if handle_send_result(response, &mut retry_sleep_s)
-            .await
-            .is_ok()
-        {
-            break;
+        match handle_send_result(response, &mut retry_sleep_s).await {
+            Ok(()) => {
+                transport::stream_succeeded().await;
+                break;
+            }
+            Err(e) => transport::stream_failed(&e).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    // The send queue holds up to the spool memory limit of messages, which
+    // has to fit the gateways with 128 MB of RAM
+    const QUEUE_BUDGET: usize = 16 * 1024 * 1024;
+
+    // EEC1 of J1939, a busy message with eight signals
+    const EEC1: &str = r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 2364540158 EEC1: 8 ECU
+ SG_ EngineTorqueMode : 0|4@1+ (1,0) [0|15] "" Vector__XXX
+ SG_ ActualEnginePercentTorqueFractional : 4|4@1+ (0.125,0) [0|0.875] "%" Vector__XXX
+ SG_ DriversDemandEnginePercentTorque : 8|8@1+ (1,-125) [-125|125] "%" Vector__XXX
+ SG_ ActualEnginePercentTorque : 16|8@1+ (1,-125) [-125|125] "%" Vector__XXX
+ SG_ EngineSpeed : 24|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+ SG_ SourceAddressOfControllingDevice : 40|8@1+ (1,0) [0|255] "" Vector__XXX
+ SG_ EngineStarterMode : 48|4@1+ (1,0) [0|15] "" Vector__XXX
+ SG_ EngineDemandPercentTorque : 56|8@1+ (1,-125) [-125|125] "%" Vector__XXX
+"#;
+
+    #[test]
+    fn queued_messages_fit_memory_budget() {
+        let dbc = can_dbc::DBC::from_slice(EEC1.as_bytes()).unwrap();
+        let eec1 = &dbc.messages()[0];
+        let data = [0xf3, 0x7d, 0x8c, 0x70, 0x2e, 0x00, 0xf3, 0x7d];
+        let decoded = decode_signals(eec1, &data, &dbc, false);
+        assert_eq!(decoded.len(), 8);
+        let message = CanMessage {
+            bus: "can0".to_string(),
+            time_stamp: Some(1_700_000_000_000),
+            signal: decoded
+                .into_iter()
+                .map(|d| CanSignal {
+                    signal_name: eec1.signals()[d.index].name().clone(),
+                    unit: d.unit,
+                    value: d.value,
+                    raw: d.raw,
+                    refresh: false,
+                })
+                .collect(),
+            composite: String::new(),
         };
+
+        // What is sent and spooled, and what is held in memory
+        let encoded = message.encoded_len();
+        let in_memory = std::mem::size_of::<QueuedMessage>()
+            + message.bus.capacity()
+            + message.signal.capacity() * std::mem::size_of::<CanSignal>()
+            + message
+                .signal
+                .iter()
+                .map(|s| s.signal_name.capacity() + s.unit.capacity())
+                .sum::<usize>();
+        assert!(encoded < in_memory);
+        assert!(
+            in_memory * spool::DEFAULT_MEMORY_LIMIT <= QUEUE_BUDGET,
+            "{in_memory} bytes per message"
+        );
     }
 }
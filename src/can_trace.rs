@@ -0,0 +1,192 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Tracing of the CAN traffic of a port to stderr, and so to the journal,
+// for debugging on site. Each frame is logged with its decoded signals.
+// The number of lines per second and the length of each line are capped
+// so that a busy bus cannot flood the journal, and a trace ends by
+// itself after a while.
+
+use lazy_static::lazy_static;
+use lib::host_insight::{can_signal, CanTraceRequest};
+use lib::CONFIG;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(600);
+const MAX_LINES_PER_S: u32 = 20;
+const MAX_LINE_LEN: usize = 300;
+
+struct Trace {
+    until: Instant,
+    window_start: Instant,
+    lines: u32,
+    skipped: u32,
+}
+
+// Checked first, so that tracing costs nothing when no port is traced
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TRACES: Mutex<HashMap<String, Trace>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub async fn enable(port: &str, duration: Duration) {
+    let now = Instant::now();
+    TRACES.lock().await.insert(
+        port.to_string(),
+        Trace {
+            until: now + duration,
+            window_start: now,
+            lines: 0,
+            skipped: 0,
+        },
+    );
+    ENABLED.store(true, Ordering::Relaxed);
+    eprintln!("Tracing CAN port {} for {} s", port, duration.as_secs());
+}
+
+pub async fn disable(port: &str) {
+    let mut traces = TRACES.lock().await;
+    if traces.remove(port).is_some() {
+        eprintln!("Stopped tracing CAN port {}", port);
+    }
+    ENABLED.store(!traces.is_empty(), Ordering::Relaxed);
+}
+
+fn is_configured(port: &str) -> bool {
+    CONFIG
+        .can
+        .iter()
+        .flat_map(|c| c.ports.iter().flatten())
+        .any(|p| p.name == port)
+}
+
+// Trace a port on request of the server. A duration of 0 stops the trace.
+// Only configured ports are traced, so that the server cannot grow the
+// traces without bound.
+pub async fn request_trace(request: CanTraceRequest) {
+    match request.duration_s {
+        0 => disable(&request.port).await,
+        _ if !is_configured(&request.port) => {
+            eprintln!("Not tracing unknown CAN port {}", request.port)
+        }
+        s => enable(&request.port, Duration::from_secs(s as u64)).await,
+    }
+}
+
+fn format_value(value: &Option<can_signal::Value>) -> String {
+    match value {
+        Some(can_signal::Value::ValF64(v)) => v.to_string(),
+        Some(can_signal::Value::ValStr(v)) => v.clone(),
+        Some(can_signal::Value::ValI64(v)) => v.to_string(),
+        Some(can_signal::Value::ValU64(v)) => v.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+// The line of a frame, with its flags, e.g. FD and BRS for CAN FD
+// frames, and the decoded signals as (name, value)
+pub fn format_frame(
+    port: &str,
+    id: u32,
+    flags: &[&str],
+    data: &[u8],
+    signals: &[(&str, &Option<can_signal::Value>)],
+) -> String {
+    let mut line = format!("{} {:#x} ", port, id);
+    for flag in flags {
+        let _ = write!(line, "{} ", flag);
+    }
+    line.push('[');
+    for (i, b) in data.iter().enumerate() {
+        let _ = write!(line, "{}{:02x}", if i == 0 { "" } else { " " }, b);
+    }
+    line.push(']');
+    for (name, value) in signals {
+        let _ = write!(line, " {}={}", name, format_value(value));
+    }
+    if line.len() > MAX_LINE_LEN {
+        let mut end = MAX_LINE_LEN;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+        line.push_str("...");
+    }
+    line
+}
+
+// Log a frame if its port is traced and the rate allows
+pub async fn trace(port: &str, line: impl FnOnce() -> String) {
+    let mut traces = TRACES.lock().await;
+    let trace = match traces.get_mut(port) {
+        Some(trace) => trace,
+        None => return,
+    };
+    let now = Instant::now();
+    if now >= trace.until {
+        drop(traces);
+        disable(port).await;
+        return;
+    }
+    if now.duration_since(trace.window_start) >= Duration::from_secs(1) {
+        if trace.skipped > 0 {
+            eprintln!("{}: {} frames not traced", port, trace.skipped);
+        }
+        trace.window_start = now;
+        trace.lines = 0;
+        trace.skipped = 0;
+    }
+    if trace.lines >= MAX_LINES_PER_S {
+        trace.skipped += 1;
+        return;
+    }
+    trace.lines += 1;
+    eprintln!("{}", line());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_frame() {
+        let speed = Some(can_signal::Value::ValF64(42.5));
+        assert_eq!(
+            format_frame("can0", 0x123, &[], &[0x01, 0xab], &[("Speed", &speed)]),
+            "can0 0x123 [01 ab] Speed=42.5"
+        );
+        assert_eq!(
+            format_frame("can0", 0x123, &["FD", "BRS"], &[0x01], &[]),
+            "can0 0x123 FD BRS [01]"
+        );
+
+        let long = Some(can_signal::Value::ValStr("x".repeat(1000)));
+        assert!(
+            format_frame("can0", 0x123, &[], &[], &[("Text", &long)]).len() <= MAX_LINE_LEN + 3
+        );
+    }
+}
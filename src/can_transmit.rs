@@ -0,0 +1,216 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Transmission of CAN frames requested by the server, e.g. for remote
+// actuation of body controllers. Only the frame IDs that are listed for
+// a port in the configuration are sent, and nothing is sent while the
+// local override is active. An ECU that only accepts the frames once
+// unlocked can be unlocked with the UDS security access of the port
+// before each frame. The result of each request is reported back.
+
+use super::health::record_contact;
+use super::isotp::{self, IsoTpSocket};
+use super::journal;
+use super::local_override;
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use futures::stream::StreamExt;
+use lib::{
+    host_insight::{
+        agent_client::AgentClient, remote_control_client::RemoteControlClient, CanTransmitRequest,
+        CanTransmitResult, ControlScope, ControlStatus, UnitControlStatus,
+    },
+    TransmitConfig, CONFIG,
+};
+use std::error::Error;
+use tokio_socketcan::{CANFrame, CANSocket};
+use tonic::transport::Channel;
+
+pub fn is_enabled() -> bool {
+    CONFIG
+        .can
+        .as_ref()
+        .is_some_and(|c| c.transmit.as_ref().is_some_and(|t| !t.is_empty()))
+}
+
+// Check a request against the allowlist of its port, and return the
+// transmit config of the port
+fn check_request<'a>(
+    request: &CanTransmitRequest,
+    allowed: &'a [TransmitConfig],
+) -> Result<&'a TransmitConfig, String> {
+    // Without an ID the request could not be kept from running twice
+    if request.request_id.is_empty() {
+        return Err("Transmit request without a request ID".to_string());
+    }
+    let transmit = allowed
+        .iter()
+        .find(|t| t.port == request.port)
+        .ok_or_else(|| format!("Transmit is not allowed on {}", request.port))?;
+    if !transmit.ids.contains(&request.id) {
+        return Err(format!(
+            "Transmit of {:#x} is not allowed on {}",
+            request.id, request.port
+        ));
+    }
+    if request.data.len() > 8 {
+        return Err(format!(
+            "Frame {:#x} has more than 8 data bytes",
+            request.id
+        ));
+    }
+    Ok(transmit)
+}
+
+// Unlock the ECU with the security access of the port, if the transmit
+// config of the port asks for it
+async fn unlock_ecu(transmit: &TransmitConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (ids, config) = match (&transmit.unlock, isotp::security_access(&transmit.port)) {
+        (Some(ids), Some(config)) => (ids, config),
+        _ => return Ok(()),
+    };
+    let socket = IsoTpSocket::open(&transmit.port, ids.tx_id, ids.rx_id)?;
+    isotp::unlock(&socket, config, isotp::DEFAULT_TIMEOUT).await?;
+    Ok(())
+}
+
+async fn send_frame(request: &CanTransmitRequest) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let frame = CANFrame::new(request.id, &request.data, false, false)?;
+    let socket = CANSocket::open(&request.port)?;
+    socket.write_frame(frame)?.await?;
+    Ok(())
+}
+
+async fn run_request(request: &CanTransmitRequest) -> CanTransmitResult {
+    let allowed = CONFIG.can.as_ref().and_then(|c| c.transmit.as_deref());
+    let mut result = CanTransmitResult {
+        request_id: request.request_id.clone(),
+        sent: false,
+        error: String::new(),
+    };
+    let command = format!(
+        "transmit {} {:#x} {:02x?}",
+        request.port, request.id, request.data
+    );
+    match check_request(request, allowed.unwrap_or_default()) {
+        Err(e) => result.error = e,
+        Ok(_) if local_override::is_active().await => {
+            result.error = "The local override is active".to_string();
+        }
+        Ok(_) if journal::executed(&request.request_id).await => {
+            result.error = "The request has already been executed".to_string();
+        }
+        Ok(transmit) => match unlock_ecu(transmit).await {
+            Err(e) => result.error = format!("Failed to unlock the ECU: {e}"),
+            Ok(()) => match send_frame(request).await {
+                Ok(()) => {
+                    journal::accept(&request.request_id, &command).await;
+                    result.sent = true;
+                }
+                Err(e) => result.error = e.to_string(),
+            },
+        },
+    }
+    if !result.error.is_empty() {
+        eprintln!(
+            "CAN transmit {} failed: {}",
+            request.request_id, result.error
+        );
+    }
+    result
+}
+
+async fn send_result(channel: Channel, result: CanTransmitResult) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendCanTransmitResult", &result).await;
+        let response = client.send_can_transmit_result(result.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        };
+    }
+}
+
+pub async fn run_transmit_session(channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let status = ControlStatus {
+        code: UnitControlStatus::UnitReady as i32,
+        scope: ControlScope::CanTransmit as i32,
+        outputs: Vec::new(),
+    };
+    let mut client = RemoteControlClient::with_interceptor(channel.clone(), intercept);
+    tap::record("CanTransmitStream", &status).await;
+    let mut stream = client.can_transmit_stream(status).await?.into_inner();
+
+    while let Some(request) = stream.next().await {
+        let request = request?;
+        record_contact().await;
+        println!(
+            "CAN transmit {} on {} of {:#x}",
+            request.request_id, request.port, request.id
+        );
+        let result = run_request(&request).await;
+        send_result(channel.clone(), result).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist() {
+        let allowed = [TransmitConfig {
+            port: "can0".to_string(),
+            ids: vec![0x2f0, 0x18ff0021],
+            unlock: None,
+        }];
+        let request = |port: &str, id, len| CanTransmitRequest {
+            request_id: "1".to_string(),
+            port: port.to_string(),
+            id,
+            data: vec![0; len],
+        };
+        assert!(check_request(&request("can0", 0x2f0, 8), &allowed).is_ok());
+        assert!(check_request(&request("can0", 0x18ff0021, 0), &allowed).is_ok());
+        assert_eq!(
+            check_request(&request("can0", 0x2f1, 1), &allowed).unwrap_err(),
+            "Transmit of 0x2f1 is not allowed on can0"
+        );
+        assert_eq!(
+            check_request(&request("can1", 0x2f0, 1), &allowed).unwrap_err(),
+            "Transmit is not allowed on can1"
+        );
+        assert_eq!(
+            check_request(&request("can0", 0x2f0, 9), &allowed).unwrap_err(),
+            "Frame 0x2f0 has more than 8 data bytes"
+        );
+        let without_id = CanTransmitRequest {
+            request_id: String::new(),
+            ..request("can0", 0x2f0, 8)
+        };
+        assert_eq!(
+            check_request(&without_id, &allowed).unwrap_err(),
+            "Transmit request without a request ID"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Expiry monitoring and renewal of the client certificate used for
+// mutual TLS. A certificate that is about to expire is reported in the
+// heartbeat well in advance, and renewed from the provisioning endpoint
+// if one is configured, so that units do not drop off when their
+// certificates expire. A renewal can also be requested by the server.
+//
+// The renewal creates a new key and sends a certificate signing request
+// to the endpoint, authenticated with the current certificate. The
+// endpoint answers with the new certificate in PEM format. A certificate
+// is renewed when less than warn_days or half of its lifetime is left,
+// whichever is less, so that a CA that issues short lived certificates
+// does not cause a renewal at every start.
+
+use super::net::{clear_status, set_status};
+use super::utils::clean_up;
+use lib::{ca_file, conf_dir, StatusCodes, TlsConfig, CONFIG, IDENTITY};
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout};
+
+const DEFAULT_WARN_DAYS: u64 = 30;
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+// Limit of the request to the provisioning endpoint, and of the whole
+// renewal including openssl
+const CURL_MAX_TIME_S: u64 = 60;
+const RENEW_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Set while a renewal runs, so that a renewal requested by the server
+// does not race one started by the monitor
+static RENEWING: AtomicBool = AtomicBool::new(false);
+
+// Certificate and key files are relative to the configuration directory
+pub fn cert_path(s: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}", conf_dir(), s))
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.to_path_buf().into_os_string();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn run(command: &mut Command) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Parse a date in the output of openssl x509 -startdate -enddate, e.g.
+// "notAfter=Jun  1 12:00:00 2027 GMT", into seconds since the epoch
+fn parse_date(s: &str, field: &str) -> Option<i64> {
+    let date = s
+        .lines()
+        .find_map(|l| l.trim().strip_prefix(field)?.strip_prefix('='))?;
+    let fields: Vec<_> = date.split_whitespace().collect();
+    let (month, day, time, year) = match fields[..] {
+        [month, day, time, year, "GMT"] => (month, day, time, year),
+        _ => return None,
+    };
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let hms: Vec<i64> = time
+        .split(':')
+        .map(|t| t.parse().ok())
+        .collect::<Option<_>>()?;
+    let (h, m, sec) = match hms[..] {
+        [h, m, sec] => (h, m, sec),
+        _ => return None,
+    };
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+    Some(days * SECONDS_PER_DAY + h * 3600 + m * 60 + sec)
+}
+
+// Start and expiry time of a PEM certificate in seconds since the epoch
+fn validity(path: &Path) -> Result<(i64, i64), Box<dyn Error + Send + Sync>> {
+    let out = run(Command::new("openssl")
+        .args(["x509", "-noout", "-startdate", "-enddate", "-in"])
+        .arg(path))?;
+    parse_date(&out, "notBefore")
+        .zip(parse_date(&out, "notAfter"))
+        .ok_or_else(|| format!("Unexpected validity dates {out:?}").into())
+}
+
+// How long before expiry a certificate valid from start to expiry is
+// renewed
+fn renew_before_s(start: i64, expiry: i64, warn_days: i64) -> i64 {
+    (warn_days * SECONDS_PER_DAY).min((expiry - start) / 2)
+}
+
+// The key is renamed into place before the certificate. A certificate
+// part without a key part is therefore a renewal that was interrupted
+// between the two renames, and is completed here. Any other parts are
+// from a failed renewal and are removed. Called before the certificate
+// is used.
+pub fn finish_renewal(tls: &TlsConfig) {
+    let cert = cert_path(&tls.cert_file);
+    let key = cert_path(&tls.key_file);
+    let (cert_part, key_part) = (part_path(&cert), part_path(&key));
+    if cert_part.exists() && !key_part.exists() {
+        match fs::rename(&cert_part, &cert) {
+            Ok(()) => println!("Completed interrupted client certificate renewal"),
+            Err(e) => eprintln!("Failed to complete client certificate renewal: {e}"),
+        }
+    } else {
+        let _ = fs::remove_file(&cert_part);
+        let _ = fs::remove_file(&key_part);
+    }
+}
+
+// Create a new key and get a certificate for it from the provisioning
+// endpoint. The current files are only replaced once the new
+// certificate is found to match the new key.
+pub fn renew_certificate(tls: &TlsConfig) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let url = tls
+        .renew_url
+        .as_ref()
+        .ok_or("No tls.renew_url configured")?;
+    let cert = cert_path(&tls.cert_file);
+    let key = cert_path(&tls.key_file);
+    let (cert_part, key_part) = (part_path(&cert), part_path(&key));
+    let csr = PathBuf::from(format!("{}/client.csr", conf_dir()));
+
+    run(Command::new("openssl")
+        .args(["req", "-new", "-nodes", "-newkey", "ec"])
+        .args(["-pkeyopt", "ec_paramgen_curve:prime256v1"])
+        .arg("-subj")
+        .arg(format!("/CN={}", IDENTITY.uid))
+        .arg("-keyout")
+        .arg(&key_part)
+        .arg("-out")
+        .arg(&csr))?;
+    fs::set_permissions(&key_part, fs::Permissions::from_mode(0o600))?;
+
+    let result = run(Command::new("curl")
+        .args(["--fail", "--silent", "--show-error"])
+        .arg("--max-time")
+        .arg(CURL_MAX_TIME_S.to_string())
+        .arg("--cacert")
+        .arg(ca_file())
+        .arg("--cert")
+        .arg(&cert)
+        .arg("--key")
+        .arg(&key)
+        .args(["--header", "Content-Type: application/pkcs10"])
+        .arg("--data-binary")
+        .arg(format!("@{}", csr.display()))
+        .arg("-o")
+        .arg(&cert_part)
+        .arg(url));
+    let _ = fs::remove_file(&csr);
+    let result = result.and_then(|_| {
+        let cert_key = run(Command::new("openssl")
+            .args(["x509", "-noout", "-pubkey", "-in"])
+            .arg(&cert_part))?;
+        let new_key = run(Command::new("openssl")
+            .args(["pkey", "-pubout", "-in"])
+            .arg(&key_part))?;
+        if cert_key != new_key {
+            return Err("Renewed certificate does not match the new key".into());
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&cert_part);
+        let _ = fs::remove_file(&key_part);
+        return Err(e);
+    }
+
+    // See finish_renewal for a crash between the renames
+    fs::rename(&key_part, &key)?;
+    fs::rename(&cert_part, &cert)?;
+    Ok(())
+}
+
+// Renew the certificate and restart to connect with it. The renewal runs
+// commands, so it is kept off the runtime and bounded in time.
+async fn renew_and_restart(tls: &'static TlsConfig) {
+    if RENEWING.swap(true, Ordering::SeqCst) {
+        println!("Client certificate renewal already in progress");
+        return;
+    }
+    let result = match timeout(
+        RENEW_TIMEOUT,
+        tokio::task::spawn_blocking(move || renew_certificate(tls)),
+    )
+    .await
+    {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err("timed out".into()),
+    };
+    match result {
+        Ok(()) => {
+            println!("Client certificate renewed, restarting");
+            clean_up();
+            std::process::exit(0);
+        }
+        Err(e) => eprintln!("Failed to renew client certificate: {e}"),
+    }
+    RENEWING.store(false, Ordering::SeqCst);
+}
+
+// Renewal requested by the server
+pub fn request_renewal() {
+    match &CONFIG.tls {
+        Some(tls) => {
+            tokio::spawn(renew_and_restart(tls));
+        }
+        None => eprintln!("Ignoring certificate renewal, no client certificate configured"),
+    }
+}
+
+pub async fn cert_monitor() -> Result<(), Box<dyn Error>> {
+    let tls = CONFIG.tls.as_ref().unwrap();
+    let warn_days = tls.warn_days.unwrap_or(DEFAULT_WARN_DAYS) as i64;
+
+    loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        match validity(&cert_path(&tls.cert_file)) {
+            Ok((start, expiry)) if expiry - now < renew_before_s(start, expiry, warn_days) => {
+                eprintln!(
+                    "Client certificate expires in {} days",
+                    (expiry - now) / SECONDS_PER_DAY
+                );
+                set_status(StatusCodes::CertificateExpiring).await;
+                if tls.renew_url.is_some() {
+                    renew_and_restart(tls).await;
+                }
+            }
+            Ok(_) => clear_status(StatusCodes::CertificateExpiring).await,
+            Err(e) => eprintln!("Failed to read client certificate expiry: {e}"),
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_openssl_dates() {
+        let not_after = |s| parse_date(s, "notAfter");
+        assert_eq!(not_after("notAfter=Jan  1 00:00:00 1970 GMT"), Some(0));
+        assert_eq!(
+            not_after("notBefore=Jan  1 00:00:00 1970 GMT\nnotAfter=Jun  1 12:30:15 2027 GMT\n"),
+            Some(1811853015)
+        );
+        assert_eq!(
+            not_after("notAfter=Feb 29 00:00:00 2024 GMT"),
+            Some(1709164800)
+        );
+        assert_eq!(not_after("notAfter=Foo  1 00:00:00 2027 GMT"), None);
+        assert_eq!(not_after("garbage"), None);
+        assert_eq!(
+            parse_date("notBefore=Jan  2 00:00:00 1970 GMT", "notBefore"),
+            Some(SECONDS_PER_DAY)
+        );
+    }
+
+    #[test]
+    fn short_lived_certificates_are_renewed_at_half_their_lifetime() {
+        let day = SECONDS_PER_DAY;
+        assert_eq!(renew_before_s(0, 365 * day, 30), 30 * day);
+        assert_eq!(renew_before_s(0, 10 * day, 30), 5 * day);
+    }
+}
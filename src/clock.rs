@@ -0,0 +1,143 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Detection of steps of the wall clock, e.g. when NTP steps the clock
+// of a unit without a real-time clock long after boot. The time stamps
+// of the data recorded before a step are corrected, both in memory and
+// in the spool. Intervals are measured with monotonic clocks and are
+// not affected by steps.
+//
+// Whether a time stamp was taken before the step is told from the time
+// stamp itself. After a step forward, the time stamps taken before it
+// are older than the time of the step on the old clock. After a step
+// backward, those newer than the current time are corrected, while the
+// older ones cannot be told apart from the new ones and are kept.
+
+use super::can::{restamp_message, restamp_queue as restamp_can_queue};
+use super::gpio::{restamp_queue as restamp_value_queue, restamp_value};
+use super::routing::restamp_queues as restamp_route_queues;
+use super::spool;
+use lib::{
+    history::{self, unix_millis},
+    host_insight::{CanMessage, Value},
+};
+use nix::time::{clock_gettime, ClockId};
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_STEP_MS: i64 = 2000;
+
+// Milliseconds since boot, including time suspended, so that a suspend
+// is not taken for a step
+fn boot_millis() -> i64 {
+    clock_gettime(ClockId::CLOCK_BOOTTIME)
+        .map(|t| Duration::from(t).as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn is_synchronized() -> bool {
+    // SAFETY: timex is a plain C struct, for which all zeros is valid, and
+    // with modes 0 adjtimex only reads the clock state into it
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    unsafe { libc::adjtimex(&mut timex) != libc::TIME_ERROR }
+}
+
+// The corrected time stamp, if it was taken before a step of step_ms
+// that was detected at now_ms on the new clock
+pub fn correct(time_stamp_ms: i64, now_ms: i64, step_ms: i64) -> Option<i64> {
+    let taken_before = match step_ms > 0 {
+        true => time_stamp_ms <= now_ms - step_ms,
+        false => time_stamp_ms > now_ms,
+    };
+    taken_before.then(|| time_stamp_ms + step_ms)
+}
+
+pub async fn clock_monitor() -> Result<(), Box<dyn Error>> {
+    let mut synchronized = is_synchronized();
+    if !synchronized {
+        println!("Wall clock is not synchronized");
+    }
+    let mut last = (boot_millis(), unix_millis(SystemTime::now()));
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        let now = (boot_millis(), unix_millis(SystemTime::now()));
+        let step_ms = (now.1 - last.1) - (now.0 - last.0);
+        if step_ms.abs() >= MIN_STEP_MS {
+            println!("Wall clock stepped by {} ms", step_ms);
+            restamp(now.1, step_ms).await;
+        }
+        if !synchronized && is_synchronized() {
+            println!("Wall clock synchronized");
+            synchronized = true;
+        }
+        last = now;
+    }
+}
+
+async fn restamp(now_ms: i64, step_ms: i64) {
+    let correct = move |ms: i64| correct(ms, now_ms, step_ms);
+    restamp_can_queue(correct).await;
+    restamp_value_queue(correct).await;
+    restamp_route_queues(correct).await;
+    history::restamp(|time| {
+        correct(unix_millis(time)).map(|ms| UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64))
+    })
+    .await;
+
+    if spool::is_enabled() {
+        // The segments are rewritten on disk
+        let restamped = spawn_blocking(move || {
+            spool::restamp_segments::<CanMessage>(
+                spool::CAN_SPOOL,
+                |m| restamp_message(m, correct),
+                correct,
+            );
+            spool::restamp_segments::<Value>(
+                spool::VALUE_SPOOL,
+                |v| restamp_value(v, correct),
+                correct,
+            );
+        })
+        .await;
+        if let Err(e) = restamped {
+            eprintln!("Failed to restamp the spool: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_correct() {
+        // A step forward from the epoch to 2023
+        let now = 1_700_000_000_000;
+        let step = now - 60_000;
+        assert_eq!(correct(59_000, now, step), Some(59_000 + step));
+        assert_eq!(correct(now - 500, now, step), None);
+
+        // A step back by an hour
+        let step = -3_600_000;
+        assert_eq!(correct(now + 1_000_000, now, step), Some(now - 2_600_000));
+        assert_eq!(correct(now - 1000, now, step), None);
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Composite messages combine signals from possibly different CAN frames
+// into one coherent record. A record is sent when all of its signals
+// have been updated within the window, and only contains samples that
+// were not part of the previous record.
+
+use super::can::queue_can_message;
+use lazy_static::lazy_static;
+use lib::{
+    cache, history,
+    host_insight::{CanMessage, CanSignal},
+    CompositeConfig, COMPOSITE_BUS, CONFIG,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+lazy_static! {
+    static ref LAST_SENT: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+fn composites() -> &'static [CompositeConfig] {
+    CONFIG
+        .can
+        .as_ref()
+        .and_then(|c| c.composites.as_deref())
+        .unwrap_or_default()
+}
+
+// Check the composites that contain any of the signals just updated
+pub async fn check_composites<'a>(updated: impl Iterator<Item = &'a str>) {
+    let updated: Vec<&str> = updated.collect();
+    for composite in composites() {
+        if composite
+            .signals
+            .iter()
+            .any(|s| updated.contains(&s.as_str()))
+        {
+            if let Some(message) = build_record(composite).await {
+                queue_can_message(message).await;
+            }
+        }
+    }
+}
+
+async fn build_record(composite: &CompositeConfig) -> Option<CanMessage> {
+    let mut samples = Vec::new();
+    for name in &composite.signals {
+        samples.push((name, cache::get_any(name).await?));
+    }
+    let oldest = samples.iter().map(|(_, v)| v.updated_at).min()?;
+    let (_, newest) = samples.iter().max_by_key(|(_, v)| v.updated_at)?;
+    let time_stamp = newest.updated;
+    let newest = newest.updated_at;
+    if newest.duration_since(oldest) > Duration::from_millis(composite.window_ms) {
+        return None;
+    }
+
+    let mut last_sent = LAST_SENT.lock().await;
+    if last_sent
+        .get(&composite.name)
+        .is_some_and(|sent| oldest <= *sent)
+    {
+        return None;
+    }
+    last_sent.insert(composite.name.clone(), newest);
+
+    Some(CanMessage {
+        bus: COMPOSITE_BUS.to_string(),
+        time_stamp: Some(history::unix_millis(time_stamp)),
+        signal: samples
+            .into_iter()
+            .map(|(name, cached)| CanSignal {
+                signal_name: name.clone(),
+                unit: cached.unit,
+                value: Some(cached.value),
+                raw: None,
+                refresh: false,
+            })
+            .collect(),
+        composite: composite.name.clone(),
+    })
+}
@@ -0,0 +1,146 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Configs pushed by the server are validated in memory before they are
+// accepted. The result is reported back to the server, and only a config
+// that passed validation is written to conf-new.toml and picked up by
+// restarting the client. A config given inline cannot be replaced that
+// way, so pushed configs are rejected while one is used. The client also
+// restarts to reload the config when a schedule with config overrides
+// starts or ends. Such a restart is marked in the run directory, so that
+// the digital out startup sequence is not run again.
+
+use super::journal;
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use super::utils::{clean_up, write_atomic_async};
+use lazy_static::lazy_static;
+use lib::{
+    conf_dir,
+    host_insight::{agent_client::AgentClient, ConfigValidation},
+    instance, is_inline_config, run_dir, schedule, validate_config, CONFIG,
+};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+
+lazy_static! {
+    static ref CONFIG_UPDATES: Mutex<Vec<(String, Vec<u8>)>> = Mutex::new(Vec::new());
+}
+
+// The update is journaled under the command ID once it has been written,
+// not when it is queued here
+pub async fn request_config_update(command_id: String, config: Vec<u8>) {
+    CONFIG_UPDATES.lock().await.push((command_id, config));
+}
+
+pub async fn config_update_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+    loop {
+        let updates: Vec<(String, Vec<u8>)> = CONFIG_UPDATES.lock().await.drain(..).collect();
+        for (command_id, config) in updates {
+            let result = if is_inline_config() {
+                Err(
+                    "The config is given inline with --config or HOST_INSIGHT_CONFIG \
+                     and cannot be updated remotely"
+                        .to_string(),
+                )
+            } else {
+                std::str::from_utf8(&config)
+                    .map_err(|e| e.to_string())
+                    .and_then(validate_config)
+            };
+            let validation = match &result {
+                Ok(_) => ConfigValidation {
+                    accepted: true,
+                    reason: String::new(),
+                },
+                Err(e) => {
+                    eprintln!("Rejecting config update: {e}");
+                    ConfigValidation {
+                        accepted: false,
+                        reason: e.clone(),
+                    }
+                }
+            };
+            send_config_validation(channel.clone(), validation).await;
+
+            if result.is_ok() {
+                let new_local_conf = PathBuf::from(format!("{}/conf-new.toml", conf_dir()));
+                write_atomic_async(new_local_conf, config)
+                    .await
+                    .expect("Failed to write new config file");
+                journal::accept(&command_id, "config_update").await;
+
+                clean_up();
+                std::process::exit(0);
+            }
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+pub async fn schedule_monitor() -> Result<(), Box<dyn Error>> {
+    loop {
+        sleep(Duration::from_secs(30)).await;
+        let active = schedule::active(&CONFIG.schedules, schedule::local_time());
+        if active != CONFIG.active_schedules {
+            println!("Active schedules changed to {active:?}, reloading config");
+            if let Err(e) =
+                fs::create_dir_all(run_dir()).and_then(|_| fs::write(schedule_reload_marker(), ""))
+            {
+                eprintln!("Failed to mark the restart for the schedule change: {e}");
+            }
+            clean_up();
+            std::process::exit(0);
+        }
+    }
+}
+
+// Instances share the run directory but have their own marker
+fn schedule_reload_marker() -> PathBuf {
+    match instance() {
+        Some(instance) => Path::new(run_dir()).join(format!("schedule_reload.{instance}")),
+        None => Path::new(run_dir()).join("schedule_reload"),
+    }
+}
+
+// Whether the client was restarted to reload the config for a schedule
+// change. Only true once per restart.
+pub fn take_schedule_reload() -> bool {
+    fs::remove_file(schedule_reload_marker()).is_ok()
+}
+
+async fn send_config_validation(channel: Channel, validation: ConfigValidation) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendConfigValidation", &validation).await;
+        let response = client.send_config_validation(validation.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        };
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Remote control sessions requested by the server. Each scope (outputs,
+// CAN transmit, diagnostics) has its own session with its own lifecycle,
+// so that e.g. diagnostics can run while an output session is active.
+// Only one session per scope runs at a time.
+
+use super::can_transmit::{self, run_transmit_session};
+use super::gpio::run_output_session;
+use super::isotp::run_diagnostic_session;
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{ControlRequest, ControlScope},
+    CONFIG,
+};
+use std::collections::HashSet;
+use std::error::Error;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+use tonic::transport::Channel;
+
+lazy_static! {
+    static ref CONTROL_REQUESTS: Mutex<Vec<ControlScope>> = Mutex::new(Vec::new());
+    static ref ACTIVE_SCOPES: Mutex<HashSet<ControlScope>> = Mutex::new(HashSet::new());
+    // Wakes the monitor when a session is requested
+    static ref REQUESTED: Notify = Notify::new();
+}
+
+pub async fn request_control_session(request: ControlRequest) {
+    let scope = match ControlScope::from_i32(request.scope) {
+        Some(scope) => scope,
+        None => {
+            eprintln!("Unknown remote control scope {}", request.scope);
+            return;
+        }
+    };
+    if ACTIVE_SCOPES.lock().await.contains(&scope) {
+        eprintln!("Remote control session for {scope:?} is already in process.");
+        return;
+    }
+    CONTROL_REQUESTS.lock().await.push(scope);
+    REQUESTED.notify_one();
+}
+
+// Mark the requested scopes as active and return those that were not
+// already active
+async fn start_requested() -> Vec<ControlScope> {
+    let requests: Vec<ControlScope> = CONTROL_REQUESTS.lock().await.drain(..).collect();
+    let mut active = ACTIVE_SCOPES.lock().await;
+    requests.into_iter().filter(|s| active.insert(*s)).collect()
+}
+
+// Start a session for every requested scope that is not already active
+pub async fn control_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+    loop {
+        for scope in start_requested().await {
+            tokio::spawn(run_control_scope(channel.clone(), scope));
+        }
+        REQUESTED.notified().await;
+    }
+}
+
+async fn run_control_scope(channel: Channel, scope: ControlScope) {
+    println!("Starting remote control session for {scope:?}");
+    if let Err(e) = run_session(channel, scope).await {
+        eprintln!("Remote control session for {scope:?} failed: {e}");
+    }
+    ACTIVE_SCOPES.lock().await.remove(&scope);
+}
+
+async fn run_session(
+    channel: Channel,
+    scope: ControlScope,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match scope {
+        ControlScope::Outputs if CONFIG.digital_out.is_some() || CONFIG.analog_out.is_some() => {
+            run_output_session(channel).await
+        }
+        ControlScope::CanTransmit if can_transmit::is_enabled() => {
+            run_transmit_session(channel).await
+        }
+        ControlScope::Diagnostics if CONFIG.can.is_some() => run_diagnostic_session(channel).await,
+        _ => Err(format!("{scope:?} is not supported by this unit").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    async fn request(scope: ControlScope) {
+        request_control_session(ControlRequest {
+            scope: scope as i32,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn one_session_per_scope() {
+        request(ControlScope::Outputs).await;
+        request(ControlScope::Outputs).await;
+        request(ControlScope::Diagnostics).await;
+        assert_eq!(
+            start_requested().await,
+            [ControlScope::Outputs, ControlScope::Diagnostics]
+        );
+
+        // Rejected while the session runs
+        request(ControlScope::Outputs).await;
+        assert!(start_requested().await.is_empty());
+
+        ACTIVE_SCOPES.lock().await.remove(&ControlScope::Outputs);
+        request(ControlScope::Outputs).await;
+        assert_eq!(start_requested().await, [ControlScope::Outputs]);
+
+        // The requests wake the monitor
+        timeout(Duration::from_secs(1), REQUESTED.notified())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Loading and consistency checking of the DBC file. Problems in a DBC
+// otherwise only show up as strange values on the server, so they are
+// reported upstream when the file is loaded, together with the catalog
+// of signals that the client can decode.
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use can_dbc::DBC;
+use flate2::read::GzDecoder;
+use lazy_static::lazy_static;
+use lib::{
+    conf_dir,
+    decode::{lint, parse_dbc},
+    host_insight::{agent_client::AgentClient, DbcCatalog, DbcLintReport, SignalInfo, ValueLabel},
+    signal_name, CONFIG,
+};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+
+// Time to wait before trying to load a DBC file that failed to load
+pub const DBC_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// Limits of a download, including the retries
+const CONNECT_TIMEOUT_S: &str = "10";
+const MAX_TIME_S: &str = "120";
+
+lazy_static! {
+    // The CAN ports and the report share one copy of the DBC, which can
+    // be several MB for a whole vehicle
+    static ref SHARED_DBC: Mutex<Option<Arc<DBC>>> = Mutex::new(None);
+}
+
+fn is_remote(s: &str) -> bool {
+    s.starts_with("https://")
+}
+
+// Local path of the DBC file. A remote DBC file is cached in the
+// configuration directory under the name of the last URL component.
+pub fn dbc_path(s: &str) -> PathBuf {
+    if is_remote(s) {
+        let name = s.split(['?', '#']).next().unwrap_or(s);
+        let name = name.rsplit('/').next().unwrap_or("dbc");
+        PathBuf::from(format!("{}/remote-{}", conf_dir(), name))
+    } else {
+        PathBuf::from(format!("{}/{}", conf_dir(), s))
+    }
+}
+
+// Fetch a remote DBC file unless the cached copy is still current,
+// which is checked with the ETag of the previous download. The file is
+// only replaced if it matches the configured SHA-256 hash, if any.
+pub async fn update_remote_dbc(url: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || fetch_remote_dbc(&url)).await?
+}
+
+// Redirects are followed, but only to HTTPS, and a slow server cannot
+// hold up the start for longer than the maximum time
+fn fetch_remote_dbc(url: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dst = dbc_path(url);
+    let mut etag = dst.clone().into_os_string();
+    etag.push(".etag");
+    let mut partial = dst.clone().into_os_string();
+    partial.push(".part");
+
+    let _ = fs::remove_file(&partial);
+    let mut curl = Command::new("curl");
+    curl.arg("--fail")
+        .arg("--silent")
+        .arg("--location")
+        .arg("--proto")
+        .arg("=https")
+        .arg("--proto-redir")
+        .arg("=https")
+        .arg("--connect-timeout")
+        .arg(CONNECT_TIMEOUT_S)
+        .arg("--max-time")
+        .arg(MAX_TIME_S)
+        .arg("--retry")
+        .arg("5");
+    // Without a cached copy the server must not answer 304
+    if dst.exists() {
+        curl.arg("--etag-compare").arg(&etag);
+    }
+    let status = curl
+        .arg("--etag-save")
+        .arg(&etag)
+        .arg("-o")
+        .arg(&partial)
+        .arg(url)
+        .status()?;
+    if !status.success() {
+        return Err(format!("curl failed to download {url}: {status}").into());
+    }
+
+    // Nothing is written if the cached copy is current
+    if !Path::new(&partial).exists() {
+        return Ok(());
+    }
+    if let Some(expected) = CONFIG.can.as_ref().and_then(|c| c.dbc_sha256.as_ref()) {
+        let actual = sha256_file(Path::new(&partial))?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            let _ = fs::remove_file(&partial);
+            let _ = fs::remove_file(&etag);
+            return Err(format!("{url} does not match dbc_sha256, got {actual}").into());
+        }
+    }
+    fs::rename(&partial, &dst)?;
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+// Load a DBC file, which may be gzip compressed. A remote DBC file is
+// fetched if it has not been cached yet.
+pub fn load_dbc_file(s: &str) -> Result<DBC, Box<dyn Error + Send + Sync>> {
+    let path = dbc_path(s);
+    if is_remote(s) && !path.exists() {
+        fetch_remote_dbc(s)?;
+    }
+
+    let f = fs::File::open(&path)?;
+    let mut buffer = String::new();
+    if path.extension().is_some_and(|e| e == "gz") {
+        GzDecoder::new(f).read_to_string(&mut buffer)?;
+    } else {
+        BufReader::new(f).read_to_string(&mut buffer)?;
+    }
+    Ok(parse_dbc(s, &buffer)?)
+}
+
+// Load the DBC file on first use, on the blocking pool, and share it
+// after that
+pub async fn shared_dbc(s: &str) -> Result<Arc<DBC>, Box<dyn Error + Send + Sync>> {
+    let mut shared = SHARED_DBC.lock().await;
+    if let Some(dbc) = shared.as_ref() {
+        return Ok(dbc.clone());
+    }
+    let s = s.to_string();
+    let dbc = Arc::new(tokio::task::spawn_blocking(move || load_dbc_file(&s)).await??);
+    *shared = Some(dbc.clone());
+    Ok(dbc)
+}
+
+// The signals in the DBC with their units, ranges and value labels, so
+// that e.g. dashboards can be set up without a copy of the DBC
+pub fn catalog(dbc: &DBC) -> Vec<SignalInfo> {
+    let mut signals = Vec::new();
+    for message in dbc.messages() {
+        for s in message.signals() {
+            let labels = dbc
+                .value_descriptions_for_signal(*message.message_id(), s.name())
+                .unwrap_or_default()
+                .iter()
+                .map(|d| ValueLabel {
+                    value: *d.a(),
+                    label: d.b().clone(),
+                })
+                .collect();
+            signals.push(SignalInfo {
+                message_name: message.message_name().clone(),
+                message_id: message.message_id().0,
+                signal_name: s.name().clone(),
+                unit: s.unit().clone(),
+                min: s.min,
+                max: s.max,
+                labels,
+            });
+        }
+    }
+    signals
+}
+
+// Check the configured DBC file and send the result to the server. If
+// the file cannot be loaded, that is reported instead and the file is
+// checked again once it loads.
+pub async fn report_dbc(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let dbc_file = CONFIG.can.as_ref().unwrap().dbc_file.clone().unwrap();
+
+    let mut load_error_reported = false;
+    let dbc = loop {
+        match shared_dbc(&dbc_file).await {
+            Ok(dbc) => break dbc,
+            Err(e) => {
+                if !load_error_reported {
+                    let issues = vec![format!("Failed to load {dbc_file}: {e}")];
+                    send_dbc_lint(channel.clone(), dbc_file.clone(), issues).await;
+                    load_error_reported = true;
+                }
+                sleep(DBC_RETRY_INTERVAL).await;
+            }
+        }
+    };
+
+    let issues = lint(&dbc);
+    for issue in &issues {
+        eprintln!("DBC: {issue}");
+    }
+    send_dbc_lint(channel.clone(), dbc_file.clone(), issues).await;
+
+    // The signals are listed by the names they are sent with
+    let mut signals = catalog(&dbc);
+    for s in signals.iter_mut() {
+        s.signal_name = signal_name(&s.signal_name).to_string();
+    }
+    let catalog = DbcCatalog { dbc_file, signals };
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendDbcCatalog", &catalog).await;
+        let response = client.send_dbc_catalog(catalog.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        };
+    }
+    Ok(())
+}
+
+async fn send_dbc_lint(channel: Channel, dbc_file: String, issues: Vec<String>) {
+    let report = DbcLintReport { dbc_file, issues };
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendDbcLint", &report).await;
+        let response = client.send_dbc_lint(report.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_lists_signals_with_labels() {
+        let dbc = DBC::try_from(
+            r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 512 Body: 1 ECU
+ SG_ Door : 0|2@1+ (1,0) [0|3] "" Vector__XXX
+
+VAL_ 512 Door 0 "Closed" 1 "Open" ;
+"#,
+        )
+        .unwrap();
+        let signals = catalog(&dbc);
+        assert_eq!(signals.len(), 1);
+        assert_eq!(signals[0].message_name, "Body");
+        assert_eq!(signals[0].message_id, 512);
+        assert_eq!(signals[0].signal_name, "Door");
+        assert_eq!(signals[0].max, 3.0);
+        let labels: Vec<_> = signals[0].labels.iter().map(|l| l.label.as_str()).collect();
+        assert_eq!(labels, ["Closed", "Open"]);
+    }
+}
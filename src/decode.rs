@@ -0,0 +1,977 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Parsing and checking of DBC files and decoding of CAN frames. These
+// only depend on the DBC and the frame data, and are kept apart from
+// the CAN monitor so that they can be fuzzed without a CAN interface.
+
+use crate::error::ClientError;
+use crate::host_insight::can_signal;
+use can_dbc::{ByteOrder, MultiplexIndicator, SignalExtendedValueType, DBC};
+use std::collections::HashMap;
+
+// Bits in the largest frame, a 64 byte CAN FD frame
+const MAX_FRAME_BITS: u64 = 8 * 64;
+
+// Parse the text of a DBC file. The file name is only used for the error.
+pub fn parse_dbc(file: &str, text: &str) -> Result<DBC, ClientError> {
+    DBC::try_from(text).map_err(|e| ClientError::Dbc {
+        file: file.to_string(),
+        reason: format!("Failed to parse: {}", parse_error(text, &e)),
+    })
+}
+
+// Longest part of the unparsed input quoted in a parse error
+const PARSE_ERROR_EXCERPT: usize = 40;
+
+// Describe where parsing stopped. The Debug form of the error holds the
+// partially parsed DBC and the rest of the file, which can be megabytes.
+fn parse_error(input: &str, e: &can_dbc::Error) -> String {
+    let rest = match e {
+        can_dbc::Error::Incomplete(_, rest) => *rest,
+        can_dbc::Error::Nom(_) => return "invalid DBC syntax".to_string(),
+        can_dbc::Error::MultipleMultiplexors => return "multiple multiplexors".to_string(),
+    };
+    let offset = input.len() - rest.len();
+    let line = input[..offset].lines().count().max(1);
+    let excerpt: String = rest
+        .trim_start()
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take(PARSE_ERROR_EXCERPT)
+        .collect();
+    format!("unexpected input at line {line}: \"{excerpt}\"")
+}
+
+// Physical bit positions (8 * byte + bit) covered by a signal, or None
+// if the signal does not fit in even the largest CAN FD frame
+fn signal_bits(s: &can_dbc::Signal) -> Option<Vec<u64>> {
+    if *s.start_bit() >= MAX_FRAME_BITS || *s.signal_size() > MAX_FRAME_BITS {
+        return None;
+    }
+    let mut bits = Vec::new();
+    let mut pos = *s.start_bit();
+    for i in 0..*s.signal_size() {
+        match s.byte_order() {
+            ByteOrder::LittleEndian => bits.push(*s.start_bit() + i),
+            ByteOrder::BigEndian => {
+                bits.push(pos);
+                pos = if pos & 7 == 0 { pos + 15 } else { pos - 1 };
+            }
+        }
+    }
+    Some(bits)
+}
+
+// Whether a 32 or 64 bit integer signal has a range that it cannot
+// reach as an integer, which suggests that the SIG_VALTYPE_ of a float
+// signal is missing
+fn lacks_float_type(dbc: &DBC, id: &can_dbc::MessageId, s: &can_dbc::Signal) -> bool {
+    let size = *s.signal_size();
+    if size != 32 && size != 64 {
+        return false;
+    }
+    if dbc
+        .signal_extended_value_type_list()
+        .iter()
+        .any(|e| e.message_id() == id && e.signal_name() == s.name())
+    {
+        return false;
+    }
+    let (lo, hi) = match s.value_type() {
+        can_dbc::ValueType::Unsigned => (0.0, 2f64.powi(size as i32) - 1.0),
+        can_dbc::ValueType::Signed => (
+            -(2f64.powi(size as i32 - 1)),
+            2f64.powi(size as i32 - 1) - 1.0,
+        ),
+    };
+    let (a, b) = (lo * s.factor() + s.offset(), hi * s.factor() + s.offset());
+    s.min < a.min(b) || s.max > a.max(b)
+}
+
+// Multiplexed signals only share the frame with signals of the same
+// multiplex value
+fn multiplex_group(s: &can_dbc::Signal) -> Option<u64> {
+    match s.multiplexer_indicator() {
+        MultiplexIndicator::MultiplexedSignal(val) => Some(*val),
+        _ => None,
+    }
+}
+
+// Check a DBC for duplicate message IDs, signals outside their message,
+// overlapping signals, missing float value types and inconsistent
+// extended value types
+pub fn lint(dbc: &DBC) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    let mut ids: HashMap<u32, usize> = HashMap::new();
+    for message in dbc.messages() {
+        *ids.entry(message.message_id().0).or_default() += 1;
+    }
+    let mut duplicates: Vec<_> = ids.into_iter().filter(|(_, n)| *n > 1).collect();
+    duplicates.sort();
+    for (id, n) in duplicates {
+        issues.push(format!("Message ID {id:#x} is defined {n} times"));
+    }
+
+    for message in dbc.messages() {
+        let name = message.message_name();
+        let frame_bits = message.message_size().saturating_mul(8);
+
+        let signals: Vec<_> = message
+            .signals()
+            .iter()
+            .map(|s| (s, signal_bits(s)))
+            .collect();
+
+        for (s, bits) in &signals {
+            if !matches!(bits, Some(bits) if bits.iter().all(|b| *b < frame_bits)) {
+                issues.push(format!(
+                    "Signal {}.{} exceeds the message size of {} bytes",
+                    name,
+                    s.name(),
+                    message.message_size()
+                ));
+            }
+            if lacks_float_type(dbc, message.message_id(), s) {
+                issues.push(format!(
+                    "Signal {}.{} has a range that only a float reaches but no float value type",
+                    name,
+                    s.name()
+                ));
+            }
+        }
+
+        for (i, (a, a_bits)) in signals.iter().enumerate() {
+            for (b, b_bits) in &signals[i + 1..] {
+                let (ga, gb) = (multiplex_group(a), multiplex_group(b));
+                if ga.is_some() && gb.is_some() && ga != gb {
+                    continue;
+                }
+                let (a_bits, b_bits) = match (a_bits, b_bits) {
+                    (Some(a_bits), Some(b_bits)) => (a_bits, b_bits),
+                    _ => continue,
+                };
+                if a_bits.iter().any(|bit| b_bits.contains(bit)) {
+                    issues.push(format!(
+                        "Signals {}.{} and {}.{} overlap",
+                        name,
+                        a.name(),
+                        name,
+                        b.name()
+                    ));
+                }
+            }
+        }
+    }
+
+    for elem in dbc.signal_extended_value_type_list() {
+        let signal = match dbc.signal_by_name(*elem.message_id(), elem.signal_name()) {
+            Some(s) => s,
+            None => {
+                issues.push(format!(
+                    "Value type given for unknown signal {} in message ID {:#x}",
+                    elem.signal_name(),
+                    elem.message_id().0
+                ));
+                continue;
+            }
+        };
+        let expected_size = match elem.signal_extended_value_type() {
+            SignalExtendedValueType::IEEEfloat32Bit => 32,
+            SignalExtendedValueType::IEEEdouble64bit => 64,
+            SignalExtendedValueType::SignedOrUnsignedInteger => continue,
+        };
+        if *signal.signal_size() != expected_size {
+            issues.push(format!(
+                "Signal {} is a {} bit float but {} bits long",
+                signal.name(),
+                expected_size,
+                signal.signal_size()
+            ));
+        }
+    }
+
+    issues
+}
+
+// A decoded signal of a frame, by its index in the DBC message
+pub struct DecodedSignal {
+    pub index: usize,
+    pub unit: String,
+    pub value: Option<can_signal::Value>,
+    pub raw: Option<u64>,
+}
+
+// Decode the signals of a frame, leaving out multiplexors and the
+// signals of other multiplexer values
+pub fn decode_signals(
+    message: &can_dbc::Message,
+    data: &[u8],
+    dbc: &can_dbc::DBC,
+    raw_enums: bool,
+) -> Vec<DecodedSignal> {
+    let mut decoded = Vec::new();
+    let mut multiplex_val = 0;
+
+    for (index, signal) in message.signals().iter().enumerate() {
+        let can_signal_value = match get_can_signal_value(message.message_id(), data, signal, dbc) {
+            Some(val) => Some(val),
+            // FIXME: Report an error to the server instead of just skipping the signal
+            None => continue,
+        };
+
+        let signal_unit = if str::is_empty(signal.unit()) {
+            match can_signal_value {
+                Some(can_signal::Value::ValStr(_)) => "enum".to_string(),
+                _ => "N/A".to_string(),
+            }
+        } else {
+            signal.unit().clone()
+        };
+        // If the signal is a multiplexor, store the value of that signal.
+        if is_multiplexor(signal) {
+            if let Some(can_signal::Value::ValU64(val)) = can_signal_value.clone() {
+                multiplex_val = val;
+            }
+            continue;
+        }
+
+        // If the value is a multiplexed signal
+        // Check if the multiplex signal value matches the multiplexor value of this signal
+        // Else continue and discard the signal
+        // FIXME: This is dependent on that the multipexor signal is parsed firs in the for-loop.
+        // otherwise the multiplex_val variable will be 0
+        if is_multiplexed(signal) {
+            if let Some(can_signal::Value::ValU64(_)) = can_signal_value.clone() {
+                if multiplex_val != get_multiplex_val(signal) {
+                    continue;
+                }
+            }
+        }
+
+        // Enums are sent either as labels along with the raw
+        // value, or as the raw value alone
+        let mut raw = get_enum_raw_value(message.message_id(), data, signal, dbc);
+        let can_signal_value = match raw {
+            Some(r) if raw_enums => {
+                raw = None;
+                Some(can_signal::Value::ValU64(r))
+            }
+            _ => can_signal_value,
+        };
+
+        decoded.push(DecodedSignal {
+            index,
+            unit: signal_unit,
+            value: can_signal_value,
+            raw,
+        });
+    }
+    decoded
+}
+
+// Get the can signal value based on the message ID, the data part of
+// the frame, the signal, and extra metadata contained in the DBC
+// file.
+// The following can_signal::can_signal::Value types can be returned:
+//   can_signal::Value::ValF64, ValStr, ValI64, ValU64
+fn get_can_signal_value(
+    id: &can_dbc::MessageId,
+    d: &[u8],
+    s: &can_dbc::Signal,
+    dbc: &can_dbc::DBC,
+) -> Option<can_signal::Value> {
+    let signal_value = get_signal_value(d, *s.start_bit(), *s.signal_size(), s.byte_order())?;
+
+    match get_signal_value_type(s, dbc, id) {
+        Some(SignalValueType::Float) => get_float(signal_value, *s.factor(), *s.offset()),
+        Some(SignalValueType::Signed) => {
+            get_signed_number(signal_value, *s.signal_size(), *s.factor(), *s.offset())
+        }
+        Some(SignalValueType::Unsigned) => {
+            get_unsigned_number(signal_value, *s.factor(), *s.offset())
+        }
+        Some(SignalValueType::Double) => get_double(signal_value, *s.factor(), *s.offset()),
+        // FIXME: IMPLEMENT BOOL
+        Some(SignalValueType::String) => get_string(signal_value, dbc, id, s),
+        _ => None,
+    }
+}
+
+// Get the raw numeric value of a signal with value descriptions, so that
+// it can be sent along with the label
+fn get_enum_raw_value(
+    id: &can_dbc::MessageId,
+    d: &[u8],
+    s: &can_dbc::Signal,
+    dbc: &can_dbc::DBC,
+) -> Option<u64> {
+    dbc.value_descriptions_for_signal(*id, s.name())?;
+    get_signal_value(d, *s.start_bit(), *s.signal_size(), s.byte_order())
+}
+
+fn is_multiplexor(s: &can_dbc::Signal) -> bool {
+    match s.multiplexer_indicator() {
+        MultiplexIndicator::Multiplexor => true,
+        MultiplexIndicator::MultiplexedSignal(_val) => false,
+        MultiplexIndicator::MultiplexorAndMultiplexedSignal(_val) => false,
+        MultiplexIndicator::Plain => false,
+    }
+}
+
+fn is_multiplexed(s: &can_dbc::Signal) -> bool {
+    match s.multiplexer_indicator() {
+        MultiplexIndicator::Multiplexor => false,
+        MultiplexIndicator::MultiplexedSignal(_val) => true,
+        MultiplexIndicator::MultiplexorAndMultiplexedSignal(_val) => false,
+        MultiplexIndicator::Plain => false,
+    }
+}
+
+fn get_multiplex_val(s: &can_dbc::Signal) -> u64 {
+    match s.multiplexer_indicator() {
+        MultiplexIndicator::Multiplexor => 0,
+        MultiplexIndicator::MultiplexedSignal(val) => *val,
+        MultiplexIndicator::MultiplexorAndMultiplexedSignal(val) => *val,
+        MultiplexIndicator::Plain => 0,
+    }
+}
+
+#[derive(Debug)]
+enum SignalValueType {
+    Float,
+    Signed,
+    Unsigned,
+    Double,
+    // Bool,  UNIMPLEMENTED
+    String,
+}
+
+fn get_signal_value_type(
+    s: &can_dbc::Signal,
+    dbc: &can_dbc::DBC,
+    id: &can_dbc::MessageId,
+) -> Option<SignalValueType> {
+    let val_desc = dbc.value_descriptions_for_signal(*id, s.name());
+    if val_desc.is_some() {
+        return Some(SignalValueType::String);
+    }
+
+    let mut value_type_extended: Option<can_dbc::SignalExtendedValueType> =
+        Some(can_dbc::SignalExtendedValueType::SignedOrUnsignedInteger);
+
+    for elem in dbc.signal_extended_value_type_list() {
+        if elem.signal_name() == s.name() {
+            value_type_extended = Some(*elem.signal_extended_value_type());
+            break;
+        }
+    }
+    match value_type_extended {
+        Some(SignalExtendedValueType::IEEEfloat32Bit) => Some(SignalValueType::Float),
+        Some(SignalExtendedValueType::IEEEdouble64bit) => Some(SignalValueType::Double),
+        Some(SignalExtendedValueType::SignedOrUnsignedInteger) => match *s.value_type() {
+            can_dbc::ValueType::Unsigned => Some(SignalValueType::Unsigned),
+            can_dbc::ValueType::Signed => Some(SignalValueType::Signed),
+        },
+        _ => None,
+    }
+}
+
+fn get_string(
+    signal_value: u64,
+    dbc: &can_dbc::DBC,
+    id: &can_dbc::MessageId,
+    s: &can_dbc::Signal,
+) -> Option<can_signal::Value> {
+    let val_desc = dbc.value_descriptions_for_signal(*id, s.name());
+
+    if let Some(desc) = val_desc {
+        for elem in desc {
+            if *elem.a() == signal_value as f64 {
+                return Some(can_signal::Value::ValStr(elem.b().to_string()));
+            }
+        }
+        // Signal exists in value description but key could not be found
+        return Some(can_signal::Value::ValStr(signal_value.to_string()));
+    }
+    None
+}
+
+fn get_float(
+    signal_value: u64,
+    signal_factor: f64,
+    signal_offset: f64,
+) -> Option<can_signal::Value> {
+    Some(can_signal::Value::ValF64(
+        f32::from_bits(signal_value as u32) as f64 * signal_factor + signal_offset,
+    ))
+}
+
+fn get_double(
+    signal_value: u64,
+    signal_factor: f64,
+    signal_offset: f64,
+) -> Option<can_signal::Value> {
+    Some(can_signal::Value::ValF64(
+        f64::from_bits(signal_value) * signal_factor + signal_offset,
+    ))
+}
+
+fn get_unsigned_number(
+    signal_value: u64,
+    signal_factor: f64,
+    signal_offset: f64,
+) -> Option<can_signal::Value> {
+    Some(scale_integer(
+        signal_value as i128,
+        signal_factor,
+        signal_offset,
+        signal_factor >= 0.0 && signal_offset >= 0.0,
+    ))
+}
+
+fn get_signed_number(
+    signal_value: u64,
+    signal_length: u64,
+    signal_factor: f64,
+    signal_offset: f64,
+) -> Option<can_signal::Value> {
+    let signed_mask = 1 << (signal_length - 1);
+    let is_negative = (signed_mask & signal_value) != 0;
+
+    let raw = if is_negative && signal_length < 64 {
+        let max_val: u64 = 0xFFFFFFFFFFFFFFFF;
+        ((max_val << signal_length) | signal_value) as i64
+    } else {
+        signal_value as i64
+    };
+
+    Some(scale_integer(
+        raw as i128,
+        signal_factor,
+        signal_offset,
+        false,
+    ))
+}
+
+// Apply factor and offset to an integer raw value. Integer variants are
+// only used when both factor and offset are integral, in which case the
+// result is computed exactly. The variant depends on the signal
+// definition only, so a signal does not change type between frames.
+// Results that do not fit the integer variant fall back to ValF64.
+fn scale_integer(raw: i128, factor: f64, offset: f64, unsigned: bool) -> can_signal::Value {
+    let as_f64 = || can_signal::Value::ValF64(raw as f64 * factor + offset);
+
+    if is_float(factor) || is_float(offset) {
+        return as_f64();
+    }
+
+    let scaled = raw
+        .checked_mul(factor as i128)
+        .and_then(|v| v.checked_add(offset as i128));
+    match scaled {
+        Some(v) if unsigned => match u64::try_from(v) {
+            Ok(v) => can_signal::Value::ValU64(v),
+            Err(_) => as_f64(),
+        },
+        Some(v) => match i64::try_from(v) {
+            Ok(v) => can_signal::Value::ValI64(v),
+            Err(_) => as_f64(),
+        },
+        None => as_f64(),
+    }
+}
+
+fn is_float(f: f64) -> bool {
+    f.fract() != 0.0 || !f.is_finite() || f.abs() >= i64::MAX as f64
+}
+
+// Extract the raw value of a signal from the frame data. Signals that
+// do not fit in the data, e.g. in a frame shorter than the message, give
+// None. The data is a classic frame of up to 8 bytes, a CAN FD frame of
+// up to 64 bytes or a reassembled J1939 message of up to 1785 bytes, and
+// signals are at most 64 bits long.
+//
+// For little endian (Intel) signals the start bit is the least
+// significant bit. For big endian (Motorola) signals it is the most
+// significant bit, counted within each byte from bit 0 upwards.
+fn get_signal_value(
+    d: &[u8],
+    start_bit: u64,
+    signal_size: u64,
+    byte_order: &ByteOrder,
+) -> Option<u64> {
+    let frame_bits = 8 * d.len() as u64;
+    if signal_size == 0 || signal_size > 64 || start_bit >= frame_bits {
+        return None;
+    }
+
+    // A signal of up to 64 bits spans at most 9 bytes, which are read
+    // into a window starting at the byte of its first bit
+    let window = |first: u64| {
+        let mut window = [0; 16];
+        for (index, value) in d[first as usize..].iter().take(9).enumerate() {
+            window[index] = *value;
+        }
+        window
+    };
+
+    match byte_order {
+        ByteOrder::LittleEndian => {
+            if start_bit + signal_size > frame_bits {
+                return None;
+            }
+            let value = u128::from_le_bytes(window(start_bit / 8)) >> (start_bit % 8);
+            Some(value as u64 & (u64::MAX >> (64 - signal_size)))
+        }
+        ByteOrder::BigEndian => {
+            // Position of the most significant bit counted from the
+            // start of the frame
+            let msb = (start_bit / 8) * 8 + (7 - start_bit % 8);
+            if msb + signal_size > frame_bits {
+                return None;
+            }
+            let value = u128::from_be_bytes(window(msb / 8)) << (msb % 8);
+            Some((value >> (128 - signal_size)) as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DBC_WITH_ISSUES: &str = r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 256 Engine: 2 ECU
+ SG_ Speed : 0|12@1+ (1,0) [0|0] "rpm" Vector__XXX
+ SG_ Temp : 8|8@1+ (1,-40) [0|0] "C" Vector__XXX
+
+BO_ 257 Gear: 1 ECU
+ SG_ Gear : 7|16@0+ (1,0) [0|0] "" Vector__XXX
+
+BO_ 256 Duplicate: 8 ECU
+ SG_ Level : 0|16@1+ (1,0) [0|0] "" Vector__XXX
+
+BO_ 258 Fuel: 8 ECU
+ SG_ Rate : 0|32@1+ (1,0) [-1000|1000] "l/h" Vector__XXX
+ SG_ Total : 32|32@1+ (0.5,0) [0|2147483647.5] "l" Vector__XXX
+
+SIG_VALTYPE_ 256 Speed : 1;
+"#;
+
+    #[test]
+    fn parse_error_quotes_the_failing_line() {
+        let input = format!(
+            "{DBC_WITH_ISSUES}BO_ 259 Broken 8 ECU\n{}",
+            " ".repeat(1 << 20)
+        );
+        let e = parse_dbc("test.dbc", &input).unwrap_err();
+        let message = e.to_string();
+        assert!(
+            message.starts_with("DBC file test.dbc: Failed to parse: unexpected input at line ")
+        );
+        assert!(message.contains("BO_ 259 Broken 8 ECU"));
+        assert!(message.len() < 150);
+    }
+
+    #[test]
+    fn lint_finds_issues() {
+        let dbc = DBC::try_from(DBC_WITH_ISSUES).unwrap();
+        let issues = lint(&dbc);
+        assert_eq!(
+            issues,
+            vec![
+                "Message ID 0x100 is defined 2 times",
+                "Signals Engine.Speed and Engine.Temp overlap",
+                "Signal Gear.Gear exceeds the message size of 1 bytes",
+                "Signal Fuel.Rate has a range that only a float reaches but no float value type",
+                "Signal Speed is a 32 bit float but 12 bits long",
+            ]
+        );
+    }
+
+    // Start bits and sizes far outside of any frame are reported, and
+    // such signals are not decoded
+    #[test]
+    fn lint_survives_huge_signals() {
+        let dbc = parse_dbc(
+            "test.dbc",
+            r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 258 Broken: 18446744073709551615 ECU
+ SG_ Far : 18446744073709551615|8@0+ (1,0) [0|0] "" Vector__XXX
+ SG_ Long : 0|18446744073709551615@1- (1,0) [0|0] "" Vector__XXX
+"#,
+        )
+        .unwrap();
+        let issues = lint(&dbc);
+        assert_eq!(
+            issues,
+            vec![
+                "Signal Broken.Far exceeds the message size of 18446744073709551615 bytes",
+                "Signal Broken.Long exceeds the message size of 18446744073709551615 bytes",
+            ]
+        );
+        let message = &dbc.messages()[0];
+        assert!(decode_signals(message, &[0xFF; 64], &dbc, false).is_empty());
+    }
+
+    // Straightforward bit by bit decoder to compare against, following
+    // the bit numbering of the DBC format
+    fn reference_decode(d: &[u8], start_bit: u64, signal_size: u64, byte_order: &ByteOrder) -> u64 {
+        let bit = |pos: u64| (d[(pos / 8) as usize] >> (pos % 8)) as u64 & 1;
+        let mut value = 0;
+        match byte_order {
+            ByteOrder::LittleEndian => {
+                for i in 0..signal_size {
+                    value |= bit(start_bit + i) << i;
+                }
+            }
+            ByteOrder::BigEndian => {
+                let mut pos = start_bit;
+                for _ in 0..signal_size {
+                    value = (value << 1) | bit(pos);
+                    pos = if pos & 7 == 0 { pos + 15 } else { pos - 1 };
+                }
+            }
+        }
+        value
+    }
+
+    // Start bits and sizes of signals that fit in a frame of len bytes
+    fn signal_layout(len: usize) -> impl Strategy<Value = (u64, u64, ByteOrder)> {
+        let bits = 8 * len as u64;
+        prop_oneof![
+            (0..bits)
+                .prop_flat_map(move |start| (Just(start), 1..=(bits - start).min(64)))
+                .prop_map(|(start, size)| (start, size, ByteOrder::LittleEndian)),
+            (0..bits)
+                .prop_flat_map(move |start| {
+                    let msb = (start / 8) * 8 + (7 - start % 8);
+                    (Just(start), 1..=(bits - msb).min(64))
+                })
+                .prop_map(|(start, size)| (start, size, ByteOrder::BigEndian)),
+        ]
+    }
+
+    // Write a raw value into the frame, the inverse of reference_decode
+    fn reference_encode(
+        d: &mut [u8],
+        value: u64,
+        start_bit: u64,
+        signal_size: u64,
+        byte_order: &ByteOrder,
+    ) {
+        let mut set = |pos: u64, bit: u64| {
+            let byte = &mut d[(pos / 8) as usize];
+            *byte = (*byte & !(1 << (pos % 8))) | ((bit as u8) << (pos % 8));
+        };
+        match byte_order {
+            ByteOrder::LittleEndian => {
+                for i in 0..signal_size {
+                    set(start_bit + i, (value >> i) & 1);
+                }
+            }
+            ByteOrder::BigEndian => {
+                let mut pos = start_bit;
+                for i in (0..signal_size).rev() {
+                    set(pos, (value >> i) & 1);
+                    pos = if pos & 7 == 0 { pos + 15 } else { pos - 1 };
+                }
+            }
+        }
+    }
+
+    // Sign extension by arithmetic shift, to compare the two's
+    // complement handling against
+    fn reference_signed(raw: u64, signal_size: u64) -> i64 {
+        let shift = 64 - signal_size;
+        ((raw << shift) as i64) >> shift
+    }
+
+    fn as_f64(value: Option<can_signal::Value>) -> f64 {
+        match value {
+            Some(can_signal::Value::ValF64(v)) => v,
+            Some(can_signal::Value::ValI64(v)) => v as f64,
+            Some(can_signal::Value::ValU64(v)) => v as f64,
+            _ => f64::NAN,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn signal_value_matches_reference(
+            (d, (start_bit, signal_size, byte_order)) in (1usize..=64)
+                .prop_flat_map(|len| (prop::collection::vec(any::<u8>(), len), signal_layout(len)))
+        ) {
+            prop_assert_eq!(
+                get_signal_value(&d, start_bit, signal_size, &byte_order),
+                Some(reference_decode(&d, start_bit, signal_size, &byte_order))
+            );
+        }
+
+        // A signed value written into a random frame reads back the same,
+        // whatever the other bits of the frame are
+        #[test]
+        fn signed_value_round_trips(
+            (d, (start_bit, signal_size, byte_order), value) in (
+                prop::collection::vec(any::<u8>(), 8),
+                signal_layout(8),
+                any::<i64>(),
+            )
+        ) {
+            let mut d = d;
+            let raw = if signal_size == 64 {
+                value as u64
+            } else {
+                value as u64 & ((1 << signal_size) - 1)
+            };
+            reference_encode(&mut d, raw, start_bit, signal_size, &byte_order);
+            let read = get_signal_value(&d, start_bit, signal_size, &byte_order).unwrap();
+            prop_assert_eq!(read, raw);
+            prop_assert_eq!(
+                get_signed_number(read, signal_size, 1.0, 0.0),
+                Some(can_signal::Value::ValI64(reference_signed(raw, signal_size)))
+            );
+        }
+
+        // Integral factors and offsets are applied exactly
+        #[test]
+        fn integral_scaling_is_exact(
+            raw in any::<u32>(),
+            signal_size in 1u64..=32,
+            factor in -1000i64..=1000,
+            offset in -100_000i64..=100_000,
+        ) {
+            let raw = raw as u64 & ((1 << signal_size) - 1);
+            let signed = reference_signed(raw, signal_size) as i128;
+            let expected = signed * factor as i128 + offset as i128;
+            prop_assert_eq!(
+                get_signed_number(raw, signal_size, factor as f64, offset as f64),
+                Some(can_signal::Value::ValI64(expected as i64))
+            );
+
+            let expected = raw as i128 * factor as i128 + offset as i128;
+            let value = get_unsigned_number(raw, factor as f64, offset as f64);
+            if factor >= 0 && offset >= 0 {
+                prop_assert_eq!(value, Some(can_signal::Value::ValU64(expected as u64)));
+            } else {
+                prop_assert_eq!(value, Some(can_signal::Value::ValI64(expected as i64)));
+            }
+        }
+
+        // Fractional factors and offsets give floats
+        #[test]
+        fn fractional_scaling_matches_reference(
+            raw in any::<u16>(),
+            factor in -100.0f64..100.0,
+            offset in -1000.0f64..1000.0,
+        ) {
+            let factor = factor.trunc() + 0.25;
+            let value = get_unsigned_number(raw as u64, factor, offset);
+            prop_assert!(matches!(value, Some(can_signal::Value::ValF64(_))));
+            let expected = raw as f64 * factor + offset;
+            prop_assert!((as_f64(value) - expected).abs() <= 1e-9 * expected.abs().max(1.0));
+        }
+
+        // IEEE floats are read from their bits before scaling. The frame
+        // is built bit by bit, and the expected value is read from its
+        // bytes, where a byte aligned float is stored in the byte order
+        // of the signal.
+        #[test]
+        fn float_values_match_reference(
+            (d, bits, byte, big_endian) in (
+                prop::collection::vec(any::<u8>(), 16),
+                any::<u64>(),
+                0usize..=8,
+                any::<bool>(),
+            ),
+            factor in -10.0f64..10.0,
+            offset in -10.0f64..10.0,
+        ) {
+            let (byte_order, start_bit) = if big_endian {
+                (ByteOrder::BigEndian, 8 * byte as u64 + 7)
+            } else {
+                (ByteOrder::LittleEndian, 8 * byte as u64)
+            };
+
+            let mut d = d;
+            reference_encode(&mut d, bits & 0xFFFF_FFFF, start_bit, 32, &byte_order);
+            let bytes: [u8; 4] = d[byte..byte + 4].try_into().unwrap();
+            let f = if big_endian { f32::from_be_bytes(bytes) } else { f32::from_le_bytes(bytes) };
+            prop_assume!(f.is_finite());
+            let raw = get_signal_value(&d, start_bit, 32, &byte_order).unwrap();
+            prop_assert_eq!(
+                get_float(raw, factor, offset),
+                Some(can_signal::Value::ValF64(f as f64 * factor + offset))
+            );
+
+            reference_encode(&mut d, bits, start_bit, 64, &byte_order);
+            let bytes: [u8; 8] = d[byte..byte + 8].try_into().unwrap();
+            let f = if big_endian { f64::from_be_bytes(bytes) } else { f64::from_le_bytes(bytes) };
+            prop_assume!(f.is_finite());
+            let raw = get_signal_value(&d, start_bit, 64, &byte_order).unwrap();
+            prop_assert_eq!(
+                get_double(raw, factor, offset),
+                Some(can_signal::Value::ValF64(f * factor + offset))
+            );
+        }
+
+        // Integral values within the i64 range are not floats, and values
+        // with a fraction are
+        #[test]
+        fn is_float_detects_fractions(v in -1_000_000_000i64..1_000_000_000) {
+            prop_assert!(!is_float(v as f64));
+            prop_assert!(is_float(v as f64 + 0.5));
+        }
+    }
+
+    // Known values for a frame shorter than 8 bytes
+    #[test]
+    fn big_endian_short_frame() {
+        let d = [0x12, 0x34, 0x56];
+        assert_eq!(
+            get_signal_value(&d, 7, 16, &ByteOrder::BigEndian),
+            Some(0x1234)
+        );
+        assert_eq!(
+            get_signal_value(&d, 3, 8, &ByteOrder::BigEndian),
+            Some(0x23)
+        );
+        assert_eq!(
+            get_signal_value(&d, 23, 8, &ByteOrder::BigEndian),
+            Some(0x56)
+        );
+        assert_eq!(
+            get_signal_value(&d, 0, 16, &ByteOrder::LittleEndian),
+            Some(0x3412)
+        );
+        // Outside of the frame
+        assert_eq!(get_signal_value(&d, 31, 8, &ByteOrder::BigEndian), None);
+        assert_eq!(get_signal_value(&d, 16, 16, &ByteOrder::LittleEndian), None);
+    }
+
+    // Signals beyond the first 8 bytes of a CAN FD frame
+    #[test]
+    fn fd_frame() {
+        let mut d = [0; 64];
+        d[40] = 0x12;
+        d[41] = 0x34;
+        d[63] = 0x80;
+        assert_eq!(
+            get_signal_value(&d, 320, 16, &ByteOrder::LittleEndian),
+            Some(0x3412)
+        );
+        assert_eq!(
+            get_signal_value(&d, 327, 16, &ByteOrder::BigEndian),
+            Some(0x1234)
+        );
+        assert_eq!(
+            get_signal_value(&d, 511, 1, &ByteOrder::LittleEndian),
+            Some(1)
+        );
+        assert_eq!(
+            get_signal_value(&d, 504, 16, &ByteOrder::LittleEndian),
+            None
+        );
+        assert_eq!(
+            get_signal_value(&d[..48], 384, 8, &ByteOrder::LittleEndian),
+            None
+        );
+    }
+
+    // J1939 EEC1 EngineSpeed: 16 bit, factor 0.125 rpm/bit, offset 0
+    #[test]
+    fn unsigned_fractional_factor() {
+        assert_eq!(
+            get_unsigned_number(0x3E80, 0.125, 0.0),
+            Some(can_signal::Value::ValF64(2000.0))
+        );
+        assert_eq!(
+            get_unsigned_number(3, 0.25, 0.0),
+            Some(can_signal::Value::ValF64(0.75))
+        );
+    }
+
+    // J1939 ET1 EngineCoolantTemperature: 8 bit, factor 1, offset -40
+    #[test]
+    fn unsigned_negative_offset() {
+        assert_eq!(
+            get_unsigned_number(0, 1.0, -40.0),
+            Some(can_signal::Value::ValI64(-40))
+        );
+        assert_eq!(
+            get_unsigned_number(130, 1.0, -40.0),
+            Some(can_signal::Value::ValI64(90))
+        );
+    }
+
+    #[test]
+    fn unsigned_integral_scaling() {
+        assert_eq!(
+            get_unsigned_number(7, 5.0, 10.0),
+            Some(can_signal::Value::ValU64(45))
+        );
+        // Exact for raw values that do not fit in the f64 mantissa
+        assert_eq!(
+            get_unsigned_number(u64::MAX, 1.0, 0.0),
+            Some(can_signal::Value::ValU64(u64::MAX))
+        );
+        assert_eq!(
+            get_unsigned_number(u64::MAX, 2.0, 0.0),
+            Some(can_signal::Value::ValF64(u64::MAX as f64 * 2.0))
+        );
+    }
+
+    #[test]
+    fn signed_scaling() {
+        assert_eq!(
+            get_signed_number(0xFF, 8, 1.0, 0.0),
+            Some(can_signal::Value::ValI64(-1))
+        );
+        assert_eq!(
+            get_signed_number(0xFE, 8, 0.5, 0.0),
+            Some(can_signal::Value::ValF64(-1.0))
+        );
+        assert_eq!(
+            get_signed_number(0x7F, 8, 2.0, -100.0),
+            Some(can_signal::Value::ValI64(154))
+        );
+        assert_eq!(
+            get_signed_number(u64::MAX, 64, 1.0, 0.0),
+            Some(can_signal::Value::ValI64(-1))
+        );
+    }
+}
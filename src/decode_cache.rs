@@ -0,0 +1,123 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Cache of decoded frames keyed by ID and payload. It is approximately
+// least recently used: entries are kept in two generations, a hit in
+// the older generation moves the entry to the current one, and when the
+// current generation is full the older one is dropped. This keeps both
+// lookups and eviction constant time.
+
+use std::collections::HashMap;
+
+// CAN FD frames have at most 64 bytes of data
+type FrameKey = (u32, u8, [u8; 64]);
+
+pub struct DecodeCache<V> {
+    capacity: usize,
+    current: HashMap<FrameKey, V>,
+    previous: HashMap<FrameKey, V>,
+}
+
+fn key(id: u32, data: &[u8]) -> Option<FrameKey> {
+    let mut payload = [0; 64];
+    payload.get_mut(..data.len())?.copy_from_slice(data);
+    Some((id, data.len() as u8, payload))
+}
+
+impl<V: Clone> DecodeCache<V> {
+    // A capacity of 0 disables the cache
+    pub fn new(capacity: usize) -> DecodeCache<V> {
+        DecodeCache {
+            capacity,
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, id: u32, data: &[u8]) -> Option<V> {
+        let key = key(id, data)?;
+        if let Some(value) = self.current.get(&key) {
+            return Some(value.clone());
+        }
+        let value = self.previous.remove(&key)?;
+        self.insert_key(key, value.clone());
+        Some(value)
+    }
+
+    pub fn insert(&mut self, id: u32, data: &[u8], value: V) {
+        if let Some(key) = key(id, data) {
+            self.insert_key(key, value);
+        }
+    }
+
+    fn insert_key(&mut self, key: FrameKey, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.current.len() >= self.capacity.div_ceil(2) {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(key, value);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_and_eviction() {
+        let mut cache = DecodeCache::new(4);
+        cache.insert(0x100, &[1, 2], "a");
+        cache.insert(0x100, &[1, 3], "b");
+        assert_eq!(cache.get(0x100, &[1, 2]), Some("a"));
+        assert_eq!(cache.get(0x100, &[1, 2, 0]), None);
+        assert_eq!(cache.get(0x100, &[0; 65]), None);
+        assert_eq!(cache.get(0x101, &[1, 2]), None);
+
+        // "b" is used again, so it survives while "a" is evicted
+        cache.insert(0x200, &[], "c");
+        cache.insert(0x200, &[1], "d");
+        assert_eq!(cache.get(0x100, &[1, 3]), Some("b"));
+        cache.insert(0x200, &[2], "e");
+        cache.insert(0x200, &[3], "f");
+        assert_eq!(cache.get(0x100, &[1, 2]), None);
+        assert_eq!(cache.get(0x100, &[1, 3]), Some("b"));
+        assert!(cache.len() <= 4);
+    }
+
+    #[test]
+    fn fd_frames() {
+        let mut cache = DecodeCache::new(4);
+        cache.insert(0x100, &[0xff; 64], "a");
+        assert_eq!(cache.get(0x100, &[0xff; 64]), Some("a"));
+        assert_eq!(cache.get(0x100, &[0xff; 48]), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = DecodeCache::new(0);
+        cache.insert(0x100, &[1], 1);
+        assert_eq!(cache.get(0x100, &[1]), None);
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Discovery of the hardware of a unit at install time. The CAN
+// interfaces, GPIO lines, IIO devices, serial ports and modems are
+// listed as a skeleton config to start from and as a JSON inventory. Nothing is configured or changed.
+
+use gpio_cdev::LineDirection;
+use serde_derive::Serialize;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// ARPHRD_CAN in /sys/class/net/<interface>/type
+const ARPHRD_CAN: &str = "280";
+
+#[derive(Serialize)]
+struct Inventory {
+    can: Vec<CanInterface>,
+    gpio: Vec<GpioChip>,
+    iio: Vec<IioDevice>,
+    serial: Vec<String>,
+    modems: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CanInterface {
+    name: String,
+    state: String,
+}
+
+#[derive(Serialize)]
+struct GpioChip {
+    name: String,
+    label: String,
+    lines: Vec<GpioLine>,
+}
+
+#[derive(Serialize)]
+struct GpioLine {
+    offset: u32,
+    name: Option<String>,
+    consumer: Option<String>,
+    output: bool,
+}
+
+#[derive(Serialize)]
+struct IioDevice {
+    name: String,
+    path: String,
+    channels: Vec<String>,
+}
+
+fn dir_names(dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn can_interfaces() -> Vec<CanInterface> {
+    dir_names("/sys/class/net")
+        .into_iter()
+        .filter(|name| read_trimmed(format!("/sys/class/net/{name}/type")) == ARPHRD_CAN)
+        .map(|name| CanInterface {
+            state: read_trimmed(format!("/sys/class/net/{name}/operstate")),
+            name,
+        })
+        .collect()
+}
+
+fn gpio_chips() -> Vec<GpioChip> {
+    let chips = match gpio_cdev::chips() {
+        Ok(chips) => chips,
+        Err(_) => return Vec::new(),
+    };
+    chips
+        .flatten()
+        .map(|chip| GpioChip {
+            name: chip.name().to_string(),
+            label: chip.label().to_string(),
+            lines: chip
+                .lines()
+                .filter_map(|line| line.info().ok())
+                .map(|info| GpioLine {
+                    offset: info.line().offset(),
+                    name: info.name().map(str::to_string),
+                    consumer: info.consumer().map(str::to_string),
+                    output: info.direction() == LineDirection::Out,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn iio_devices() -> Vec<IioDevice> {
+    dir_names("/sys/bus/iio/devices")
+        .into_iter()
+        .filter(|d| d.starts_with("iio:device"))
+        .map(|d| {
+            let path = format!("/sys/bus/iio/devices/{d}");
+            IioDevice {
+                name: read_trimmed(format!("{path}/name")),
+                channels: dir_names(&path)
+                    .into_iter()
+                    .filter(|c| c.ends_with("_raw"))
+                    .collect(),
+                path,
+            }
+        })
+        .collect()
+}
+
+// Serial ports backed by a device, which leaves out the virtual consoles
+fn serial_ports() -> Vec<String> {
+    dir_names("/sys/class/tty")
+        .into_iter()
+        .filter(|t| Path::new(&format!("/sys/class/tty/{t}/device")).exists())
+        .map(|t| format!("/dev/{t}"))
+        .collect()
+}
+
+fn modems() -> Vec<String> {
+    match Command::new("mmcli").arg("-L").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim)
+            .filter(|l| l.contains("/Modem/"))
+            .map(str::to_string)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn inventory() -> Inventory {
+    Inventory {
+        can: can_interfaces(),
+        gpio: gpio_chips(),
+        iio: iio_devices(),
+        serial: serial_ports(),
+        modems: modems(),
+    }
+}
+
+// A config to start from. The external names are the internal ones and
+// are meant to be renamed.
+fn skeleton(inventory: &Inventory) -> String {
+    let mut toml = String::from("# Generated by host-insight-client discover\n");
+    let named_lines = |output: bool| -> Vec<&str> {
+        inventory
+            .gpio
+            .iter()
+            .flat_map(|c| &c.lines)
+            .filter(|l| l.output == output && l.consumer.is_none())
+            .filter_map(|l| l.name.as_deref())
+            .collect()
+    };
+
+    let inputs = named_lines(false);
+    if !inputs.is_empty() {
+        toml.push_str("\n[digital_in]\nports = [\n");
+        for name in inputs {
+            let _ = writeln!(
+                toml,
+                "  {{ internal_name = \"{name}\", external_name = \"{name}\" }},"
+            );
+        }
+        toml.push_str("]\n");
+    }
+
+    let outputs = named_lines(true);
+    if !outputs.is_empty() {
+        toml.push_str("\n[digital_out]\nports = [\n");
+        for name in outputs {
+            let _ = writeln!(
+                toml,
+                "  {{ internal_name = \"{name}\", external_name = \"{name}\", default_state = 0 }},"
+            );
+        }
+        toml.push_str("]\n");
+    }
+
+    let analog: Vec<String> = inventory
+        .iio
+        .iter()
+        .flat_map(|d| {
+            d.channels
+                .iter()
+                .filter(|c| c.starts_with("out_"))
+                .map(move |c| format!("{}/{}", d.path, c))
+        })
+        .collect();
+    if !analog.is_empty() {
+        toml.push_str("\n# Set the range and default of each analog out\n");
+        toml.push_str("# [analog_out]\n# ports = [\n");
+        for path in analog {
+            let _ = writeln!(
+                toml,
+                "#   {{ external_name = \"\", path = \"{path}\", min = 0.0, max = 0.0, default = 0.0 }},"
+            );
+        }
+        toml.push_str("# ]\n");
+    }
+
+    if !inventory.can.is_empty() {
+        toml.push_str("\n# Set the DBC file of the CAN ports\n");
+        toml.push_str("# [can]\n# dbc_file = \"\"\n# ports = [\n");
+        for interface in &inventory.can {
+            let _ = writeln!(
+                toml,
+                "#   {{ name = \"{}\", bitrate = 500000, listen_only = true }},",
+                interface.name
+            );
+        }
+        toml.push_str("# ]\n");
+    }
+
+    toml.push_str("\n[time]\nsleep_min_s = 1\nsleep_max_s = 3600\nheartbeat_s = 30\n");
+    toml
+}
+
+// Print the skeleton config and write the inventory to a file
+pub fn discover(inventory_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let inventory = inventory();
+    fs::write(inventory_file, serde_json::to_string_pretty(&inventory)?)?;
+    print!("{}", skeleton(&inventory));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skeleton_is_a_valid_config() {
+        let inventory = Inventory {
+            can: vec![CanInterface {
+                name: "can0".to_string(),
+                state: "up".to_string(),
+            }],
+            gpio: vec![GpioChip {
+                name: "gpiochip0".to_string(),
+                label: "test".to_string(),
+                lines: vec![
+                    GpioLine {
+                        offset: 0,
+                        name: Some("digital-in-0".to_string()),
+                        consumer: None,
+                        output: false,
+                    },
+                    GpioLine {
+                        offset: 1,
+                        name: Some("digital-out-source-0".to_string()),
+                        consumer: None,
+                        output: true,
+                    },
+                ],
+            }],
+            iio: Vec::new(),
+            serial: Vec::new(),
+            modems: Vec::new(),
+        };
+        let skeleton = skeleton(&inventory);
+        assert!(lib::validate_config(&skeleton).is_ok());
+        let config: toml::Value = toml::from_str(&skeleton).unwrap();
+        assert!(skeleton.contains("{ name = \"can0\", bitrate = 500000, listen_only = true }"));
+        assert_eq!(
+            config["digital_out"]["ports"][0]["internal_name"].as_str(),
+            Some("digital-out-source-0")
+        );
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Occupancy statistics of digital inputs, e.g. PTO engaged or door open.
+// The total active time and the number of activations of each input
+// are accumulated per interval and sent as derived values named
+// <input>/active_ms and <input>/activations, so that the server does
+// not have to reconstruct them from edges with gaps.
+
+use super::gpio::queue_value;
+use lazy_static::lazy_static;
+use lib::{history, host_insight::Value, CONFIG};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+#[derive(Default)]
+struct Occupancy {
+    active_since: Option<Instant>,
+    active_time: Duration,
+    activations: u32,
+}
+
+impl Occupancy {
+    fn record(&mut self, active: bool, now: Instant) {
+        match (active, self.active_since) {
+            (true, None) => {
+                self.active_since = Some(now);
+                self.activations += 1;
+            }
+            (false, Some(since)) => {
+                self.active_time += now.duration_since(since);
+                self.active_since = None;
+            }
+            _ => (),
+        }
+    }
+
+    // Take the statistics of the interval ending now. An input that is
+    // still active is counted up to now, and from now in the next one.
+    fn take(&mut self, now: Instant) -> (Duration, u32) {
+        let mut active_time = std::mem::take(&mut self.active_time);
+        if let Some(since) = self.active_since {
+            active_time += now.duration_since(since);
+            self.active_since = Some(now);
+        }
+        (active_time, std::mem::take(&mut self.activations))
+    }
+}
+
+lazy_static! {
+    static ref OCCUPANCY: Mutex<HashMap<String, Occupancy>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG.digital_in.as_ref().is_some_and(|d| d.duty.is_some())
+}
+
+fn is_tracked(name: &str) -> bool {
+    CONFIG
+        .digital_in
+        .as_ref()
+        .and_then(|d| d.duty.as_ref())
+        .is_some_and(|duty| duty.ports.iter().any(|p| p == name))
+}
+
+pub async fn record(name: &str, active: bool) {
+    if !is_tracked(name) {
+        return;
+    }
+    OCCUPANCY
+        .lock()
+        .await
+        .entry(name.to_string())
+        .or_default()
+        .record(active, Instant::now());
+}
+
+pub async fn duty_reporter() -> Result<(), Box<dyn Error>> {
+    let duty = CONFIG.digital_in.as_ref().unwrap().duty.as_ref().unwrap();
+    loop {
+        sleep(Duration::from_secs(duty.interval_s.max(1))).await;
+        let now = Instant::now();
+        let time_stamp_us = Some(history::unix_millis(SystemTime::now()) * 1000);
+
+        let mut values = Vec::new();
+        let mut occupancy = OCCUPANCY.lock().await;
+        for name in &duty.ports {
+            let (active_time, activations) = occupancy.entry(name.clone()).or_default().take(now);
+            values.push(Value {
+                name: format!("{name}/active_ms"),
+                value: active_time.as_millis().min(i32::MAX as u128) as i32,
+                time_stamp_us,
+            });
+            values.push(Value {
+                name: format!("{name}/activations"),
+                value: activations.min(i32::MAX as u32) as i32,
+                time_stamp_us,
+            });
+        }
+        drop(occupancy);
+
+        for value in values {
+            queue_value(value).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_time_and_activations() {
+        let t0 = Instant::now();
+        let s = Duration::from_secs;
+        let mut o = Occupancy::default();
+
+        o.record(true, t0);
+        o.record(true, t0 + s(1)); // Not a new activation
+        o.record(false, t0 + s(2));
+        o.record(false, t0 + s(3));
+        o.record(true, t0 + s(4));
+        assert_eq!(o.take(t0 + s(10)), (s(8), 2));
+
+        // Still active from the previous interval
+        o.record(false, t0 + s(12));
+        assert_eq!(o.take(t0 + s(20)), (s(2), 0));
+        assert_eq!(o.take(t0 + s(30)), (s(0), 0));
+    }
+}
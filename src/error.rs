@@ -0,0 +1,117 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Errors of the CAN, GPIO, network and update code, with the port, file
+// or RPC they concern. The kind of error tells whether restarting the
+// client can help, so that the supervisor does not need to look at the
+// messages.
+
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("CAN port {port}: {source}")]
+    Can {
+        port: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("DBC file {file}: {reason}")]
+    Dbc { file: String, reason: String },
+    #[error("GPIO {name}: {source}")]
+    Gpio {
+        name: String,
+        #[source]
+        source: gpio_cdev::Error,
+    },
+    #[error("GPIO {name}: no such line")]
+    GpioLineNotFound { name: String },
+    #[error("Analog out {name}: {source}")]
+    AnalogOut {
+        name: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unknown output {0}")]
+    UnknownOutput(String),
+    #[error("{rpc}: {status}")]
+    Rpc {
+        rpc: &'static str,
+        #[source]
+        status: Box<tonic::Status>,
+    },
+    #[error("{path}: {source}")]
+    File {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Server {url}: {reason}")]
+    Endpoint { url: String, reason: String },
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+// What to do when a task fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    // Restart the client, e.g. after an I/O error
+    Restart,
+    // Stop the task and report it, since the client would fail the same
+    // way after a restart, e.g. when a configured GPIO line is missing
+    StopTask,
+}
+
+impl ClientError {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            ClientError::Dbc { .. }
+            | ClientError::GpioLineNotFound { .. }
+            | ClientError::UnknownOutput(_)
+            | ClientError::Endpoint { .. }
+            | ClientError::Unsupported(_) => Recovery::StopTask,
+            _ => Recovery::Restart,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_carry_context_and_recovery() {
+        let e = ClientError::Can {
+            port: "can0".to_string(),
+            source: io::Error::new(io::ErrorKind::NotFound, "No such device"),
+        };
+        assert_eq!(e.to_string(), "CAN port can0: No such device");
+        assert_eq!(e.recovery(), Recovery::Restart);
+
+        let e = ClientError::GpioLineNotFound {
+            name: "digital-in-3".to_string(),
+        };
+        assert_eq!(e.to_string(), "GPIO digital-in-3: no such line");
+        assert_eq!(e.recovery(), Recovery::StopTask);
+
+        // The supervisor gets the errors of the tasks boxed
+        let boxed: Box<dyn std::error::Error> = e.into();
+        assert!(boxed.downcast_ref::<ClientError>().is_some());
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Failsafe behavior of outputs when the connection to the server is
+// lost, whether or not a remote control session is running. Once the
+// server has not been reached for after_s, an output holds its state,
+// goes to its default or goes to a specific state, as configured per
+// output. The action is taken once per loss of connection.
+
+use super::analog::set_analog_out;
+use super::gpio::set_digital_out;
+use super::health::since_contact;
+use super::local_override;
+use lib::{Failsafe, FailsafeAction, CONFIG};
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::sleep;
+
+enum Output {
+    Digital,
+    Analog { default: f64 },
+}
+
+fn outputs() -> Vec<(&'static str, &'static Failsafe, Output)> {
+    let digital = CONFIG
+        .digital_out
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+        .filter_map(|p| {
+            Some((
+                p.external_name.as_str(),
+                p.failsafe.as_ref()?,
+                Output::Digital,
+            ))
+        });
+    let analog = CONFIG
+        .analog_out
+        .iter()
+        .flat_map(|a| &a.ports)
+        .filter_map(|p| {
+            Some((
+                p.external_name.as_str(),
+                p.failsafe.as_ref()?,
+                Output::Analog { default: p.default },
+            ))
+        });
+    digital.chain(analog).collect()
+}
+
+pub fn is_enabled() -> bool {
+    !outputs().is_empty()
+}
+
+fn apply(name: &str, failsafe: &Failsafe, output: &Output) -> Result<(), Box<dyn Error>> {
+    let value = failsafe.value.unwrap_or_default();
+    match (failsafe.action, output) {
+        (FailsafeAction::Hold, _) => (),
+        (FailsafeAction::Default, Output::Digital) => set_digital_out(name, false)?,
+        (FailsafeAction::Default, Output::Analog { default }) => {
+            set_analog_out(name, *default)?;
+        }
+        (FailsafeAction::Set, Output::Digital) => set_digital_out(name, value != 0.0)?,
+        (FailsafeAction::Set, Output::Analog { .. }) => {
+            set_analog_out(name, value)?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn failsafe_monitor() -> Result<(), Box<dyn Error>> {
+    let outputs = outputs();
+    let mut applied = HashSet::new();
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        let silence = since_contact().await;
+        for (name, failsafe, output) in &outputs {
+            if silence < Duration::from_secs(failsafe.after_s) {
+                applied.remove(name);
+                continue;
+            }
+            // The local override has precedence
+            if local_override::is_active().await {
+                continue;
+            }
+            if applied.insert(name) {
+                eprintln!(
+                    "No contact with the server for {} s, failsafe {} of {name}",
+                    silence.as_secs(),
+                    match failsafe.action {
+                        FailsafeAction::Hold => "hold",
+                        FailsafeAction::Default => "default",
+                        FailsafeAction::Set => "set",
+                    }
+                );
+                if let Err(e) = apply(name, failsafe, output) {
+                    eprintln!("Failed to apply the failsafe of {name}: {e}");
+                }
+            }
+        }
+    }
+}
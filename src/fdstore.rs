@@ -0,0 +1,483 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Passing of the CAN sockets across restarts through the systemd file
+// descriptor store. Sockets are handed to systemd when opened, and after
+// a restart, e.g. for a client upgrade, the stored sockets are used
+// instead of new ones. Frames received while the client restarts are
+// then buffered in the sockets by the kernel rather than lost. Without
+// NOTIFY_SOCKET, i.e. when not run by systemd, sockets are just opened.
+//
+// A socket is stored together with a hash of the link settings of its
+// port, so that a port whose settings have changed is set up again with
+// a new socket instead of keeping the old configuration.
+//
+// The sockets also report when the kernel received each frame, which is
+// the time stamp of the frame rather than when the client got to it.
+
+use lazy_static::lazy_static;
+use lib::{BridgeConfig, CanPort, CONFIG};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{
+    recvmsg, sendmsg, setsockopt, sockopt::ReceiveTimestamp, ControlMessage, ControlMessageOwned,
+    MsgFlags, UnixAddr,
+};
+use nix::sys::time::TimeVal;
+use socketcan::{CANFilter, CANFrame};
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::io::{self, IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+// Sockets passed by systemd on start with the link settings they were
+// stored with, by name
+pub type ReceivedFds = HashMap<String, (String, socketcan::CANSocket)>;
+
+lazy_static! {
+    static ref STORED: Mutex<ReceivedFds> = Mutex::new(HashMap::new());
+}
+
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_EFF_MASK: u32 = 0x1fff_ffff;
+const CAN_SFF_MASK: u32 = 0x7ff;
+const CANFD_BRS: u8 = 0x01;
+const CANFD_FDF: u8 = 0x04;
+const CAN_MTU: usize = 16;
+const CANFD_MTU: usize = 72;
+
+// A classic or CAN FD frame, laid out as struct canfd_frame, which
+// starts like struct can_frame. The kernel frame is read into the first
+// CANFD_MTU bytes, so the fields before received must stay as they are:
+// integers without padding, for which any bytes are valid.
+#[repr(C)]
+pub struct Frame {
+    can_id: u32,
+    len: u8,
+    flags: u8,
+    res0: u8,
+    res1: u8,
+    data: [u8; 64],
+    // Not part of the kernel frame
+    received: SystemTime,
+}
+
+impl Frame {
+    pub fn id(&self) -> u32 {
+        if self.can_id & CAN_EFF_FLAG != 0 {
+            self.can_id & CAN_EFF_MASK
+        } else {
+            self.can_id & CAN_SFF_MASK
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.can_id & CAN_EFF_FLAG != 0
+    }
+
+    // The data length code, which for FD frames longer than 8 bytes is
+    // not the length
+    pub fn dlc(&self) -> u8 {
+        match self.len {
+            len @ 0..=8 => len,
+            9..=12 => 9,
+            13..=16 => 10,
+            17..=20 => 11,
+            21..=24 => 12,
+            25..=32 => 13,
+            33..=48 => 14,
+            _ => 15,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len.min(64) as usize]
+    }
+
+    // When the kernel received the frame
+    pub fn received(&self) -> SystemTime {
+        self.received
+    }
+
+    // Flags of FD frames, for the trace
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        if self.flags & CANFD_FDF != 0 {
+            flags.push("FD");
+        }
+        if self.flags & CANFD_BRS != 0 {
+            flags.push("BRS");
+        }
+        flags
+    }
+}
+
+// A CAN socket that is read asynchronously
+pub struct CanSocket(AsyncFd<socketcan::CANSocket>);
+
+impl CanSocket {
+    pub async fn read_frame(&self) -> io::Result<CANFrame> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(result) = guard.try_io(|s| s.get_ref().read_frame()) {
+                return result;
+            }
+        }
+    }
+
+    // Read a classic or, after enable_fd, CAN FD frame
+    pub async fn read_fd_frame(&self) -> io::Result<Frame> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(result) = guard.try_io(|s| read_raw_frame(s.get_ref().as_raw_fd())) {
+                return result;
+            }
+        }
+    }
+
+    // Receive CAN FD frames in addition to classic frames
+    pub fn enable_fd(&self) -> io::Result<()> {
+        let enable: libc::c_int = 1;
+        // SAFETY: the option value is a c_int of the given size that lives
+        // for the call
+        let rv = unsafe {
+            libc::setsockopt(
+                self.0.get_ref().as_raw_fd(),
+                libc::SOL_CAN_RAW,
+                libc::CAN_RAW_FD_FRAMES,
+                &enable as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if rv != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_filter(&self, filters: &[CANFilter]) -> io::Result<()> {
+        self.0.get_ref().set_filter(filters)
+    }
+}
+
+// Read one frame from a CAN socket. Classic frames are CAN_MTU bytes
+// and FD frames CANFD_MTU bytes. Without a time stamp from the kernel,
+// e.g. on a socket that did not enable them, the frame is stamped now.
+fn read_raw_frame(fd: RawFd) -> io::Result<Frame> {
+    let mut frame = Frame {
+        can_id: 0,
+        len: 0,
+        flags: 0,
+        res0: 0,
+        res1: 0,
+        data: [0; 64],
+        received: UNIX_EPOCH,
+    };
+    // SAFETY: Frame is repr(C) and its first CANFD_MTU bytes are the
+    // integer fields of struct canfd_frame, so the slice stays within the
+    // frame and any bytes written to it are valid. The frame is not used
+    // while the slice lives.
+    let buf =
+        unsafe { std::slice::from_raw_parts_mut(&mut frame as *mut Frame as *mut u8, CANFD_MTU) };
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(TimeVal);
+    let msg = recvmsg::<()>(fd, &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+    let n = msg.bytes;
+    let received = msg.cmsgs().find_map(|c| match c {
+        ControlMessageOwned::ScmTimestamp(tv) => {
+            Some(UNIX_EPOCH + Duration::new(tv.tv_sec() as u64, tv.tv_usec() as u32 * 1000))
+        }
+        _ => None,
+    });
+    frame.received = received.unwrap_or_else(SystemTime::now);
+    match n {
+        // The flags are padding in classic frames, and older kernels do
+        // not mark FD frames
+        CAN_MTU => {
+            frame.len = frame.len.min(8);
+            frame.flags = 0;
+        }
+        CANFD_MTU => frame.flags |= CANFD_FDF,
+        n => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Incomplete CAN frame of {n} bytes"),
+            ))
+        }
+    }
+    Ok(frame)
+}
+
+// Take the sockets passed by systemd. The environment is changed, which
+// is only safe before other threads are started, so this is called in
+// main before the runtime is built.
+pub fn received_fds() -> ReceivedFds {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse().ok());
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // Neither the variables nor the sockets are meant for child
+    // processes, e.g. curl or the commands of jobs
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    if pid != Some(std::process::id()) {
+        return HashMap::new();
+    }
+
+    names
+        .split(':')
+        .zip(LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|(name, fd)| {
+            if let Err(e) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                eprintln!("Failed to set close-on-exec on the {name} socket: {e}");
+            }
+            let (name, settings) = split_stored_name(name);
+            // SAFETY: the descriptors are passed to this process only and
+            // are owned by nothing else, so the socket closes them
+            (
+                name,
+                (settings, unsafe { socketcan::CANSocket::from_raw_fd(fd) }),
+            )
+        })
+        .collect()
+}
+
+// Use the sockets passed by systemd. Must be called before any socket
+// is opened.
+pub fn init(fds: ReceivedFds) {
+    *STORED.lock().unwrap() = fds;
+}
+
+// Hash of the settings that the link of a port is set up with
+pub fn link_settings(port: &CanPort) -> String {
+    let settings = format!(
+        "{:?}",
+        (
+            port.bitrate,
+            port.listen_only,
+            port.sample_point,
+            port.restart_ms,
+            port.termination,
+            port.fd,
+            port.data_bitrate,
+        )
+    );
+    format!("{:08x}", crc32fast::hash(settings.as_bytes()))
+}
+
+// Link settings of the port of an interface
+fn interface_settings(ifname: &str) -> String {
+    CONFIG
+        .can
+        .as_ref()
+        .and_then(|c| c.ports.as_ref())
+        .and_then(|ports| ports.iter().find(|p| p.name == ifname))
+        .map(link_settings)
+        .unwrap_or_default()
+}
+
+// Whether a socket of the port is stored with its current link settings
+pub fn is_current(port: &CanPort) -> bool {
+    STORED
+        .lock()
+        .unwrap()
+        .get(&port.name)
+        .is_some_and(|(settings, _)| *settings == link_settings(port))
+}
+
+// The settings are appended to the name the socket is stored under
+fn stored_name(name: &str, settings: &str) -> String {
+    format!("{name}.{settings}")
+}
+
+fn split_stored_name(stored: &str) -> (String, String) {
+    match stored.rsplit_once('.') {
+        Some((name, settings)) => (name.to_string(), settings.to_string()),
+        None => (stored.to_string(), String::new()),
+    }
+}
+
+// Name of the socket that a bridge reads from
+pub fn bridge_socket_name(bridge: &BridgeConfig) -> String {
+    format!("bridge-{}-{}", bridge.from, bridge.to)
+}
+
+// Open a CAN socket for reading, or take the one stored under the name
+// if the link settings are unchanged
+pub fn open_can(ifname: &str, name: &str) -> io::Result<CanSocket> {
+    let settings = interface_settings(ifname);
+    let stored = STORED.lock().unwrap().remove(name);
+    let socket = match stored {
+        Some((stored_settings, socket)) if stored_settings == settings => {
+            println!("Using the {name} socket from before the restart");
+            socket
+        }
+        stored => {
+            if let Some((stored_settings, _)) = stored {
+                println!("The settings of {ifname} have changed, replacing the {name} socket");
+                remove_from_store(&stored_name(name, &stored_settings));
+            }
+            let socket = socketcan::CANSocket::open(ifname).map_err(io::Error::other)?;
+            let state = format!("FDSTORE=1\nFDNAME={}", stored_name(name, &settings));
+            if let Err(e) = notify(&state, &[socket.as_raw_fd()]) {
+                eprintln!("Failed to store the {name} socket: {e}");
+            }
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    if let Err(e) = setsockopt(socket.as_raw_fd(), ReceiveTimestamp, &true) {
+        eprintln!("No receive time stamps on {ifname}: {e}");
+    }
+    Ok(CanSocket(AsyncFd::new(socket)?))
+}
+
+// Release stored sockets that are no longer used, e.g. of a port that
+// was removed from the config
+pub fn discard_unused() {
+    let mut used = Vec::new();
+    if let Some(can) = &CONFIG.can {
+        used.extend(can.ports.iter().flatten().map(|p| p.name.clone()));
+        used.extend(can.bridges.iter().flatten().map(bridge_socket_name));
+    }
+
+    STORED.lock().unwrap().retain(|name, (settings, _)| {
+        if used.contains(name) {
+            return true;
+        }
+        remove_from_store(&stored_name(name, settings));
+        false
+    });
+}
+
+fn remove_from_store(stored: &str) {
+    if let Err(e) = notify(&format!("FDSTOREREMOVE=1\nFDNAME={stored}"), &[]) {
+        eprintln!("Failed to remove the {stored} socket from the store: {e}");
+    }
+}
+
+// Send a message to the service manager
+fn notify(state: &str, fds: &[RawFd]) -> Result<(), Box<dyn Error>> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => UnixAddr::new_abstract(name.as_bytes())?,
+        None => UnixAddr::new(path.as_str())?,
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    let cmsgs = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(fds)]
+    };
+    sendmsg(
+        socket.as_raw_fd(),
+        &[IoSlice::new(state.as_bytes())],
+        &cmsgs,
+        MsgFlags::empty(),
+        Some(&addr),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+    use nix::unistd::write;
+
+    #[test]
+    fn stored_names_keep_the_settings() {
+        let stored = stored_name("can0.1", "0badc0de");
+        assert_eq!(
+            split_stored_name(&stored),
+            ("can0.1".to_string(), "0badc0de".to_string())
+        );
+        // Without settings, a stored socket never matches a port
+        assert_eq!(
+            split_stored_name("can0"),
+            ("can0".to_string(), String::new())
+        );
+    }
+
+    #[test]
+    fn kernel_frame_ends_before_the_time_stamp() {
+        let frame = Frame {
+            can_id: 0,
+            len: 0,
+            flags: 0,
+            res0: 0,
+            res1: 0,
+            data: [0; 64],
+            received: UNIX_EPOCH,
+        };
+        let start = &frame as *const Frame as usize;
+        let received = &frame.received as *const SystemTime as usize;
+        assert_eq!(received - start, CANFD_MTU);
+    }
+
+    #[test]
+    fn frames_are_time_stamped() {
+        let (tx, rx) = socketpair(
+            AddressFamily::Unix,
+            SockType::Datagram,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        setsockopt(rx.as_raw_fd(), ReceiveTimestamp, &true).unwrap();
+
+        // A classic frame with garbage in the padding, then an FD frame
+        let mut classic = [0u8; CAN_MTU];
+        classic[..4].copy_from_slice(&(0x123u32).to_ne_bytes());
+        classic[4] = 2;
+        classic[5] = 0xff;
+        classic[8..10].copy_from_slice(&[0xab, 0xcd]);
+        write(tx.as_raw_fd(), &classic).unwrap();
+        let mut fd = [0u8; CANFD_MTU];
+        fd[..4].copy_from_slice(&(0x18fef100 | CAN_EFF_FLAG).to_ne_bytes());
+        fd[4] = 12;
+        fd[5] = CANFD_BRS;
+        write(tx.as_raw_fd(), &fd).unwrap();
+
+        let frame = read_raw_frame(rx.as_raw_fd()).unwrap();
+        assert_eq!(frame.id(), 0x123);
+        assert_eq!(frame.data(), [0xab, 0xcd]);
+        assert!(frame.flags().is_empty());
+        let age = SystemTime::now().duration_since(frame.received()).unwrap();
+        assert!(age < Duration::from_secs(5));
+
+        let frame = read_raw_frame(rx.as_raw_fd()).unwrap();
+        assert_eq!(frame.id(), 0x18fef100);
+        assert_eq!(frame.data().len(), 12);
+        assert_eq!(frame.dlc(), 9);
+        assert_eq!(frame.flags(), ["FD", "BRS"]);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Geographically adaptive reporting. The position and speed of the
+// vehicle, e.g. from a GNSS receiver on the CAN bus, are read from the
+// value cache, and the rate policy of the situation is applied in the
+// backpressure layer: inside a zone the policy of the zone, e.g. full
+// rate at a depot, and otherwise the policy for moving or parked, e.g.
+// only rare updates while parked.
+
+use super::backpressure;
+use lib::{cache, GeoConfig, RatePolicy, CONFIG};
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MOVING_KMH: f64 = 5.0;
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Debug, PartialEq)]
+enum Situation {
+    // Neither position nor speed is known yet
+    Unknown,
+    Parked,
+    Moving,
+    // Inside the zone with the index
+    Zone(usize),
+}
+
+// Great-circle distance between two positions in degrees
+fn distance_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.1 - a.1).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+fn situation(config: &GeoConfig, position: Option<(f64, f64)>, speed: Option<f64>) -> Situation {
+    let zones = config.zones.as_deref().unwrap_or_default();
+    if let Some(position) = position {
+        let inside = zones
+            .iter()
+            .position(|z| distance_m(position, (z.latitude, z.longitude)) <= z.radius_m);
+        if let Some(zone) = inside {
+            return Situation::Zone(zone);
+        }
+    }
+    match speed {
+        Some(kmh) if kmh >= config.moving_kmh.unwrap_or(DEFAULT_MOVING_KMH) => Situation::Moving,
+        Some(_) => Situation::Parked,
+        None => Situation::Unknown,
+    }
+}
+
+fn policy(config: &GeoConfig, situation: &Situation) -> Option<RatePolicy> {
+    match situation {
+        Situation::Unknown => None,
+        Situation::Parked => config.parked.clone(),
+        Situation::Moving => config.moving.clone(),
+        Situation::Zone(zone) => config.zones.as_ref()?[*zone].policy.clone(),
+    }
+}
+
+async fn numeric(name: &str) -> Option<f64> {
+    cache::get_any(name)
+        .await
+        .and_then(|cached| cache::numeric(&cached.value))
+}
+
+pub async fn geo_monitor() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.geo.as_ref().unwrap();
+    let mut current = Situation::Unknown;
+    loop {
+        let position = match (
+            numeric(&config.latitude).await,
+            numeric(&config.longitude).await,
+        ) {
+            (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+            _ => None,
+        };
+        let speed = numeric(&config.speed).await;
+
+        let next = situation(config, position, speed);
+        if next != current {
+            match &next {
+                Situation::Zone(zone) => {
+                    println!(
+                        "Entered zone {}",
+                        config.zones.as_ref().unwrap()[*zone].name
+                    )
+                }
+                Situation::Moving => println!("Vehicle is moving"),
+                Situation::Parked => println!("Vehicle is parked"),
+                Situation::Unknown => {}
+            }
+            backpressure::set_geo_policy(policy(config, &next)).await;
+            current = next;
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib::ZoneConfig;
+
+    #[test]
+    fn distances() {
+        // Gothenburg to Stockholm is about 398 km
+        let d = distance_m((57.7089, 11.9746), (59.3293, 18.0686));
+        assert!((d - 398_000.0).abs() < 5_000.0, "{d}");
+        assert_eq!(distance_m((57.7, 11.9), (57.7, 11.9)), 0.0);
+    }
+
+    #[test]
+    fn zones_before_speed() {
+        let config = GeoConfig {
+            latitude: "Latitude".to_string(),
+            longitude: "Longitude".to_string(),
+            speed: "Speed".to_string(),
+            moving_kmh: None,
+            moving: None,
+            parked: Some(RatePolicy {
+                min_interval_ms: Some(60000),
+                ..Default::default()
+            }),
+            zones: Some(vec![ZoneConfig {
+                name: "Depot".to_string(),
+                latitude: 57.7089,
+                longitude: 11.9746,
+                radius_m: 500.0,
+                policy: None,
+            }]),
+        };
+        let depot = Some((57.7100, 11.9750));
+        let away = Some((57.8, 12.0));
+        assert_eq!(situation(&config, depot, Some(80.0)), Situation::Zone(0));
+        assert_eq!(situation(&config, away, Some(80.0)), Situation::Moving);
+        assert_eq!(situation(&config, away, Some(2.0)), Situation::Parked);
+        assert_eq!(situation(&config, None, Some(2.0)), Situation::Parked);
+        assert_eq!(situation(&config, away, None), Situation::Unknown);
+        assert_eq!(policy(&config, &Situation::Parked), config.parked);
+        assert_eq!(policy(&config, &Situation::Zone(0)), None);
+    }
+}
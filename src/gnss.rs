@@ -0,0 +1,327 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Positions from a GNSS receiver through gpsd, sent like CAN signals on
+// their own bus together with the quality of the fix, so that bad
+// positions can be filtered on the server. The positions are also
+// cached, e.g. for the adaptive reporting. The receiver settings, such
+// as the constellations, are applied at startup with ubxtool. Nothing is
+// reported while the server has paused the GNSS subsystem.
+
+use super::can::queue_can_message;
+use super::subsystem::is_enabled;
+#[cfg(test)]
+use lib::GNSS_SIGNALS;
+use lib::{
+    cache, history,
+    host_insight::{can_signal, CanMessage, CanSignal, Subsystem},
+    Constellation, GnssConfig, CONFIG, DEFAULT_GNSS_BUS,
+};
+use serde_json::Value;
+use std::error::Error;
+use std::io;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+const DEFAULT_GPSD: &str = "127.0.0.1:2947";
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+// ubxtool waits for the receiver to acknowledge, which a missing or
+// misconfigured receiver never does
+const UBXTOOL_TIMEOUT: Duration = Duration::from_secs(10);
+const WATCH: &[u8] = b"?WATCH={\"enable\":true,\"json\":true};\n";
+
+// The constellations by their ubxtool names
+const CONSTELLATIONS: [(Constellation, &str); 6] = [
+    (Constellation::Gps, "GPS"),
+    (Constellation::Galileo, "GALILEO"),
+    (Constellation::Glonass, "GLONASS"),
+    (Constellation::Beidou, "BEIDOU"),
+    (Constellation::Qzss, "QZSS"),
+    (Constellation::Sbas, "SBAS"),
+];
+
+// The latest fix, from the TPV and SKY reports of gpsd
+#[derive(Default, Debug, PartialEq)]
+struct Fix {
+    // 0 unknown, 1 no fix, 2 2D and 3 3D
+    mode: u64,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+    speed_kmh: Option<f64>,
+    heading: Option<f64>,
+    hdop: Option<f64>,
+    satellites_used: Option<u64>,
+}
+
+// Update the fix with a report. Returns whether it was a position report.
+fn update(fix: &mut Fix, report: &Value) -> bool {
+    let number = |key: &str| report.get(key).and_then(Value::as_f64);
+    match report.get("class").and_then(Value::as_str) {
+        Some("TPV") => {
+            fix.mode = report.get("mode").and_then(Value::as_u64).unwrap_or(0);
+            fix.latitude = number("lat");
+            fix.longitude = number("lon");
+            fix.altitude = number("altMSL").or_else(|| number("alt"));
+            fix.speed_kmh = number("speed").map(|mps| mps * 3.6);
+            fix.heading = number("track");
+            true
+        }
+        Some("SKY") => {
+            if let Some(hdop) = number("hdop") {
+                fix.hdop = Some(hdop);
+            }
+            // Older versions of gpsd only list the satellites
+            let used = report.get("uSat").and_then(Value::as_u64).or_else(|| {
+                let satellites = report.get("satellites")?.as_array()?;
+                let used = satellites
+                    .iter()
+                    .filter(|s| s.get("used").and_then(Value::as_bool) == Some(true));
+                Some(used.count() as u64)
+            });
+            if used.is_some() {
+                fix.satellites_used = used;
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+// The signals of a fix. Without a fix only the quality is sent.
+fn signals(fix: &Fix) -> Vec<(&'static str, &'static str, can_signal::Value)> {
+    let mut signals = vec![("FixType", "", can_signal::Value::ValU64(fix.mode))];
+    if let Some(used) = fix.satellites_used {
+        signals.push(("SatellitesUsed", "", can_signal::Value::ValU64(used)));
+    }
+    if let Some(hdop) = fix.hdop {
+        signals.push(("Hdop", "", can_signal::Value::ValF64(hdop)));
+    }
+    if fix.mode < 2 {
+        return signals;
+    }
+    let position = [
+        ("Latitude", "deg", fix.latitude),
+        ("Longitude", "deg", fix.longitude),
+        ("Altitude", "m", fix.altitude.filter(|_| fix.mode >= 3)),
+        ("Speed", "km/h", fix.speed_kmh),
+        ("Heading", "deg", fix.heading),
+    ];
+    for (name, unit, value) in position {
+        if let Some(value) = value {
+            signals.push((name, unit, can_signal::Value::ValF64(value)));
+        }
+    }
+    signals
+}
+
+// The ubxtool arguments that apply the receiver settings
+fn ubxtool_commands(config: &GnssConfig) -> Vec<Vec<String>> {
+    let mut commands = Vec::new();
+    if let Some(enabled) = &config.constellations {
+        for (constellation, name) in CONSTELLATIONS {
+            let flag = if enabled.contains(&constellation) {
+                "-e"
+            } else {
+                "-d"
+            };
+            commands.push(vec![flag.to_string(), name.to_string()]);
+        }
+    }
+    // These use the configuration interface of u-blox 9 and later
+    if let Some(hz) = config.update_rate_hz {
+        let item = format!("CFG-RATE-MEAS,{}", 1000 / hz);
+        commands.push(vec!["-P".into(), "27".into(), "-z".into(), item]);
+    }
+    if let Some(deg) = config.elevation_mask_deg {
+        let item = format!("CFG-NAVSPG-INFIL_MINELEV,{deg}");
+        commands.push(vec!["-P".into(), "27".into(), "-z".into(), item]);
+    }
+    commands
+}
+
+// Run a command and kill it if it has not exited within the timeout, in
+// which case None is returned
+fn run_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut child = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn configure(config: &GnssConfig) {
+    for args in ubxtool_commands(config) {
+        let mut command = Command::new("ubxtool");
+        match (&config.device, &config.gpsd) {
+            (Some(device), _) => command.arg("-f").arg(device),
+            (None, Some(gpsd)) => command.arg(gpsd),
+            (None, None) => &mut command,
+        };
+        match run_with_timeout(command.args(&args), UBXTOOL_TIMEOUT) {
+            Ok(Some(status)) if status.success() => {}
+            Ok(Some(status)) => eprintln!("ubxtool {} failed: {}", args.join(" "), status),
+            Ok(None) => eprintln!("ubxtool {} timed out", args.join(" ")),
+            Err(e) => eprintln!("Failed to run ubxtool: {e}"),
+        }
+    }
+}
+
+async fn read_gpsd(config: &GnssConfig) -> Result<(), Box<dyn Error>> {
+    let address = config.gpsd.as_deref().unwrap_or(DEFAULT_GPSD);
+    let bus = config.bus.as_deref().unwrap_or(DEFAULT_GNSS_BUS);
+    let interval = Duration::from_millis(config.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS));
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(WATCH).await?;
+    println!("Reading positions from gpsd at {address}");
+    let mut lines = BufReader::new(stream).lines();
+
+    let mut fix = Fix::default();
+    let mut last_sent: Option<Instant> = None;
+    while let Some(line) = lines.next_line().await? {
+        let report = match serde_json::from_str::<Value>(&line) {
+            Ok(report) => report,
+            Err(_) => continue,
+        };
+        if !update(&mut fix, &report) || last_sent.is_some_and(|t| t.elapsed() < interval) {
+            continue;
+        }
+        if !is_enabled(Subsystem::Gnss).await {
+            continue;
+        }
+        last_sent = Some(Instant::now());
+
+        let mut can_signals = Vec::new();
+        for (name, unit, value) in signals(&fix) {
+            cache::update(bus, name, unit, value.clone()).await;
+            can_signals.push(CanSignal {
+                signal_name: name.to_string(),
+                unit: unit.to_string(),
+                value: Some(value),
+                raw: None,
+                refresh: false,
+            });
+        }
+        let message = CanMessage {
+            bus: bus.to_string(),
+            time_stamp: Some(history::unix_millis(SystemTime::now())),
+            signal: can_signals,
+            composite: String::new(),
+        };
+        queue_can_message(message).await;
+    }
+    Err("gpsd closed the connection".into())
+}
+
+pub async fn gnss_reporter() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.gnss.as_ref().unwrap();
+    // The commands block, so they are kept off the runtime
+    tokio::task::spawn_blocking(move || configure(config)).await?;
+    loop {
+        if let Err(e) = read_gpsd(config).await {
+            eprintln!("GNSS: {e}");
+        }
+        sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let mut fix = Fix::default();
+        let sky = r#"{"class":"SKY","hdop":0.9,"satellites":[{"PRN":1,"used":true},{"PRN":2,"used":false}]}"#;
+        assert!(!update(&mut fix, &serde_json::from_str(sky).unwrap()));
+        assert_eq!(fix.satellites_used, Some(1));
+        // A SKY report without DOPs keeps the last ones
+        let sky = r#"{"class":"SKY","uSat":7}"#;
+        update(&mut fix, &serde_json::from_str(sky).unwrap());
+        assert_eq!((fix.hdop, fix.satellites_used), (Some(0.9), Some(7)));
+
+        let tpv = r#"{"class":"TPV","mode":2,"lat":57.7089,"lon":11.9746,"alt":12.0,"speed":10.0}"#;
+        assert!(update(&mut fix, &serde_json::from_str(tpv).unwrap()));
+        let names: Vec<_> = signals(&fix).iter().map(|s| s.0).collect();
+        // No altitude with a 2D fix
+        assert_eq!(
+            names,
+            [
+                "FixType",
+                "SatellitesUsed",
+                "Hdop",
+                "Latitude",
+                "Longitude",
+                "Speed"
+            ]
+        );
+
+        let tpv = r#"{"class":"TPV","mode":1}"#;
+        update(&mut fix, &serde_json::from_str(tpv).unwrap());
+        let names: Vec<_> = signals(&fix).iter().map(|s| s.0).collect();
+        assert_eq!(names, ["FixType", "SatellitesUsed", "Hdop"]);
+
+        // A 3D fix has all signals, which the config check knows
+        let fix = Fix {
+            mode: 3,
+            latitude: Some(57.7),
+            longitude: Some(11.9),
+            altitude: Some(12.0),
+            speed_kmh: Some(36.0),
+            heading: Some(90.0),
+            hdop: Some(0.9),
+            satellites_used: Some(7),
+        };
+        let names: Vec<_> = signals(&fix).iter().map(|s| s.0).collect();
+        assert_eq!(names, GNSS_SIGNALS);
+    }
+
+    #[test]
+    fn receiver_settings() {
+        let config = GnssConfig {
+            gpsd: None,
+            bus: None,
+            interval_ms: None,
+            device: None,
+            constellations: Some(vec![Constellation::Gps, Constellation::Galileo]),
+            update_rate_hz: Some(5),
+            elevation_mask_deg: None,
+        };
+        let commands = ubxtool_commands(&config);
+        assert_eq!(commands.len(), 7);
+        assert_eq!(commands[0], ["-e", "GPS"]);
+        assert_eq!(commands[2], ["-d", "GLONASS"]);
+        assert_eq!(commands[6], ["-P", "27", "-z", "CFG-RATE-MEAS,200"]);
+    }
+}
@@ -16,143 +16,454 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
-use super::net::{handle_send_result, intercept};
-use async_lock::Barrier;
-use async_std::sync::Mutex;
+use super::analog::{is_analog_out, set_analog_out};
+use super::attention;
+use super::duty;
+use super::health::record_contact;
+use super::journal::{self, Output};
+use super::local_override;
+use super::log_level::debug;
+use super::net::{handle_send_result, intercept, set_status};
+use super::routing;
+use super::scrub;
+use super::spool;
+use super::subsystem::is_enabled;
+use super::tap;
+use super::transport;
+use super::utils::stream_batch;
 use futures::stream::StreamExt;
 use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, EventType, LineRequestFlags};
 use lazy_static::lazy_static;
 use lib::{
+    cache,
+    error::ClientError,
     host_insight::{
-        agent_client::AgentClient, remote_control_client::RemoteControlClient, ControlStatus,
-        GpioState, UnitControlStatus, Value, Values,
+        agent_client::AgentClient, can_signal, remote_control_client::RemoteControlClient,
+        ControlCommand, ControlScope, ControlStatus, GpioState, Subsystem, UnitControlStatus,
+        Value, Values,
     },
-    DigitalInPort, DigitalOutPort, CONFIG,
+    DigitalInPort, DigitalOutPort, StartupStep, StatusCodes, CONFIG,
 };
-use std::collections::HashMap;
+use nix::time::{clock_gettime, ClockId};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
 use tonic::transport::Channel;
 use tonic::Request;
 
+const MAX_VALUES_TO_SEND: usize = 100;
+const MAX_VALUES_PER_MSG: usize = 10;
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 lazy_static! {
     static ref DIGITAL_OUT_MAP: Option<HashMap<String, DigitalOutPort>> = create_digital_out_map();
-    pub static ref REMOTE_CONTROL_BARRIER: Arc<Barrier> = Arc::new(Barrier::new(2));
-    pub static ref REMOTE_CONTROL_IN_PROCESS: Mutex<bool> = Mutex::new(false);
+    static ref VALUE_QUEUE: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+    // The last level of each digital in that a monitor holds
+    static ref DIGITAL_IN_LEVELS: std::sync::Mutex<HashMap<String, u8>> =
+        std::sync::Mutex::new(HashMap::new());
 }
 
-// Get some HashMap of <external name, value> or None
-pub async fn read_all_digital_in() -> Option<HashMap<String, u8>> {
+// Get a HashMap of <external name, value> of the digital ins. The lines
+// that digital_in_monitor holds cannot be requested again, so their
+// levels are taken from the monitor. The others are read directly.
+pub async fn read_all_digital_in() -> Result<HashMap<String, u8>, ClientError> {
     let mut external_name_values = HashMap::new();
+    // Held while reading, so that a monitor does not request a line that
+    // is being read
+    let levels = DIGITAL_IN_LEVELS.lock().unwrap();
 
-    for (i, p) in CONFIG.digital_in.as_ref()?.clone().ports.iter().enumerate() {
-        if let Some((chip_name, line)) = get_digital_chip_and_line(&p[i].internal_name) {
-            if let Ok(mut chip) = Chip::new(chip_name) {
-                let handle = chip
-                    .get_line(line)
-                    .unwrap()
-                    .request(LineRequestFlags::INPUT, 0, "read-input")
-                    .unwrap();
-                external_name_values
-                    .insert(p[i].external_name.clone(), handle.get_value().unwrap());
-            }
+    for p in CONFIG
+        .digital_in
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+    {
+        let value = match levels.get(&p.external_name) {
+            Some(value) => *value,
+            None => read_digital_in(p)?,
+        };
+        external_name_values.insert(p.external_name.clone(), value);
+    }
+    Ok(external_name_values)
+}
+
+fn read_digital_in(port: &DigitalInPort) -> Result<u8, ClientError> {
+    let name = &port.internal_name;
+    let (chip_name, line) = get_digital_chip_and_line(name)
+        .ok_or_else(|| ClientError::GpioLineNotFound { name: name.clone() })?;
+    Chip::new(chip_name)
+        .and_then(|mut chip| chip.get_line(line))
+        .and_then(|l| l.request(LineRequestFlags::INPUT, 0, "read-input"))
+        .and_then(|h| h.get_value())
+        .map_err(|source| ClientError::Gpio {
+            name: name.clone(),
+            source,
+        })
+}
+
+#[derive(Clone, Copy)]
+enum ControlSessionEnd {
+    Closed,
+    // The connection broke. progress is true if any command was received.
+    Broken { progress: bool },
+}
+
+// Run a remote control session of the outputs until the server closes
+// it. A broken connection is reopened with backoff, and the current state
+// of the outputs is sent when reconnecting so that the server can resync.
+// The session is given up once the backoff reaches the max sleep time.
+// The outputs are set to their defaults when the session ends, except
+// that outputs with a failsafe behavior are left to it when the session
+// was given up.
+pub async fn run_output_session(channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let result = control_outputs(channel).await;
+    let connection_lost = matches!(result, Ok(ControlSessionEnd::Broken { .. }));
+
+    for p in CONFIG
+        .digital_out
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+    {
+        if !(connection_lost && p.failsafe.is_some()) {
+            set_digital_out(&p.external_name, false)?;
+        }
+    }
+    for p in CONFIG.analog_out.iter().flat_map(|a| &a.ports) {
+        if !(connection_lost && p.failsafe.is_some()) {
+            set_analog_out(&p.external_name, p.default)?;
         }
     }
+    journal::end_session().await;
+    result.map(|_| ())
+}
 
-    if external_name_values.is_empty() {
-        None
-    } else {
-        Some(external_name_values)
+async fn control_outputs(
+    channel: Channel,
+) -> Result<ControlSessionEnd, Box<dyn Error + Send + Sync>> {
+    // The state of each output that was set, as reported to the server
+    let mut outputs: HashMap<String, ControlCommand> = HashMap::new();
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        let code = match local_override::is_active().await {
+            true => UnitControlStatus::UnitBusy,
+            false => UnitControlStatus::UnitReady,
+        };
+        let status = ControlStatus {
+            code: code as i32,
+            scope: ControlScope::Outputs as i32,
+            outputs: outputs.values().cloned().collect(),
+        };
+
+        let end = if transport::use_streams().await {
+            run_control_stream(channel.clone(), status, &mut outputs).await?
+        } else {
+            run_control_polling(channel.clone(), status, &mut outputs).await?
+        };
+
+        match end {
+            ControlSessionEnd::Closed => return Ok(end),
+            ControlSessionEnd::Broken { progress } => {
+                if progress {
+                    retry_sleep_s = CONFIG.time.sleep_min_s;
+                }
+                if retry_sleep_s > CONFIG.time.sleep_max_s {
+                    eprintln!("Giving up remote control session");
+                    return Ok(end);
+                }
+                eprintln!("Reconnecting remote control session in {retry_sleep_s} s");
+                sleep(Duration::from_secs(retry_sleep_s)).await;
+                retry_sleep_s *= 2;
+            }
+        }
     }
 }
 
-pub async fn remote_control_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+async fn run_control_stream(
+    channel: Channel,
+    status: ControlStatus,
+    outputs: &mut HashMap<String, ControlCommand>,
+) -> Result<ControlSessionEnd, Box<dyn Error + Send + Sync>> {
     let mut client = RemoteControlClient::with_interceptor(channel, intercept);
-    let status = ControlStatus {
-        code: UnitControlStatus::UnitReady as i32,
+    tap::record("ControlStream", &status).await;
+    let mut stream = match client.control_stream(status).await {
+        Ok(response) => response.into_inner(),
+        Err(e) => {
+            eprintln!("Failed to open remote control stream: {e}");
+            transport::stream_failed(&e).await;
+            return Ok(ControlSessionEnd::Broken { progress: false });
+        }
     };
-    loop {
-        REMOTE_CONTROL_BARRIER.wait().await;
-        let mut allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-        *allow_remote_control = true;
-        drop(allow_remote_control);
-        let mut stream = client
-            .control_stream(status.clone())
-            .await
-            .unwrap()
-            .into_inner();
-        while let Some(item) = stream.next().await {
-            match item.as_ref() {
-                Err(e) => {
-                    eprintln!("Error: Item from remote control stream did not contain a command.");
-                    eprintln!("{e}");
-                    set_all_digital_out_to_defaults()?;
-                    let mut allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-                    *allow_remote_control = false;
-                    drop(allow_remote_control);
-                    break;
+
+    let mut progress = false;
+    while let Some(item) = stream.next().await {
+        match item {
+            Err(e) => {
+                eprintln!("Error: Item from remote control stream did not contain a command.");
+                eprintln!("{e}");
+                transport::stream_failed(&e).await;
+                return Ok(ControlSessionEnd::Broken { progress });
+            }
+            Ok(item) => {
+                progress = true;
+                record_contact().await;
+                if apply_control_command(&item, outputs).await? {
+                    transport::stream_succeeded().await;
+                    return Ok(ControlSessionEnd::Closed);
                 }
-                Ok(item) => {
-                    if item.cmd == "Close" {
-                        set_all_digital_out_to_defaults()?;
-                        let mut allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-                        *allow_remote_control = false;
-                        drop(allow_remote_control);
-                        break;
-                    } else if !DIGITAL_OUT_MAP.as_ref().unwrap().contains_key(&item.cmd) {
-                        eprintln!("Invalid command: {}.", &item.cmd);
-                    } else {
-                        set_digital_out(&item.cmd, item.state)?;
+            }
+        };
+    }
+    Ok(ControlSessionEnd::Broken { progress })
+}
+
+// Poll for commands when streams do not work
+async fn run_control_polling(
+    channel: Channel,
+    status: ControlStatus,
+    outputs: &mut HashMap<String, ControlCommand>,
+) -> Result<ControlSessionEnd, Box<dyn Error + Send + Sync>> {
+    let mut client = RemoteControlClient::with_interceptor(channel, intercept);
+    let mut progress = false;
+    loop {
+        tap::record("PollControl", &status).await;
+        match client.poll_control(status.clone()).await {
+            Ok(response) => {
+                record_contact().await;
+                for item in response.into_inner().commands {
+                    progress = true;
+                    if apply_control_command(&item, outputs).await? {
+                        return Ok(ControlSessionEnd::Closed);
                     }
                 }
-            };
+            }
+            Err(e) => {
+                eprintln!("Failed to poll for remote control commands: {e}");
+                return Ok(ControlSessionEnd::Broken { progress });
+            }
         }
+        sleep(CONTROL_POLL_INTERVAL).await;
     }
 }
 
-pub async fn digital_in_monitor(
-    port: &DigitalInPort,
-    channel: Channel,
-) -> Result<(), Box<dyn Error>> {
-    if let Some((chip_name, line_number)) = get_digital_chip_and_line(&port.internal_name) {
-        let mut chip = Chip::new(chip_name)?;
-        let line = chip.get_line(line_number)?;
-
-        let mut events = AsyncLineEventHandle::new(line.events(
-            LineRequestFlags::INPUT,
-            EventRequestFlags::BOTH_EDGES,
-            "gpioevents",
-        )?)?;
-
-        while let Some(event) = events.next().await {
-            send_value(
-                channel.clone(),
-                &port.external_name,
-                (event?.event_type() == EventType::RisingEdge) as u8,
-            )
-            .await
+// Returns true when the command ends the remote control session
+async fn apply_control_command(
+    item: &ControlCommand,
+    outputs: &mut HashMap<String, ControlCommand>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    debug!(
+        "Control command {} state {} setpoint {:?}",
+        item.cmd, item.state, item.setpoint
+    );
+    if item.cmd == "Close" {
+        return Ok(true);
+    } else if local_override::is_active().await {
+        eprintln!("Ignoring {} while the local override is active.", &item.cmd);
+    } else if let Some(setpoint) = item.setpoint {
+        if !is_analog_out(&item.cmd) {
+            eprintln!("Invalid setpoint command: {}.", &item.cmd);
+            return Ok(false);
+        }
+        if journal::executed(&item.command_id).await {
+            eprintln!("Ignoring already executed command {}", item.command_id);
+            return Ok(false);
         }
-        Ok(())
+        let seq = journal::begin(&item.command_id, &item.cmd, Output::Analog(setpoint)).await;
+        let setpoint = match set_analog_out(&item.cmd, setpoint) {
+            Ok(setpoint) => setpoint,
+            Err(e) => {
+                journal::failed(seq).await;
+                return Err(e.into());
+            }
+        };
+        journal::applied(seq, &item.command_id, Output::Analog(setpoint)).await;
+        outputs.insert(
+            item.cmd.clone(),
+            ControlCommand {
+                cmd: item.cmd.clone(),
+                state: GpioState::Active as i32,
+                command_id: String::new(),
+                setpoint: Some(setpoint),
+            },
+        );
+    } else if !is_digital_out(&item.cmd) {
+        eprintln!("Invalid command: {}.", &item.cmd);
     } else {
-        Err("Could not find chip name or line number from {&port.internal}".into())
+        let active = item.state == GpioState::Active as i32;
+        if journal::executed(&item.command_id).await {
+            eprintln!("Ignoring already executed command {}", item.command_id);
+            return Ok(false);
+        }
+        let seq = journal::begin(&item.command_id, &item.cmd, Output::Digital(active)).await;
+        if let Err(e) = set_digital_out(&item.cmd, active) {
+            journal::failed(seq).await;
+            return Err(e.into());
+        }
+        journal::applied(seq, &item.command_id, Output::Digital(active)).await;
+        outputs.insert(
+            item.cmd.clone(),
+            ControlCommand {
+                cmd: item.cmd.clone(),
+                state: if active {
+                    GpioState::Active as i32
+                } else {
+                    GpioState::Inactive as i32
+                },
+                command_id: String::new(),
+                setpoint: None,
+            },
+        );
     }
+    Ok(false)
 }
 
-pub fn set_all_digital_out_to_defaults() -> Result<(), gpio_cdev::Error> {
-    for (i, p) in CONFIG.digital_out.clone().unwrap().ports.iter().enumerate() {
-        if let Some((chip_name, line)) = get_digital_chip_and_line(&p[i].internal_name) {
-            if let Ok(mut chip) = Chip::new(chip_name) {
-                let handle = chip
-                    .get_line(line)
-                    .unwrap()
-                    .request(
-                        LineRequestFlags::OUTPUT,
-                        0,
-                        "set_all_digital_out_to_defaults",
-                    )
-                    .unwrap();
+pub async fn digital_in_monitor(port: &DigitalInPort) -> Result<(), Box<dyn Error>> {
+    let name = &port.internal_name;
+    let (chip_name, line_number) = get_digital_chip_and_line(name)
+        .ok_or_else(|| ClientError::GpioLineNotFound { name: name.clone() })?;
+    let gpio_error = |source| ClientError::Gpio {
+        name: name.clone(),
+        source,
+    };
+    let mut chip = Chip::new(chip_name).map_err(gpio_error)?;
+    let line = chip.get_line(line_number).map_err(gpio_error)?;
+
+    let mut events = {
+        let mut levels = DIGITAL_IN_LEVELS.lock().unwrap();
+        let handle = line
+            .events(
+                LineRequestFlags::INPUT,
+                EventRequestFlags::BOTH_EDGES,
+                "gpioevents",
+            )
+            .map_err(gpio_error)?;
+        levels.insert(
+            port.external_name.clone(),
+            handle.get_value().map_err(gpio_error)?,
+        );
+        AsyncLineEventHandle::new(handle).map_err(gpio_error)?
+    };
+
+    let result = monitor_events(&port.external_name, &mut events).await;
+    let mut levels = DIGITAL_IN_LEVELS.lock().unwrap();
+    drop(events);
+    levels.remove(&port.external_name);
+    Ok(result.map_err(gpio_error)?)
+}
+
+async fn monitor_events(
+    name: &str,
+    events: &mut AsyncLineEventHandle,
+) -> Result<(), gpio_cdev::Error> {
+    while let Some(event) = events.next().await {
+        let event = event?;
+        let rising = event.event_type() == EventType::RisingEdge;
+        DIGITAL_IN_LEVELS
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), rising as u8);
+        report_edge(name, rising, event_time_us(event.timestamp())).await
+    }
+    Ok(())
+}
+
+// The attention check counts the edges themselves, so that a check is
+// acknowledged while the digital inputs are paused, but not by the
+// current level of an input that is reported on resume
+async fn report_edge(name: &str, rising: bool, time_stamp_us: i64) {
+    if rising {
+        attention::rising_edge(name);
+    }
+    if !is_enabled(Subsystem::DigitalIn).await {
+        return;
+    }
+    send_value(name, rising as u8, Some(time_stamp_us)).await
+}
+
+// Run the startup sequence of the digital outs, if any, once they are
+// at their defaults. It runs as a task of its own, so that its delays do
+// not hold up the client. A failing sequence is reported in the
+// heartbeat status but does not stop the client.
+pub async fn run_startup_sequence() {
+    let sequence = match CONFIG
+        .digital_out
+        .as_ref()
+        .and_then(|d| d.startup_sequence.as_deref())
+    {
+        Some(sequence) => sequence,
+        None => return,
+    };
+    if local_override::read_input().await {
+        println!("Local override active, skipping the startup sequence");
+        return;
+    }
+    let set = |step: &StartupStep| {
+        if !matches!(DIGITAL_OUT_MAP.as_ref(), Some(map) if map.contains_key(&step.port)) {
+            return Err(format!("unknown digital out {}", step.port));
+        }
+        set_digital_out(&step.port, step.active).map_err(|e| e.to_string())
+    };
+    if let Err(e) = run_steps(sequence, set).await {
+        eprintln!("Digital out startup sequence failed: {e}");
+        set_status(StatusCodes::StartupSequenceFailed).await;
+    }
+}
+
+// Set the digital outs in the configured order. A step that requires
+// ports that earlier steps did not set, e.g. because setting one failed,
+// is skipped, while the steps that do not depend on it go on.
+async fn run_steps(
+    sequence: &[StartupStep],
+    mut set: impl FnMut(&StartupStep) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut done = HashSet::new();
+    let mut failures = Vec::new();
+    for (i, step) in sequence.iter().enumerate() {
+        let mut requires = step.requires.iter().flatten();
+        if let Some(missing) = requires.find(|p| !done.contains(p.as_str())) {
+            failures.push(format!("Step {i}: skipped since {missing} was not set"));
+            continue;
+        }
+        if let Some(delay_ms) = step.delay_ms {
+            sleep(Duration::from_millis(delay_ms)).await;
+        }
+        match set(step) {
+            Ok(()) => done.insert(step.port.as_str()),
+            Err(e) => {
+                failures.push(format!("Step {i}: failed to set {}: {e}", step.port));
+                done.remove(step.port.as_str())
+            }
+        };
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(failures.join(", ")),
+    }
+}
 
-                handle.set_value(p[i].default_state)?;
+pub fn set_all_digital_out_to_defaults() -> Result<(), ClientError> {
+    for p in CONFIG
+        .digital_out
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+    {
+        if let Some((chip_name, line)) = get_digital_chip_and_line(&p.internal_name) {
+            if let Ok(mut chip) = Chip::new(chip_name) {
+                let gpio_error = |source| ClientError::Gpio {
+                    name: p.internal_name.clone(),
+                    source,
+                };
+                chip.get_line(line)
+                    .and_then(|l| {
+                        l.request(
+                            LineRequestFlags::OUTPUT,
+                            0,
+                            "set_all_digital_out_to_defaults",
+                        )
+                    })
+                    .and_then(|h| h.set_value(p.default_state))
+                    .map_err(gpio_error)?;
             }
         }
     }
@@ -172,31 +483,43 @@ fn create_digital_out_map() -> Option<HashMap<String, DigitalOutPort>> {
     None
 }
 
-fn set_digital_out(external_name: &str, state: i32) -> Result<(), gpio_cdev::Error> {
+pub fn is_digital_out(external_name: &str) -> bool {
+    DIGITAL_OUT_MAP
+        .as_ref()
+        .is_some_and(|map| map.contains_key(external_name))
+}
+
+// The level of an output line, which is the default state unless active
+pub fn line_value(default_state: u8, active: bool) -> u8 {
+    match active {
+        true => 1 - default_state,
+        false => default_state,
+    }
+}
+
+pub fn set_digital_out(external_name: &str, active: bool) -> Result<(), ClientError> {
     let p = DIGITAL_OUT_MAP
         .as_ref()
-        .expect("Could not find digital out map.")
-        .get(external_name)
-        .expect("Could not map external name to port.");
+        .and_then(|map| map.get(external_name))
+        .ok_or_else(|| ClientError::UnknownOutput(external_name.to_string()))?;
     let internal_name = &p.internal_name;
 
     if let Some((chip_name, line)) = get_digital_chip_and_line(internal_name) {
         if let Ok(mut chip) = Chip::new(chip_name) {
-            let handle = chip
-                .get_line(line)
-                .unwrap()
-                .request(
-                    LineRequestFlags::OUTPUT,
-                    0,
-                    "set_digital_out {external_name} to {state}",
-                )
-                .unwrap();
-
-            if state == GpioState::Active as i32 {
-                handle.set_value(1 - p.default_state)?;
-            } else {
-                handle.set_value(p.default_state)?;
-            }
+            let value = line_value(p.default_state, active);
+            chip.get_line(line)
+                .and_then(|l| {
+                    l.request(
+                        LineRequestFlags::OUTPUT,
+                        0,
+                        "set_digital_out {external_name} to {active}",
+                    )
+                })
+                .and_then(|h| h.set_value(value))
+                .map_err(|source| ClientError::Gpio {
+                    name: internal_name.clone(),
+                    source,
+                })?;
         }
     }
     Ok(())
@@ -228,30 +551,188 @@ fn get_digital_chip_and_line(internal_port_name: &str) -> Option<(String, u32)>
     None
 }
 
-pub async fn send_value(channel: Channel, channel_name: &str, channel_vale: u8) {
-    let mut client = AgentClient::with_interceptor(channel, intercept);
+// Line event timestamps are from CLOCK_MONOTONIC since Linux 5.7 and
+// from CLOCK_REALTIME before that. Either way, convert them to Unix time
+// in microseconds.
+fn event_time_us(timestamp_ns: u64) -> i64 {
+    let clock_ns = |clock| {
+        clock_gettime(clock)
+            .map(|t| Duration::from(t).as_nanos() as i64)
+            .unwrap_or(0)
+    };
+    to_unix_us(
+        timestamp_ns as i64,
+        clock_ns(ClockId::CLOCK_REALTIME),
+        clock_ns(ClockId::CLOCK_MONOTONIC),
+    )
+}
+
+// The timestamp is taken to be from the clock that it is closest to
+fn to_unix_us(timestamp_ns: i64, realtime_ns: i64, monotonic_ns: i64) -> i64 {
+    let unix_ns = if (realtime_ns - timestamp_ns).abs() < (monotonic_ns - timestamp_ns).abs() {
+        timestamp_ns
+    } else {
+        realtime_ns - (monotonic_ns - timestamp_ns)
+    };
+    unix_ns / 1000
+}
 
-    //Create Vector "list" of Value. Value is defined in host_insight.proto
-    let mut v: Vec<Value> = Vec::new();
+// Queue a value for sending. The values are batched and sent by value_sender.
+// Values of line events carry the time of the event, so that edge timing
+// is kept even when sending is delayed.
+pub async fn send_value(channel_name: &str, channel_value: u8, time_stamp_us: Option<i64>) {
+    cache::update(
+        cache::DIGITAL_IN_SOURCE,
+        channel_name,
+        "N/A",
+        can_signal::Value::ValU64(channel_value as u64),
+    )
+    .await;
+    duty::record(channel_name, channel_value != 0).await;
+    local_override::input_changed(channel_name, channel_value).await;
 
-    //Create measurement of type Value
+    //Create measurement of type Value. Value is defined in host_insight.proto
     let meas = Value {
         name: channel_name.into(),
-        value: channel_vale as i32,
+        value: channel_value as i32,
+        time_stamp_us,
     };
-    //Add measurement to vector "list"
-    v.push(meas);
+    queue_value(meas).await;
+}
+
+// Queue a value without updating the cache, e.g. a derived value
+pub async fn queue_value(meas: Value) {
+    if !scrub::keep_value(&meas.name) {
+        return;
+    }
+    routing::route_value(&meas).await;
+    let mut queue = VALUE_QUEUE.lock().await;
+    queue.push(meas);
 
+    // Move the oldest values to the spool if the queue has grown too large
+    if spool::should_spool(queue.len()) {
+        let n = queue.len().min(MAX_VALUES_TO_SEND);
+        let oldest: Vec<Value> = queue.drain(..n).collect();
+        drop(queue);
+        let result = spool::write_segment(spool::VALUE_SPOOL, oldest.clone()).await;
+        if result.is_err() {
+            // Ahead of the values queued meanwhile
+            VALUE_QUEUE.lock().await.splice(..0, oldest);
+        }
+        spool::report_write(spool::VALUE_SPOOL, result).await;
+    }
+}
+
+// Correct the time stamps of the queued values after a step of the
+// wall clock
+pub async fn restamp_queue(correct: impl Fn(i64) -> Option<i64>) {
+    for value in VALUE_QUEUE.lock().await.iter_mut() {
+        restamp_value(value, &correct);
+    }
+}
+
+pub fn restamp_value(value: &mut Value, correct: impl Fn(i64) -> Option<i64>) -> bool {
+    // Keep the microseconds
+    match value
+        .time_stamp_us
+        .and_then(|us| correct(us.div_euclid(1000)).map(|ms| ms * 1000 + us.rem_euclid(1000)))
+    {
+        Some(time_stamp_us) => {
+            value.time_stamp_us = Some(time_stamp_us);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn to_values_batch(values: Vec<Value>) -> Vec<Values> {
+    values
+        .chunks(MAX_VALUES_PER_MSG)
+        .map(|chunk| Values {
+            measurements: chunk.to_vec(),
+        })
+        .collect()
+}
+
+pub async fn value_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    const MAX_BATCH_LATENCY: Duration = Duration::from_millis(500);
+
+    loop {
+        // Spooled values are older than the ones in memory
+        if spool::is_enabled() {
+            if let Some(segment) = spool::oldest_segment(spool::VALUE_SPOOL) {
+                match spool::read_segment::<Value>(&segment) {
+                    Ok(values) => {
+                        send_values_stream(channel.clone(), to_values_batch(values)).await
+                    }
+                    Err(e) => {
+                        eprintln!("Quarantining spool segment {:?}: {}", segment, e);
+                        spool::quarantine_segment(&segment);
+                        continue;
+                    }
+                }
+                spool::remove_segment(&segment);
+                continue;
+            }
+        }
+
+        if VALUE_QUEUE.lock().await.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+
+        // Let a bouncing input or a burst of edges end up in the same
+        // batch, but never delay the first value longer than the max
+        // latency.
+        let deadline = Instant::now() + MAX_BATCH_LATENCY;
+        while Instant::now() < deadline && VALUE_QUEUE.lock().await.len() < MAX_VALUES_TO_SEND {
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        let mut queue = VALUE_QUEUE.lock().await;
+        let len = std::cmp::min(queue.len(), MAX_VALUES_TO_SEND);
+        let batch = to_values_batch(queue.drain(..len).collect());
+        drop(queue);
+
+        send_values_stream(channel.clone(), batch).await;
+    }
+}
+
+async fn send_values_stream(channel: Channel, values: Vec<Values>) {
+    let mut client = AgentClient::with_interceptor(channel.clone(), intercept);
+
+    let values = Arc::new(values);
     let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
     loop {
+        if !transport::use_streams().await {
+            for v in values.iter() {
+                send_values(channel.clone(), v.clone()).await;
+            }
+            break;
+        }
+
         //Create request of type Values. Values is defined in host_insight.proto
-        let request = Request::new(Values {
-            measurements: v.clone(),
-        });
+        let request = Request::new(stream_batch(&values));
 
-        //Send values. send_values is autogenerated when host_insight.proto is compiled
-        //send_values is the defined RPC SendValues. Rust converts to snake_case
-        let response = client.send_values(request).await;
+        tap::record_all("SendValuesStream", values.as_slice()).await;
+        let response = client.send_values_stream(request).await;
+        match handle_send_result(response, &mut retry_sleep_s).await {
+            Ok(()) => {
+                transport::stream_succeeded().await;
+                break;
+            }
+            Err(e) => transport::stream_failed(&e).await,
+        }
+    }
+}
+
+async fn send_values(channel: Channel, values: Values) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendValues", &values).await;
+        let response = client.send_values(values.clone()).await;
         if handle_send_result(response, &mut retry_sleep_s)
             .await
             .is_ok()
@@ -260,3 +741,86 @@ pub async fn send_value(channel: Channel, channel_name: &str, channel_vale: u8)
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn startup_steps_wait_for_their_requirements() {
+        let step = |port: &str, requires: &[&str]| StartupStep {
+            port: port.to_string(),
+            active: true,
+            delay_ms: None,
+            requires: Some(requires.iter().map(|p| p.to_string()).collect()),
+        };
+        let sequence = [
+            step("Pump", &[]),
+            step("Fan", &[]),
+            step("Valve", &["Pump"]),
+            step("Light", &[]),
+            step("Heater", &["Fan", "Valve"]),
+        ];
+        let mut set = Vec::new();
+        let result = run_steps(&sequence, |step| {
+            set.push(step.port.clone());
+            match step.port.as_str() {
+                "Pump" => Err("line busy".to_string()),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert_eq!(set, ["Pump", "Fan", "Light"]);
+        let error = result.unwrap_err();
+        assert!(error.contains("Step 0: failed to set Pump: line busy"));
+        assert!(error.contains("Step 2: skipped since Pump was not set"));
+        assert!(error.contains("Step 4: skipped since Valve was not set"));
+
+        let mut set = Vec::new();
+        let result = run_steps(&sequence, |step| {
+            set.push(step.port.clone());
+            Ok(())
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(set.len(), sequence.len());
+    }
+
+    #[test]
+    fn event_timestamps_from_either_clock() {
+        let realtime = 1_700_000_000_000_000_000;
+        let monotonic = 3_600_000_000_000;
+        // An event 2 ms ago
+        assert_eq!(
+            to_unix_us(monotonic - 2_000_000, realtime, monotonic),
+            (realtime - 2_000_000) / 1000
+        );
+        assert_eq!(
+            to_unix_us(realtime - 2_000_000, realtime, monotonic),
+            (realtime - 2_000_000) / 1000
+        );
+    }
+
+    #[tokio::test]
+    async fn attention_counts_rising_edges_while_paused() {
+        use super::super::subsystem::{control_subsystem, resume};
+        use lib::host_insight::SubsystemControl;
+
+        control_subsystem(SubsystemControl {
+            subsystem: Subsystem::DigitalIn as i32,
+            enabled: false,
+        })
+        .await;
+        let name = "attention-test-input";
+        report_edge(name, true, 0).await;
+        assert_eq!(attention::activations(name), 1);
+
+        // An input that stays active is not a new acknowledgement
+        let count = attention::activations(name);
+        report_edge(name, false, 0).await;
+        assert_eq!(attention::activations(name), count);
+        report_edge(name, true, 0).await;
+        assert_eq!(attention::activations(name), count + 1);
+        resume(Subsystem::DigitalIn).await;
+    }
+}
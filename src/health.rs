@@ -0,0 +1,115 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Health endpoint for container orchestrators. GET /health answers
+// 200 while the server has been reached recently and 503 otherwise, so
+// that e.g. a liveness probe can restart a client that is stuck.
+
+use lazy_static::lazy_static;
+use lib::CONFIG;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+// A client that does not send its request in time is disconnected
+const RESPOND_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref LAST_CONTACT: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+static CONTACTED: AtomicBool = AtomicBool::new(false);
+
+// Called for every reply from the server
+pub async fn record_contact() {
+    CONTACTED.store(true, Ordering::Relaxed);
+    *LAST_CONTACT.lock().await = Instant::now();
+}
+
+// Whether the server has replied since the client started
+pub fn has_contact() -> bool {
+    CONTACTED.load(Ordering::Relaxed)
+}
+
+// Time since the server was last reached
+pub async fn since_contact() -> Duration {
+    LAST_CONTACT.lock().await.elapsed()
+}
+
+async fn is_healthy() -> bool {
+    // Allow a few missed heartbeats and one retry at the max sleep time
+    let max_silence = Duration::from_secs(3 * CONFIG.time.heartbeat_s + CONFIG.time.sleep_max_s);
+    LAST_CONTACT.lock().await.elapsed() < max_silence
+}
+
+pub async fn health_server(port: u16) {
+    if let Err(e) = serve(port).await {
+        eprintln!("Health endpoint failed: {e}");
+    }
+}
+
+async fn serve(port: u16) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("Serving health checks on port {port}");
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                eprintln!("Failed to accept a health check: {e}");
+                sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            match timeout(RESPOND_TIMEOUT, respond(socket)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Health check failed: {e}"),
+                Err(_) => eprintln!("Health check timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut buf = [0; 1024];
+    let n = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+
+    let (status, body) = match request.lines().next() {
+        Some(line) if line.starts_with("GET /health ") => {
+            if is_healthy().await {
+                ("200 OK", "ok")
+            } else {
+                ("503 Service Unavailable", "server unreachable")
+            }
+        }
+        _ => ("404 Not Found", "not found"),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}\n",
+        body.len() + 1
+    );
+    socket.write_all(response.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Short in-memory history of selected signals, so that the last few
+// seconds of data can be uploaded as context when something happens.
+
+use super::host_insight::can_signal;
+use super::CONFIG;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub source: String,
+    pub name: String,
+    pub value: can_signal::Value,
+    pub time: SystemTime,
+}
+
+// A request to upload the recent history of some signals
+#[derive(Clone, Debug)]
+pub struct UploadRequest {
+    pub signals: Vec<String>,
+    pub window: Duration,
+}
+
+lazy_static! {
+    static ref HISTORY: Mutex<HashMap<String, VecDeque<Sample>>> = Mutex::new(HashMap::new());
+    static ref UPLOAD_REQUESTS: Mutex<Vec<UploadRequest>> = Mutex::new(Vec::new());
+}
+
+fn is_recorded(name: &str) -> bool {
+    match &CONFIG.history {
+        Some(h) => h.signals.iter().any(|s| s == name),
+        None => false,
+    }
+}
+
+// Add a sample to the history of a signal, if that signal is configured
+// to be recorded. The oldest sample is dropped once the depth is reached.
+pub async fn record(source: &str, name: &str, value: &can_signal::Value, time: SystemTime) {
+    if !is_recorded(name) {
+        return;
+    }
+    let depth = CONFIG.history.as_ref().unwrap().depth;
+
+    let mut history = HISTORY.lock().await;
+    let samples = history.entry(name.to_string()).or_default();
+    if samples.len() >= depth {
+        samples.pop_front();
+    }
+    samples.push_back(Sample {
+        source: source.to_string(),
+        name: name.to_string(),
+        value: value.clone(),
+        time,
+    });
+}
+
+// Get all samples of the given signals (or all recorded signals if
+// empty) that are younger than the window, oldest first
+pub async fn recent(signals: &[String], window: Duration) -> Vec<Sample> {
+    let since = SystemTime::now().checked_sub(window).unwrap_or(UNIX_EPOCH);
+    let history = HISTORY.lock().await;
+
+    let mut samples: Vec<Sample> = history
+        .iter()
+        .filter(|(name, _)| signals.is_empty() || signals.contains(name))
+        .flat_map(|(_, s)| s.iter().filter(|s| s.time >= since).cloned())
+        .collect();
+    samples.sort_by_key(|s| s.time);
+    samples
+}
+
+// Ask for the recent history to be uploaded by the history sender
+pub async fn request_upload(signals: Vec<String>, window: Duration) {
+    UPLOAD_REQUESTS
+        .lock()
+        .await
+        .push(UploadRequest { signals, window });
+}
+
+pub async fn take_upload_requests() -> Vec<UploadRequest> {
+    UPLOAD_REQUESTS.lock().await.drain(..).collect()
+}
+
+// Correct the times of the samples after a step of the wall clock
+pub async fn restamp(correct: impl Fn(SystemTime) -> Option<SystemTime>) {
+    for samples in HISTORY.lock().await.values_mut() {
+        for sample in samples.iter_mut() {
+            if let Some(time) = correct(sample.time) {
+                sample.time = time;
+            }
+        }
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
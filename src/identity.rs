@@ -0,0 +1,192 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+use super::{conf_dir, Identity, IdentityConfig, IdentityProviderKind};
+use anyhow::{Context, Error};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::Command;
+
+const DEVICE_TREE_SERIAL_PATH: &str = "/proc/device-tree/serial-number";
+const DEFAULT_EEPROM_SERIAL_LENGTH: usize = 16;
+
+// Something that can establish the identity of this unit
+pub trait IdentityProvider {
+    fn identity(&self) -> Result<Identity, Error>;
+}
+
+// Select an identity provider based on the (optional) identity config.
+// Without any config, the identity is read from file as before.
+pub fn identity_provider(
+    config: Option<&IdentityConfig>,
+) -> Result<Box<dyn IdentityProvider>, Error> {
+    let config = match config {
+        Some(c) => c.clone(),
+        None => return Ok(Box::new(FileIdentity)),
+    };
+
+    Ok(match config.provider {
+        IdentityProviderKind::File => Box::new(FileIdentity),
+        IdentityProviderKind::DeviceTree => Box::new(DeviceTreeIdentity {
+            path: config
+                .path
+                .unwrap_or_else(|| DEVICE_TREE_SERIAL_PATH.to_string()),
+            domain: config.domain,
+        }),
+        IdentityProviderKind::Eeprom => Box::new(EepromIdentity {
+            path: config
+                .path
+                .context("An EEPROM identity provider requires a path")?,
+            offset: config.offset.unwrap_or(0),
+            length: config.length.unwrap_or(DEFAULT_EEPROM_SERIAL_LENGTH),
+            domain: config.domain,
+        }),
+        IdentityProviderKind::Tpm => Box::new(TpmIdentity {
+            handle: config
+                .handle
+                .context("A TPM identity provider requires a key handle")?,
+            domain: config.domain,
+        }),
+    })
+}
+
+// Identity read from identity.toml or identity-fallback.toml (in that order)
+pub struct FileIdentity;
+
+impl IdentityProvider for FileIdentity {
+    fn identity(&self) -> Result<Identity, Error> {
+        Ok(toml::from_str(&read_identity_file()?)?)
+    }
+}
+
+fn read_identity_file() -> Result<String, Error> {
+    let identity = PathBuf::from(format!("{}/identity.toml", conf_dir()));
+    let fallback_identity = PathBuf::from(format!("{}/identity-fallback.toml", conf_dir()));
+
+    fs::read_to_string(&identity)
+        .or_else(|_| fs::read_to_string(&fallback_identity))
+        .context("Could not read any identity file")
+}
+
+// The profile selected in the identity file, if any. It is read
+// directly from the file since the config, which selects the identity
+// provider, depends on it.
+pub fn profile() -> Option<String> {
+    let table: toml::value::Table = toml::from_str(&read_identity_file().ok()?).ok()?;
+    table.get("profile")?.as_str().map(|p| p.to_string())
+}
+
+// Serial number exported by the bootloader in the device tree
+pub struct DeviceTreeIdentity {
+    pub path: String,
+    pub domain: Option<String>,
+}
+
+impl IdentityProvider for DeviceTreeIdentity {
+    fn identity(&self) -> Result<Identity, Error> {
+        let raw = fs::read(&self.path).with_context(|| format!("Could not read {}", self.path))?;
+        Ok(Identity {
+            uid: parse_serial(&raw)?,
+            domain: resolve_domain(&self.domain)?,
+            profile: profile(),
+        })
+    }
+}
+
+// Serial number programmed into an EEPROM, e.g. through the nvmem sysfs interface
+pub struct EepromIdentity {
+    pub path: String,
+    pub offset: u64,
+    pub length: usize,
+    pub domain: Option<String>,
+}
+
+impl IdentityProvider for EepromIdentity {
+    fn identity(&self) -> Result<Identity, Error> {
+        let mut f =
+            fs::File::open(&self.path).with_context(|| format!("Could not open {}", self.path))?;
+        f.seek(SeekFrom::Start(self.offset))?;
+        let mut raw = vec![0; self.length];
+        f.read_exact(&mut raw)?;
+        Ok(Identity {
+            uid: parse_serial(&raw)?,
+            domain: resolve_domain(&self.domain)?,
+            profile: profile(),
+        })
+    }
+}
+
+// Identity derived from the name (hash of the public area) of a
+// persistent TPM key, using tpm2-tools
+pub struct TpmIdentity {
+    pub handle: String,
+    pub domain: Option<String>,
+}
+
+impl IdentityProvider for TpmIdentity {
+    fn identity(&self) -> Result<Identity, Error> {
+        let output = Command::new("tpm2_readpublic")
+            .arg("-c")
+            .arg(&self.handle)
+            .output()
+            .context("Failed to execute tpm2_readpublic")?;
+        if !output.status.success() {
+            return Err(Error::msg(format!(
+                "tpm2_readpublic failed for handle {}",
+                self.handle
+            )));
+        }
+
+        let stdout = String::from_utf8(output.stdout)?;
+        let name = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("name:"))
+            .map(|name| name.trim().to_string())
+            .context("No key name in tpm2_readpublic output")?;
+
+        Ok(Identity {
+            uid: name,
+            domain: resolve_domain(&self.domain)?,
+            profile: profile(),
+        })
+    }
+}
+
+// Serial numbers are stored as strings padded with NUL (device tree)
+// or 0xFF (erased EEPROM cells).
+fn parse_serial(raw: &[u8]) -> Result<String, Error> {
+    let end = raw
+        .iter()
+        .position(|b| *b == 0 || *b == 0xFF)
+        .unwrap_or(raw.len());
+    let serial = std::str::from_utf8(&raw[..end])?.trim().to_string();
+    if serial.is_empty() {
+        return Err(Error::msg("Serial number is empty"));
+    }
+    Ok(serial)
+}
+
+// Hardware providers only know the uid. The domain is taken from the
+// identity config or, if missing there, from the identity file.
+fn resolve_domain(domain: &Option<String>) -> Result<String, Error> {
+    match domain {
+        Some(d) => Ok(d.clone()),
+        None => Ok(FileIdentity.identity()?.domain),
+    }
+}
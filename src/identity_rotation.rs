@@ -0,0 +1,139 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Rotation of the identity given by the server. The new identity is
+// tried with a heartbeat before it is written, so that a wrong uid or
+// domain is rejected while the unit can still report it. The previous
+// identity is kept for a grace period after the restart, and restored if
+// the server cannot be reached with the new one.
+
+use super::health;
+use super::net::{connect, current_status, set_status};
+use super::utils::{clean_up, write_atomic_async};
+use lib::{
+    conf_dir, error::ClientError, host_insight::agent_client::AgentClient, Identity, StatusCodes,
+    IDENTITY,
+};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
+use tonic::{metadata::MetadataValue, Request, Status};
+
+// Time to reach the server with a new identity before it is rolled back
+const GRACE_PERIOD: Duration = Duration::from_secs(15 * 60);
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn identity_path() -> PathBuf {
+    PathBuf::from(format!("{}/identity.toml", conf_dir()))
+}
+
+fn previous_path() -> PathBuf {
+    PathBuf::from(format!("{}/identity-previous.toml", conf_dir()))
+}
+
+// Marks that the last rotation was rolled back, to report it after the
+// restart with the previous identity
+fn rollback_path() -> PathBuf {
+    PathBuf::from(format!("{}/identity-rolled-back", conf_dir()))
+}
+
+async fn test_identity(identity: &Identity) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let uid: MetadataValue<_> = identity.uid.parse()?;
+    let channel = connect(&identity.domain).await?;
+    #[allow(clippy::result_large_err)]
+    let mut client = AgentClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut().insert("uid", uid.clone());
+        Ok::<_, Status>(req)
+    });
+    // Only whether the server answers matters, actions in the reply are
+    // left for the heartbeat with the identity in use
+    timeout(TEST_TIMEOUT, client.heart_beat(current_status().await))
+        .await
+        .map_err(|_| "timed out")?
+        .map_err(|status| ClientError::Rpc {
+            rpc: "HeartBeat",
+            status: Box::new(status),
+        })?;
+    Ok(())
+}
+
+// Switch to a new identity if the server can be reached with it. Returns
+// true if it was written, after which the client is to restart with it,
+// otherwise the client keeps running with the current one.
+pub async fn rotate_identity(identity: Identity) -> bool {
+    if let Err(e) = test_identity(&identity).await {
+        eprintln!(
+            "Rejecting identity update to {} at {}: {e}",
+            identity.uid, identity.domain
+        );
+        set_status(StatusCodes::IdentityUpdateFailed).await;
+        return false;
+    }
+
+    let previous = toml::to_string(&*IDENTITY).expect("Could not encode current identity as TOML");
+    let toml_string = toml::to_string(&identity).expect("Could not encode new identity as TOML");
+    write_atomic_async(previous_path(), previous.into_bytes())
+        .await
+        .expect("Could not write to file!");
+    write_atomic_async(identity_path(), toml_string.into_bytes())
+        .await
+        .expect("Could not write to file!");
+    println!(
+        "Switching identity to {} at {}, the previous identity is kept for {} s",
+        identity.uid,
+        identity.domain,
+        GRACE_PERIOD.as_secs()
+    );
+    true
+}
+
+// Confirm or roll back a rotation done before the last restart
+pub async fn rotation_monitor() -> Result<(), Box<dyn Error>> {
+    if rollback_path().exists() {
+        eprintln!("The last identity update was rolled back");
+        set_status(StatusCodes::IdentityUpdateFailed).await;
+        let _ = fs::remove_file(rollback_path());
+    }
+    if !previous_path().exists() {
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    loop {
+        if health::has_contact() {
+            fs::remove_file(previous_path())?;
+            println!("Identity update to {} confirmed", IDENTITY.uid);
+            return Ok(());
+        }
+        if start.elapsed() >= GRACE_PERIOD {
+            eprintln!(
+                "No contact with the server as {} in {} s, restoring the previous identity",
+                IDENTITY.uid,
+                GRACE_PERIOD.as_secs()
+            );
+            fs::write(rollback_path(), "")?;
+            fs::rename(previous_path(), identity_path())?;
+            clean_up();
+            std::process::exit(0);
+        }
+        sleep(CHECK_INTERVAL).await;
+    }
+}
@@ -0,0 +1,306 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// CAN intrusion and anomaly detection. Frames are checked for IDs that
+// are neither in the DBC file nor explicitly allowed, for changes of the
+// data length of an ID, and for frame rates well above the ones seen
+// while learning after start-up. Anomalies are reported to the server as
+// security events, each kind at most once per holdoff per port and ID.
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use lazy_static::lazy_static;
+use lib::{
+    history,
+    host_insight::{agent_client::AgentClient, SecurityEvent, SecurityEventKind},
+    IntrusionConfig, CONFIG,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+
+const DEFAULT_LEARN_S: u64 = 300;
+const DEFAULT_WINDOW_S: u64 = 10;
+const DEFAULT_RATE_FACTOR: f64 = 3.0;
+const DEFAULT_HOLDOFF_S: u64 = 60;
+// The IDs come from the bus, so the state kept per ID is bounded
+const MAX_TRACKED_IDS: usize = 4096;
+const MAX_REPORTED: usize = 4096;
+const MAX_QUEUED_EVENTS: usize = 1000;
+
+struct IdState {
+    dlc: usize,
+    window_start: Instant,
+    count: u32,
+    // Highest number of frames per window while learning
+    baseline: Option<u32>,
+}
+
+pub struct Detector {
+    learn_until: Instant,
+    window: Duration,
+    rate_factor: f64,
+    holdoff: Duration,
+    allowed_ids: HashSet<u32>,
+    ids: HashMap<(String, u32), IdState>,
+    reported: HashMap<(String, u32, SecurityEventKind), Instant>,
+}
+
+lazy_static! {
+    static ref DETECTOR: Mutex<Option<Detector>> = Mutex::new(
+        CONFIG
+            .can
+            .as_ref()
+            .and_then(|c| c.intrusion.as_ref())
+            .map(|c| Detector::new(c, Instant::now()))
+    );
+    static ref EVENT_QUEUE: Mutex<VecDeque<SecurityEvent>> = Mutex::new(VecDeque::new());
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG.can.as_ref().is_some_and(|c| c.intrusion.is_some())
+}
+
+impl Detector {
+    pub fn new(config: &IntrusionConfig, now: Instant) -> Detector {
+        Detector {
+            learn_until: now + Duration::from_secs(config.learn_s.unwrap_or(DEFAULT_LEARN_S)),
+            window: Duration::from_secs(config.window_s.unwrap_or(DEFAULT_WINDOW_S)),
+            rate_factor: config.rate_factor.unwrap_or(DEFAULT_RATE_FACTOR),
+            holdoff: Duration::from_secs(config.holdoff_s.unwrap_or(DEFAULT_HOLDOFF_S)),
+            allowed_ids: config.allowed_ids.iter().flatten().copied().collect(),
+            ids: HashMap::new(),
+            reported: HashMap::new(),
+        }
+    }
+
+    // Check a frame. The expected DLC is the message size in the DBC
+    // file, or None if the ID is not in it.
+    pub fn check(
+        &mut self,
+        port: &str,
+        id: u32,
+        dlc: usize,
+        expected_dlc: Option<usize>,
+        now: Instant,
+    ) -> Vec<(SecurityEventKind, String)> {
+        let mut anomalies = Vec::new();
+        let learning = now < self.learn_until;
+
+        if expected_dlc.is_none() && !self.allowed_ids.contains(&id) {
+            anomalies.push((
+                SecurityEventKind::UnexpectedId,
+                format!("ID {id:#x} is not in the DBC file"),
+            ));
+        }
+
+        let key = (port.to_string(), id);
+        if self.ids.len() >= MAX_TRACKED_IDS && !self.ids.contains_key(&key) {
+            // Only unexpected IDs are checked for IDs beyond the limit
+            return self.hold_off(port, id, anomalies, now);
+        }
+        let state = self.ids.entry(key).or_insert_with(|| IdState {
+            dlc: expected_dlc.unwrap_or(dlc),
+            window_start: now,
+            count: 0,
+            baseline: None,
+        });
+
+        if dlc != state.dlc {
+            anomalies.push((
+                SecurityEventKind::DlcChange,
+                format!("ID {id:#x} changed DLC from {} to {dlc}", state.dlc),
+            ));
+            if expected_dlc.is_none() {
+                state.dlc = dlc;
+            }
+        }
+
+        if now.duration_since(state.window_start) >= self.window {
+            if state.window_start < self.learn_until {
+                state.baseline = state.baseline.max(Some(state.count));
+            }
+            state.window_start = now;
+            state.count = 0;
+        }
+        state.count += 1;
+        if let Some(baseline) = state.baseline {
+            let limit = f64::from(baseline.max(1)) * self.rate_factor;
+            if !learning && f64::from(state.count) > limit {
+                anomalies.push((
+                    SecurityEventKind::RateAnomaly,
+                    format!(
+                        "ID {id:#x} exceeded {limit:.0} frames per {} s",
+                        self.window.as_secs()
+                    ),
+                ));
+            }
+        }
+
+        self.hold_off(port, id, anomalies, now)
+    }
+
+    // Leave out anomalies that were reported within the holdoff. When
+    // too many are held off, e.g. during a flood of random IDs, new ones
+    // are left out too until older ones have expired.
+    fn hold_off(
+        &mut self,
+        port: &str,
+        id: u32,
+        mut anomalies: Vec<(SecurityEventKind, String)>,
+        now: Instant,
+    ) -> Vec<(SecurityEventKind, String)> {
+        if self.reported.len() >= MAX_REPORTED {
+            let holdoff = self.holdoff;
+            self.reported
+                .retain(|_, last| now.duration_since(*last) < holdoff);
+        }
+        anomalies.retain(|(kind, _)| {
+            let key = (port.to_string(), id, *kind);
+            match self.reported.get(&key) {
+                Some(last) if now.duration_since(*last) < self.holdoff => false,
+                None if self.reported.len() >= MAX_REPORTED => false,
+                _ => {
+                    self.reported.insert(key, now);
+                    true
+                }
+            }
+        });
+        anomalies
+    }
+}
+
+pub async fn inspect(port: &str, id: u32, dlc: usize, expected_dlc: Option<usize>) {
+    let anomalies = match DETECTOR.lock().await.as_mut() {
+        Some(detector) => detector.check(port, id, dlc, expected_dlc, Instant::now()),
+        None => return,
+    };
+    for (kind, detail) in anomalies {
+        eprintln!("Security event on {port}: {detail}");
+        let mut queue = EVENT_QUEUE.lock().await;
+        if queue.len() >= MAX_QUEUED_EVENTS {
+            queue.pop_front();
+        }
+        queue.push_back(SecurityEvent {
+            time_stamp: history::unix_millis(SystemTime::now()),
+            bus: port.to_string(),
+            can_id: id,
+            kind: kind as i32,
+            detail,
+        });
+    }
+}
+
+pub async fn security_event_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    loop {
+        let events: Vec<SecurityEvent> = EVENT_QUEUE.lock().await.drain(..).collect();
+        for event in events {
+            let mut retry_sleep_s = CONFIG.time.sleep_min_s;
+            loop {
+                tap::record("SendSecurityEvent", &event).await;
+                let response = client.send_security_event(event.clone()).await;
+                if handle_send_result(response, &mut retry_sleep_s)
+                    .await
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> IntrusionConfig {
+        IntrusionConfig {
+            learn_s: Some(60),
+            window_s: Some(1),
+            rate_factor: Some(2.0),
+            holdoff_s: Some(10),
+            allowed_ids: Some(vec![0x700]),
+        }
+    }
+
+    #[test]
+    fn unexpected_id_and_dlc_change() {
+        let t0 = Instant::now();
+        let mut d = Detector::new(&config(), t0);
+
+        assert!(d.check("can0", 0x100, 8, Some(8), t0).is_empty());
+        assert!(d.check("can0", 0x700, 2, None, t0).is_empty());
+
+        let a = d.check("can0", 0x123, 8, None, t0);
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].0, SecurityEventKind::UnexpectedId);
+        // Held off
+        assert!(d.check("can0", 0x123, 8, None, t0).is_empty());
+
+        let a = d.check("can0", 0x100, 4, Some(8), t0);
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].0, SecurityEventKind::DlcChange);
+    }
+
+    #[test]
+    fn state_is_bounded() {
+        let t0 = Instant::now();
+        let mut d = Detector::new(&config(), t0);
+
+        let reported = (0..2 * MAX_TRACKED_IDS as u32)
+            .map(|id| d.check("can0", id, 8, None, t0).len())
+            .sum::<usize>();
+        assert_eq!(reported, MAX_REPORTED);
+        assert_eq!(d.ids.len(), MAX_TRACKED_IDS);
+        assert_eq!(d.reported.len(), MAX_REPORTED);
+
+        // New IDs are reported again once the holdoff has expired
+        let t = t0 + Duration::from_secs(10);
+        assert_eq!(d.check("can0", 0x1fff_0000, 8, None, t).len(), 1);
+        assert_eq!(d.reported.len(), 1);
+    }
+
+    #[test]
+    fn rate_anomaly_after_learning() {
+        let t0 = Instant::now();
+        let mut d = Detector::new(&config(), t0);
+
+        // Learn 10 frames per second
+        for s in 0..60 {
+            for i in 0..10 {
+                let t = t0 + Duration::from_millis(s * 1000 + i * 100);
+                assert!(d.check("can0", 0x100, 8, Some(8), t).is_empty());
+            }
+        }
+
+        // Up to twice the learnt rate is normal
+        let t = t0 + Duration::from_secs(61);
+        for _ in 0..20 {
+            assert!(d.check("can0", 0x100, 8, Some(8), t).is_empty());
+        }
+        let a = d.check("can0", 0x100, 8, Some(8), t);
+        assert_eq!(a.len(), 1);
+        assert_eq!(a[0].0, SecurityEventKind::RateAnomaly);
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Inventory of the software of a unit: the OS image, the kernel, the
+// versions of the packages the client depends on, such as ModemManager,
+// and the drivers of the CAN ports. It is sent when the server asks for
+// it and, with an interval_s, periodically, so that compatibility
+// problems can be looked into without shell access.
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{agent_client::AgentClient, CanDriver, Inventory, Package},
+    CONFIG,
+};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tonic::transport::Channel;
+
+const DEFAULT_PACKAGES: [&str; 4] = ["modemmanager", "libqmi", "libmbim", "can-utils"];
+
+lazy_static! {
+    // Wakes the reporter when the server asks for the inventory
+    static ref REQUESTED: Notify = Notify::new();
+}
+
+pub fn request_inventory() {
+    REQUESTED.notify_one();
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+// The value of a key in an os-release file, without quotes
+fn os_release_value(os_release: &str, key: &str) -> Option<String> {
+    os_release.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value.trim_matches(|c| c == '"' || c == '\'').to_string())
+    })
+}
+
+// Name and version of the OS image, with the build ID of images that
+// have one
+fn os_version() -> String {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .unwrap_or_default();
+    let mut version = os_release_value(&os_release, "PRETTY_NAME").unwrap_or_default();
+    if let Some(build) = os_release_value(&os_release, "BUILD_ID") {
+        version = format!("{version} ({build})");
+    }
+    version
+}
+
+// Version of an installed package from whichever package manager the
+// image has, or None if it is not installed
+fn package_version(name: &str) -> Option<String> {
+    let queries: [(&str, &[&str]); 3] = [
+        ("dpkg-query", &["-W", "-f=${Version}"]),
+        ("rpm", &["-q", "--qf", "%{VERSION}-%{RELEASE}"]),
+        ("opkg", &["status"]),
+    ];
+    for (program, args) in queries {
+        let output = match Command::new(program).args(args).arg(name).output() {
+            Ok(output) if output.status.success() => output,
+            _ => continue,
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = match program {
+            "opkg" => stdout
+                .lines()
+                .find_map(|l| l.strip_prefix("Version:"))
+                .map(|v| v.trim().to_string()),
+            _ => Some(stdout.trim().to_string()),
+        };
+        if let Some(version) = version.filter(|v| !v.is_empty()) {
+            return Some(version);
+        }
+    }
+    None
+}
+
+// The driver of each configured CAN port, with the version of the module
+// if it has one, e.g. an out-of-tree driver
+fn can_drivers() -> Vec<CanDriver> {
+    let ports = CONFIG.can.iter().flat_map(|c| c.ports.iter().flatten());
+    ports
+        .filter_map(|port| {
+            let link = fs::read_link(format!("/sys/class/net/{}/device/driver", port.name)).ok()?;
+            let driver = link.file_name()?.to_string_lossy().into_owned();
+            Some(CanDriver {
+                port: port.name.clone(),
+                version: read_trimmed(format!("/sys/module/{driver}/version")),
+                driver,
+            })
+        })
+        .collect()
+}
+
+fn collect() -> Inventory {
+    let packages = match CONFIG.inventory.as_ref().and_then(|i| i.packages.clone()) {
+        Some(packages) => packages,
+        None => DEFAULT_PACKAGES.iter().map(|p| p.to_string()).collect(),
+    };
+    Inventory {
+        os_version: os_version(),
+        kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+        packages: packages
+            .into_iter()
+            .filter_map(|name| {
+                let version = package_version(&name)?;
+                Some(Package { name, version })
+            })
+            .collect(),
+        can_drivers: can_drivers(),
+    }
+}
+
+pub async fn inventory_reporter(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let interval = CONFIG
+        .inventory
+        .as_ref()
+        .and_then(|i| i.interval_s)
+        .map(Duration::from_secs);
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    // A periodic inventory is also sent at startup
+    if interval.is_none() {
+        REQUESTED.notified().await;
+    }
+    loop {
+        let inventory = tokio::task::spawn_blocking(collect).await?;
+        let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+        loop {
+            tap::record("SendInventory", &inventory).await;
+            let response = client.send_inventory(inventory.clone()).await;
+            if handle_send_result(response, &mut retry_sleep_s)
+                .await
+                .is_ok()
+            {
+                break;
+            };
+        }
+
+        match interval {
+            Some(interval) => {
+                let _ = timeout(interval, REQUESTED.notified()).await;
+            }
+            None => REQUESTED.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release_values() {
+        let os_release = "NAME=\"HMX Linux\"\nVERSION_ID=4.2\n\
+                          PRETTY_NAME=\"HMX Linux 4.2\"\nBUILD_ID='20240115'\n";
+        assert_eq!(
+            os_release_value(os_release, "PRETTY_NAME").as_deref(),
+            Some("HMX Linux 4.2")
+        );
+        assert_eq!(
+            os_release_value(os_release, "BUILD_ID").as_deref(),
+            Some("20240115")
+        );
+        assert_eq!(
+            os_release_value(os_release, "VERSION_ID").as_deref(),
+            Some("4.2")
+        );
+        assert_eq!(os_release_value(os_release, "VERSION"), None);
+    }
+}
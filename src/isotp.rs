@@ -0,0 +1,503 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// UDS diagnostics over ISO-TP, run in a remote control session with the
+// diagnostics scope. The server sends UDS requests over the session
+// stream, each for a CAN port, a pair of ISO-TP IDs and a service, and
+// the responses are streamed back as they come in. Only reading
+// services are supported: ReadDataByIdentifier and ReadDTCInformation
+// (reportDTCByStatusMask). The kernel ISO-TP sockets take care of the
+// segmentation and flow control, and requests can only be sent on
+// ports that are not in listen-only mode. On ports with security access
+// configured, the ECU is unlocked with SecurityAccess before the request.
+
+use super::health::record_contact;
+use super::net::{handle_send_result, intercept};
+use super::seed_key::key_algorithm;
+use super::tap;
+use super::utils::stream_batch;
+use futures::stream::StreamExt;
+use lib::{
+    host_insight::{
+        agent_client::AgentClient, remote_control_client::RemoteControlClient, ControlScope,
+        ControlStatus, Dtc, UdsRequest, UdsResponse, UdsService, UnitControlStatus,
+    },
+    SecurityAccessConfig, CONFIG,
+};
+use nix::net::if_::if_nametoindex;
+use std::error::Error;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::task::spawn_blocking;
+use tokio::time::{timeout_at, Instant};
+use tonic::transport::Channel;
+use tonic::Request;
+
+const CAN_ISOTP: c_int = 6;
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_SFF_MASK: u32 = 0x7ff;
+
+const SID_DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+const SID_SECURITY_ACCESS: u8 = 0x27;
+const SID_OBD_VEHICLE_INFORMATION: u8 = 0x09;
+pub const SID_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+const SID_READ_DTC_INFORMATION: u8 = 0x19;
+const REPORT_DTC_BY_STATUS_MASK: u8 = 0x02;
+const NEGATIVE_RESPONSE: u8 = 0x7f;
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+// Time to wait after a response pending, P2* of ISO 14229-2
+const PENDING_TIMEOUT: Duration = Duration::from_millis(5000);
+// Largest ISO-TP message with a 12 bit length
+const MAX_MESSAGE_LEN: usize = 4095;
+
+// Address of an ISO-TP socket, laid out as struct sockaddr_can
+#[repr(C)]
+struct SockaddrCanTp {
+    can_family: libc::sa_family_t,
+    can_ifindex: c_int,
+    rx_id: u32,
+    tx_id: u32,
+    // The rest of the address union, used by J1939
+    reserved: [u8; 8],
+}
+
+// An ISO-TP connection to one ECU
+pub struct IsoTpSocket(AsyncFd<OwnedFd>);
+
+// IDs above the standard range are extended IDs
+fn can_id(id: u32) -> u32 {
+    if id > CAN_SFF_MASK {
+        id | CAN_EFF_FLAG
+    } else {
+        id
+    }
+}
+
+impl IsoTpSocket {
+    pub fn open(ifname: &str, tx_id: u32, rx_id: u32) -> io::Result<IsoTpSocket> {
+        let ifindex = if_nametoindex(ifname)?;
+        // SAFETY: socket takes no pointers
+        let fd = unsafe {
+            libc::socket(
+                libc::PF_CAN,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                CAN_ISOTP,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just opened and is owned by nothing
+        // else, so the OwnedFd closes it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let addr = SockaddrCanTp {
+            can_family: libc::AF_CAN as libc::sa_family_t,
+            can_ifindex: ifindex as c_int,
+            rx_id: can_id(rx_id),
+            tx_id: can_id(tx_id),
+            reserved: [0; 8],
+        };
+        // SAFETY: the address is a sockaddr_can of the given size that
+        // lives for the call
+        let rv = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const SockaddrCanTp as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrCanTp>() as libc::socklen_t,
+            )
+        };
+        if rv != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(IsoTpSocket(AsyncFd::new(fd)?))
+    }
+
+    async fn send(&self, message: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.0.writable().await?;
+            let result = guard.try_io(|fd| {
+                // SAFETY: the message is valid for reads of its length
+                let n = unsafe {
+                    libc::write(
+                        fd.as_raw_fd(),
+                        message.as_ptr() as *const libc::c_void,
+                        message.len(),
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+            if let Ok(result) = result {
+                return result;
+            }
+        }
+    }
+
+    async fn recv(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; MAX_MESSAGE_LEN];
+        loop {
+            let mut guard = self.0.readable().await?;
+            let result = guard.try_io(|fd| {
+                // SAFETY: the buffer is valid for writes of its length
+                let n = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(result) = result {
+                buf.truncate(result?);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+// The outcome of a UDS request
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    // The response data after the service ID and echoed parameters
+    Positive(Vec<u8>),
+    Negative(u8),
+    // A response pending, to keep waiting for the final response
+    Pending,
+    // A response that does not belong to the request
+    Unexpected,
+}
+
+// Classify a response to a request. The parameters that a positive
+// response echoes, e.g. the data identifier, are checked and left out.
+fn parse_response(request: &[u8], response: &[u8]) -> Outcome {
+    let sid = request[0];
+    match response {
+        [NEGATIVE_RESPONSE, s, NRC_RESPONSE_PENDING, ..] if *s == sid => Outcome::Pending,
+        [NEGATIVE_RESPONSE, s, nrc, ..] if *s == sid => Outcome::Negative(*nrc),
+        [s, rest @ ..] if *s == sid + 0x40 => {
+            let echo = match sid {
+                SID_READ_DATA_BY_IDENTIFIER => &request[1..3],
+                SID_OBD_VEHICLE_INFORMATION
+                | SID_DIAGNOSTIC_SESSION_CONTROL
+                | SID_SECURITY_ACCESS
+                | SID_READ_DTC_INFORMATION => &request[1..2],
+                _ => &[][..],
+            };
+            match rest.strip_prefix(echo) {
+                Some(data) => Outcome::Positive(data.to_vec()),
+                None => Outcome::Unexpected,
+            }
+        }
+        _ => Outcome::Unexpected,
+    }
+}
+
+// The DTCs of a reportDTCByStatusMask response, after the availability
+// mask, as 3 byte codes with a status byte each
+fn parse_dtcs(data: &[u8]) -> Vec<Dtc> {
+    data.get(1..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .map(|r| Dtc {
+            code: u32::from_be_bytes([0, r[0], r[1], r[2]]),
+            status: r[3] as u32,
+        })
+        .collect()
+}
+
+// Send a request and wait for its response
+pub async fn transact(
+    socket: &IsoTpSocket,
+    request: &[u8],
+    wait: Duration,
+) -> Result<Outcome, String> {
+    socket
+        .send(request)
+        .await
+        .map_err(|e| format!("Failed to send: {e}"))?;
+    let mut deadline = Instant::now() + wait;
+    loop {
+        let response = match timeout_at(deadline, socket.recv()).await {
+            Ok(response) => response.map_err(|e| format!("Failed to receive: {e}"))?,
+            Err(_) => return Err("No response".to_string()),
+        };
+        match parse_response(request, &response) {
+            Outcome::Pending => deadline = Instant::now() + PENDING_TIMEOUT,
+            Outcome::Unexpected => {}
+            outcome => return Ok(outcome),
+        }
+    }
+}
+
+// The data of a positive response, or why there was none
+pub fn positive(outcome: Outcome, service: &str) -> Result<Vec<u8>, String> {
+    match outcome {
+        Outcome::Positive(data) => Ok(data),
+        Outcome::Negative(nrc) => Err(format!("{service} was rejected with NRC {nrc:#04x}")),
+        _ => Err(format!("No response to {service}")),
+    }
+}
+
+// Unlock the ECU with SecurityAccess, after entering the configured
+// diagnostic session
+pub async fn unlock(
+    socket: &IsoTpSocket,
+    config: &'static SecurityAccessConfig,
+    wait: Duration,
+) -> Result<(), String> {
+    if let Some(session) = config.session {
+        let outcome = transact(socket, &[SID_DIAGNOSTIC_SESSION_CONTROL, session], wait).await?;
+        positive(outcome, "DiagnosticSessionControl")?;
+    }
+    let level = config.level.unwrap_or(1);
+    let outcome = transact(socket, &[SID_SECURITY_ACCESS, level], wait).await?;
+    let seed = positive(outcome, "SecurityAccess")?;
+    // A zero seed means that the ECU is already unlocked
+    if seed.iter().all(|b| *b == 0) {
+        return Ok(());
+    }
+    let key = spawn_blocking(move || key_algorithm(config)?.key(level, &seed))
+        .await
+        .map_err(|e| e.to_string())??;
+    let mut message = vec![SID_SECURITY_ACCESS, level + 1];
+    message.extend(key);
+    let outcome = transact(socket, &message, wait).await?;
+    positive(outcome, "SecurityAccess")?;
+    Ok(())
+}
+
+pub fn security_access(port: &str) -> Option<&'static SecurityAccessConfig> {
+    CONFIG
+        .can
+        .iter()
+        .flat_map(|c| c.security_access.iter().flatten())
+        .find(|a| a.port == port)
+}
+
+fn port_error(request: &UdsRequest) -> Option<String> {
+    let ports = CONFIG.can.iter().flat_map(|c| c.ports.iter().flatten());
+    match ports.into_iter().find(|p| p.name == request.port) {
+        None => Some(format!("{} is not a configured CAN port", request.port)),
+        Some(p) if p.listen_only != Some(false) => {
+            Some(format!("{} is in listen-only mode", request.port))
+        }
+        Some(_) => None,
+    }
+}
+
+// Run a request from the server, with a response per data identifier or
+// one response with the DTCs
+async fn run_request(request: &UdsRequest) -> Vec<UdsResponse> {
+    let response = |data_identifier| UdsResponse {
+        request_id: request.request_id.clone(),
+        data_identifier,
+        ..Default::default()
+    };
+    let failed = |error: String| {
+        vec![UdsResponse {
+            error,
+            ..response(0)
+        }]
+    };
+
+    if let Some(error) = port_error(request) {
+        return failed(error);
+    }
+    let socket = match IsoTpSocket::open(&request.port, request.tx_id, request.rx_id) {
+        Ok(socket) => socket,
+        Err(e) => return failed(format!("Failed to open ISO-TP socket: {e}")),
+    };
+    let wait = match request.timeout_ms {
+        0 => DEFAULT_TIMEOUT,
+        ms => Duration::from_millis(ms as u64),
+    };
+
+    if let Some(config) = security_access(&request.port) {
+        if let Err(e) = unlock(&socket, config, wait).await {
+            return failed(e);
+        }
+    }
+
+    let mut responses = Vec::new();
+    match UdsService::from_i32(request.service) {
+        Some(UdsService::ReadDataByIdentifier) => {
+            for did in &request.data_identifiers {
+                let mut r = response(*did);
+                let [_, _, hi, lo] = did.to_be_bytes();
+                match transact(&socket, &[SID_READ_DATA_BY_IDENTIFIER, hi, lo], wait).await {
+                    Ok(Outcome::Positive(data)) => r.data = data,
+                    Ok(Outcome::Negative(nrc)) => r.negative_response_code = nrc as u32,
+                    Ok(_) => {}
+                    Err(e) => r.error = e,
+                }
+                responses.push(r);
+            }
+        }
+        Some(UdsService::ReadDtc) => {
+            let mut r = response(0);
+            let mask = request.dtc_status_mask as u8;
+            let message = [SID_READ_DTC_INFORMATION, REPORT_DTC_BY_STATUS_MASK, mask];
+            match transact(&socket, &message, wait).await {
+                Ok(Outcome::Positive(data)) => {
+                    r.dtcs = parse_dtcs(&data);
+                    r.data = data;
+                }
+                Ok(Outcome::Negative(nrc)) => r.negative_response_code = nrc as u32,
+                Ok(_) => {}
+                Err(e) => r.error = e,
+            }
+            responses.push(r);
+        }
+        None => return failed(format!("Unknown UDS service {}", request.service)),
+    }
+    responses
+}
+
+async fn send_responses(channel: Channel, responses: Vec<UdsResponse>) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let responses = Arc::new(responses);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record_all("SendUdsResponses", responses.as_slice()).await;
+        let request = Request::new(stream_batch(&responses));
+        let response = client.send_uds_responses(request).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            break;
+        }
+    }
+}
+
+// Run UDS requests from the server until it closes the session
+pub async fn run_diagnostic_session(channel: Channel) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let status = ControlStatus {
+        code: UnitControlStatus::UnitReady as i32,
+        scope: ControlScope::Diagnostics as i32,
+        outputs: Vec::new(),
+    };
+    let mut client = RemoteControlClient::with_interceptor(channel.clone(), intercept);
+    tap::record("DiagnosticStream", &status).await;
+    let mut stream = client.diagnostic_stream(status).await?.into_inner();
+
+    while let Some(request) = stream.next().await {
+        let request = request?;
+        record_contact().await;
+        println!(
+            "UDS request {} on {} to {:#x}",
+            request.request_id, request.port, request.tx_id
+        );
+        let responses = run_request(&request).await;
+        send_responses(channel.clone(), responses).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        let request = [SID_READ_DATA_BY_IDENTIFIER, 0xf1, 0x90];
+        assert_eq!(
+            parse_response(&request, &[0x62, 0xf1, 0x90, b'W', b'V']),
+            Outcome::Positive(vec![b'W', b'V'])
+        );
+        // A response for another identifier
+        assert_eq!(
+            parse_response(&request, &[0x62, 0xf1, 0x91, 0x00]),
+            Outcome::Unexpected
+        );
+        assert_eq!(
+            parse_response(&request, &[0x7f, 0x22, 0x31]),
+            Outcome::Negative(0x31)
+        );
+        assert_eq!(
+            parse_response(&request, &[0x7f, 0x22, 0x78]),
+            Outcome::Pending
+        );
+        assert_eq!(
+            parse_response(&request, &[0x7f, 0x19, 0x31]),
+            Outcome::Unexpected
+        );
+        assert_eq!(parse_response(&request, &[]), Outcome::Unexpected);
+    }
+
+    #[test]
+    fn seed() {
+        let request = [SID_SECURITY_ACCESS, 0x01];
+        assert_eq!(
+            parse_response(&request, &[0x67, 0x01, 0x12, 0x34]),
+            Outcome::Positive(vec![0x12, 0x34])
+        );
+        // The key response of another level
+        assert_eq!(parse_response(&request, &[0x67, 0x02]), Outcome::Unexpected);
+        assert_eq!(
+            positive(Outcome::Negative(0x35), "SecurityAccess").unwrap_err(),
+            "SecurityAccess was rejected with NRC 0x35"
+        );
+    }
+
+    #[test]
+    fn dtcs() {
+        let request = [SID_READ_DTC_INFORMATION, REPORT_DTC_BY_STATUS_MASK, 0x08];
+        let response = [
+            0x59, 0x02, 0xff, 0x01, 0x23, 0x45, 0x08, 0xc1, 0x00, 0x01, 0x2f,
+        ];
+        let data = match parse_response(&request, &response) {
+            Outcome::Positive(data) => data,
+            _ => panic!("Not a positive response"),
+        };
+        assert_eq!(
+            parse_dtcs(&data),
+            [
+                Dtc {
+                    code: 0x012345,
+                    status: 0x08
+                },
+                Dtc {
+                    code: 0xc10001,
+                    status: 0x2f
+                },
+            ]
+        );
+        assert!(parse_dtcs(&[]).is_empty());
+    }
+
+    #[test]
+    fn address_layout() {
+        assert_eq!(std::mem::size_of::<SockaddrCanTp>(), 24);
+        assert_eq!(can_id(0x7e0), 0x7e0);
+        assert_eq!(can_id(0x18da00f1), 0x98da00f1);
+    }
+}
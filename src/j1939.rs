@@ -0,0 +1,368 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// J1939 on CAN ports with j1939 = true. The 29-bit ID of a J1939 frame
+// holds the priority, the parameter group number (PGN) and the source
+// and destination addresses. Messages are looked up in the DBC by PGN
+// rather than by the whole ID, and messages of more than 8 bytes, which
+// are sent in several frames with the transport protocol (TP), are
+// reassembled before they are decoded. Both broadcast (TP.BAM) and
+// connection mode (TP.CM RTS/CTS) transfers are reassembled; the client
+// only listens and never answers an RTS.
+
+use can_dbc::{Message, DBC};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_EFF_MASK: u32 = 0x1fff_ffff;
+
+// PGNs of the transport protocol connection management and data transfer
+const PGN_TP_CM: u32 = 0xec00;
+const PGN_TP_DT: u32 = 0xeb00;
+
+const TP_CM_RTS: u8 = 16;
+const TP_CM_BAM: u8 = 32;
+const TP_CM_ABORT: u8 = 255;
+
+// Global destination address, used for broadcasts
+const GLOBAL_ADDRESS: u8 = 0xff;
+
+// Transfers are dropped after this long without a data packet, which is
+// the T1 timeout of J1939-21
+const TP_TIMEOUT: Duration = Duration::from_millis(750);
+
+// The fields of a J1939 ID
+#[derive(Debug, PartialEq)]
+pub struct J1939Id {
+    pub priority: u8,
+    pub pgn: u32,
+    pub source: u8,
+    // The global address for PDU2 messages, which are always broadcast
+    pub destination: u8,
+}
+
+// Split a 29-bit ID. For PDU1 messages, with a PDU format below 240, the
+// PDU specific byte is the destination address and not part of the PGN.
+pub fn parse_id(id: u32) -> J1939Id {
+    let id = id & CAN_EFF_MASK;
+    let pdu_format = (id >> 16) & 0xff;
+    let pdu_specific = (id >> 8) & 0xff;
+    let (pgn, destination) = if pdu_format < 240 {
+        ((id >> 8) & 0x3ff00, pdu_specific as u8)
+    } else {
+        ((id >> 8) & 0x3ffff, GLOBAL_ADDRESS)
+    };
+    J1939Id {
+        priority: (id >> 26) as u8,
+        pgn,
+        source: id as u8,
+        destination,
+    }
+}
+
+// The messages of a DBC by PGN. A message defined for the source address
+// of a frame is used before one of the same PGN for another source.
+pub struct Messages<'a> {
+    by_source: HashMap<(u32, u8), &'a Message>,
+    by_pgn: HashMap<u32, &'a Message>,
+}
+
+impl<'a> Messages<'a> {
+    // Only messages with extended IDs are J1939 messages
+    pub fn new(dbc: &'a DBC) -> Messages<'a> {
+        let mut by_source = HashMap::new();
+        let mut by_pgn = HashMap::new();
+        for message in dbc.messages() {
+            let id = message.message_id().0;
+            if id & CAN_EFF_FLAG == 0 {
+                continue;
+            }
+            let id = parse_id(id);
+            by_source.entry((id.pgn, id.source)).or_insert(message);
+            by_pgn.entry(id.pgn).or_insert(message);
+        }
+        Messages { by_source, by_pgn }
+    }
+
+    pub fn get(&self, id: &J1939Id) -> Option<&'a Message> {
+        self.by_source
+            .get(&(id.pgn, id.source))
+            .or_else(|| self.by_pgn.get(&id.pgn))
+            .copied()
+    }
+}
+
+// A frame after transport protocol handling
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    // A frame that is not part of the transport protocol
+    Single,
+    // A transport protocol frame of a transfer that is not complete yet
+    Consumed,
+    // A reassembled message with its PGN
+    Complete(u32, Vec<u8>),
+}
+
+// A transfer in progress
+struct Transfer {
+    pgn: u32,
+    size: usize,
+    packets: u8,
+    next: u8,
+    data: Vec<u8>,
+    last: Instant,
+}
+
+// Reassembly of transport protocol transfers, by source and destination
+#[derive(Default)]
+pub struct Reassembler {
+    transfers: HashMap<(u8, u8), Transfer>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    pub fn handle(&mut self, id: &J1939Id, data: &[u8], now: Instant) -> Packet {
+        self.transfers
+            .retain(|_, t| now.duration_since(t.last) <= TP_TIMEOUT);
+
+        match id.pgn {
+            PGN_TP_CM => {
+                self.connection_management(id, data, now);
+                Packet::Consumed
+            }
+            PGN_TP_DT => match self.data_transfer(id, data, now) {
+                Some((pgn, data)) => Packet::Complete(pgn, data),
+                None => Packet::Consumed,
+            },
+            _ => Packet::Single,
+        }
+    }
+
+    fn connection_management(&mut self, id: &J1939Id, data: &[u8], now: Instant) {
+        if data.len() < 8 {
+            return;
+        }
+        let key = (id.source, id.destination);
+        let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        match data[0] {
+            TP_CM_RTS | TP_CM_BAM => {
+                let size = u16::from_le_bytes([data[1], data[2]]) as usize;
+                let packets = data[3];
+                // A transfer is at most 255 packets of 7 bytes
+                if packets == 0 || size > 7 * packets as usize {
+                    self.transfers.remove(&key);
+                    return;
+                }
+                self.transfers.insert(
+                    key,
+                    Transfer {
+                        pgn,
+                        size,
+                        packets,
+                        next: 1,
+                        data: Vec::with_capacity(7 * packets as usize),
+                        last: now,
+                    },
+                );
+            }
+            // Either side may abort a connection
+            TP_CM_ABORT => {
+                self.transfers.remove(&key);
+                self.transfers.remove(&(id.destination, id.source));
+            }
+            // CTS and the acknowledgement are sent by the receiver
+            _ => {}
+        }
+    }
+
+    fn data_transfer(&mut self, id: &J1939Id, data: &[u8], now: Instant) -> Option<(u32, Vec<u8>)> {
+        let key = (id.source, id.destination);
+        let transfer = self.transfers.get_mut(&key)?;
+        let (&sequence, payload) = data.split_first()?;
+
+        // A retransmission after a CTS repeats packets that were received
+        if sequence < transfer.next {
+            transfer.last = now;
+            return None;
+        }
+        if sequence != transfer.next {
+            self.transfers.remove(&key);
+            return None;
+        }
+        transfer
+            .data
+            .extend_from_slice(&payload[..payload.len().min(7)]);
+        transfer.next = transfer.next.wrapping_add(1);
+        transfer.last = now;
+
+        if sequence < transfer.packets {
+            return None;
+        }
+        let mut transfer = self.transfers.remove(&key)?;
+        if transfer.data.len() < transfer.size {
+            return None;
+        }
+        transfer.data.truncate(transfer.size);
+        Some((transfer.pgn, transfer.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids() {
+        // EEC1 from the engine, a PDU2 broadcast
+        assert_eq!(
+            parse_id(0x0cf00400),
+            J1939Id {
+                priority: 3,
+                pgn: 0xf004,
+                source: 0x00,
+                destination: GLOBAL_ADDRESS,
+            }
+        );
+        // TP.CM from 0x17 to 0x00, a PDU1 message
+        assert_eq!(
+            parse_id(0x1cec0017 | CAN_EFF_FLAG),
+            J1939Id {
+                priority: 7,
+                pgn: PGN_TP_CM,
+                source: 0x17,
+                destination: 0x00,
+            }
+        );
+    }
+
+    fn cm(source: u8, destination: u8) -> J1939Id {
+        parse_id(0x1cec0000 | (destination as u32) << 8 | source as u32)
+    }
+
+    fn dt(source: u8, destination: u8) -> J1939Id {
+        parse_id(0x1ceb0000 | (destination as u32) << 8 | source as u32)
+    }
+
+    #[test]
+    fn broadcast_transfer() {
+        let mut tp = Reassembler::new();
+        let now = Instant::now();
+        // 10 bytes of PGN 0xfeca (DM1) in 2 packets
+        let bam = [TP_CM_BAM, 10, 0, 2, 0xff, 0xca, 0xfe, 0x00];
+        assert_eq!(tp.handle(&cm(0, 0xff), &bam, now), Packet::Consumed);
+        let first = [1, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(tp.handle(&dt(0, 0xff), &first, now), Packet::Consumed);
+        let second = [2, 8, 9, 10, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(
+            tp.handle(&dt(0, 0xff), &second, now),
+            Packet::Complete(0xfeca, (1..=10).collect())
+        );
+        assert_eq!(
+            tp.handle(&parse_id(0x0cf00400), &[0; 8], now),
+            Packet::Single
+        );
+    }
+
+    #[test]
+    fn connection_mode_transfer() {
+        let mut tp = Reassembler::new();
+        let now = Instant::now();
+        let rts = [TP_CM_RTS, 9, 0, 2, 2, 0x00, 0xef, 0x00];
+        tp.handle(&cm(0x17, 0x00), &rts, now);
+        // The CTS of the receiver
+        let cts = [17, 2, 1, 0xff, 0xff, 0x00, 0xef, 0x00];
+        assert_eq!(tp.handle(&cm(0x00, 0x17), &cts, now), Packet::Consumed);
+        tp.handle(&dt(0x17, 0x00), &[1, 1, 2, 3, 4, 5, 6, 7], now);
+        // A repeated packet is ignored
+        tp.handle(&dt(0x17, 0x00), &[1, 1, 2, 3, 4, 5, 6, 7], now);
+        // Packets of another transfer are not mixed in
+        tp.handle(&dt(0x18, 0x00), &[2, 0, 0, 0, 0, 0, 0, 0], now);
+        assert_eq!(
+            tp.handle(
+                &dt(0x17, 0x00),
+                &[2, 8, 9, 0xff, 0xff, 0xff, 0xff, 0xff],
+                now
+            ),
+            Packet::Complete(0xef00, (1..=9).collect())
+        );
+    }
+
+    #[test]
+    fn aborted_and_stale_transfers() {
+        let mut tp = Reassembler::new();
+        let now = Instant::now();
+        let bam = [TP_CM_BAM, 9, 0, 2, 0xff, 0xca, 0xfe, 0x00];
+
+        tp.handle(&cm(0, 0xff), &bam, now);
+        tp.handle(&dt(0, 0xff), &[1, 1, 2, 3, 4, 5, 6, 7], now);
+        tp.handle(
+            &cm(0, 0xff),
+            &[TP_CM_ABORT, 0, 0, 0, 0, 0xca, 0xfe, 0x00],
+            now,
+        );
+        let last = [2, 8, 9, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(tp.handle(&dt(0, 0xff), &last, now), Packet::Consumed);
+
+        tp.handle(&cm(0, 0xff), &bam, now);
+        tp.handle(&dt(0, 0xff), &[1, 1, 2, 3, 4, 5, 6, 7], now);
+        let late = now + TP_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(tp.handle(&dt(0, 0xff), &last, late), Packet::Consumed);
+
+        // A missed packet ends the transfer
+        tp.handle(&cm(0, 0xff), &bam, now);
+        tp.handle(&dt(0, 0xff), &last, now);
+        assert!(tp.transfers.is_empty());
+    }
+
+    #[test]
+    fn messages_by_pgn() {
+        let dbc = DBC::try_from(
+            r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: ECU
+
+BO_ 2364540158 EEC1: 8 Vector__XXX
+ SG_ EngineSpeed : 24|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+
+BO_ 2565866497 EEC1_Retarder: 8 Vector__XXX
+ SG_ RetarderSpeed : 24|16@1+ (0.125,0) [0|8031.875] "rpm" Vector__XXX
+
+BO_ 256 Classic: 8 Vector__XXX
+ SG_ Level : 0|8@1+ (1,0) [0|0] "" Vector__XXX
+"#,
+        )
+        .unwrap();
+        let messages = Messages::new(&dbc);
+        let name = |id| {
+            messages
+                .get(&parse_id(id))
+                .map(|m| m.message_name().as_str())
+        };
+        assert_eq!(name(0x0cf00403), Some("EEC1"));
+        assert_eq!(name(0x18f00401), Some("EEC1_Retarder"));
+        assert_eq!(name(0x0cf00503), None);
+    }
+}
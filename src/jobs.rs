@@ -0,0 +1,194 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Recurring local jobs, e.g. a full snapshot of the signals every
+// morning or a weekly self-test, run at a local time on given days. A
+// job runs at most once per minute, and jobs that were due while the
+// client was not running are not made up for. Commands and the state,
+// which waits for the server, run in the background, so that a long
+// self-test or an offline unit does not hold up other jobs.
+//
+// Since the config can be pushed by the server, commands are only run
+// if that has been allowed locally, on the command line of the client,
+// and a command that runs for too long is killed.
+
+use super::inventory::request_inventory;
+use super::net::send_state;
+use super::periodic::send_snapshot;
+use lib::{
+    schedule::{self, LocalTime},
+    JobAction, JobConfig, CONFIG,
+};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout};
+use tonic::transport::Channel;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// Minutes that are made up for when a check is late; a longer gap is a
+// change of the clock
+const MAX_CATCH_UP: u32 = 10;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+// Whether a job is due in the minute of the given time
+fn is_due(job: &JobConfig, t: LocalTime) -> bool {
+    let days = job.days.as_deref().unwrap_or_default();
+    let on = days.is_empty()
+        || days
+            .iter()
+            .any(|d| schedule::parse_day(d) == Some(t.weekday));
+    on && schedule::parse_time(&job.at) == Some(t.minute)
+}
+
+// The minutes after the last check up to and including t, so that no
+// job is skipped when a check is late. After a change of the clock only
+// t itself is checked.
+fn minutes_since(last: Option<LocalTime>, t: LocalTime) -> Vec<LocalTime> {
+    let of_week = |t: LocalTime| t.weekday * MINUTES_PER_DAY + t.minute;
+    let now = of_week(t);
+    let elapsed = match last {
+        Some(last) => (now + MINUTES_PER_WEEK - of_week(last)) % MINUTES_PER_WEEK,
+        None => 1,
+    };
+    if elapsed > MAX_CATCH_UP {
+        return vec![t];
+    }
+    (0..elapsed)
+        .rev()
+        .map(|ago| {
+            let m = (now + MINUTES_PER_WEEK - ago) % MINUTES_PER_WEEK;
+            LocalTime {
+                weekday: m / MINUTES_PER_DAY,
+                minute: m % MINUTES_PER_DAY,
+            }
+        })
+        .collect()
+}
+
+static COMMANDS_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+// Allow jobs to run commands, which is set on the command line
+pub fn allow_commands() {
+    COMMANDS_ALLOWED.store(true, Ordering::Relaxed);
+}
+
+fn run_command(name: String, command: Vec<String>, timeout_s: Option<u64>) {
+    const DEFAULT_TIMEOUT_S: u64 = 3600;
+
+    if !COMMANDS_ALLOWED.load(Ordering::Relaxed) {
+        eprintln!("Job {name} not run, commands are not allowed (--allow-job-commands)");
+        return;
+    }
+    let limit = Duration::from_secs(timeout_s.unwrap_or(DEFAULT_TIMEOUT_S));
+    tokio::spawn(async move {
+        let status = Command::new(&command[0])
+            .args(&command[1..])
+            .kill_on_drop(true)
+            .status();
+        match timeout(limit, status).await {
+            Ok(Ok(status)) if status.success() => println!("Job {name} done"),
+            Ok(Ok(status)) => eprintln!("Job {name} failed: {status}"),
+            Ok(Err(e)) => eprintln!("Job {name} failed to run {}: {e}", command[0]),
+            Err(_) => eprintln!("Job {name} killed after {limit:?}"),
+        }
+    });
+}
+
+async fn run(job: &JobConfig, channel: Channel) {
+    println!("Running job {}", job.name);
+    match job.action {
+        JobAction::Snapshot => send_snapshot().await,
+        JobAction::State => {
+            // Retried until the server is reached
+            tokio::spawn(send_state(channel));
+        }
+        JobAction::Inventory => request_inventory(),
+        JobAction::Command => {
+            run_command(
+                job.name.clone(),
+                job.command.clone().unwrap_or_default(),
+                job.timeout_s,
+            );
+        }
+    }
+}
+
+pub async fn job_scheduler(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let jobs = CONFIG.jobs.as_ref().unwrap();
+    let mut last = None;
+    loop {
+        let t = schedule::local_time();
+        for minute in minutes_since(last, t) {
+            for job in jobs.iter().filter(|job| is_due(job, minute)) {
+                run(job, channel.clone()).await;
+            }
+        }
+        last = Some(t);
+        sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_jobs() {
+        let job = |at: &str, days: Option<Vec<&str>>| JobConfig {
+            name: "job".to_string(),
+            at: at.to_string(),
+            days: days.map(|d| d.into_iter().map(String::from).collect()),
+            action: JobAction::State,
+            command: None,
+            timeout_s: None,
+        };
+        let monday_six = LocalTime {
+            weekday: 1,
+            minute: 6 * 60,
+        };
+        assert!(is_due(&job("06:00", None), monday_six));
+        assert!(!is_due(&job("06:01", None), monday_six));
+        assert!(is_due(&job("06:00", Some(vec!["sun", "mon"])), monday_six));
+        assert!(!is_due(&job("06:00", Some(vec!["sun"])), monday_six));
+    }
+
+    #[test]
+    fn late_checks_catch_up() {
+        let at = |weekday, minute| LocalTime { weekday, minute };
+        let minutes = |last, t| -> Vec<_> {
+            minutes_since(last, t)
+                .iter()
+                .map(|t| (t.weekday, t.minute))
+                .collect()
+        };
+        assert_eq!(minutes(None, at(1, 360)), vec![(1, 360)]);
+        assert_eq!(minutes(Some(at(1, 360)), at(1, 360)), vec![]);
+        // A check that was held up for two minutes
+        assert_eq!(
+            minutes(Some(at(1, 358)), at(1, 360)),
+            vec![(1, 359), (1, 360)]
+        );
+        // Across the end of the week
+        assert_eq!(minutes(Some(at(6, 1439)), at(0, 0)), vec![(0, 0)]);
+        // A change of the clock
+        assert_eq!(minutes(Some(at(1, 300)), at(1, 360)), vec![(1, 360)]);
+    }
+}
@@ -0,0 +1,448 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Journal of the commands executed on behalf of the server. Commands
+// with an ID from the server are only executed once, even across
+// reconnects and restarts, so that a command the server retries because
+// it thinks it was lost is not executed again.
+//
+// The output commands of remote control sessions are written before the
+// output is set, as pending, and again once the output was set or
+// failed. Until a session ends with the outputs back at their defaults,
+// these entries tell the client which outputs a session had set if it
+// stopped during the session.
+
+use super::utils::write_atomic;
+use lazy_static::lazy_static;
+use lib::{
+    conf_dir, history,
+    host_insight::{OutputCommandRecord, OutputCommandStatus},
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+// Number of command IDs remembered
+const MAX_ENTRIES: usize = 1000;
+// Output commands reported after a recovery, the latest ones
+pub const MAX_REPORTED: usize = 100;
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+struct Journal {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    lines: usize,
+    // Output commands were written since the last session end
+    session_open: bool,
+}
+
+impl Journal {
+    // Returns false if the ID is already remembered
+    fn remember(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > MAX_ENTRIES {
+            if let Some(id) = self.order.pop_front() {
+                self.ids.remove(&id);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Output {
+    Digital(bool),
+    Analog(f64),
+}
+
+lazy_static! {
+    static ref JOURNAL: Mutex<Journal> = Mutex::new(load_journal());
+}
+
+fn journal_path() -> PathBuf {
+    PathBuf::from(format!("{}/commands.journal", conf_dir()))
+}
+
+// Each line is "<unix time in ms> <command ID or -> <command>", or for
+// the output commands of remote control sessions one of
+//   "<time> <ID or -> pending <sequence> <digital|analog> <value> <output>"
+//   "<time> <ID or -> applied <sequence> <digital|analog> <value>"
+//   "<time> <ID or -> failed <sequence>"
+//   "<time> - session_end"
+// An output command only counts as executed once it was applied.
+fn load_journal() -> Journal {
+    let mut journal = Journal {
+        ids: HashSet::new(),
+        order: VecDeque::new(),
+        lines: 0,
+        session_open: false,
+    };
+    let content = fs::read_to_string(journal_path()).unwrap_or_default();
+    for line in content.lines() {
+        journal.lines += 1;
+        let fields: Vec<_> = line.splitn(4, ' ').collect();
+        let id = match fields[..] {
+            [_, _, "session_end"] => {
+                journal.session_open = false;
+                continue;
+            }
+            [_, _, "pending" | "failed", _] => {
+                journal.session_open = true;
+                continue;
+            }
+            [_, id, "applied", _] => {
+                journal.session_open = true;
+                id
+            }
+            [_, id, ..] => id,
+            _ => continue,
+        };
+        if id != "-" {
+            journal.remember(id);
+        }
+    }
+    journal
+}
+
+// Append a line and return the number of lines, which is less after a
+// compaction. Many commands end with a restart, so make sure the entry
+// is on disk.
+fn append_line(line: &str, lines: usize) -> io::Result<usize> {
+    let path = journal_path();
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    f.write_all(line.as_bytes())?;
+    f.sync_data()?;
+
+    // Keep the file from growing without bounds
+    if lines < 2 * MAX_ENTRIES {
+        return Ok(lines + 1);
+    }
+    let compacted = compact(&fs::read_to_string(&path)?);
+    write_atomic(&path, compacted.as_bytes())?;
+    Ok(compacted.lines().count())
+}
+
+async fn append(journal: &mut Journal, entry: String) {
+    let line = format!("{} {entry}\n", history::unix_millis(SystemTime::now()));
+    let lines = journal.lines;
+    match tokio::task::spawn_blocking(move || append_line(&line, lines)).await {
+        Ok(Ok(lines)) => journal.lines = lines,
+        Ok(Err(e)) => eprintln!("Failed to write command journal: {e}"),
+        Err(e) => eprintln!("Failed to write command journal: {e}"),
+    }
+}
+
+fn id_field(command_id: &str) -> &str {
+    if command_id.is_empty() {
+        "-"
+    } else {
+        command_id
+    }
+}
+
+// Whether a command with this ID has already been executed
+pub async fn executed(command_id: &str) -> bool {
+    !command_id.is_empty() && JOURNAL.lock().await.ids.contains(command_id)
+}
+
+// Record a command once it has been executed, so that a command that
+// failed is executed again if the server retries it. Returns false if a
+// command with the same ID has already been recorded.
+pub async fn accept(command_id: &str, command: &str) -> bool {
+    let mut journal = JOURNAL.lock().await;
+    if !command_id.is_empty() && !journal.remember(command_id) {
+        return false;
+    }
+    append(&mut journal, format!("{} {command}", id_field(command_id))).await;
+    true
+}
+
+fn format_output(output: Output) -> String {
+    match output {
+        Output::Digital(active) => format!("digital {active}"),
+        Output::Analog(setpoint) => format!("analog {setpoint}"),
+    }
+}
+
+// Record an output command before the output is set, and return the
+// sequence number to record its outcome with
+pub async fn begin(command_id: &str, output_name: &str, output: Output) -> u64 {
+    let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let mut journal = JOURNAL.lock().await;
+    journal.session_open = true;
+    let entry = format!(
+        "{} pending {seq} {} {output_name}",
+        id_field(command_id),
+        format_output(output)
+    );
+    append(&mut journal, entry).await;
+    seq
+}
+
+// Record that the output was set, to the given value after clamping.
+// The command then counts as executed.
+pub async fn applied(seq: u64, command_id: &str, output: Output) {
+    let mut journal = JOURNAL.lock().await;
+    if !command_id.is_empty() {
+        journal.remember(command_id);
+    }
+    let entry = format!(
+        "{} applied {seq} {}",
+        id_field(command_id),
+        format_output(output)
+    );
+    append(&mut journal, entry).await;
+}
+
+pub async fn failed(seq: u64) {
+    let mut journal = JOURNAL.lock().await;
+    append(&mut journal, format!("- failed {seq}")).await;
+}
+
+async fn close_session(journal: &mut Journal) {
+    if journal.session_open {
+        append(journal, "- session_end".to_string()).await;
+        journal.session_open = false;
+    }
+}
+
+// The session ended with the outputs at their defaults
+pub async fn end_session() {
+    close_session(&mut *JOURNAL.lock().await).await;
+}
+
+// The output commands of a session that the client stopped during, in
+// order with their outcome. The session is ended, so that they are only
+// returned once. A command that is still pending may or may not have
+// been applied.
+pub async fn take_interrupted_session() -> Vec<OutputCommandRecord> {
+    let mut journal = JOURNAL.lock().await;
+    if !journal.session_open {
+        return Vec::new();
+    }
+    let content = fs::read_to_string(journal_path()).unwrap_or_default();
+    let lines: Vec<&str> = content.lines().collect();
+    let records = parse_session(&lines[session_start(&lines)..])
+        .into_iter()
+        .map(|(_, record)| record)
+        .collect();
+    close_session(&mut journal).await;
+    records
+}
+
+// The index of the first line after the last session end
+fn session_start(lines: &[&str]) -> usize {
+    lines
+        .iter()
+        .rposition(|line| line.split(' ').nth(2) == Some("session_end"))
+        .map_or(0, |i| i + 1)
+}
+
+// The sequence number of an output command line
+fn output_seq(line: &str) -> Option<&str> {
+    let fields: Vec<_> = line.splitn(5, ' ').collect();
+    match fields[..] {
+        [_, _, "pending" | "applied" | "failed", seq, ..] => Some(seq),
+        _ => None,
+    }
+}
+
+fn parse_output(kind: &str, value: &str) -> Option<Output> {
+    match kind {
+        "digital" => Some(Output::Digital(value.parse().ok()?)),
+        "analog" => Some(Output::Analog(value.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn set_output(record: &mut OutputCommandRecord, output: Output) {
+    match output {
+        Output::Digital(active) => {
+            record.active = active;
+            record.setpoint = None;
+        }
+        Output::Analog(setpoint) => {
+            record.active = true;
+            record.setpoint = Some(setpoint);
+        }
+    }
+}
+
+// The output commands of a session with their sequence numbers
+fn parse_session<'a>(lines: &[&'a str]) -> Vec<(&'a str, OutputCommandRecord)> {
+    let mut records = Vec::new();
+    let mut by_seq: HashMap<&str, usize> = HashMap::new();
+    for line in lines {
+        let fields: Vec<_> = line.splitn(7, ' ').collect();
+        match fields[..] {
+            [_, command_id, "pending", seq, kind, value, cmd] => {
+                let output = match parse_output(kind, value) {
+                    Some(output) => output,
+                    None => continue,
+                };
+                let mut record = OutputCommandRecord {
+                    cmd: cmd.to_string(),
+                    command_id: match command_id {
+                        "-" => String::new(),
+                        id => id.to_string(),
+                    },
+                    ..Default::default()
+                };
+                set_output(&mut record, output);
+                by_seq.insert(seq, records.len());
+                records.push((seq, record));
+            }
+            [_, _, "applied", seq, kind, value] => {
+                let (i, output) = match (by_seq.get(seq), parse_output(kind, value)) {
+                    (Some(i), Some(output)) => (i, output),
+                    _ => continue,
+                };
+                set_output(&mut records[*i].1, output);
+                records[*i]
+                    .1
+                    .set_status(OutputCommandStatus::OutputCommandApplied);
+            }
+            [_, _, "failed", seq] => {
+                if let Some(i) = by_seq.get(seq) {
+                    records[*i]
+                        .1
+                        .set_status(OutputCommandStatus::OutputCommandFailed);
+                }
+            }
+            _ => {}
+        }
+    }
+    records
+}
+
+// Keep the latest entries, and the output commands of an open session
+// that a recovery uses: the latest commands that are reported, those
+// still pending and the last applied command of each output
+fn compact(content: &str) -> String {
+    let lines: Vec<&str> = content.lines().collect();
+    let start = session_start(&lines);
+    let records = parse_session(&lines[start..]);
+    let mut needed: HashSet<&str> = records
+        .iter()
+        .rev()
+        .take(MAX_REPORTED)
+        .map(|(seq, _)| *seq)
+        .collect();
+    let mut last: HashMap<&str, &str> = HashMap::new();
+    for (seq, record) in &records {
+        match record.status() {
+            OutputCommandStatus::OutputCommandPending => {
+                needed.insert(seq);
+            }
+            OutputCommandStatus::OutputCommandApplied => {
+                last.insert(&record.cmd, seq);
+            }
+            _ => {}
+        }
+    }
+    needed.extend(last.into_values());
+
+    let recent = lines.len().saturating_sub(MAX_ENTRIES);
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(i, line)| {
+            *i >= recent || (*i >= start && output_seq(line).is_some_and(|s| needed.contains(s)))
+        })
+        .map(|(_, line)| format!("{line}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Vec<OutputCommandRecord> {
+        let lines: Vec<&str> = content.lines().collect();
+        parse_session(&lines[session_start(&lines)..])
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect()
+    }
+
+    #[test]
+    fn parses_session() {
+        let records = parse(
+            "1 - pending 0 digital false - Horn\n\
+             2 - session_end\n\
+             3 c0 config_update\n\
+             4 c1 pending 0 digital true Pump\n\
+             5 c1 applied 0 digital true\n\
+             6 - pending 1 analog 150 Fan speed\n\
+             7 - applied 1 analog 100\n\
+             8 c3 pending 2 digital false Pump\n\
+             9 - failed 2\n\
+             10 c4 pending 3 digital true Horn\n",
+        );
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].command_id, "c1");
+        assert_eq!(records[1].cmd, "Fan speed");
+        assert_eq!(records[1].command_id, "");
+        assert_eq!(records[1].setpoint, Some(100.0));
+        assert_eq!(
+            records[2].status(),
+            OutputCommandStatus::OutputCommandFailed
+        );
+        assert_eq!(
+            records[3].status(),
+            OutputCommandStatus::OutputCommandPending
+        );
+    }
+
+    #[test]
+    fn compaction_keeps_what_a_recovery_needs() {
+        let mut journal = "1 - session_end\n\
+                           2 c0 pending 0 digital true Pump\n2 c0 applied 0 digital true\n\
+                           3 c1 pending 1 digital true Horn\n"
+            .to_string();
+        for seq in 2..2 + MAX_ENTRIES as u64 {
+            journal +=
+                &format!("4 - pending {seq} digital true Fan\n4 - applied {seq} digital true\n");
+        }
+        let compacted = compact(&journal);
+        assert_eq!(compacted.lines().count(), MAX_ENTRIES + 3);
+        let records = parse(&compacted);
+        assert_eq!(records.len(), MAX_ENTRIES / 2 + 2);
+        assert_eq!(records[0].cmd, "Pump");
+        assert_eq!(
+            records[1].status(),
+            OutputCommandStatus::OutputCommandPending
+        );
+
+        // Nothing before the end of the last session is needed
+        journal += "5 - session_end\n";
+        assert_eq!(compact(&journal).lines().count(), MAX_ENTRIES);
+        assert!(parse(&compact(&journal)).is_empty());
+    }
+}
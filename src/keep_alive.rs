@@ -0,0 +1,159 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Periodic transmission of keep-alive frames, e.g. tester present, for
+// ECUs that only keep broadcasting data while they see them. Each port
+// has a scheduler of its own, which pauses while the controller is bus
+// off, since the frames cannot be sent then anyway, and resumes when the
+// controller has restarted.
+
+use futures::future::{select_all, try_join_all};
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use lib::KeepAliveFrame;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_socketcan::{CANFrame, CANSocket};
+
+// Error classes of an error frame, from linux/can/error.h
+const CAN_ERR_BUSOFF: u32 = 0x40;
+const CAN_ERR_RESTARTED: u32 = 0x100;
+
+// Whether the controller is bus off after an error frame
+fn is_bus_off(errors: u32, bus_off: bool) -> bool {
+    if errors & CAN_ERR_RESTARTED != 0 {
+        false
+    } else {
+        bus_off || errors & CAN_ERR_BUSOFF != 0
+    }
+}
+
+// The bus state after a read from the socket. A read error, e.g.
+// ENETDOWN while the port is brought up again after a bus off, is
+// skipped like in can_monitor; bringing the port up restarts the
+// controller.
+fn read_bus_off(read: io::Result<CANFrame>, bus_off: bool) -> bool {
+    match read {
+        Ok(frame) => is_bus_off(frame.err(), bus_off),
+        Err(_) => false,
+    }
+}
+
+pub async fn keep_alive_sender(frames: &[KeepAliveFrame]) -> Result<(), Box<dyn Error>> {
+    let mut by_port: BTreeMap<&str, Vec<&KeepAliveFrame>> = BTreeMap::new();
+    for frame in frames {
+        by_port.entry(&frame.port).or_default().push(frame);
+    }
+    try_join_all(
+        by_port
+            .into_iter()
+            .map(|(port, frames)| send_port(port, frames)),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Send the keep-alive frames of one port
+async fn send_port(
+    port: &str,
+    frames: Vec<&KeepAliveFrame>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut socket = CANSocket::open(port)?;
+    // Only the bus state is read from the socket
+    socket.filter_drop_all()?;
+    socket.set_error_filter(CAN_ERR_BUSOFF | CAN_ERR_RESTARTED)?;
+
+    let can_frames = frames
+        .iter()
+        .map(|f| CANFrame::new(f.id, &f.data, false, false))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut ticks: Vec<_> = frames
+        .iter()
+        .map(|f| {
+            let mut ticks = interval(Duration::from_millis(f.interval_ms));
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticks
+        })
+        .collect();
+    println!("Sending {} keep-alive frames on {}", frames.len(), port);
+
+    let mut bus_off = false;
+    // Whether the last frame failed, to only log the first failure
+    let mut failing = false;
+    loop {
+        let due = select_all(ticks.iter_mut().map(|t| t.tick().boxed()));
+        tokio::select! {
+            read = socket.next() => {
+                let read = match read {
+                    Some(read) => read,
+                    None => return Ok(()),
+                };
+                let was_bus_off = bus_off;
+                bus_off = read_bus_off(read, bus_off);
+                if bus_off != was_bus_off {
+                    match bus_off {
+                        true => eprintln!("{port} is bus off, pausing keep-alive frames"),
+                        false => println!("{port} has restarted, resuming keep-alive frames"),
+                    }
+                }
+            }
+            (_, index, _) = due => {
+                if bus_off {
+                    continue;
+                }
+                match socket.write_frame(can_frames[index])?.await {
+                    Ok(()) => failing = false,
+                    Err(e) if !failing => {
+                        eprintln!(
+                            "Failed to send keep-alive frame {:#x} on {}: {}",
+                            frames[index].id, port, e
+                        );
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bus_state() {
+        assert!(!is_bus_off(0, false));
+        assert!(is_bus_off(CAN_ERR_BUSOFF, false));
+        // Other errors do not end a bus off
+        assert!(is_bus_off(0x04, true));
+        assert!(!is_bus_off(CAN_ERR_RESTARTED, true));
+    }
+
+    #[test]
+    fn read_errors_are_skipped() {
+        let bus_off = CANFrame::new(CAN_ERR_BUSOFF, &[], false, true).unwrap();
+        assert!(read_bus_off(Ok(bus_off), false));
+        let down = io::Error::from_raw_os_error(libc::ENETDOWN);
+        assert!(!read_bus_off(Err(down), true));
+    }
+}
@@ -17,9 +17,14 @@
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
 use lazy_static::lazy_static;
+use serde::de::Error as _;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 pub enum ExitCodes {
     Enoent = 2,     // No such file or directory
@@ -27,27 +32,445 @@ pub enum ExitCodes {
     SwUpdate = 100, // Software upgrade
 }
 
+// Status codes reported in the heartbeat
+#[derive(Clone, Copy)]
+pub enum StatusCodes {
+    Ok = 0,
+    StartupSequenceFailed = 1, // Digital out startup sequence did not complete
+    SpoolFailed = 2,           // Spool segments cannot be written, data kept in memory
+    StorageNearFull = 3,       // Filesystem nearly full despite eviction
+    StorageEvicted = 4,        // Client files evicted to free filesystem space
+    SafeMode = 5,              // Crash loop detected, running in safe mode
+    LocalOverride = 6,         // Outputs overridden by the local override input
+    IdentityUpdateFailed = 7,  // New identity rejected or rolled back
+    CertificateExpiring = 8,   // Client certificate expires soon
+    TunnelOpen = 9,            // Support tunnel open
+    LoadShedding = 10,         // Data shed because of CPU load or event loop lag
+    TaskStopped = 11,          // A task failed in a way a restart would not fix
+    AttentionMissed = 12,      // A lone worker attention check was not acknowledged
+}
+
 pub mod host_insight {
     tonic::include_proto!("host_insight");
 }
 
+pub mod cache;
+pub mod decode;
+pub mod error;
+pub mod history;
+pub mod identity;
+pub mod schedule;
+
 #[derive(Deserialize, Serialize)]
 pub struct Identity {
     pub uid: String,
     pub domain: String,
+    // Selects the profile blocks of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
+    pub analog_out: Option<AnalogOutConfig>,
+    pub attention: Option<AttentionConfig>,
+    pub backpressure: Option<Vec<BackpressureLevel>>,
+    // The names of the profiles in the config file
+    #[serde(skip)]
+    pub profiles: Vec<String>,
+    #[serde(skip)]
+    pub schedules: Vec<schedule::Schedule>,
+    // The schedules that were active when the config was loaded
+    #[serde(skip)]
+    pub active_schedules: Vec<String>,
+    // SHA-256 of the config after includes, profile and schedules
+    #[serde(skip)]
+    pub effective_hash: String,
+    pub bulk: Option<BulkConfig>,
     pub can: Option<CanConfig>,
+    pub debug_tap: Option<DebugTapConfig>,
     pub digital_in: Option<DigitalInConfig>,
     pub digital_out: Option<DigitalOutConfig>,
+    pub geo: Option<GeoConfig>,
+    pub gnss: Option<GnssConfig>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    pub history: Option<HistoryConfig>,
+    pub identity: Option<IdentityConfig>,
+    pub inventory: Option<InventoryConfig>,
+    pub jobs: Option<Vec<JobConfig>>,
+    pub load_shedding: Option<LoadSheddingConfig>,
+    pub network: Option<NetworkConfig>,
+    pub opcua: Option<OpcUaConfig>,
+    pub outputs: Option<OutputsConfig>,
+    #[serde(rename = "override")]
+    pub output_override: Option<OverrideConfig>,
+    pub routes: Option<Vec<RouteConfig>>,
+    pub scrub: Option<ScrubConfig>,
+    pub spool: Option<SpoolConfig>,
+    pub stats: Option<StatsConfig>,
+    pub storage: Option<StorageConfig>,
+    pub test_signals: Option<TestSignalsConfig>,
     pub time: Time,
+    pub tls: Option<TlsConfig>,
+    pub transfer: Option<TransferConfig>,
+    pub tunnel: Option<TunnelConfig>,
+    pub vpn: Option<VpnConfig>,
+    pub wake: Option<WakeConfig>,
+}
+
+// What to do with the outputs that were set by a remote control session
+// when the client restarts during the session
+#[derive(Deserialize, Clone)]
+pub struct OutputsConfig {
+    pub recovery: Option<OutputRecoveryPolicy>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputRecoveryPolicy {
+    Revert,  // Leave the outputs at their defaults
+    Restore, // Set the outputs to their last commanded states
+}
+
+// A physical switch that, while active, blocks remote output commands
+// and keeps the outputs in their default states
+#[derive(Deserialize, Clone)]
+pub struct OverrideConfig {
+    pub input: String,
+}
+
+// A periodic attention check of a lone worker. The output, e.g. a
+// buzzer, is activated every interval_s until the input is activated.
+// A check that is not acknowledged within window_s is escalated.
+#[derive(Deserialize, Clone)]
+pub struct AttentionConfig {
+    pub output: String,
+    pub input: String,
+    pub interval_s: u64,
+    pub window_s: u64,
+    // A further output, e.g. a beacon, that is activated on escalation
+    pub alarm: Option<String>,
+}
+
+// Check-ins while parked, waking the unit with the RTC alarm. The unit
+// is parked while the parked input is inactive.
+#[derive(Deserialize, Clone)]
+pub struct WakeConfig {
+    pub interval_s: u64,
+    pub awake_s: Option<u64>,
+    pub rtc: Option<String>,
+    pub parked_input: String,
+    pub suspend: Option<bool>,
+}
+
+// Client certificate for mutual TLS. The certificate is reported when
+// it expires within warn_days, and renewed from renew_url if given.
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    pub warn_days: Option<u64>,
+    pub renew_url: Option<String>,
+}
+
+// Jump host for support tunnels. The key and known hosts files are
+// relative to the configuration directory.
+#[derive(Deserialize, Clone)]
+pub struct TunnelConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: String,
+    pub key_file: String,
+    pub known_hosts_file: Option<String>,
+    pub local_port: Option<u16>,
+    pub max_duration_s: Option<u64>,
+}
+
+// WireGuard interfaces whose status is reported with the statistics.
+// Settings pushed by the server are only applied if manage is set.
+#[derive(Deserialize, Clone)]
+pub struct VpnConfig {
+    pub interfaces: Vec<String>,
+    pub manage: Option<bool>,
+}
+
+// Synthetic signals for load testing the server without a vehicle. The
+// values are handled like decoded CAN signals on the given bus.
+#[derive(Deserialize, Clone)]
+pub struct TestSignalsConfig {
+    pub bus: Option<String>,
+    pub signals: Vec<TestSignal>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TestSignal {
+    pub name: String,
+    pub waveform: Waveform,
+    pub interval_ms: u64,
+    pub period_s: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Waveform {
+    Ramp, // From min to max, then restarting at min
+    Sine, // Between min and max
+    Step, // Alternating between min and max every half period
+}
+
+// Rules for personal data in the signals that are sent. The key is
+// used for hashing.
+#[derive(Deserialize, Clone)]
+pub struct ScrubConfig {
+    pub key: Option<String>,
+    pub rules: Vec<ScrubRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ScrubRule {
+    pub signal: String,
+    pub action: ScrubAction,
+    pub decimals: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrubAction {
+    Drop,  // Leave the signal out
+    Hash,  // Replace the value with a keyed hash
+    Round, // Round a float value to the given decimals
+}
+
+// A secondary endpoint that gets a copy of selected data. Without
+// sources or signals, all sources or signals match.
+#[derive(Deserialize, Clone)]
+pub struct RouteConfig {
+    pub name: String,
+    pub domain: String,
+    pub uid: String,
+    pub sources: Option<Vec<String>>,
+    pub signals: Option<Vec<String>>,
+}
+
+// UDP sinks, as host:port, that the server may point the debug tap at
+#[derive(Deserialize, Clone)]
+pub struct DebugTapConfig {
+    pub udp_sinks: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HeartbeatConfig {
+    // Signals whose latest values are sent with each heartbeat
+    pub signals: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HistoryConfig {
+    pub depth: usize,
+    pub signals: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IdentityConfig {
+    pub provider: IdentityProviderKind,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub offset: Option<u64>,
+    pub length: Option<usize>,
+    pub handle: Option<String>,
+}
+
+// An OPC UA server, e.g. of a PLC at a stationary installation, whose
+// nodes are read periodically and sent like CAN signals on the bus. Only
+// the security policy None is supported.
+#[derive(Deserialize, Clone)]
+pub struct OpcUaConfig {
+    pub endpoint: String,
+    pub security_policy: Option<OpcUaSecurityPolicy>,
+    pub bus: Option<String>,
+    pub nodes: Vec<OpcUaNode>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+pub enum OpcUaSecurityPolicy {
+    None,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OpcUaNode {
+    pub name: String,
+    // E.g. "ns=2;s=Line1.Temperature" or "i=2258"
+    pub node_id: String,
+    pub interval_ms: Option<u64>,
+    pub unit: Option<String>,
+}
+
+// Whether a string is a numeric or string OPC UA node ID, optionally
+// with a namespace, which otherwise is 0
+pub fn is_node_id(s: &str) -> bool {
+    let id = match s.strip_prefix("ns=").and_then(|s| s.split_once(';')) {
+        Some((ns, id)) if ns.parse::<u16>().is_ok() => id,
+        Some(_) => return false,
+        None => s,
+    };
+    match (id.get(..2), id.get(2..)) {
+        (Some("i="), Some(n)) => n.parse::<u32>().is_ok(),
+        (Some("s="), Some(v)) => !v.is_empty(),
+        _ => false,
+    }
+}
+
+// A recurring local job, run at a local time on the given days, or
+// every day if none are given
+#[derive(Deserialize, Clone)]
+pub struct JobConfig {
+    pub name: String,
+    pub at: String,
+    pub days: Option<Vec<String>>,
+    pub action: JobAction,
+    // The program and its arguments, for action = "command"
+    pub command: Option<Vec<String>>,
+    // After which the program is killed
+    pub timeout_s: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobAction {
+    Snapshot,  // Send the latest value of every CAN signal
+    State,     // Send the client state
+    Inventory, // Send the software inventory
+    Command,   // Run a program, e.g. a self-test or logrotate
+}
+
+#[derive(Deserialize, Clone)]
+pub struct InventoryConfig {
+    // Report the inventory periodically, and not only when requested
+    pub interval_s: Option<u64>,
+    // Packages whose versions are reported, instead of the defaults
+    pub packages: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityProviderKind {
+    File,
+    DeviceTree,
+    Eeprom,
+    Tpm,
+}
+
+// A level of the degradation ladder, used when the backlog of messages
+// to send reaches the threshold
+#[derive(Deserialize, Clone)]
+pub struct BackpressureLevel {
+    pub backlog: usize,
+    pub min_interval_ms: Option<u64>,
+    pub deadband_percent: Option<f64>,
+    pub periodic_factor: Option<u32>,
+}
+
+// Shedding of data when the client uses too much CPU or its event loop
+// lags, e.g. on low-end SoCs. While shedding, the limits apply as for a
+// backpressure level and the best effort signals are not sent.
+#[derive(Deserialize, Clone)]
+pub struct LoadSheddingConfig {
+    pub cpu_percent: Option<f64>,
+    pub lag_ms: Option<u64>,
+    pub min_interval_ms: Option<u64>,
+    pub deadband_percent: Option<f64>,
+    pub periodic_factor: Option<u32>,
+    pub best_effort: Option<Vec<String>>,
+}
+
+// Reporting that adapts to where the vehicle is and whether it moves.
+// The position and speed are read from the value cache, and the policy
+// of the current zone, or else of moving or parked, limits the data like
+// a backpressure level.
+#[derive(Deserialize, Clone)]
+pub struct GeoConfig {
+    pub latitude: String,
+    pub longitude: String,
+    // In km/h
+    pub speed: String,
+    pub moving_kmh: Option<f64>,
+    pub moving: Option<RatePolicy>,
+    pub parked: Option<RatePolicy>,
+    pub zones: Option<Vec<ZoneConfig>>,
+}
+
+// A GNSS receiver read through gpsd. The receiver settings are applied
+// with ubxtool, so they require a u-blox receiver.
+#[derive(Deserialize, Clone)]
+pub struct GnssConfig {
+    pub gpsd: Option<String>,
+    pub bus: Option<String>,
+    pub interval_ms: Option<u64>,
+    pub device: Option<String>,
+    pub constellations: Option<Vec<Constellation>>,
+    pub update_rate_hz: Option<u32>,
+    pub elevation_mask_deg: Option<u8>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Constellation {
+    Gps,
+    Galileo,
+    Glonass,
+    Beidou,
+    Qzss,
+    Sbas,
+}
+
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct RatePolicy {
+    pub min_interval_ms: Option<u64>,
+    pub deadband_percent: Option<f64>,
+    pub periodic_factor: Option<u32>,
+}
+
+// A circular zone, e.g. a depot or a border crossing
+#[derive(Deserialize, Clone)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_m: f64,
+    pub policy: Option<RatePolicy>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BulkConfig {
+    pub domain: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct NetworkConfig {
+    pub wait_for: NetworkCondition,
+    pub wait_timeout_s: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkCondition {
+    Route,        // A default route exists
+    ModemManager, // A modem is connected according to ModemManager
 }
 
 #[derive(Deserialize, Clone)]
 pub struct DigitalInConfig {
     pub ports: Option<Vec<DigitalInPort>>,
+    pub duty: Option<DutyConfig>,
+}
+
+// Active time and activations of inputs, sent every interval
+#[derive(Deserialize, Clone)]
+pub struct DutyConfig {
+    pub interval_s: u64,
+    pub ports: Vec<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -59,6 +482,51 @@ pub struct DigitalInPort {
 #[derive(Deserialize, Clone)]
 pub struct DigitalOutConfig {
     pub ports: Option<Vec<DigitalOutPort>>,
+    pub startup_sequence: Option<Vec<StartupStep>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StartupStep {
+    pub port: String,
+    pub active: bool,
+    pub delay_ms: Option<u64>,
+    // Ports that earlier steps have to set first, e.g. a pump before a
+    // valve
+    pub requires: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AnalogOutConfig {
+    pub ports: Vec<AnalogOutPort>,
+}
+
+// An analog out, e.g. a PWM duty cycle or a DAC value, set through sysfs
+#[derive(Deserialize, Clone)]
+pub struct AnalogOutPort {
+    pub external_name: String,
+    pub path: String,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    pub failsafe: Option<Failsafe>,
+}
+
+// What an output does when the server has not been reached for after_s
+#[derive(Deserialize, Clone)]
+pub struct Failsafe {
+    pub after_s: u64,
+    pub action: FailsafeAction,
+    // The state (0 or 1) of a digital out, or the setpoint of an analog
+    // out, for action = "set"
+    pub value: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailsafeAction {
+    Hold,
+    Default,
+    Set,
 }
 
 #[derive(Deserialize, Clone)]
@@ -66,12 +534,138 @@ pub struct DigitalOutPort {
     pub internal_name: String,
     pub external_name: String,
     pub default_state: u8,
+    pub failsafe: Option<Failsafe>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct CanConfig {
     pub ports: Option<Vec<CanPort>>,
     pub dbc_file: Option<String>,
+    pub dbc_sha256: Option<String>,
+    pub resend_unchanged_s: Option<u64>,
+    pub enums: Option<EnumEncoding>,
+    pub decode_cache: Option<usize>,
+    // Whether decoded signals are sent, or only kept for e.g. heartbeats
+    pub send: Option<bool>,
+    pub redundant: Option<Vec<RedundancyGroup>>,
+    pub bridges: Option<Vec<BridgeConfig>>,
+    pub keep_alive: Option<Vec<KeepAliveFrame>>,
+    pub security_access: Option<Vec<SecurityAccessConfig>>,
+    pub transmit: Option<Vec<TransmitConfig>>,
+    // Whether error frames are reported, false if not given
+    pub bus_errors: Option<bool>,
+    // Whether the VIN is requested at startup, true if not given
+    pub detect_vin: Option<bool>,
+    pub intrusion: Option<IntrusionConfig>,
+    pub signals: Option<Vec<SignalConfig>>,
+    pub composites: Option<Vec<CompositeConfig>>,
+    // External names of DBC signals, by DBC name
+    pub names: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnumEncoding {
+    Label, // The label of the value, along with the raw value
+    Raw,   // The raw value only, labels are in the DBC catalog
+}
+
+// Detection of anomalous CAN traffic
+#[derive(Deserialize, Clone)]
+pub struct IntrusionConfig {
+    pub learn_s: Option<u64>,
+    pub window_s: Option<u64>,
+    pub rate_factor: Option<f64>,
+    pub holdoff_s: Option<u64>,
+    pub allowed_ids: Option<Vec<u32>>,
+}
+
+// Forwarding of frames from one port to another
+#[derive(Deserialize, Clone)]
+pub struct BridgeConfig {
+    pub from: String,
+    pub to: String,
+    pub ids: Vec<u32>,
+    pub max_rate_hz: Option<f64>,
+}
+
+// A frame that is sent periodically, e.g. a tester present for an ECU
+// that only broadcasts while it sees one
+#[derive(Deserialize, Clone)]
+pub struct KeepAliveFrame {
+    pub port: String,
+    pub id: u32,
+    pub data: Vec<u8>,
+    pub interval_ms: u64,
+}
+
+// The frame IDs that the server may request to be sent on a port, e.g.
+// for remote actuation of body controllers
+#[derive(Deserialize, Clone, Debug)]
+pub struct TransmitConfig {
+    pub port: String,
+    pub ids: Vec<u32>,
+    // The ECU to unlock with the security access of the port before
+    // each frame, for ECUs that only accept the frames once unlocked
+    pub unlock: Option<IsoTpIds>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct IsoTpIds {
+    pub tx_id: u32,
+    pub rx_id: u32,
+}
+
+// UDS SecurityAccess on a port, for ECUs that require it before e.g.
+// writes. The key is computed from the seed of the ECU by an embedded
+// algorithm or by a seed/key library.
+#[derive(Deserialize, Clone)]
+pub struct SecurityAccessConfig {
+    pub port: String,
+    pub algorithm: KeyAlgorithmKind,
+    // The requestSeed sub-function, 1 if not given
+    pub level: Option<u8>,
+    // A diagnostic session to enter first, e.g. 3 for extended
+    pub session: Option<u8>,
+    pub library: Option<String>,
+    pub variant: Option<String>,
+    pub mask: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAlgorithmKind {
+    Xor,     // The seed XOR a mask
+    Library, // A shared object with the GenerateKeyEx function
+}
+
+// Ports that carry the same messages, reported as one bus
+#[derive(Deserialize, Clone)]
+pub struct RedundancyGroup {
+    pub name: String,
+    pub ports: Vec<String>,
+    pub window_ms: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CompositeConfig {
+    pub name: String,
+    pub signals: Vec<String>,
+    pub window_ms: u64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SignalConfig {
+    pub name: String,
+    pub mode: ReportingMode,
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportingMode {
+    OnChange,
+    Periodic,
 }
 
 #[derive(Deserialize, Clone)]
@@ -79,6 +673,54 @@ pub struct CanPort {
     pub name: String,
     pub bitrate: Option<u32>,
     pub listen_only: Option<bool>,
+    pub sample_point: Option<f64>,
+    pub restart_ms: Option<u32>,
+    pub termination: Option<u16>,
+    pub fd: Option<bool>,
+    pub data_bitrate: Option<u32>,
+    pub j1939: Option<bool>,
+    // Send the frames undecoded, without a DBC
+    pub raw: Option<bool>,
+    // Send the frames undecoded while the DBC file cannot be loaded
+    pub raw_fallback: Option<bool>,
+    // Time to wait before bringing the port up again after bus-off
+    pub bus_off_backoff_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SpoolConfig {
+    pub dir: String,
+    pub memory_limit: Option<usize>,
+    pub encrypt: Option<bool>,
+    pub key_file: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StatsConfig {
+    pub interval_s: u64,
+    pub top_talkers: Option<usize>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StorageConfig {
+    pub check_interval_s: Option<u64>,
+    pub near_full_percent: Option<u64>,
+    pub dirs: Vec<StorageDir>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StorageDir {
+    pub path: String,
+    pub quota_mb: u64,
+    pub priority: u32,
+    // Kept when evicting for a full filesystem
+    pub reserve_mb: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TransferConfig {
+    pub upload_dirs: Vec<String>,
+    pub chunk_size: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -93,44 +735,1431 @@ lazy_static! {
     pub static ref CONFIG: Config = load_config();
 }
 
-pub const BIN_DIR: &str = env!("BIN_DIR");
-pub const CONF_DIR: &str = env!("CONF_DIR");
+pub const DEFAULT_CONF_DIR: &str = env!("CONF_DIR");
+pub const DEFAULT_CA_FILE: &str = env!("CA_FILE");
+pub const DEFAULT_RUN_DIR: &str = env!("RUN_DIR");
 pub const GIT_COMMIT_DESCRIBE: &str = env!("GIT_VERSION");
+// Shortest interval of a keep-alive frame, to not flood the bus
+pub const MIN_KEEP_ALIVE_INTERVAL_MS: u64 = 10;
+// Lowest rate limit of a bridge, about one frame per quarter of an hour
+pub const MIN_BRIDGE_RATE_HZ: f64 = 0.001;
+// Smallest spool memory limit, since the oldest messages are spooled in
+// batches of this size
+pub const MIN_SPOOL_MEMORY_LIMIT: usize = 100;
+// The buses of signals that are not read from CAN, unless configured
+pub const COMPOSITE_BUS: &str = "composite";
+pub const DEFAULT_GNSS_BUS: &str = "gnss";
+pub const DEFAULT_OPCUA_BUS: &str = "opcua";
+pub const DEFAULT_TEST_SIGNALS_BUS: &str = "test";
+// The signals of a GNSS fix
+pub const GNSS_SIGNALS: [&str; 8] = [
+    "FixType",
+    "SatellitesUsed",
+    "Hdop",
+    "Latitude",
+    "Longitude",
+    "Altitude",
+    "Speed",
+    "Heading",
+];
+
+// Install paths. The compiled defaults can be overridden at runtime, e.g.
+// from the command line, so that one binary fits different layouts.
+pub struct Paths {
+    pub conf_dir: String,
+    pub ca_file: String,
+    // Runtime state shared with the helper, e.g. requested upgrades
+    pub run_dir: String,
+    // Name of this instance when several clients run on one unit
+    pub instance: Option<String>,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            conf_dir: DEFAULT_CONF_DIR.to_string(),
+            ca_file: DEFAULT_CA_FILE.to_string(),
+            run_dir: DEFAULT_RUN_DIR.to_string(),
+            instance: None,
+        }
+    }
+}
+
+static PATHS_USED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref GIVEN_PATHS: Mutex<Option<Paths>> = Mutex::new(None);
+    static ref INLINE_CONFIG: Mutex<Option<String>> = Mutex::new(None);
+    static ref PATHS: Paths = {
+        PATHS_USED.store(true, Ordering::Relaxed);
+        GIVEN_PATHS.lock().unwrap().take().unwrap_or_default()
+    };
+}
+
+// Must be called before the paths, CONFIG or IDENTITY are used
+pub fn set_paths(paths: Paths) {
+    let mut given = GIVEN_PATHS.lock().unwrap();
+    if PATHS_USED.load(Ordering::Relaxed) || given.is_some() {
+        panic!("The paths were set after being used.");
+    }
+    *given = Some(paths);
+}
+
+// Use a config given as TOML, e.g. in the environment of a container,
+// instead of the config files. Must be called before CONFIG is used.
+pub fn set_inline_config(config: String) {
+    INLINE_CONFIG_GIVEN.store(true, Ordering::Relaxed);
+    *INLINE_CONFIG.lock().unwrap() = Some(config);
+}
+
+static INLINE_CONFIG_GIVEN: AtomicBool = AtomicBool::new(false);
+
+// An inline config cannot be replaced by writing conf-new.toml, since the
+// config files are not read when it is given.
+pub fn is_inline_config() -> bool {
+    INLINE_CONFIG_GIVEN.load(Ordering::Relaxed)
+}
+
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+// In safe mode, the fallback config is used. Must be called before
+// CONFIG is used.
+pub fn set_safe_mode() {
+    SAFE_MODE.store(true, Ordering::Relaxed);
+}
+
+pub fn is_safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
+fn paths() -> &'static Paths {
+    &PATHS
+}
+
+pub fn conf_dir() -> &'static str {
+    &paths().conf_dir
+}
+
+pub fn ca_file() -> &'static str {
+    &paths().ca_file
+}
+
+pub fn run_dir() -> &'static str {
+    &paths().run_dir
+}
+
+pub fn instance() -> Option<&'static str> {
+    paths().instance.as_deref()
+}
+
+// The name a DBC signal is known by, after the optional renaming
+pub fn signal_name(dbc_name: &str) -> &str {
+    CONFIG
+        .can
+        .as_ref()
+        .and_then(|c| c.names.as_ref())
+        .and_then(|names| names.get(dbc_name))
+        .map_or(dbc_name, String::as_str)
+}
+
+// Parse a config. The blocks under [profiles.<name>] are only used when
+// the profile is selected, and then replace the top level blocks of the
+// same name. This way one config file can cover several installation
+// types.
+pub fn parse_config(s: &str, profile: Option<&str>) -> Result<Config, toml::de::Error> {
+    parse_config_at(s, profile, schedule::local_time())
+}
+
+fn parse_config_at(
+    s: &str,
+    profile: Option<&str>,
+    time: schedule::LocalTime,
+) -> Result<Config, toml::de::Error> {
+    parse_config_with(s, profile, time, |schedule| schedule.is_active(time))
+}
+
+// Parse a config with the overrides of the selected schedules applied,
+// e.g. to validate each schedule whether it is active or not
+fn parse_config_with(
+    s: &str,
+    profile: Option<&str>,
+    time: schedule::LocalTime,
+    selected: impl Fn(&schedule::Schedule) -> bool,
+) -> Result<Config, toml::de::Error> {
+    let mut root: toml::value::Table = toml::from_str(s)?;
+    apply_includes(&mut root, conf_dir()).map_err(toml::de::Error::custom)?;
+    let mut profiles = match root.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        _ => toml::value::Table::new(),
+    };
+    let names: Vec<String> = profiles.keys().cloned().collect();
+
+    if let Some(toml::Value::Table(blocks)) = profile.and_then(|p| profiles.remove(p)) {
+        root.extend(blocks);
+    }
+    let schedules = schedule::apply(&mut root, selected).map_err(toml::de::Error::custom)?;
+
+    let effective = toml::to_string(&root).map_err(toml::de::Error::custom)?;
+    let mut config: Config = toml::Value::Table(root).try_into()?;
+    config.effective_hash = sha256_hex(effective.as_bytes());
+    config.profiles = names;
+    config.active_schedules = schedule::active(&schedules, time);
+    config.schedules = schedules;
+    Ok(config)
+}
+
+// Merge the files given by include into the config. The included files
+// are merged in the given order and the including file last, so that
+// e.g. fleet-common settings can be tuned per site. Blocks are merged
+// key by key, while other values, including lists, are replaced.
+// Included files are relative to the configuration directory and cannot
+// include others.
+fn apply_includes(root: &mut toml::value::Table, dir: &str) -> Result<(), String> {
+    let includes = match root.remove("include") {
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => return Err("include must be a list of files".to_string()),
+        None => return Ok(()),
+    };
+    let mut merged = toml::value::Table::new();
+    for include in &includes {
+        let name = include.as_str().ok_or("include must be a list of files")?;
+        let path = format!("{dir}/{name}");
+        let s = fs::read_to_string(&path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        let table: toml::value::Table = toml::from_str(&s).map_err(|e| format!("{name}: {e}"))?;
+        if table.contains_key("include") {
+            return Err(format!("{name}: included files cannot include others"));
+        }
+        schedule::merge(&mut merged, &table);
+    }
+    schedule::merge(&mut merged, root);
+    *root = merged;
+    Ok(())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
 
 fn load_config() -> Config {
-    let new_local_conf = PathBuf::from(format!("{}/conf-new.toml", CONF_DIR));
-    let local_conf = PathBuf::from(format!("{}/conf.toml", CONF_DIR));
-    let fallback_conf = PathBuf::from(format!("{}/conf-fallback.toml", CONF_DIR));
+    if let Some(s) = INLINE_CONFIG.lock().unwrap().take() {
+        return validate_config(&s).unwrap_or_else(|e| panic!("The inline config is invalid: {e}"));
+    }
+
+    let new_local_conf = PathBuf::from(format!("{}/conf-new.toml", conf_dir()));
+    let local_conf = PathBuf::from(format!("{}/conf.toml", conf_dir()));
+    let fallback_conf = PathBuf::from(format!("{}/conf-fallback.toml", conf_dir()));
+
+    if is_safe_mode() {
+        if let Ok(s) = fs::read_to_string(&fallback_conf) {
+            return parse_config(&s, identity::profile().as_deref())
+                .expect("Failed to load the fallback config.");
+        }
+        eprintln!("No fallback config, using the local config in safe mode");
+    }
 
     if new_local_conf.exists() {
         if let Ok(s) = &fs::read_to_string(new_local_conf.clone()) {
-            let result: Result<Config, toml::de::Error> = toml::from_str(s);
-            if let Ok(config) = result {
-                fs::rename(&new_local_conf, &local_conf).unwrap();
-                return config;
-            } else {
-                eprintln!("The new local config is invalid. Removing it.");
-                fs::remove_file(new_local_conf).unwrap();
+            match validate_config(s) {
+                Ok(config) => {
+                    fs::rename(&new_local_conf, &local_conf).unwrap();
+                    return config;
+                }
+                Err(e) => {
+                    eprintln!("The new local config is invalid: {e}. Removing it.");
+                    fs::remove_file(new_local_conf).unwrap();
+                }
             }
         } else {
             eprintln!("Could not parse the new local config as a string. Removing it...");
             fs::remove_file(new_local_conf).unwrap();
         };
     }
-    toml::from_str(
-        &fs::read_to_string(local_conf)
-            .unwrap_or_else(|_| fs::read_to_string(fallback_conf).unwrap()),
+    let profile = identity::profile();
+    let local = fs::read_to_string(local_conf).ok().and_then(|s| {
+        parse_local_config(&s, profile.as_deref())
+            .map_err(|e| eprintln!("The local config is invalid: {e}"))
+            .ok()
+    });
+    local.unwrap_or_else(|| {
+        parse_local_config(
+            &fs::read_to_string(fallback_conf).unwrap(),
+            profile.as_deref(),
+        )
+        .expect("Failed to load any config file.")
+    })
+}
+
+// Parse the config to run with. If the overrides of the active
+// schedules break it, which validation should have caught, it is used
+// without them rather than stopping the client when a schedule starts.
+fn parse_local_config(s: &str, profile: Option<&str>) -> Result<Config, toml::de::Error> {
+    let time = schedule::local_time();
+    parse_config_at(s, profile, time).or_else(|e| {
+        eprintln!("Failed to apply the active schedules: {e}");
+        parse_config_with(s, profile, time, |_| false)
+    })
+}
+
+// Parse a config and check that it makes sense as a whole, e.g. before
+// accepting a config pushed by the server. All problems found are
+// returned, separated by semicolons.
+pub fn validate_config(s: &str) -> Result<Config, String> {
+    validate_config_for(s, identity::profile().as_deref())
+}
+
+// Validate a config as used by a unit with the given profile. The
+// config is also checked without schedules and with each schedule on
+// its own, so that an override that breaks it does not go unnoticed
+// until the schedule starts.
+fn validate_config_for(s: &str, profile: Option<&str>) -> Result<Config, String> {
+    let time = schedule::local_time();
+    let config = parse_config_at(s, profile, time).map_err(|e| e.to_string())?;
+    let mut issues = config_issues(&config, profile);
+
+    let variants = std::iter::once(("without schedules".to_string(), None)).chain(
+        config
+            .schedules
+            .iter()
+            .map(|s| (format!("with schedule {}", s.name), Some(s.name.as_str()))),
+    );
+    let mut variant_issues = Vec::new();
+    for (variant, name) in variants {
+        let selected = |schedule: &schedule::Schedule| Some(schedule.name.as_str()) == name;
+        match parse_config_with(s, profile, time, selected) {
+            Ok(c) => variant_issues.extend(
+                config_issues(&c, profile)
+                    .into_iter()
+                    .filter(|issue| !issues.contains(issue))
+                    .map(|issue| format!("{issue} ({variant})")),
+            ),
+            Err(e) => variant_issues.push(format!("{e} ({variant})")),
+        }
+    }
+    issues.extend(variant_issues);
+
+    if issues.is_empty() {
+        Ok(config)
+    } else {
+        Err(issues.join("; "))
+    }
+}
+
+// The problems of a parsed config
+fn config_issues(config: &Config, profile: Option<&str>) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    if let Some(profile) = profile {
+        if !config.profiles.iter().any(|p| p == profile) {
+            issues.push(format!("Profile {profile} is not defined"));
+        }
+    }
+
+    let time = &config.time;
+    if time.heartbeat_s == 0 {
+        issues.push("time.heartbeat_s must be greater than 0".to_string());
+    }
+    if time.sleep_min_s == 0 {
+        issues.push("time.sleep_min_s must be greater than 0".to_string());
+    }
+    if time.sleep_min_s > time.sleep_max_s {
+        issues.push("time.sleep_min_s is greater than time.sleep_max_s".to_string());
+    }
+
+    if let Some(can) = &config.can {
+        let ports = can.ports.as_deref().unwrap_or_default();
+        if ports.iter().any(|p| p.raw != Some(true)) && can.dbc_file.is_none() {
+            issues.push("can.dbc_file is required unless all CAN ports are raw".to_string());
+        }
+        check_unique("can.ports", ports.iter().map(|p| &p.name), &mut issues);
+        for p in ports {
+            if matches!(p.sample_point, Some(sp) if !(sp > 0.0 && sp < 1.0)) {
+                issues.push(format!(
+                    "The sample_point of {} must be between 0 and 1",
+                    p.name
+                ));
+            }
+            if p.data_bitrate.is_some() && p.fd != Some(true) {
+                issues.push(format!("The data_bitrate of {} requires fd = true", p.name));
+            }
+        }
+        for signal in can.signals.as_deref().unwrap_or_default() {
+            if signal.mode == ReportingMode::Periodic
+                && !matches!(signal.interval_ms, Some(i) if i > 0)
+            {
+                issues.push(format!(
+                    "Periodic signal {} requires an interval_ms greater than 0",
+                    signal.name
+                ));
+            }
+        }
+        for bridge in can.bridges.as_deref().unwrap_or_default() {
+            for name in [&bridge.from, &bridge.to] {
+                if !ports.iter().any(|p| &p.name == name) {
+                    issues.push(format!("Bridge uses unknown port {name}"));
+                }
+            }
+            if bridge.from == bridge.to {
+                issues.push(format!("Bridge from {} to itself", bridge.from));
+            }
+            if ports
+                .iter()
+                .any(|p| p.name == bridge.to && p.listen_only != Some(false))
+            {
+                issues.push(format!(
+                    "Bridge to {} requires listen_only = false on that port",
+                    bridge.to
+                ));
+            }
+            if matches!(bridge.max_rate_hz, Some(hz) if hz.is_nan() || hz < MIN_BRIDGE_RATE_HZ) {
+                issues.push(format!(
+                    "The max_rate_hz of a bridge must be at least {MIN_BRIDGE_RATE_HZ}"
+                ));
+            }
+        }
+
+        for frame in can.keep_alive.as_deref().unwrap_or_default() {
+            if !ports.iter().any(|p| p.name == frame.port) {
+                issues.push(format!("Keep-alive frame uses unknown port {}", frame.port));
+            }
+            if ports
+                .iter()
+                .any(|p| p.name == frame.port && p.listen_only != Some(false))
+            {
+                issues.push(format!(
+                    "Keep-alive frames on {} require listen_only = false on that port",
+                    frame.port
+                ));
+            }
+            if frame.id > libc::CAN_EFF_MASK {
+                issues.push(format!("Keep-alive frame ID {:#x} is too large", frame.id));
+            }
+            if frame.data.len() > 8 {
+                issues.push(format!(
+                    "Keep-alive frame {:#x} has more than 8 data bytes",
+                    frame.id
+                ));
+            }
+            if frame.interval_ms < MIN_KEEP_ALIVE_INTERVAL_MS {
+                issues.push(format!(
+                    "The interval_ms of keep-alive frame {:#x} must be at least {}",
+                    frame.id, MIN_KEEP_ALIVE_INTERVAL_MS
+                ));
+            }
+        }
+
+        for access in can.security_access.as_deref().unwrap_or_default() {
+            if !ports.iter().any(|p| p.name == access.port) {
+                issues.push(format!("Security access uses unknown port {}", access.port));
+            }
+            if matches!(access.level, Some(level) if level % 2 == 0 || level > 0x7d) {
+                issues.push(format!(
+                    "The security access level of {} must be an odd requestSeed level",
+                    access.port
+                ));
+            }
+            match access.algorithm {
+                KeyAlgorithmKind::Xor if access.mask.as_deref().unwrap_or_default().is_empty() => {
+                    issues.push(format!(
+                        "The xor key algorithm of {} requires a mask",
+                        access.port
+                    ));
+                }
+                KeyAlgorithmKind::Library => match &access.library {
+                    None => issues.push(format!(
+                        "The library key algorithm of {} requires a library",
+                        access.port
+                    )),
+                    Some(library) if !is_plain_file_name(library) => issues.push(format!(
+                        "The seed/key library {library} of {} must be a file name in the \
+                         configuration directory",
+                        access.port
+                    )),
+                    Some(_) => {}
+                },
+                _ => {}
+            }
+        }
+        check_unique(
+            "can.security_access",
+            can.security_access.iter().flatten().map(|a| &a.port),
+            &mut issues,
+        );
+
+        for transmit in can.transmit.as_deref().unwrap_or_default() {
+            if !ports.iter().any(|p| p.name == transmit.port) {
+                issues.push(format!("Transmit uses unknown port {}", transmit.port));
+            }
+            if ports
+                .iter()
+                .any(|p| p.name == transmit.port && p.listen_only != Some(false))
+            {
+                issues.push(format!(
+                    "Transmit on {} requires listen_only = false on that port",
+                    transmit.port
+                ));
+            }
+            for id in transmit.ids.iter().filter(|id| **id > libc::CAN_EFF_MASK) {
+                issues.push(format!("Transmit ID {id:#x} is too large"));
+            }
+            if let Some(unlock) = &transmit.unlock {
+                let security_access = can.security_access.iter().flatten();
+                if !security_access.into_iter().any(|a| a.port == transmit.port) {
+                    issues.push(format!(
+                        "Transmit on {} unlocks an ECU but the port has no security access",
+                        transmit.port
+                    ));
+                }
+                for id in [unlock.tx_id, unlock.rx_id] {
+                    if id > libc::CAN_EFF_MASK {
+                        issues.push(format!("Transmit unlock ID {id:#x} is too large"));
+                    }
+                }
+            }
+        }
+        check_unique(
+            "can.transmit",
+            can.transmit.iter().flatten().map(|t| &t.port),
+            &mut issues,
+        );
+
+        if let Some(intrusion) = &can.intrusion {
+            if intrusion.window_s == Some(0) {
+                issues.push("The intrusion window_s must be greater than 0".to_string());
+            }
+            if matches!(intrusion.rate_factor, Some(f) if f <= 1.0) {
+                issues.push("The intrusion rate_factor must be greater than 1".to_string());
+            }
+        }
+
+        let mut grouped = HashSet::new();
+        for group in can.redundant.as_deref().unwrap_or_default() {
+            for name in &group.ports {
+                if !ports.iter().any(|p| &p.name == name) {
+                    issues.push(format!(
+                        "Redundancy group {} uses unknown port {}",
+                        group.name, name
+                    ));
+                }
+                if !grouped.insert(name) {
+                    issues.push(format!("Port {name} is in more than one redundancy group"));
+                }
+            }
+        }
+        if let Some(names) = &can.names {
+            check_unique("can.names", names.values(), &mut issues);
+        }
+        let composites = can.composites.as_deref().unwrap_or_default();
+        check_unique(
+            "can.composites",
+            composites.iter().map(|c| &c.name),
+            &mut issues,
+        );
+        let known = can
+            .dbc_file
+            .as_deref()
+            .and_then(|f| dbc_signal_names(f, can.names.as_ref()));
+        for composite in composites {
+            if composite.signals.is_empty() {
+                issues.push(format!("Composite {} has no signals", composite.name));
+            }
+            check_unique(
+                &format!("Composite {}", composite.name),
+                composite.signals.iter(),
+                &mut issues,
+            );
+            if let Some(known) = &known {
+                for signal in composite.signals.iter().filter(|s| !known.contains(*s)) {
+                    issues.push(format!(
+                        "Composite {} uses unknown signal {}",
+                        composite.name, signal
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(digital_in) = &config.digital_in {
+        let ports = digital_in.ports.as_deref().unwrap_or_default();
+        check_unique(
+            "digital_in.ports",
+            ports.iter().map(|p| &p.external_name),
+            &mut issues,
+        );
+        for name in digital_in.duty.iter().flat_map(|d| &d.ports) {
+            if !ports.iter().any(|p| &p.external_name == name) {
+                issues.push(format!("Duty statistics use unknown digital in {name}"));
+            }
+        }
+    }
+
+    if let Some(digital_out) = &config.digital_out {
+        let ports = digital_out.ports.as_deref().unwrap_or_default();
+        check_unique(
+            "digital_out.ports",
+            ports.iter().map(|p| &p.external_name),
+            &mut issues,
+        );
+        for p in ports {
+            if let Some(failsafe) = &p.failsafe {
+                check_failsafe(&p.external_name, failsafe, (0.0, 1.0), &mut issues);
+            }
+        }
+        for p in ports.iter().filter(|p| p.default_state > 1) {
+            issues.push(format!(
+                "Digital out {} has a default_state other than 0 or 1",
+                p.external_name
+            ));
+        }
+        let sequence = digital_out.startup_sequence.as_deref().unwrap_or_default();
+        for (i, step) in sequence.iter().enumerate() {
+            if !ports.iter().any(|p| p.external_name == step.port) {
+                issues.push(format!(
+                    "Startup sequence step {} uses unknown digital out {}",
+                    i, step.port
+                ));
+            }
+            for required in step.requires.iter().flatten() {
+                if !sequence[..i].iter().any(|s| &s.port == required) {
+                    issues.push(format!(
+                        "Startup sequence step {i} requires {required}, which no earlier step sets"
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(analog_out) = &config.analog_out {
+        check_unique(
+            "analog_out.ports",
+            analog_out.ports.iter().map(|p| &p.external_name),
+            &mut issues,
+        );
+        for p in &analog_out.ports {
+            if let Some(failsafe) = &p.failsafe {
+                check_failsafe(&p.external_name, failsafe, (p.min, p.max), &mut issues);
+            }
+            if p.min > p.max || p.min.is_nan() || p.max.is_nan() {
+                issues.push(format!(
+                    "Analog out {} has a min greater than its max",
+                    p.external_name
+                ));
+            } else if !(p.min <= p.default && p.default <= p.max) {
+                issues.push(format!(
+                    "Analog out {} has a default outside of its min and max",
+                    p.external_name
+                ));
+            }
+        }
+    }
+
+    check_external_names(config, &mut issues);
+
+    if let Some(output_override) = &config.output_override {
+        let ports = config
+            .digital_in
+            .as_ref()
+            .and_then(|d| d.ports.as_deref())
+            .unwrap_or_default();
+        if !ports
+            .iter()
+            .any(|p| p.external_name == output_override.input)
+        {
+            issues.push(format!(
+                "Override uses unknown digital in {}",
+                output_override.input
+            ));
+        }
+    }
+
+    if let Some(opcua) = &config.opcua {
+        if !opcua.endpoint.starts_with("opc.tcp://") {
+            issues.push(format!(
+                "The OPC UA endpoint {} is not opc.tcp://",
+                opcua.endpoint
+            ));
+        }
+        check_unique(
+            "opcua.nodes",
+            opcua.nodes.iter().map(|n| &n.name),
+            &mut issues,
+        );
+        for node in &opcua.nodes {
+            if !is_node_id(&node.node_id) {
+                issues.push(format!("OPC UA node {} has an invalid node_id", node.name));
+            }
+            if node.interval_ms == Some(0) {
+                issues.push(format!(
+                    "OPC UA node {} requires interval_ms > 0",
+                    node.name
+                ));
+            }
+        }
+    }
+
+    if let Some(jobs) = &config.jobs {
+        check_unique("jobs", jobs.iter().map(|j| &j.name), &mut issues);
+        for job in jobs {
+            if schedule::parse_time(&job.at).is_none() {
+                issues.push(format!("Job {} requires at as HH:MM", job.name));
+            }
+            for day in job.days.iter().flatten() {
+                if schedule::parse_day(day).is_none() {
+                    issues.push(format!("Job {} has an invalid day {day}", job.name));
+                }
+            }
+            let has_command = !job.command.as_deref().unwrap_or_default().is_empty();
+            if has_command != (job.action == JobAction::Command) {
+                issues.push(format!(
+                    "Job {} requires a command if and only if action = \"command\"",
+                    job.name
+                ));
+            }
+        }
+    }
+
+    if let Some(attention) = &config.attention {
+        let digital_in = config
+            .digital_in
+            .iter()
+            .flat_map(|d| d.ports.iter().flatten());
+        if !digital_in
+            .clone()
+            .any(|p| p.external_name == attention.input)
+        {
+            issues.push(format!(
+                "Attention check uses unknown digital in {}",
+                attention.input
+            ));
+        }
+        let digital_out = config
+            .digital_out
+            .iter()
+            .flat_map(|d| d.ports.iter().flatten());
+        for output in std::iter::once(&attention.output).chain(&attention.alarm) {
+            if !digital_out.clone().any(|p| &p.external_name == output) {
+                issues.push(format!("Attention check uses unknown digital out {output}"));
+            }
+        }
+        if attention.window_s == 0 || attention.window_s >= attention.interval_s {
+            issues.push(
+                "The attention window_s must be greater than 0 and less than interval_s"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let Some(wake) = &config.wake {
+        if wake.interval_s == 0 || wake.awake_s == Some(0) {
+            issues.push("wake.interval_s and wake.awake_s must be greater than 0".to_string());
+        }
+        let ports = config
+            .digital_in
+            .as_ref()
+            .and_then(|d| d.ports.as_deref())
+            .unwrap_or_default();
+        if !ports.iter().any(|p| p.external_name == wake.parked_input) {
+            issues.push(format!(
+                "Wake uses unknown digital in {}",
+                wake.parked_input
+            ));
+        }
+    }
+
+    if let Some(tls) = &config.tls {
+        if matches!(&tls.renew_url, Some(url) if !url.starts_with("https://")) {
+            issues.push("tls.renew_url must be an https URL".to_string());
+        }
+    }
+
+    if let Some(tunnel) = &config.tunnel {
+        if tunnel.max_duration_s == Some(0) {
+            issues.push("tunnel.max_duration_s must be greater than 0".to_string());
+        }
+    }
+
+    if let Some(vpn) = &config.vpn {
+        check_unique("vpn.interfaces", vpn.interfaces.iter(), &mut issues);
+    }
+
+    if let Some(scrub) = &config.scrub {
+        check_unique(
+            "scrub.rules",
+            scrub.rules.iter().map(|r| &r.signal),
+            &mut issues,
+        );
+        if scrub.key.is_none() && scrub.rules.iter().any(|r| r.action == ScrubAction::Hash) {
+            issues.push("scrub.key is required for hash rules".to_string());
+        }
+    }
+
+    if let Some(test_signals) = &config.test_signals {
+        check_unique(
+            "test_signals.signals",
+            test_signals.signals.iter().map(|s| &s.name),
+            &mut issues,
+        );
+        for signal in &test_signals.signals {
+            if signal.interval_ms == 0 {
+                issues.push(format!(
+                    "The interval_ms of test signal {} must be greater than 0",
+                    signal.name
+                ));
+            }
+            if matches!(signal.period_s, Some(p) if p <= 0.0) {
+                issues.push(format!(
+                    "The period_s of test signal {} must be greater than 0",
+                    signal.name
+                ));
+            }
+        }
+    }
+
+    if let Some(routes) = &config.routes {
+        check_unique("routes", routes.iter().map(|r| &r.name), &mut issues);
+        for route in routes {
+            if route.sources.is_none() && route.signals.is_none() {
+                issues.push(format!(
+                    "Route {} requires sources or signals, or it would copy everything",
+                    route.name
+                ));
+            }
+        }
+    }
+
+    if let Some(identity) = &config.identity {
+        match identity.provider {
+            IdentityProviderKind::Eeprom if identity.path.is_none() => {
+                issues.push("An EEPROM identity provider requires a path".to_string())
+            }
+            IdentityProviderKind::Tpm if identity.handle.is_none() => {
+                issues.push("A TPM identity provider requires a key handle".to_string())
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(levels) = &config.backpressure {
+        if levels.windows(2).any(|l| l[0].backlog >= l[1].backlog) {
+            issues.push("The backpressure levels must have increasing backlogs".to_string());
+        }
+    }
+
+    if let Some(shedding) = &config.load_shedding {
+        if matches!(shedding.cpu_percent, Some(p) if p <= 0.0) {
+            issues.push("load_shedding.cpu_percent must be greater than 0".to_string());
+        }
+        if shedding.lag_ms == Some(0) {
+            issues.push("load_shedding.lag_ms must be greater than 0".to_string());
+        }
+    }
+
+    if let Some(geo) = &config.geo {
+        if matches!(geo.moving_kmh, Some(kmh) if kmh <= 0.0) {
+            issues.push("geo.moving_kmh must be greater than 0".to_string());
+        }
+        for zone in geo.zones.as_deref().unwrap_or_default() {
+            if zone.radius_m <= 0.0 {
+                issues.push(format!(
+                    "The radius_m of zone {} must be greater than 0",
+                    zone.name
+                ));
+            }
+            if zone.latitude.abs() > 90.0 || zone.longitude.abs() > 180.0 {
+                issues.push(format!("Zone {} is not a valid position", zone.name));
+            }
+        }
+        check_unique(
+            "geo.zones",
+            geo.zones.iter().flatten().map(|z| &z.name),
+            &mut issues,
+        );
+    }
+
+    if let Some(gnss) = &config.gnss {
+        if gnss.interval_ms == Some(0) {
+            issues.push("gnss.interval_ms must be greater than 0".to_string());
+        }
+        if matches!(gnss.update_rate_hz, Some(hz) if hz == 0 || hz > 25) {
+            issues.push("gnss.update_rate_hz must be between 1 and 25".to_string());
+        }
+        if matches!(gnss.elevation_mask_deg, Some(deg) if deg > 90) {
+            issues.push("gnss.elevation_mask_deg must be at most 90".to_string());
+        }
+    }
+
+    if matches!(&config.inventory, Some(inventory) if inventory.interval_s == Some(0)) {
+        issues.push("inventory.interval_s must be greater than 0".to_string());
+    }
+
+    if matches!(&config.stats, Some(stats) if stats.interval_s == 0) {
+        issues.push("stats.interval_s must be greater than 0".to_string());
+    }
+
+    if matches!(&config.spool, Some(spool) if spool.memory_limit.is_some_and(|l| l < MIN_SPOOL_MEMORY_LIMIT))
+    {
+        issues.push(format!(
+            "spool.memory_limit must be at least {MIN_SPOOL_MEMORY_LIMIT}"
+        ));
+    }
+
+    if let Some(storage) = &config.storage {
+        if matches!(storage.near_full_percent, Some(p) if p == 0 || p > 100) {
+            issues.push("storage.near_full_percent must be between 1 and 100".to_string());
+        }
+        check_unique(
+            "storage.dirs",
+            storage.dirs.iter().map(|d| &d.path),
+            &mut issues,
+        );
+    }
+
+    if let Some(debug_tap) = &config.debug_tap {
+        for sink in &debug_tap.udp_sinks {
+            if !sink
+                .rsplit_once(':')
+                .is_some_and(|(h, p)| !h.is_empty() && p.parse::<u16>().is_ok())
+            {
+                issues.push(format!(
+                    "debug_tap.udp_sinks entry {sink} must be host:port"
+                ));
+            }
+        }
+    }
+    issues
+}
+
+// A file name without a directory, for files that have to be in the
+// configuration directory
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+// Characters allowed in external names. A slash is left out since it
+// separates the derived values from the name, e.g. Door/active_ms.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.trim() == name
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.'))
+}
+
+// The signal names of a local DBC file, after the optional renaming, or
+// None if the file is remote or cannot be read, e.g. when validating a
+// config on another machine
+fn dbc_signal_names(
+    dbc_file: &str,
+    names: Option<&HashMap<String, String>>,
+) -> Option<HashSet<String>> {
+    if dbc_file.contains("://") {
+        return None;
+    }
+    let text = fs::read_to_string(Path::new(conf_dir()).join(dbc_file)).ok()?;
+    let dbc = decode::parse_dbc(dbc_file, &text).ok()?;
+    let rename = |name: &String| names.and_then(|n| n.get(name)).unwrap_or(name).clone();
+    Some(
+        dbc.messages()
+            .iter()
+            .flat_map(|m| m.signals())
+            .map(|s| rename(s.name()))
+            .collect(),
     )
-    .expect("Failed to load any config file.")
+}
+
+// The external names are the names of the series in the backend, so a
+// name may only be used by one input, output or signal. Inputs and
+// outputs have no bus and collide with signals on any bus, while signals
+// only collide with signals on the same bus.
+fn check_external_names(config: &Config, issues: &mut Vec<String>) {
+    let dbc_names = config
+        .can
+        .as_ref()
+        .and_then(|c| c.dbc_file.as_deref())
+        .and_then(|f| dbc_signal_names(f, None));
+    let mut names: Vec<(&str, Option<&str>, &str)> = Vec::new();
+    for port in config
+        .digital_in
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+    {
+        names.push(("digital_in", None, &port.external_name));
+    }
+    for port in config
+        .digital_out
+        .iter()
+        .flat_map(|d| d.ports.iter().flatten())
+    {
+        names.push(("digital_out", None, &port.external_name));
+    }
+    for port in config.analog_out.iter().flat_map(|a| &a.ports) {
+        names.push(("analog_out", None, &port.external_name));
+    }
+
+    // DBC signals are decoded on every CAN port that is not raw
+    if let Some(can) = &config.can {
+        let buses: Vec<_> = can
+            .ports
+            .iter()
+            .flatten()
+            .filter(|p| p.raw != Some(true))
+            .map(|p| p.name.as_str())
+            .collect();
+        let renamed = can.names.as_ref();
+        for bus in &buses {
+            for name in renamed.iter().flat_map(|n| n.values()) {
+                names.push(("can.names", Some(*bus), name));
+            }
+            let dbc_names = dbc_names.iter().flatten();
+            for name in dbc_names.filter(|n| !renamed.is_some_and(|r| r.contains_key(*n))) {
+                names.push(("dbc_file", Some(*bus), name));
+            }
+        }
+        for composite in can.composites.iter().flatten() {
+            names.push(("can.composites", Some(COMPOSITE_BUS), &composite.name));
+        }
+    }
+    if let Some(test_signals) = &config.test_signals {
+        let bus = test_signals
+            .bus
+            .as_deref()
+            .unwrap_or(DEFAULT_TEST_SIGNALS_BUS);
+        for signal in &test_signals.signals {
+            names.push(("test_signals", Some(bus), &signal.name));
+        }
+    }
+    if let Some(opcua) = &config.opcua {
+        let bus = opcua.bus.as_deref().unwrap_or(DEFAULT_OPCUA_BUS);
+        for node in &opcua.nodes {
+            names.push(("opcua.nodes", Some(bus), &node.name));
+        }
+    }
+    if let Some(gnss) = &config.gnss {
+        let bus = gnss.bus.as_deref().unwrap_or(DEFAULT_GNSS_BUS);
+        for name in GNSS_SIGNALS {
+            names.push(("gnss", Some(bus), name));
+        }
+    }
+
+    let mut seen: HashMap<&str, Vec<(&str, Option<&str>)>> = HashMap::new();
+    for (section, bus, name) in names {
+        if !is_safe_name(name) {
+            issues.push(format!(
+                "{section} name {name:?} may only contain letters, digits, spaces, '_', '-' and '.'"
+            ));
+        }
+        let users = seen.entry(name).or_default();
+        let collides = |(other, other_bus): &(&str, Option<&str>)| {
+            *other != section && (bus.is_none() || other_bus.is_none() || bus == *other_bus)
+        };
+        if let Some((other, _)) = users.iter().find(|u| collides(u)) {
+            let issue = format!("{name} is used by both {other} and {section}");
+            if !issues.contains(&issue) {
+                issues.push(issue);
+            }
+        }
+        users.push((section, bus));
+    }
+}
+
+fn check_failsafe(output: &str, failsafe: &Failsafe, range: (f64, f64), issues: &mut Vec<String>) {
+    match (failsafe.action, failsafe.value) {
+        (FailsafeAction::Set, None) => {
+            issues.push(format!("The failsafe of {output} requires a value"))
+        }
+        (FailsafeAction::Set, Some(v)) if v < range.0 || v > range.1 => issues.push(format!(
+            "The failsafe value of {output} is outside of {} to {}",
+            range.0, range.1
+        )),
+        _ => (),
+    }
+}
+
+fn check_unique<'a>(
+    section: &str,
+    names: impl Iterator<Item = &'a String>,
+    issues: &mut Vec<String>,
+) {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            issues.push(format!("{section} contains {name} more than once"));
+        }
+    }
 }
 
 fn load_identity() -> Identity {
-    let identity = PathBuf::from(format!("{}/identity.toml", CONF_DIR));
-    let fallback_identity = PathBuf::from(format!("{}/identity-fallback.toml", CONF_DIR));
+    identity::identity_provider(CONFIG.identity.as_ref())
+        .and_then(|provider| provider.identity())
+        .expect("Identity could not be established.")
+}
 
-    toml::from_str(
-        &fs::read_to_string(identity)
-            .unwrap_or_else(|_| fs::read_to_string(fallback_identity).unwrap()),
-    )
-    .expect("Identity could not be established.")
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: &str = "[time]\nheartbeat_s = 60\nsleep_max_s = 600\nsleep_min_s = 1\n";
+
+    // Validate as a unit without a profile, independent of the identity
+    // file of the host
+    fn validate(s: &str) -> Result<Config, String> {
+        validate_config_for(s, None)
+    }
+
+    #[test]
+    fn validate_accepts_minimal_config() {
+        assert!(validate(TIME).is_ok());
+    }
+
+    #[test]
+    fn validate_reports_all_issues() {
+        let config = format!(
+            "{TIME}[can]\nports = [{{ name = \"can0\" }}, {{ name = \"can0\" }}]\n\
+             [debug_tap]\nudp_sinks = [\"127.0.0.1\"]\n\
+             [stats]\ninterval_s = 0\n[spool]\ndir = \"spool\"\nmemory_limit = 10\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("can.dbc_file is required"));
+        assert!(issues.contains("can.ports contains can0 more than once"));
+        assert!(issues.contains("stats.interval_s"));
+        assert!(issues.contains("spool.memory_limit must be at least 100"));
+        assert!(issues.contains("debug_tap.udp_sinks entry 127.0.0.1 must be host:port"));
+    }
+
+    #[test]
+    fn validate_accepts_raw_ports_without_dbc() {
+        let config = format!("{TIME}[can]\nports = [{{ name = \"can0\", raw = true }}]\n");
+        assert!(validate(&config).is_ok());
+
+        // Raw ports decode no signals, so their names do not collide
+        let config = format!(
+            "{TIME}[can]\nports = [{{ name = \"can0\", raw = true }}]\n\
+             [can.names]\nEngineSpeed = \"Speed\"\n\
+             [test_signals]\nbus = \"can0\"\nsignals = [{{ name = \"Speed\", \
+             waveform = \"ramp\", interval_ms = 100 }}]\n"
+        );
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_bad_security_access() {
+        let config = format!(
+            "{TIME}[can]\ndbc_file = \"vehicle.dbc\"\nports = [{{ name = \"can0\" }}]\n\
+             [[can.security_access]]\nport = \"can0\"\nalgorithm = \"library\"\nlevel = 2\n\
+             library = \"../lib/keygen.so\"\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("must be an odd requestSeed level"));
+        assert!(issues.contains("library ../lib/keygen.so of can0 must be a file name"));
+        assert!(is_plain_file_name("keygen.so"));
+        assert!(!is_plain_file_name("/usr/lib/keygen.so"));
+        assert!(!is_plain_file_name(".."));
+    }
+
+    #[test]
+    fn validate_rejects_bad_zones() {
+        let config = format!(
+            "{TIME}[geo]\nlatitude = \"Lat\"\nlongitude = \"Lon\"\nspeed = \"Speed\"\n\
+             [[geo.zones]]\nname = \"Depot\"\nlatitude = 57.7\nlongitude = 11.9\nradius_m = 0\n\
+             [[geo.zones]]\nname = \"Border\"\nlatitude = 95\nlongitude = 11.9\nradius_m = 500\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("The radius_m of zone Depot must be greater than 0"));
+        assert!(issues.contains("Zone Border is not a valid position"));
+    }
+
+    #[test]
+    fn validate_rejects_bad_keep_alive() {
+        let config = format!(
+            "{TIME}[can]\ndbc_file = \"vehicle.dbc\"\nports = [{{ name = \"can0\" }}]\n\
+             [[can.keep_alive]]\nport = \"can0\"\nid = 0x7DF\ndata = [1, 2, 3, 4, 5, 6, 7, 8, 9]\n\
+             interval_ms = 1\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("require listen_only = false"));
+        assert!(issues.contains("more than 8 data bytes"));
+        assert!(issues.contains("must be at least 10"));
+    }
+
+    #[test]
+    fn validate_rejects_bad_bridge_rate() {
+        let config = format!(
+            "{TIME}[can]\ndbc_file = \"vehicle.dbc\"\n\
+             ports = [{{ name = \"can0\" }}, {{ name = \"can1\", listen_only = false }}]\n\
+             [[can.bridges]]\nfrom = \"can0\"\nto = \"can1\"\nids = [0x100]\nmax_rate_hz = 1e-300\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("The max_rate_hz of a bridge must be at least 0.001"));
+    }
+
+    #[test]
+    fn validate_rejects_bad_transmit() {
+        let config = format!(
+            "{TIME}[can]\ndbc_file = \"vehicle.dbc\"\nports = [{{ name = \"can0\" }}]\n\
+             [[can.transmit]]\nport = \"can0\"\nids = [0x2F0, 0x40000000]\n\
+             unlock = {{ tx_id = 0x7E0, rx_id = 0x7E8 }}\n\
+             [[can.transmit]]\nport = \"can1\"\nids = []\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Transmit on can0 requires listen_only = false"));
+        assert!(issues.contains("Transmit ID 0x40000000 is too large"));
+        assert!(issues.contains("Transmit uses unknown port can1"));
+        assert!(
+            issues.contains("Transmit on can0 unlocks an ECU but the port has no security access")
+        );
+    }
+
+    #[test]
+    fn validate_rejects_bad_attention() {
+        let config = format!(
+            "{TIME}[digital_in]\nports = [{{ internal_name = \"in0\", external_name = \"Ack\" }}]\n\
+             [attention]\noutput = \"Buzzer\"\ninput = \"Ack\"\ninterval_s = 60\nwindow_s = 60\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("unknown digital out Buzzer"));
+        assert!(!issues.contains("unknown digital in"));
+        assert!(issues.contains("less than interval_s"));
+    }
+
+    #[test]
+    fn validate_rejects_bad_jobs() {
+        let config = format!(
+            "{TIME}[[jobs]]\nname = \"snapshot\"\nat = \"6:00\"\naction = \"snapshot\"\n\
+             [[jobs]]\nname = \"self-test\"\nat = \"03:00\"\ndays = [\"sunday\"]\n\
+             action = \"command\"\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(!issues.contains("Job snapshot"));
+        assert!(issues.contains("Job self-test has an invalid day sunday"));
+        assert!(issues.contains("Job self-test requires a command"));
+
+        let config = format!("{TIME}[[jobs]]\nname = \"x\"\nat = \"24:00\"\naction = \"state\"\n");
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Job x requires at as HH:MM"));
+    }
+
+    #[test]
+    fn node_ids() {
+        assert!(is_node_id("i=2258"));
+        assert!(is_node_id("ns=2;s=Line1.Temperature"));
+        assert!(!is_node_id("ns=1;g=09087e75-8e5e-499b-954f-f2a9603db28a"));
+        assert!(!is_node_id("b=M/RbKBsRVkePCePcx24oRA=="));
+        assert!(!is_node_id("ns=x;i=1"));
+        assert!(!is_node_id("ns=2;i=abc"));
+        assert!(!is_node_id("s="));
+        assert!(!is_node_id("Temperature"));
+    }
+
+    #[test]
+    fn profile_replaces_blocks() {
+        let config = format!(
+            "{TIME}[stats]\ninterval_s = 60\n\
+             [profiles.bus-city.stats]\ninterval_s = 10\n\
+             [profiles.mining-truck.history]\ndepth = 5\nsignals = []\n"
+        );
+        let default = parse_config(&config, None).unwrap();
+        assert_eq!(default.stats.unwrap().interval_s, 60);
+        assert!(default.history.is_none());
+
+        let bus = parse_config(&config, Some("bus-city")).unwrap();
+        assert_eq!(bus.stats.unwrap().interval_s, 10);
+        assert!(bus.history.is_none());
+        assert_eq!(bus.profiles, ["bus-city", "mining-truck"]);
+
+        let truck = parse_config(&config, Some("mining-truck")).unwrap();
+        assert_eq!(truck.stats.unwrap().interval_s, 60);
+        assert_eq!(truck.history.unwrap().depth, 5);
+    }
+
+    #[test]
+    fn validate_rejects_undefined_profile() {
+        let issues = validate_config_for(TIME, Some("bus-city")).err().unwrap();
+        assert!(issues.contains("Profile bus-city is not defined"));
+
+        let config = format!("{TIME}[profiles.bus-city.stats]\ninterval_s = 10\n");
+        assert!(validate_config_for(&config, Some("bus-city")).is_ok());
+        let issues = validate_config_for(&config, Some("mining-truck"))
+            .err()
+            .unwrap();
+        assert!(issues.contains("Profile mining-truck is not defined"));
+    }
+
+    #[test]
+    fn active_schedule_overrides_keys() {
+        let config = format!(
+            "{TIME}[stats]\ninterval_s = 60\ntop_talkers = 5\n\
+             [schedules.night]\nstart = \"22:00\"\nend = \"06:00\"\ndays = [\"fri\"]\n\
+             [schedules.night.overrides.stats]\ninterval_s = 600\n"
+        );
+        let at = |weekday, hour: u32| schedule::LocalTime {
+            weekday,
+            minute: hour * 60,
+        };
+
+        // Friday night, into Saturday morning
+        for t in [at(5, 23), at(6, 5)] {
+            let config = parse_config_at(&config, None, t).unwrap();
+            assert_eq!(config.active_schedules, ["night"]);
+            let stats = config.stats.unwrap();
+            assert_eq!(stats.interval_s, 600);
+            assert_eq!(stats.top_talkers, Some(5));
+        }
+        for t in [at(5, 12), at(6, 23), at(5, 5)] {
+            let config = parse_config_at(&config, None, t).unwrap();
+            assert!(config.active_schedules.is_empty());
+            assert_eq!(config.stats.unwrap().interval_s, 60);
+        }
+    }
+
+    #[test]
+    fn validate_checks_inactive_schedules() {
+        // Overrides that break the config, in schedules that are never
+        // active, since they end when they start
+        let config = format!(
+            "{TIME}[schedules.broken]\nstart = \"03:00\"\nend = \"03:00\"\n\
+             [schedules.broken.overrides.time]\nheartbeat_s = 0\n\
+             [schedules.typo]\nstart = \"04:00\"\nend = \"04:00\"\n\
+             [schedules.typo.overrides.time]\nheartbeat_s = \"soon\"\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("time.heartbeat_s must be greater than 0 (with schedule broken)"));
+        assert!(issues.contains("(with schedule typo)"));
+        assert!(!issues.contains("without schedules"));
+        // A broken schedule does not stop the config from loading
+        assert!(parse_local_config(&config, None).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_ambiguous_names() {
+        let config = format!(
+            "{TIME}[digital_in]\nports = [{{ internal_name = \"in0\", external_name = \"Pump\" }},\
+             {{ internal_name = \"in1\", external_name = \"Door/open\" }}]\n\
+             [analog_out]\nports = [{{ external_name = \"Pump\", path = \"/dev/null\", \
+             min = 0.0, max = 1.0, default = 0.0 }}]\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Pump is used by both digital_in and analog_out"));
+        assert!(issues.contains("digital_in name \"Door/open\" may only contain"));
+
+        // Signals collide with inputs and outputs
+        let config = format!(
+            "{TIME}[digital_out]\nports = [{{ internal_name = \"out0\", external_name = \"Speed\", \
+             default_state = 0 }}]\n\
+             [test_signals]\nsignals = [{{ name = \"Speed\", waveform = \"ramp\", \
+             interval_ms = 100 }}, {{ name = \"Line 1/Count\", waveform = \"ramp\", interval_ms = 100 }}]\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Speed is used by both digital_out and test_signals"));
+        assert!(issues.contains("test_signals name \"Line 1/Count\" may only contain"));
+
+        // and with each other on the same bus
+        let config = format!(
+            "{TIME}[digital_out]\nports = [{{ internal_name = \"out0\", external_name = \"Latitude\", \
+             default_state = 0 }}]\n\
+             [gnss]\n\
+             [test_signals]\nbus = \"gnss\"\nsignals = [{{ name = \"Speed\", waveform = \"ramp\", \
+             interval_ms = 100 }}, {{ name = \"Heading\", waveform = \"ramp\", interval_ms = 100 }}]\n\
+             [opcua]\nendpoint = \"opc.tcp://plc\"\nnodes = [{{ name = \"Heading\", node_id = \"i=1\" }},\
+             {{ name = \"Line 1/Count\", node_id = \"i=2\" }}]\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Latitude is used by both digital_out and gnss"));
+        assert!(issues.contains("Speed is used by both test_signals and gnss"));
+        assert!(issues.contains("Heading is used by both test_signals and gnss"));
+        assert!(!issues.contains("opcua.nodes and"));
+        assert!(!issues.contains("and opcua.nodes"));
+        assert!(issues.contains("opcua.nodes name \"Line 1/Count\" may only contain"));
+        let config = format!(
+            "{TIME}[gnss]\n[test_signals]\nsignals = [{{ name = \"Speed\", waveform = \"ramp\", \
+             interval_ms = 100 }}]\n"
+        );
+        assert!(validate(&config).is_ok());
+
+        let config = format!(
+            "{TIME}[analog_out]\nports = [{{ external_name = \"Fan\", path = \"/dev/null\", \
+             min = 1.0, max = 0.0, default = 0.5 }}]\n"
+        );
+        let issues = validate(&config).err().unwrap();
+        assert!(issues.contains("Analog out Fan has a min greater than its max"));
+        assert!(is_safe_name("Finger protection"));
+        assert!(!is_safe_name(" Pump"));
+    }
+
+    #[test]
+    fn validate_checks_composite_signals() {
+        let dir = std::env::temp_dir().join(format!("composites-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dbc = dir.join("vehicle.dbc");
+        fs::write(
+            &dbc,
+            "VERSION \"\"\n\nNS_ :\n\nBS_:\n\nBU_: ECU\n\n\
+             BO_ 256 Engine: 2 ECU\n SG_ Speed : 0|8@1+ (1,0) [0|0] \"\" Vector__XXX\n \
+             SG_ Temp : 8|8@1+ (1,0) [0|0] \"\" Vector__XXX\n",
+        )
+        .unwrap();
+        let config = format!(
+            "{TIME}[can]\ndbc_file = {:?}\nports = [{{ name = \"can0\" }}]\n\
+             [can.names]\nTemp = \"EngineTemp\"\n\
+             [[can.composites]]\nname = \"Engine\"\nwindow_ms = 100\n\
+             signals = [\"Speed\", \"EngineTemp\", \"Speed\", \"Rpm\"]\n\
+             [[can.composites]]\nname = \"Engine\"\nwindow_ms = 100\nsignals = [\"Speed\"]\n\
+             [digital_in]\nports = [{{ internal_name = \"in0\", external_name = \"Speed\" }},\
+             {{ internal_name = \"in1\", external_name = \"Temp\" }}]\n",
+            dbc.to_str().unwrap()
+        );
+        let issues = validate(&config).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(issues.contains("can.composites contains Engine more than once"));
+        assert!(issues.contains("Composite Engine contains Speed more than once"));
+        assert!(issues.contains("Composite Engine uses unknown signal Rpm"));
+        assert!(!issues.contains("unknown signal EngineTemp"));
+        // Signals that are renamed are only known by their new name
+        assert!(issues.contains("Speed is used by both digital_in and dbc_file"));
+        assert!(!issues.contains("Temp is used"));
+    }
+
+    #[test]
+    fn includes_are_merged_in_order() {
+        let dir = std::env::temp_dir().join(format!("includes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("common.toml"),
+            format!("{TIME}[stats]\ninterval_s = 60\ntop_talkers = 5\n[history]\ndepth = 5\nsignals = [\"A\"]\n"),
+        )
+        .unwrap();
+        fs::write(dir.join("site.toml"), "[stats]\ninterval_s = 10\n").unwrap();
+
+        let mut root: toml::value::Table = toml::from_str(
+            "include = [\"common.toml\", \"site.toml\"]\n[history]\nsignals = [\"B\"]\n",
+        )
+        .unwrap();
+        apply_includes(&mut root, dir.to_str().unwrap()).unwrap();
+        let config: Config = toml::Value::Table(root).try_into().unwrap();
+        let stats = config.stats.unwrap();
+        assert_eq!(stats.interval_s, 10);
+        assert_eq!(stats.top_talkers, Some(5));
+        let history = config.history.unwrap();
+        assert_eq!(history.depth, 5);
+        assert_eq!(history.signals, ["B"]);
+
+        let mut missing: toml::value::Table =
+            toml::from_str("include = [\"missing.toml\"]").unwrap();
+        assert!(apply_includes(&mut missing, dir.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn effective_hash_is_sha256() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_toml() {
+        assert!(validate("[time").is_err());
+    }
 }
@@ -0,0 +1,117 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Ad-hoc live streams of selected signals, requested by the server
+// independently of the standing configuration, e.g. for a support
+// engineer watching a signal live.
+
+use super::net::{handle_send_result, intercept};
+use super::scrub;
+use super::tap;
+use futures::stream;
+use lazy_static::lazy_static;
+use lib::{
+    cache, history,
+    host_insight::{agent_client::AgentClient, CanMessage, CanSignal, LiveStreamRequest},
+    CONFIG,
+};
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use tonic::Request;
+
+const MIN_INTERVAL_MS: u64 = 100;
+const MAX_DURATION_S: u64 = 3600;
+const LIVE_STREAM_BUS: &str = "live";
+
+lazy_static! {
+    static ref LIVE_STREAM_REQUESTS: Mutex<Vec<LiveStreamRequest>> = Mutex::new(Vec::new());
+}
+
+pub async fn request_live_stream(request: LiveStreamRequest) {
+    LIVE_STREAM_REQUESTS.lock().await.push(request);
+}
+
+// Start a live stream session for every request from the server.
+// Sessions run concurrently and end by themselves when they expire.
+pub async fn live_stream_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+    loop {
+        let requests: Vec<LiveStreamRequest> =
+            LIVE_STREAM_REQUESTS.lock().await.drain(..).collect();
+        for request in requests {
+            tokio::spawn(live_stream_session(channel.clone(), request));
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+async fn live_stream_session(channel: Channel, request: LiveStreamRequest) {
+    let interval =
+        Duration::from_millis(std::cmp::max(request.interval_ms as u64, MIN_INTERVAL_MS));
+    let duration = Duration::from_secs(std::cmp::min(request.duration_s as u64, MAX_DURATION_S));
+    println!(
+        "Starting live stream of {:?} every {:?} for {:?}",
+        request.signals, interval, duration
+    );
+
+    let deadline = Instant::now() + duration;
+    let signals = request.signals;
+    let samples = stream::unfold(signals, move |signals| async move {
+        if Instant::now() >= deadline {
+            return None;
+        }
+        sleep(interval).await;
+        let message = sample_signals(&signals).await;
+        tap::record("SendLiveStream", &message).await;
+        Some((message, signals))
+    });
+
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    match client.send_live_stream(Request::new(samples)).await {
+        Ok(response) => {
+            let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+            let _ = handle_send_result(Ok(response), &mut retry_sleep_s).await;
+        }
+        // Live data is only of interest right now, so it is not resent
+        Err(e) => eprintln!("Live stream failed: {e}"),
+    }
+}
+
+async fn sample_signals(signals: &[String]) -> CanMessage {
+    let mut can_signals = Vec::new();
+    for name in signals {
+        if let Some(cached) = cache::get_any(name).await {
+            can_signals.push(CanSignal {
+                signal_name: name.clone(),
+                unit: cached.unit,
+                value: Some(cached.value),
+                raw: None,
+                refresh: false,
+            });
+        }
+    }
+    scrub::scrub_signals(&mut can_signals);
+    CanMessage {
+        bus: LIVE_STREAM_BUS.to_string(),
+        time_stamp: Some(history::unix_millis(SystemTime::now())),
+        signal: can_signals,
+        composite: String::new(),
+    }
+}
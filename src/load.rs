@@ -0,0 +1,132 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Monitoring of the CPU use of the client and the lag of its event
+// loop. On low-end SoCs a burst of traffic can otherwise make the
+// client fall behind, with a latency that grows without bound. Above
+// the thresholds the client sheds load, see backpressure.rs, and
+// reports it in the heartbeat until the load has come down.
+
+use super::backpressure;
+use super::net::{clear_status, set_status};
+use lib::{LoadSheddingConfig, StatusCodes, CONFIG};
+use std::error::Error;
+use std::fs;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const DEFAULT_CPU_PERCENT: f64 = 80.0;
+const DEFAULT_LAG_MS: u64 = 100;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+// Number of samples the CPU use and the lag are averaged over
+const WINDOW: u32 = 50;
+// Shedding stops when both are below this fraction of their thresholds
+const RECOVERY_FRACTION: f64 = 0.75;
+
+// CPU time used by the process in clock ticks, from /proc/self/stat
+fn cpu_ticks() -> Option<u64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name in parentheses may contain spaces
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+    // utime and stime are fields 14 and 15, counting from the pid
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn ticks_per_second() -> f64 {
+    // SAFETY: sysconf only reads a system setting
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        n if n > 0 => n as f64,
+        _ => 100.0,
+    }
+}
+
+// Whether to shed load at the given CPU use, in percent of one core, and
+// event loop lag
+fn is_overloaded(config: &LoadSheddingConfig, cpu: f64, lag: Duration, shedding: bool) -> bool {
+    let max_cpu = config.cpu_percent.unwrap_or(DEFAULT_CPU_PERCENT);
+    let max_lag = Duration::from_millis(config.lag_ms.unwrap_or(DEFAULT_LAG_MS));
+    if shedding {
+        cpu >= max_cpu * RECOVERY_FRACTION || lag >= max_lag.mul_f64(RECOVERY_FRACTION)
+    } else {
+        cpu >= max_cpu || lag >= max_lag
+    }
+}
+
+pub async fn load_monitor() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.load_shedding.as_ref().unwrap();
+    let ticks_per_second = ticks_per_second();
+    loop {
+        let start = Instant::now();
+        let start_ticks = cpu_ticks();
+        let mut lag = Duration::ZERO;
+        for _ in 0..WINDOW {
+            let before = Instant::now();
+            sleep(SAMPLE_INTERVAL).await;
+            lag += before.elapsed().saturating_sub(SAMPLE_INTERVAL);
+        }
+        lag /= WINDOW;
+        let cpu = match (start_ticks, cpu_ticks()) {
+            (Some(a), Some(b)) => {
+                100.0 * b.saturating_sub(a) as f64
+                    / ticks_per_second
+                    / start.elapsed().as_secs_f64()
+            }
+            _ => 0.0,
+        };
+
+        let shedding = backpressure::is_shedding();
+        let overloaded = is_overloaded(config, cpu, lag, shedding);
+        if overloaded != shedding {
+            if overloaded {
+                eprintln!("CPU use {cpu:.0}% and event loop lag {lag:?}, shedding load");
+                set_status(StatusCodes::LoadShedding).await;
+            } else {
+                println!("CPU use {cpu:.0}% and event loop lag {lag:?}, no longer shedding load");
+                clear_status(StatusCodes::LoadShedding).await;
+            }
+            backpressure::set_shedding(overloaded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shedding_uses_hysteresis() {
+        let config = LoadSheddingConfig {
+            cpu_percent: Some(80.0),
+            lag_ms: Some(100),
+            min_interval_ms: None,
+            deadband_percent: None,
+            periodic_factor: None,
+            best_effort: None,
+        };
+        let ms = Duration::from_millis;
+        assert!(!is_overloaded(&config, 50.0, ms(10), false));
+        assert!(is_overloaded(&config, 85.0, ms(10), false));
+        assert!(is_overloaded(&config, 10.0, ms(150), false));
+        // Keep shedding until both are down to 75% of the thresholds
+        assert!(is_overloaded(&config, 70.0, ms(10), true));
+        assert!(is_overloaded(&config, 10.0, ms(80), true));
+        assert!(!is_overloaded(&config, 50.0, ms(50), true));
+    }
+}
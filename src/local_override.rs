@@ -0,0 +1,129 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Local override of the outputs by a physical input, e.g. a service
+// switch required by a machine safety assessment. While the input is
+// active, remote output commands are ignored, the startup sequence is
+// skipped and the outputs are kept in their default states. The
+// override is reported in the heartbeat status and as busy in the
+// remote control status. Until the input has been read, the override is
+// considered active.
+
+use super::analog::set_analog_out;
+use super::gpio::{read_all_digital_in, set_all_digital_out_to_defaults};
+use super::net::{clear_status, set_status};
+use lib::{
+    cache::{self, DIGITAL_IN_SOURCE},
+    host_insight::can_signal,
+    StatusCodes, CONFIG,
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// The last state of the input, 0 or 1, or UNKNOWN before it is read
+const UNKNOWN: u8 = 2;
+static LAST_STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+pub async fn is_active() -> bool {
+    match &CONFIG.output_override {
+        Some(o) => input_active(
+            cache::get(DIGITAL_IN_SOURCE, &o.input)
+                .await
+                .map(|v| v.value)
+                .as_ref(),
+        ),
+        None => false,
+    }
+}
+
+// Read the input directly, before it has been reported. The override
+// is taken to be active if the input cannot be read.
+pub async fn read_input() -> bool {
+    match &CONFIG.output_override {
+        Some(o) => match read_all_digital_in().await {
+            Ok(values) => values.get(&o.input) != Some(&0),
+            Err(e) => {
+                eprintln!("Failed to read the local override input: {e}");
+                true
+            }
+        },
+        None => false,
+    }
+}
+
+// The override is active unless the input is known to be inactive
+fn input_active(value: Option<&can_signal::Value>) -> bool {
+    !matches!(value, Some(can_signal::Value::ValU64(0)))
+}
+
+// Called when a digital input changes
+pub async fn input_changed(name: &str, value: u8) {
+    if !matches!(&CONFIG.output_override, Some(o) if o.input == name) {
+        return;
+    }
+    match state_change(&LAST_STATE, value) {
+        Some(true) => {
+            println!("Local override active, outputs are set to their defaults");
+            set_status(StatusCodes::LocalOverride).await;
+            force_defaults();
+        }
+        Some(false) => {
+            println!("Local override released");
+            clear_status(StatusCodes::LocalOverride).await;
+        }
+        None => {}
+    }
+}
+
+// Store the new state of the input and return it if it changed
+fn state_change(last: &AtomicU8, value: u8) -> Option<bool> {
+    let active = value != 0;
+    (last.swap(active as u8, Ordering::Relaxed) != active as u8).then_some(active)
+}
+
+fn force_defaults() {
+    if let Err(e) = set_all_digital_out_to_defaults() {
+        eprintln!("Failed to override the digital outs: {}", e);
+    }
+    for p in CONFIG.analog_out.iter().flat_map(|a| &a.ports) {
+        if let Err(e) = set_analog_out(&p.external_name, p.default) {
+            eprintln!("Failed to override {}: {}", p.external_name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_is_active_unless_the_input_is_off() {
+        assert!(input_active(None));
+        assert!(input_active(Some(&can_signal::Value::ValU64(1))));
+        assert!(!input_active(Some(&can_signal::Value::ValU64(0))));
+    }
+
+    #[test]
+    fn override_changes_only_on_edges() {
+        let last = AtomicU8::new(UNKNOWN);
+        assert_eq!(state_change(&last, 1), Some(true));
+        assert_eq!(state_change(&last, 1), None);
+        assert_eq!(state_change(&last, 0), Some(false));
+        assert_eq!(state_change(&last, 0), None);
+        assert_eq!(state_change(&last, 1), Some(true));
+    }
+}
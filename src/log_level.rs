@@ -0,0 +1,115 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Debug logging that can be turned on at runtime by the server, so that
+// verbose logs can be had from a misbehaving unit without restarting it
+// and losing the state of interest. The filter is either "debug" for all
+// modules or a comma separated list of modules, e.g. "net,spool". It
+// reverts to normal logging after a while.
+
+use lazy_static::lazy_static;
+use lib::host_insight::LogLevelRequest;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const DEFAULT_DURATION_S: u64 = 600;
+
+// Checked first, so that debug logging costs nothing when it is off
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// Changed on every request, so that only the latest one is reverted
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // The modules to log for
+    static ref MODULES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+// Log to stderr if debug logging is on for the calling module
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log_level::is_enabled(module_path!()) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use debug;
+
+pub fn is_enabled(module_path: &str) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let module = module_path.rsplit("::").next().unwrap_or_default();
+    MODULES
+        .read()
+        .map(|modules| modules.iter().any(|m| m == "debug" || m == module))
+        .unwrap_or(false)
+}
+
+fn set_filter(filter: &str) {
+    let modules: Vec<String> = filter
+        .split(',')
+        .map(|m| m.trim().to_string())
+        .filter(|m| !m.is_empty())
+        .collect();
+    ENABLED.store(!modules.is_empty(), Ordering::Relaxed);
+    if let Ok(mut m) = MODULES.write() {
+        *m = modules;
+    }
+}
+
+// Change the filter on request of the server. An empty filter turns debug
+// logging off.
+pub fn request_log_level(request: LogLevelRequest) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    set_filter(&request.filter);
+    if request.filter.is_empty() {
+        println!("Debug logging off");
+        return;
+    }
+
+    let duration_s = match request.duration_s {
+        0 => DEFAULT_DURATION_S,
+        s => s as u64,
+    };
+    println!("Debug logging of {} for {} s", request.filter, duration_s);
+    tokio::spawn(async move {
+        sleep(Duration::from_secs(duration_s)).await;
+        if GENERATION.load(Ordering::Relaxed) == generation {
+            set_filter("");
+            println!("Debug logging off");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        set_filter("net, spool");
+        assert!(is_enabled("host_insight_client::net"));
+        assert!(!is_enabled("host_insight_client::can"));
+        set_filter("debug");
+        assert!(is_enabled("host_insight_client::can"));
+        set_filter("");
+        assert!(!is_enabled("host_insight_client::net"));
+    }
+}
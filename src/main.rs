@@ -16,71 +16,627 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
+use alert::alert_monitor;
+use analog::set_all_analog_out_to_defaults;
+use attention::{attention_event_sender, attention_monitor};
+use bridge::bridge;
+use bus_errors::{bus_error_monitor, bus_error_sender};
+use bus_off::bus_off_monitor;
 use can::{can_monitor, can_sender, setup_can};
-use clap::command;
+use cert::{cert_monitor, finish_renewal};
+use clap::{builder::BoolishValueParser, command, value_parser, Arg, ArgAction, Command};
+use clock::clock_monitor;
+use config_update::{config_update_monitor, schedule_monitor, take_schedule_reload};
+use control::control_monitor;
+use dbc::{report_dbc, update_remote_dbc};
+use duty::duty_reporter;
+use failsafe::failsafe_monitor;
 use futures::future::try_join_all;
 use futures::future::FutureExt;
-use gpio::{digital_in_monitor, remote_control_monitor, set_all_digital_out_to_defaults};
-use lib::{CONFIG, GIT_COMMIT_DESCRIBE};
-use net::{heartbeat, send_initial_values, setup_network};
+use geo::geo_monitor;
+use gnss::gnss_reporter;
+use gpio::{
+    digital_in_monitor, run_startup_sequence, set_all_digital_out_to_defaults, value_sender,
+};
+use health::health_server;
+use identity_rotation::rotation_monitor;
+use intrusion::security_event_sender;
+use inventory::inventory_reporter;
+use jobs::job_scheduler;
+use keep_alive::keep_alive_sender;
+use lib::{
+    error::{ClientError, Recovery},
+    set_inline_config, set_paths, Paths, StatusCodes, CONFIG, DEFAULT_CA_FILE, DEFAULT_CONF_DIR,
+    DEFAULT_RUN_DIR, GIT_COMMIT_DESCRIBE,
+};
+use live::live_stream_monitor;
+use load::load_monitor;
+use net::{
+    connect, heartbeat, history_sender, send_initial_values, setup_bulk_network, setup_network,
+    wait_for_network,
+};
+use opcua::opcua_reader;
+use output_recovery::report_recovery;
+use periodic::periodic_reporter;
+use raw_can::{raw_frame_sender, raw_monitor};
+use routing::route_sender;
+use safe_mode::{check_crash_loop, run_safe_mode};
+use stats::stats_reporter;
 use std::error::Error;
-use utils::clean_up;
+use std::path::Path;
+use std::time::Duration;
+use storage::storage_manager;
+use test_signals::test_signal_generator;
+use transfer::{remove_partial_downloads, upload_monitor};
+use utils::{clean_up, exit_on_termination};
+use vin::vin_detector;
+use wake::wake_scheduler;
 
+mod alert;
+mod analog;
+mod attention;
+mod backpressure;
+mod bridge;
+mod bus_errors;
+mod bus_off;
 mod can;
+mod can_trace;
+mod can_transmit;
+mod cert;
+mod clock;
+mod composite;
+mod config_update;
+mod control;
+mod dbc;
+mod decode_cache;
+mod discover;
+mod duty;
+mod failsafe;
+mod fdstore;
+mod geo;
+mod gnss;
 mod gpio;
+mod health;
+mod identity_rotation;
+mod intrusion;
+mod inventory;
+mod isotp;
+mod j1939;
+mod jobs;
+mod journal;
+mod keep_alive;
+mod live;
+mod load;
+mod local_override;
+mod log_level;
 mod net;
+mod opcua;
+mod output_recovery;
+mod pairing;
+mod periodic;
+mod raw_can;
+mod redundancy;
+mod replay;
+mod resolve;
+mod routing;
+mod safe_mode;
+mod scrub;
+mod seed_key;
+mod spool;
+mod stats;
+mod storage;
+mod subsystem;
+mod tap;
+mod test_signals;
+mod transfer;
+mod transport;
+mod tunnel;
 mod utils;
+mod vin;
+mod vpn;
+mod wake;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    command!().version(GIT_COMMIT_DESCRIBE).get_matches();
+fn main() -> Result<(), Box<dyn Error>> {
+    // Before the runtime starts its threads
+    let fds = fdstore::received_fds();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(fds))
+}
+
+async fn run(fds: fdstore::ReceivedFds) -> Result<(), Box<dyn Error>> {
+    fdstore::init(fds);
+    let matches = command!()
+        .version(GIT_COMMIT_DESCRIBE)
+        .arg(path_arg(
+            "conf-dir",
+            "HOST_INSIGHT_CONF_DIR",
+            DEFAULT_CONF_DIR,
+            "Configuration directory",
+        ))
+        .arg(path_arg(
+            "ca-file",
+            "HOST_INSIGHT_CA_FILE",
+            DEFAULT_CA_FILE,
+            "CA certificate bundle",
+        ))
+        .arg(path_arg(
+            "run-dir",
+            "HOST_INSIGHT_RUN_DIR",
+            DEFAULT_RUN_DIR,
+            "Directory for runtime state shared with the helper",
+        ))
+        .arg(
+            Arg::new("instance")
+                .long("instance")
+                .value_name("NAME")
+                .env("HOST_INSIGHT_INSTANCE")
+                .value_parser(parse_instance)
+                .help("Name of this instance when several run on one unit"),
+        )
+        .arg(
+            Arg::new("debug-tap")
+                .long("debug-tap")
+                .value_name("TARGET")
+                .env("HOST_INSIGHT_DEBUG_TAP")
+                .value_parser(tap::parse_target)
+                .help("Mirror sent messages as JSON to a file or udp://host:port"),
+        )
+        .arg(
+            Arg::new("trace-can")
+                .long("trace-can")
+                .value_name("PORT")
+                .action(ArgAction::Append)
+                .help("Log the decoded frames of a CAN port for a while"),
+        )
+        .arg(
+            Arg::new("container")
+                .long("container")
+                .env("HOST_INSIGHT_CONTAINER")
+                .action(ArgAction::SetTrue)
+                .value_parser(BoolishValueParser::new())
+                .help("Run inside a container"),
+        )
+        .arg(
+            Arg::new("health-port")
+                .long("health-port")
+                .value_name("PORT")
+                .env("HOST_INSIGHT_HEALTH_PORT")
+                .default_value("8080")
+                .value_parser(value_parser!(u16))
+                .help("Port of the health endpoint in container mode"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("TOML")
+                .env("HOST_INSIGHT_CONFIG")
+                .hide_env_values(true)
+                .help("Configuration to use instead of the files in container mode"),
+        )
+        .arg(
+            Arg::new("allow-job-commands")
+                .long("allow-job-commands")
+                .env("HOST_INSIGHT_ALLOW_JOB_COMMANDS")
+                .action(ArgAction::SetTrue)
+                .value_parser(BoolishValueParser::new())
+                .help("Let jobs in the config run commands"),
+        )
+        .arg(
+            Arg::new("safe-mode-starts")
+                .long("safe-mode-starts")
+                .value_name("COUNT")
+                .env("HOST_INSIGHT_SAFE_MODE_STARTS")
+                .default_value("5")
+                .value_parser(value_parser!(usize))
+                .help("Unclean starts within the window after which safe mode is entered"),
+        )
+        .arg(
+            Arg::new("safe-mode-window")
+                .long("safe-mode-window")
+                .value_name("SECONDS")
+                .env("HOST_INSIGHT_SAFE_MODE_WINDOW")
+                .default_value("600")
+                .value_parser(value_parser!(u64))
+                .help("Window in which unclean starts are counted"),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("List the hardware of the unit as a skeleton config")
+                .arg(
+                    Arg::new("inventory")
+                        .long("inventory")
+                        .value_name("FILE")
+                        .default_value("inventory.json")
+                        .help("File to write the JSON inventory of the hardware to"),
+                ),
+        )
+        .subcommand(Command::new("pair").about("Show a code for pairing the unit with a vehicle"))
+        .subcommand(
+            Command::new("spool")
+                .about("Manage the spool")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("replay")
+                        .about("Upload the spooled data to an endpoint")
+                        .arg(
+                            Arg::new("endpoint")
+                                .long("endpoint")
+                                .value_name("URL")
+                                .required(true)
+                                .help("Endpoint to upload to"),
+                        )
+                        .arg(
+                            Arg::new("dir")
+                                .long("dir")
+                                .value_name("PATH")
+                                .help("Spool dir to upload instead of the configured one"),
+                        )
+                        .arg(
+                            Arg::new("remove")
+                                .long("remove")
+                                .action(ArgAction::SetTrue)
+                                .help("Remove the segments once uploaded"),
+                        ),
+                ),
+        )
+        .get_matches();
+    let path = |name: &str| matches.get_one::<String>(name).unwrap().clone();
+    // Each instance has a config directory of its own
+    let instance = matches.get_one::<String>("instance").cloned();
+    let conf_dir = match &instance {
+        Some(instance) => format!("{}/instances/{}", path("conf-dir"), instance),
+        None => path("conf-dir"),
+    };
+    set_paths(Paths {
+        conf_dir,
+        ca_file: path("ca-file"),
+        run_dir: path("run-dir"),
+        instance,
+    });
+
+    for port in matches
+        .get_many::<String>("trace-can")
+        .into_iter()
+        .flatten()
+    {
+        can_trace::enable(port, can_trace::DEFAULT_DURATION).await;
+    }
+    if let Some(target) = matches.get_one::<tap::Target>("debug-tap") {
+        tap::enable(target, None).await?;
+    }
+
+    if let Some(("discover", discover)) = matches.subcommand() {
+        return discover::discover(discover.get_one::<String>("inventory").unwrap());
+    }
+    if let Some(("pair", _)) = matches.subcommand() {
+        return pairing::pair().await;
+    }
+    if let Some(("spool", spool)) = matches.subcommand() {
+        if let Some(("replay", replay)) = spool.subcommand() {
+            return replay::replay(
+                replay.get_one::<String>("endpoint").unwrap(),
+                replay.get_one::<String>("dir"),
+                replay.get_flag("remove"),
+            )
+            .await;
+        }
+    }
+
+    if matches.get_flag("allow-job-commands") {
+        jobs::allow_commands();
+    }
+
+    // In a container everything is logged to stdout, the interfaces are
+    // set up by the host and the orchestrator probes the health endpoint
+    let container = matches.get_flag("container");
+    if container {
+        nix::unistd::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO)?;
+        if let Some(config) = matches.get_one::<String>("config") {
+            set_inline_config(config.clone());
+        }
+
+        // Start serving before the first contact with the server, which
+        // may take a while
+        let port = *matches.get_one::<u16>("health-port").unwrap();
+        tokio::spawn(health_server(port));
+    }
+
+    match lib::instance() {
+        Some(instance) => println!(
+            "Starting HOST Insight Client {} instance {}",
+            GIT_COMMIT_DESCRIBE, instance
+        ),
+        None => println!("Starting HOST Insight Client {}", GIT_COMMIT_DESCRIBE),
+    }
+    let safe_mode = check_crash_loop(
+        *matches.get_one::<usize>("safe-mode-starts").unwrap(),
+        Duration::from_secs(*matches.get_one::<u64>("safe-mode-window").unwrap()),
+    );
+    let schedule_reload = take_schedule_reload();
+    tokio::spawn(exit_on_termination());
+    if let Some(tls) = &CONFIG.tls {
+        finish_renewal(tls);
+    }
+    remove_partial_downloads(Path::new(lib::conf_dir()));
+    if spool::is_enabled() {
+        spool::remove_partial_segments();
+    }
+    wait_for_network().await;
+    let channel = setup_network().await?;
+
+    if safe_mode {
+        if let Err(e) = run_safe_mode(channel).await {
+            eprintln!("Some task failed: {e}");
+        }
+        clean_up();
+        return Ok(());
+    }
 
-    println!("Starting HOST Insight Client {}", GIT_COMMIT_DESCRIBE);
-    let channel = setup_network().await;
+    let bulk_channel = setup_bulk_network(&channel).await?;
 
+    set_all_analog_out_to_defaults();
     if CONFIG.digital_out.is_some() {
         set_all_digital_out_to_defaults()?;
     }
+    // Restored outputs take the place of the startup sequence, which is
+    // also not run again when only the active schedules changed
+    if !output_recovery::recover().await && !schedule_reload {
+        tokio::spawn(run_startup_sequence());
+    }
 
     // Send state and any initial Digital IN values
     send_initial_values(channel.clone()).await;
 
     let mut all_futures: Vec<Box<dyn FnOnce() -> Vec<_>>> = vec![];
 
+    fdstore::discard_unused();
+
     if let Some(can_config) = &CONFIG.can {
         if let Some(ports) = &can_config.ports {
-            setup_can(ports);
+            if !container {
+                setup_can(ports);
+            }
+
+            // Refresh a remote DBC file before the CAN ports load it
+            if let Some(dbc_file) = can_config
+                .dbc_file
+                .as_ref()
+                .filter(|f| f.starts_with("https://"))
+            {
+                if let Err(e) = update_remote_dbc(dbc_file).await {
+                    eprintln!("Failed to update {dbc_file}, using cached copy: {e}");
+                }
+            }
 
             let can_monitor_futures: Vec<_> = ports
                 .iter()
-                .map(can_monitor)
-                .map(|future| future.boxed())
+                .map(|port| match raw_can::is_enabled_for(port) {
+                    true => raw_monitor(port).boxed(),
+                    false => can_monitor(port).boxed(),
+                })
                 .collect();
             all_futures.push(Box::new(|| can_monitor_futures));
 
-            let can_sender_futures: Vec<_> = vec![can_sender(channel.clone()).boxed()];
+            if raw_can::any_raw() {
+                let raw_sender_futures: Vec<_> =
+                    vec![raw_frame_sender(bulk_channel.clone()).boxed()];
+                all_futures.push(Box::new(|| raw_sender_futures));
+            }
+
+            if let Some(bridges) = &can_config.bridges {
+                let bridge_futures: Vec<_> =
+                    bridges.iter().map(bridge).map(|f| f.boxed()).collect();
+                all_futures.push(Box::new(|| bridge_futures));
+            }
+
+            if vin::is_enabled() {
+                let vin_futures: Vec<_> = vec![vin_detector(channel.clone()).boxed()];
+                all_futures.push(Box::new(|| vin_futures));
+            }
+
+            if let Some(frames) = &can_config.keep_alive {
+                let keep_alive_futures: Vec<_> = vec![keep_alive_sender(frames).boxed()];
+                all_futures.push(Box::new(|| keep_alive_futures));
+            }
+
+            if bus_errors::is_enabled() {
+                let bus_error_futures: Vec<_> = ports
+                    .iter()
+                    .map(|port| bus_error_monitor(port).boxed())
+                    .collect();
+                all_futures.push(Box::new(|| bus_error_futures));
+            }
+
+            // The host owns the interfaces in a container, so they are
+            // not brought up again either
+            if bus_off::is_enabled() && container {
+                eprintln!("bus_off_backoff_ms is ignored in container mode");
+            } else if bus_off::is_enabled() {
+                let bus_off_futures: Vec<_> = ports
+                    .iter()
+                    .filter(|port| bus_off::is_enabled_for(port))
+                    .map(|port| bus_off_monitor(port).boxed())
+                    .collect();
+                all_futures.push(Box::new(|| bus_off_futures));
+            }
+
+            // Bus-off is reported like bus errors
+            if bus_errors::is_enabled() || (bus_off::is_enabled() && !container) {
+                let bus_error_sender_futures: Vec<_> =
+                    vec![bus_error_sender(channel.clone()).boxed()];
+                all_futures.push(Box::new(|| bus_error_sender_futures));
+            }
+
+            if intrusion::is_enabled() {
+                let security_futures: Vec<_> = vec![security_event_sender(channel.clone()).boxed()];
+                all_futures.push(Box::new(|| security_futures));
+            }
+
+            let can_sender_futures: Vec<_> = vec![can_sender(bulk_channel.clone()).boxed()];
             all_futures.push(Box::new(|| can_sender_futures));
+
+            if periodic::is_enabled() {
+                let periodic_reporter_futures: Vec<_> = vec![periodic_reporter().boxed()];
+                all_futures.push(Box::new(|| periodic_reporter_futures));
+            }
+
+            if can_config.dbc_file.is_some() {
+                let dbc_report_futures: Vec<_> = vec![report_dbc(channel.clone()).boxed()];
+                all_futures.push(Box::new(|| dbc_report_futures));
+            }
         }
     }
 
+    if CONFIG.test_signals.is_some() {
+        let test_signal_futures: Vec<_> = vec![test_signal_generator().boxed()];
+        all_futures.push(Box::new(|| test_signal_futures));
+    }
+
+    if CONFIG.gnss.is_some() {
+        let gnss_futures: Vec<_> = vec![gnss_reporter().boxed()];
+        all_futures.push(Box::new(|| gnss_futures));
+    }
+
+    if CONFIG.opcua.is_some() {
+        let opcua_futures: Vec<_> = vec![opcua_reader().boxed()];
+        all_futures.push(Box::new(|| opcua_futures));
+    }
+
+    // The test signals, positions and OPC UA values are sent like CAN
+    // signals
+    if (CONFIG.test_signals.is_some() || CONFIG.gnss.is_some() || CONFIG.opcua.is_some())
+        && CONFIG.can.as_ref().and_then(|c| c.ports.as_ref()).is_none()
+    {
+        let can_sender_futures: Vec<_> = vec![can_sender(bulk_channel.clone()).boxed()];
+        all_futures.push(Box::new(|| can_sender_futures));
+    }
+
     if let Some(digital_in_config) = &CONFIG.digital_in {
         if let Some(ports) = &digital_in_config.ports {
             let digital_in_monitor_futures: Vec<_> = ports
                 .iter()
-                .map(|port| digital_in_monitor(port, channel.clone()))
+                .map(digital_in_monitor)
                 .map(|future| future.boxed())
                 .collect();
             all_futures.push(Box::new(|| digital_in_monitor_futures));
         }
-        let remote_control_futures: Vec<_> = vec![remote_control_monitor(channel.clone()).boxed()];
-        all_futures.push(Box::new(|| remote_control_futures));
+        let value_sender_futures: Vec<_> = vec![value_sender(bulk_channel.clone()).boxed()];
+        all_futures.push(Box::new(|| value_sender_futures));
+
+        if duty::is_enabled() {
+            let duty_reporter_futures: Vec<_> = vec![duty_reporter().boxed()];
+            all_futures.push(Box::new(|| duty_reporter_futures));
+        }
+    }
+
+    let config_update_futures: Vec<_> = vec![config_update_monitor(channel.clone()).boxed()];
+    all_futures.push(Box::new(|| config_update_futures));
+
+    if !CONFIG.schedules.is_empty() {
+        let schedule_monitor_futures: Vec<_> = vec![schedule_monitor().boxed()];
+        all_futures.push(Box::new(|| schedule_monitor_futures));
+    }
+
+    let control_futures: Vec<_> = vec![control_monitor(channel.clone()).boxed()];
+    all_futures.push(Box::new(|| control_futures));
+
+    let output_recovery_futures: Vec<_> = vec![report_recovery(channel.clone()).boxed()];
+    all_futures.push(Box::new(|| output_recovery_futures));
+
+    let inventory_futures: Vec<_> = vec![inventory_reporter(channel.clone()).boxed()];
+    all_futures.push(Box::new(|| inventory_futures));
+
+    if CONFIG.jobs.is_some() {
+        let job_futures: Vec<_> = vec![job_scheduler(channel.clone()).boxed()];
+        all_futures.push(Box::new(|| job_futures));
+    }
+
+    if CONFIG.history.is_some() {
+        let history_sender_futures: Vec<_> = vec![history_sender(bulk_channel.clone()).boxed()];
+        all_futures.push(Box::new(|| history_sender_futures));
+    }
+
+    if let Some(routes) = &CONFIG.routes {
+        let mut route_futures = Vec::new();
+        for route in routes {
+            let channel = connect(&route.domain).await?;
+            route_futures.push(route_sender(route, channel).boxed());
+        }
+        all_futures.push(Box::new(|| route_futures));
+    }
+
+    if CONFIG.wake.is_some() {
+        let wake_futures: Vec<_> = vec![wake_scheduler(channel.clone()).boxed()];
+        all_futures.push(Box::new(|| wake_futures));
+    }
+
+    let rotation_monitor_futures: Vec<_> = vec![rotation_monitor().boxed()];
+    all_futures.push(Box::new(|| rotation_monitor_futures));
+
+    if CONFIG.tls.is_some() {
+        let cert_monitor_futures: Vec<_> = vec![cert_monitor().boxed()];
+        all_futures.push(Box::new(|| cert_monitor_futures));
+    }
+
+    if CONFIG.geo.is_some() {
+        let geo_monitor_futures: Vec<_> = vec![geo_monitor().boxed()];
+        all_futures.push(Box::new(|| geo_monitor_futures));
+    }
+
+    if CONFIG.load_shedding.is_some() {
+        let load_monitor_futures: Vec<_> = vec![load_monitor().boxed()];
+        all_futures.push(Box::new(|| load_monitor_futures));
     }
 
-    // Always add heartbeat
-    let remote_control_futures: Vec<_> = vec![heartbeat(channel.clone()).boxed()];
+    let clock_monitor_futures: Vec<_> = vec![clock_monitor().boxed()];
+    all_futures.push(Box::new(|| clock_monitor_futures));
+
+    let alert_monitor_futures: Vec<_> = vec![alert_monitor(channel.clone()).boxed()];
+    all_futures.push(Box::new(|| alert_monitor_futures));
+
+    if failsafe::is_enabled() {
+        let failsafe_futures: Vec<_> = vec![failsafe_monitor().boxed()];
+        all_futures.push(Box::new(|| failsafe_futures));
+    }
+
+    if CONFIG.attention.is_some() {
+        let attention_futures: Vec<_> = vec![
+            attention_monitor().boxed(),
+            attention_event_sender(channel.clone()).boxed(),
+        ];
+        all_futures.push(Box::new(|| attention_futures));
+    }
+
+    let live_stream_futures: Vec<_> = vec![live_stream_monitor(bulk_channel.clone()).boxed()];
+    all_futures.push(Box::new(|| live_stream_futures));
+
+    if CONFIG.transfer.is_some() {
+        let upload_monitor_futures: Vec<_> = vec![upload_monitor(bulk_channel.clone()).boxed()];
+        all_futures.push(Box::new(|| upload_monitor_futures));
+    }
+
+    if CONFIG.stats.is_some() {
+        let stats_reporter_futures: Vec<_> = vec![stats_reporter(channel.clone()).boxed()];
+        all_futures.push(Box::new(|| stats_reporter_futures));
+    }
+
+    if CONFIG.storage.is_some() {
+        let storage_manager_futures: Vec<_> = vec![storage_manager().boxed()];
+        all_futures.push(Box::new(|| storage_manager_futures));
+    }
+
+    // Always add heartbeat, on a connection of its own
+    let heartbeat_channel = setup_network().await?;
+    let remote_control_futures: Vec<_> = vec![heartbeat(heartbeat_channel).boxed()];
     all_futures.push(Box::new(|| remote_control_futures));
 
-    let flattened_futures: Vec<_> = all_futures.into_iter().flat_map(|f| f()).collect();
+    let flattened_futures: Vec<_> = all_futures
+        .into_iter()
+        .flat_map(|f| f())
+        .map(supervise)
+        .collect();
 
     match try_join_all(flattened_futures).await {
         Ok(_) => eprintln!("All tasks completed successfully"),
@@ -90,3 +646,50 @@ async fn main() -> Result<(), Box<dyn Error>> {
     clean_up();
     Ok(())
 }
+
+// Decide what the failure of a task means for the client. Most failures
+// end the client so that it is restarted, but a task that would fail the
+// same way after a restart is stopped and reported in the heartbeat, and
+// the other tasks keep running.
+async fn supervise<F>(task: F) -> Result<(), Box<dyn Error>>
+where
+    F: std::future::Future<Output = Result<(), Box<dyn Error>>>,
+{
+    let e = match task.await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    match e.downcast_ref::<ClientError>().map(ClientError::recovery) {
+        Some(Recovery::StopTask) => {
+            eprintln!("Stopping task: {e}");
+            net::set_status(StatusCodes::TaskStopped).await;
+            Ok(())
+        }
+        Some(Recovery::Restart) | None => Err(e),
+    }
+}
+
+// Instance names are used in paths
+fn parse_instance(name: &str) -> Result<String, String> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(name.to_string())
+    } else {
+        Err("only letters, digits, - and _ are allowed".to_string())
+    }
+}
+
+// An install path that can be given on the command line or in the
+// environment, and otherwise defaults to the compiled in path
+fn path_arg<'a>(name: &'a str, env: &'a str, default: &'a str, help: &'a str) -> Arg<'a> {
+    Arg::new(name)
+        .long(name)
+        .value_name("PATH")
+        .help(help)
+        .env(env)
+        .default_value(default)
+        .value_parser(value_parser!(String))
+}
@@ -16,93 +16,356 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
-use super::gpio::{
-    read_all_digital_in, send_value, REMOTE_CONTROL_BARRIER, REMOTE_CONTROL_IN_PROCESS,
-};
+use super::alert::install_alert_definitions;
+use super::backpressure;
+use super::can::{self, send_can_message_stream};
+use super::can_trace;
+use super::cert::{cert_path, request_renewal};
+use super::config_update::request_config_update;
+use super::control::request_control_session;
+use super::dbc::dbc_path;
+use super::gpio::{read_all_digital_in, send_value};
+use super::health::record_contact;
+use super::identity_rotation::rotate_identity;
+use super::inventory::request_inventory;
+use super::journal;
+use super::live::request_live_stream;
+use super::log_level::{debug, request_log_level};
+use super::periodic;
+use super::resolve;
+use super::safe_mode::record_clean_exit;
+use super::scrub;
+use super::spool;
+use super::subsystem::control_subsystem;
+use super::tap;
+use super::transfer::request_upload;
+use super::transport;
+use super::tunnel::{self, request_tunnel};
 use super::utils::{clean_up, fetch_resource, get_md5sum, update_client};
-use async_std::task;
+use super::vin;
+use super::vpn::request_vpn_settings;
+use lazy_static::lazy_static;
 use lib::{
-    host_insight::{agent_client::AgentClient, reply::Action, Reply, State},
-    ExitCodes, Identity, CONFIG, CONF_DIR, GIT_COMMIT_DESCRIBE, IDENTITY,
+    ca_file, cache, conf_dir,
+    error::ClientError,
+    history,
+    host_insight::{
+        agent_client::AgentClient, reply::Action, CanMessage, CanSignal, Reply, SignalReporting,
+        SignalReportingMode, State,
+    },
+    ExitCodes, Identity, NetworkCondition, ReportingMode, StatusCodes, CONFIG, GIT_COMMIT_DESCRIBE,
+    IDENTITY,
 };
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
 use tonic::{
-    transport::{Certificate, Channel, ClientTlsConfig},
+    transport::{Certificate, Channel, ClientTlsConfig, Uri},
     Request, Response, Status,
 };
 
 const SLEEP_OFFSET: f64 = 0.1;
 
-pub async fn setup_network() -> Channel {
-    // Connect to server
-    let pem = tokio::fs::read("/etc/ssl/certs/ca-certificates.crt").await;
-    let ca = Certificate::from_pem(pem.unwrap());
+// The statuses from the most to the least important. Several can be
+// active at once, e.g. a full filesystem while a tunnel is open, and the
+// heartbeat reports the most important of them.
+const STATUS_PRIORITY: [StatusCodes; 12] = [
+    StatusCodes::AttentionMissed,
+    StatusCodes::SafeMode,
+    StatusCodes::TaskStopped,
+    StatusCodes::LocalOverride,
+    StatusCodes::StartupSequenceFailed,
+    StatusCodes::SpoolFailed,
+    StatusCodes::StorageNearFull,
+    StatusCodes::IdentityUpdateFailed,
+    StatusCodes::CertificateExpiring,
+    StatusCodes::LoadShedding,
+    StatusCodes::StorageEvicted,
+    StatusCodes::TunnelOpen,
+];
+
+lazy_static! {
+    static ref ACTIVE_STATUSES: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+}
+
+// Report a status in the heartbeat until it is cleared
+pub async fn set_status(code: StatusCodes) {
+    ACTIVE_STATUSES.lock().await.insert(code as i32);
+}
+
+// Stop reporting a status. Other active statuses are kept.
+pub async fn clear_status(code: StatusCodes) {
+    ACTIVE_STATUSES.lock().await.remove(&(code as i32));
+}
+
+// The code of the most important active status, or OK
+fn reported_status(active: &HashSet<i32>) -> i32 {
+    STATUS_PRIORITY
+        .iter()
+        .map(|code| *code as i32)
+        .find(|code| active.contains(code))
+        .unwrap_or(StatusCodes::Ok as i32)
+}
+
+// Wait for the network to come up before starting to send, so that
+// e.g. a modem that is still connecting on cold boot does not use up
+// the retry backoff. Sending starts anyway when the wait times out.
+pub async fn wait_for_network() {
+    const DEFAULT_WAIT_TIMEOUT_S: u64 = 300;
+
+    let network = match &CONFIG.network {
+        Some(network) => network,
+        None => return,
+    };
+    let wait_timeout =
+        Duration::from_secs(network.wait_timeout_s.unwrap_or(DEFAULT_WAIT_TIMEOUT_S));
+    let start = Instant::now();
+
+    println!("Waiting for network ({:?})", network.wait_for);
+    while !is_network_up(network.wait_for).await {
+        if start.elapsed() >= wait_timeout {
+            eprintln!("No network after {wait_timeout:?}, starting anyway");
+            return;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+    println!("Network is up after {:?}", start.elapsed());
+}
+
+async fn is_network_up(condition: NetworkCondition) -> bool {
+    match condition {
+        NetworkCondition::Route => has_default_route(),
+        NetworkCondition::ModemManager => is_modem_connected().await,
+    }
+}
+
+fn has_default_route() -> bool {
+    // The destination is the second column of /proc/net/route and the
+    // first of /proc/net/ipv6_route
+    let ipv4 = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let ipv6 = fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    ipv4.lines()
+        .skip(1)
+        .any(|l| l.split_whitespace().nth(1) == Some("00000000"))
+        || ipv6.lines().any(|l| {
+            l.split_whitespace().next() == Some("00000000000000000000000000000000")
+                && !l.ends_with(" lo")
+        })
+}
+
+// A hanging mmcli, e.g. while ModemManager restarts, is killed after a
+// while and the modem counts as not connected
+async fn is_modem_connected() -> bool {
+    const MMCLI_TIMEOUT: Duration = Duration::from_secs(5);
+
+    let output = Command::new("mmcli")
+        .args(["-m", "any", "-K"])
+        .kill_on_drop(true)
+        .output();
+    match timeout(MMCLI_TIMEOUT, output).await {
+        Ok(Ok(output)) => String::from_utf8_lossy(&output.stdout).lines().any(|l| {
+            l.starts_with("modem.generic.state ") && l.trim_end().ends_with(": connected")
+        }),
+        _ => false,
+    }
+}
+
+pub async fn setup_network() -> Result<Channel, ClientError> {
+    connect(&IDENTITY.domain).await
+}
+
+// Bulk data, such as CAN messages and file uploads, can be sent on a
+// connection of its own, optionally to another endpoint, so that a
+// saturated upload does not block control traffic. Otherwise the given
+// channel is shared.
+pub async fn setup_bulk_network(channel: &Channel) -> Result<Channel, ClientError> {
+    match &CONFIG.bulk {
+        Some(bulk) => connect(bulk.domain.as_ref().unwrap_or(&IDENTITY.domain)).await,
+        None => Ok(channel.clone()),
+    }
+}
+
+pub async fn connect(domain: &str) -> Result<Channel, ClientError> {
+    connect_to(&format!("https://{}", domain), domain).await
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<u8>, ClientError> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|source| ClientError::File {
+            path: path.display().to_string(),
+            source,
+        })
+}
 
-    let tls = ClientTlsConfig::new()
+// Connect to a server given by URL instead of domain. The URL and domain
+// may come from the server, e.g. with an identity rotation, so a
+// malformed one is an error rather than a panic.
+pub async fn connect_to(url: &str, domain: &str) -> Result<Channel, ClientError> {
+    let endpoint_error = |reason: String| ClientError::Endpoint {
+        url: url.to_string(),
+        reason,
+    };
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| endpoint_error(e.to_string()))?;
+
+    let ca = Certificate::from_pem(read_pem(Path::new(ca_file())).await?);
+    let mut tls = ClientTlsConfig::new()
         .ca_certificate(ca)
-        .domain_name(IDENTITY.domain.clone());
+        .domain_name(domain);
+    if let Some(client) = &CONFIG.tls {
+        let cert = read_pem(&cert_path(&client.cert_file)).await?;
+        let key = read_pem(&cert_path(&client.key_file)).await?;
+        tls = tls.identity(tonic::transport::Identity::from_pem(cert, key));
+    }
 
-    let endpoint = Channel::builder(
-        format!("https://{}", IDENTITY.domain.clone())
-            .parse()
-            .unwrap(),
-    )
-    .tls_config(tls)
-    .unwrap();
+    let endpoint = Channel::builder(uri)
+        .tls_config(tls)
+        .map_err(|e| endpoint_error(e.to_string()))?;
 
-    endpoint.connect_lazy()
+    Ok(endpoint.connect_with_connector_lazy(tower::service_fn(resolve::connect)))
 }
 
 pub async fn send_initial_values(channel: Channel) {
-    let mut allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-    *allow_remote_control = true;
-    drop(allow_remote_control);
-
-    let initial_digital_in_vals: Option<HashMap<String, u8>> = read_all_digital_in().await;
+    let initial_digital_in_vals = read_all_digital_in().await;
 
     send_state(channel.clone()).await;
 
-    if initial_digital_in_vals.is_some() {
-        for (key, val) in initial_digital_in_vals.clone().unwrap() {
-            send_value(channel.clone(), &key, val).await;
+    match initial_digital_in_vals {
+        Ok(values) => {
+            for (key, val) in values {
+                send_value(&key, val, None).await;
+            }
         }
+        Err(e) => eprintln!("Failed to read the digital ins: {e}"),
     }
-    let mut allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-    *allow_remote_control = false;
-    drop(allow_remote_control);
 }
 
+// Latest values of the signals that are sent with each heartbeat, e.g.
+// position and odometer for a fleet overview. Signals that have not been
+// received yet are left out.
+async fn headline_values() -> Vec<CanSignal> {
+    let mut values = Vec::new();
+    for name in CONFIG.heartbeat.iter().flat_map(|h| &h.signals) {
+        if let Some(cached) = cache::get_any(name).await {
+            values.push(CanSignal {
+                signal_name: name.clone(),
+                unit: cached.unit,
+                value: Some(cached.value),
+                raw: None,
+                refresh: false,
+            });
+        }
+    }
+    scrub::scrub_signals(&mut values);
+    values
+}
+
+// The status for a heartbeat. The number of messages sent is only taken
+// by heartbeat() itself, so that e.g. check-ins and identity tests do
+// not reset it.
+pub async fn current_status() -> lib::host_insight::Status {
+    lib::host_insight::Status {
+        code: reported_status(&*ACTIVE_STATUSES.lock().await),
+        stream_fallback: transport::is_fallback().await,
+        headline: headline_values().await,
+        messages_sent: 0,
+        oldest_unsent_age_ms: can::oldest_unsent_age()
+            .await
+            .map_or(0, |age| age.as_millis() as u64),
+        spool_bytes: if spool::is_enabled() {
+            spool::size_bytes()
+        } else {
+            0
+        },
+    }
+}
+
+// Heartbeats have their own retry policy so that the liveness signal is
+// the last thing to degrade. A failed heartbeat is retried with a backoff
+// that is capped at the heartbeat interval, and the client only gives up
+// after failing for as long as the regular backoff would take to reach
+// its max. The channel should be a connection of its own, so that the
+// heartbeats do not queue behind e.g. a long catch-up upload.
 pub async fn heartbeat(channel: Channel) -> Result<(), Box<dyn Error>> {
     let mut client = AgentClient::with_interceptor(channel, intercept);
+    let give_up_after = Duration::from_secs(2 * CONFIG.time.sleep_max_s);
 
     loop {
-        let status = lib::host_insight::Status { code: 0 }; // Always report OK for now.
-        task::sleep(Duration::from_secs(CONFIG.time.heartbeat_s)).await;
+        sleep(Duration::from_secs(CONFIG.time.heartbeat_s)).await;
+        let status = lib::host_insight::Status {
+            messages_sent: can::take_sent_count(),
+            ..current_status().await
+        };
         let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+        let first_attempt = Instant::now();
 
         loop {
-            let response = client.heart_beat(status.clone()).await;
-            if handle_send_result(response, &mut retry_sleep_s)
-                .await
-                .is_ok()
-            {
-                break;
-            };
+            tap::record("HeartBeat", &status).await;
+            match client.heart_beat(status.clone()).await {
+                Ok(response) => {
+                    debug!("Heartbeat sent with status {}", status.code);
+                    let _ = handle_send_result(Ok(response), &mut retry_sleep_s).await;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Heartbeat failed: {e}");
+                    if first_attempt.elapsed() > give_up_after {
+                        give_up(&e);
+                    }
+                    sleep(Duration::from_secs(retry_sleep_s)).await;
+                    retry_sleep_s = std::cmp::min(retry_sleep_s * 2, CONFIG.time.heartbeat_s);
+                }
+            }
         }
     }
 }
 
-async fn send_state(channel: Channel) {
+// Upload the recent history of signals whenever it has been requested,
+// e.g. by the server. Each sample is sent as a time stamped CanMessage.
+pub async fn history_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    loop {
+        for request in history::take_upload_requests().await {
+            let samples = history::recent(&request.signals, request.window).await;
+            if samples.is_empty() {
+                continue;
+            }
+
+            let mut messages: Vec<CanMessage> = samples
+                .into_iter()
+                .map(|sample| CanMessage {
+                    bus: sample.source,
+                    time_stamp: Some(history::unix_millis(sample.time)),
+                    signal: vec![CanSignal {
+                        signal_name: sample.name,
+                        unit: "N/A".to_string(),
+                        value: Some(sample.value),
+                        raw: None,
+                        refresh: false,
+                    }],
+                    composite: String::new(),
+                })
+                .collect();
+            messages.iter_mut().for_each(scrub::scrub_message);
+            messages.retain(|m| !m.signal.is_empty());
+            send_can_message_stream(channel.clone(), messages).await;
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+}
+
+pub async fn send_state(channel: Channel) {
     let mut client = AgentClient::with_interceptor(channel, intercept);
 
-    let local_conf = PathBuf::from(format!("{}/conf.toml", CONF_DIR));
-    let fallback_conf = PathBuf::from(format!("{}/conf-fallback.toml", CONF_DIR));
+    let local_conf = PathBuf::from(format!("{}/conf.toml", conf_dir()));
+    let fallback_conf = PathBuf::from(format!("{}/conf-fallback.toml", conf_dir()));
     let current_config = if local_conf.exists() {
         local_conf
     } else if fallback_conf.exists() {
@@ -112,12 +375,8 @@ async fn send_state(channel: Channel) {
     };
 
     let mut dbc_hash = None;
-    if CONFIG.can.is_some() {
-        let path = PathBuf::from(format!(
-            "{}/{}",
-            CONF_DIR,
-            CONFIG.can.as_ref().unwrap().dbc_file.as_ref().unwrap()
-        ));
+    if let Some(dbc_file) = CONFIG.can.as_ref().and_then(|c| c.dbc_file.as_ref()) {
+        let path = dbc_path(dbc_file);
         dbc_hash = get_md5sum(path.to_str().unwrap());
     };
 
@@ -126,10 +385,15 @@ async fn send_state(channel: Channel) {
         sw_version: GIT_COMMIT_DESCRIBE.to_string(),
         config_md5sum: config_hash.unwrap(),
         dbc_md5sum: dbc_hash,
+        signal_reporting: signal_reporting(),
+        default_reporting: Some(default_reporting()),
+        effective_config_sha256: CONFIG.effective_hash.clone(),
+        vin: vin::vin().await,
     };
 
     let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
     loop {
+        tap::record("SendCurrentState", &state).await;
         let response = client.send_current_state(state.clone()).await;
         if handle_send_result(response, &mut retry_sleep_s)
             .await
@@ -140,105 +404,221 @@ async fn send_state(channel: Channel) {
     }
 }
 
+// How CAN signals without a reporting mode of their own are reported.
+// With resend_unchanged_s, an unchanged value is resent at that interval.
+fn default_reporting() -> SignalReporting {
+    match CONFIG.can.as_ref().and_then(|c| c.resend_unchanged_s) {
+        Some(resend_s) => SignalReporting {
+            signal_name: String::new(),
+            mode: SignalReportingMode::Deduplicated as i32,
+            interval_ms: resend_s * 1000,
+        },
+        None => SignalReporting {
+            signal_name: String::new(),
+            mode: SignalReportingMode::OnChange as i32,
+            interval_ms: 0,
+        },
+    }
+}
+
+// The reporting mode of the signals that do not use the default, so that
+// the server knows e.g. whether to hold the last value between samples
+fn signal_reporting() -> Vec<SignalReporting> {
+    let mut reporting = Vec::new();
+    if let Some(signals) = CONFIG.can.as_ref().and_then(|c| c.signals.as_ref()) {
+        for s in signals {
+            reporting.push(match s.mode {
+                ReportingMode::Periodic => SignalReporting {
+                    signal_name: s.name.clone(),
+                    mode: SignalReportingMode::Periodic as i32,
+                    interval_ms: periodic::interval_ms(s),
+                },
+                ReportingMode::OnChange => SignalReporting {
+                    signal_name: s.name.clone(),
+                    ..default_reporting()
+                },
+            });
+        }
+    }
+    // Digital inputs are sent on every edge
+    for p in CONFIG
+        .digital_in
+        .as_ref()
+        .and_then(|d| d.ports.as_ref())
+        .into_iter()
+        .flatten()
+    {
+        reporting.push(SignalReporting {
+            signal_name: p.external_name.clone(),
+            mode: SignalReportingMode::OnChange as i32,
+            interval_ms: 0,
+        });
+    }
+    reporting
+}
+
 pub async fn handle_send_result(
     r: Result<Response<Reply>, Status>,
     s: &mut u64,
 ) -> Result<(), Status> {
     match r {
-        Ok(r) => match r.into_inner().action {
-            Some(Action::CarryOnMsg(_)) => {
+        Ok(r) => {
+            record_contact().await;
+            let reply = r.into_inner();
+            if let Some(action) = &reply.action {
+                debug!("Reply {} {}", action_name(action), reply.command_id);
+            }
+            let command = reply
+                .action
+                .as_ref()
+                .filter(|a| !matches!(a, Action::CarryOnMsg(_)))
+                .map(action_name);
+            if command.is_some() && journal::executed(&reply.command_id).await {
+                eprintln!("Ignoring already executed command {}", reply.command_id);
                 *s = CONFIG.time.sleep_min_s;
                 return Ok(());
             }
-            Some(Action::ExitMsg(msg)) => {
-                clean_up();
-                std::process::exit(msg.reason);
-            }
-            Some(Action::ControlRequestMsg(_)) => {
-                *s = CONFIG.time.sleep_min_s;
-                let allow_remote_control = REMOTE_CONTROL_IN_PROCESS.lock().await;
-                if *allow_remote_control {
-                    eprintln!("Remote control session is already in process.")
-                } else {
-                    REMOTE_CONTROL_BARRIER.wait().await;
+            // Commands that end with an exit are recorded right before it,
+            // and config updates once the new config has been written
+            let record = || async {
+                if let Some(command) = command {
+                    journal::accept(&reply.command_id, command).await;
                 }
-            }
-            Some(Action::ConfigUpdateMsg(msg)) => {
-                *s = CONFIG.time.sleep_min_s;
-                println!("Config update");
-                let new_local_conf = PathBuf::from(format!("{}/conf-new.toml", CONF_DIR));
-
-                let mut file =
-                    fs::File::create(new_local_conf).expect("Could not create new config file");
-                file.write_all(&msg.config)
-                    .expect("Failed to write new config file");
-
-                clean_up();
-                std::process::exit(0);
-            }
-            Some(Action::IdentityUpdateMsg(msg)) => {
-                *s = CONFIG.time.sleep_min_s;
-                println!("Identity update");
-                let new_identity = Identity {
-                    uid: msg.uid,
-                    domain: msg.domain,
-                };
-
-                let toml_string =
-                    toml::to_string(&new_identity).expect("Could not encode new identity as TOML");
-
-                fs::write(
-                    PathBuf::from(format!("{}/identity.toml", CONF_DIR)),
-                    toml_string,
-                )
-                .expect("Could not write to file!");
-
-                clean_up();
-                std::process::exit(0);
-            }
-            Some(Action::FetchResourceMsg(msg)) => {
-                *s = CONFIG.time.sleep_min_s;
-                println!("Fetching resource");
-                fetch_resource(&msg.url, msg.target_location)?;
-
-                clean_up();
-                std::process::exit(0);
-            }
-            Some(Action::SwUpdateMsg(msg)) => {
-                *s = CONFIG.time.sleep_min_s;
-                match update_client(&msg.version) {
-                    Err(e) => eprintln!("{}: Failed to trigger software update.", e),
-                    Ok(_) => {
-                        clean_up();
-                        std::process::exit(ExitCodes::SwUpdate as i32);
+            };
+            match reply.action {
+                Some(Action::CarryOnMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    if msg.rate_percent > 0 {
+                        backpressure::set_server_hint(msg.rate_percent, msg.duration_s).await;
                     }
-                };
+                    return Ok(());
+                }
+                Some(Action::ExitMsg(msg)) => {
+                    record().await;
+                    clean_up();
+                    std::process::exit(msg.reason);
+                }
+                Some(Action::ControlRequestMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_control_session(msg).await;
+                }
+                Some(Action::ConfigUpdateMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    println!("Config update");
+                    request_config_update(reply.command_id.clone(), msg.config).await;
+                    return Ok(());
+                }
+                Some(Action::IdentityUpdateMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    println!("Identity update");
+                    let new_identity = Identity {
+                        uid: msg.uid,
+                        domain: msg.domain,
+                        profile: msg.profile,
+                    };
+
+                    if !rotate_identity(new_identity).await {
+                        // Not recorded, so that the server can retry it
+                        return Ok(());
+                    }
+                    record().await;
+                    clean_up();
+                    std::process::exit(0);
+                }
+                Some(Action::FetchResourceMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    println!("Fetching resource");
+                    fetch_resource(&msg.url, msg.target_location)?;
+
+                    record().await;
+                    clean_up();
+                    std::process::exit(0);
+                }
+                Some(Action::SwUpdateMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    match update_client(&msg.version) {
+                        Err(e) => {
+                            eprintln!("{}: Failed to trigger software update.", e);
+                            return Ok(());
+                        }
+                        Ok(_) => {
+                            record().await;
+                            clean_up();
+                            std::process::exit(ExitCodes::SwUpdate as i32);
+                        }
+                    };
+                }
+                Some(Action::HistoryRequestMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    history::request_upload(msg.signals, Duration::from_secs(msg.seconds as u64))
+                        .await;
+                }
+                Some(Action::AlertDefinitionsMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    install_alert_definitions(msg).await;
+                }
+                Some(Action::SubsystemControlMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    control_subsystem(msg).await;
+                }
+                Some(Action::LiveStreamRequestMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_live_stream(msg).await;
+                }
+                Some(Action::UploadRequestMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_upload(msg).await;
+                }
+                Some(Action::DebugTapMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    tap::request_tap(msg).await;
+                }
+                Some(Action::CanTraceMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    can_trace::request_trace(msg).await;
+                }
+                Some(Action::LogLevelMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_log_level(msg);
+                }
+                Some(Action::CertRenewalMsg(_)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    println!("Certificate renewal");
+                    request_renewal();
+                }
+                Some(Action::TunnelMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_tunnel(msg).await;
+                }
+                Some(Action::VpnSettingsMsg(msg)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_vpn_settings(msg);
+                }
+                Some(Action::InventoryMsg(_)) => {
+                    *s = CONFIG.time.sleep_min_s;
+                    request_inventory();
+                }
+                _ => panic!("Unrecognized response"),
             }
-            _ => panic!("Unrecognized response"),
-        },
+            record().await;
+        }
         Err(e) => {
             eprintln!("Error: {e}");
 
             // Add a random sleep offset of +/- 10 % to avoid the
             // situation where all clients retry at the same time.
             // Make sure not to sleep any longer than max.
-            let sleep = std::cmp::min(
+            let sleep_s = std::cmp::min(
                 rand::thread_rng()
                     .gen_range(*s * (1.0 - SLEEP_OFFSET) as u64..=*s * (1.0 + SLEEP_OFFSET) as u64),
                 CONFIG.time.sleep_max_s,
             );
-            eprintln!("Sleeping for {sleep} s");
-            task::sleep(Duration::from_secs(sleep)).await;
+            eprintln!("Sleeping for {sleep_s} s");
+            sleep(Duration::from_secs(sleep_s)).await;
 
             if *s > CONFIG.time.sleep_max_s {
                 eprintln!("Max sleep time reached");
-
-                // Database issues, such as unassigned instance ID, should not trigger an exit
-                let error_message = format!("{:?}", e);
-                if !error_message.contains("DB") {
-                    // Exit with code to let e.g. a systemd service handle this situation.
-                    std::process::exit(ExitCodes::Etime as i32);
-                }
+                give_up(&e);
             }
 
             // Double the sleep time to create a back-off effect.
@@ -250,8 +630,74 @@ pub async fn handle_send_result(
     Ok(())
 }
 
+fn give_up(e: &Status) {
+    // Database issues, such as unassigned instance ID, should not trigger an exit
+    let error_message = format!("{:?}", e);
+    if !error_message.contains("DB") {
+        // Exit with code to let e.g. a systemd service handle this situation.
+        // This is not a crash, so it does not count towards safe mode.
+        record_clean_exit();
+        tunnel::kill();
+        std::process::exit(ExitCodes::Etime as i32);
+    }
+}
+
+fn action_name(action: &Action) -> &'static str {
+    match action {
+        Action::CarryOnMsg(_) => "carry_on",
+        Action::ExitMsg(_) => "exit",
+        Action::ControlRequestMsg(_) => "control_request",
+        Action::ConfigUpdateMsg(_) => "config_update",
+        Action::IdentityUpdateMsg(_) => "identity_update",
+        Action::FetchResourceMsg(_) => "fetch_resource",
+        Action::SwUpdateMsg(_) => "sw_update",
+        Action::HistoryRequestMsg(_) => "history_request",
+        Action::AlertDefinitionsMsg(_) => "alert_definitions",
+        Action::SubsystemControlMsg(_) => "subsystem_control",
+        Action::LiveStreamRequestMsg(_) => "live_stream_request",
+        Action::UploadRequestMsg(_) => "upload_request",
+        Action::DebugTapMsg(_) => "debug_tap",
+        Action::CanTraceMsg(_) => "can_trace",
+        Action::LogLevelMsg(_) => "log_level",
+        Action::CertRenewalMsg(_) => "cert_renewal",
+        Action::TunnelMsg(_) => "tunnel",
+        Action::VpnSettingsMsg(_) => "vpn_settings",
+        Action::InventoryMsg(_) => "inventory",
+    }
+}
+
+#[allow(clippy::result_large_err)]
 pub fn intercept(mut req: Request<()>) -> Result<Request<()>, Status> {
     req.metadata_mut()
         .insert("uid", IDENTITY.uid.parse().unwrap());
     Ok(req)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn malformed_endpoint_is_an_error() {
+        assert!(
+            connect_to("https://host insight.example", "host insight.example")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn most_important_active_status_is_reported() {
+        let mut active = HashSet::new();
+        assert_eq!(reported_status(&active), StatusCodes::Ok as i32);
+        active.insert(StatusCodes::TunnelOpen as i32);
+        active.insert(StatusCodes::StorageNearFull as i32);
+        assert_eq!(
+            reported_status(&active),
+            StatusCodes::StorageNearFull as i32
+        );
+        // Clearing one status leaves the others
+        active.remove(&(StatusCodes::StorageNearFull as i32));
+        assert_eq!(reported_status(&active), StatusCodes::TunnelOpen as i32);
+    }
+}
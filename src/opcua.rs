@@ -0,0 +1,1218 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// OPC UA client for stationary installations, reading a handful of nodes
+// of a local server, e.g. of a PLC, and sending their values like CAN
+// signals. Only the binary protocol with anonymous sessions is
+// implemented, with the security policy None, which is what PLCs on a
+// local network usually offer. Each node is read at its own
+// interval, and nodes that are due together are read with one request.
+// The connection is reopened before the token of the secure channel
+// expires, and the session is kept alive by reading the current time of
+// the server while no node is due.
+//
+// The protocol is implemented here instead of with an OPC UA stack,
+// since reading nodes needs only a few services, while the stacks
+// available for Rust bring subscriptions, discovery and a server, with
+// many more dependencies than the rest of the client. That matters on
+// gateways with little flash, for a feature that only stationary
+// installations use.
+
+use super::can::queue_can_message;
+use lib::{
+    cache, history,
+    host_insight::{can_signal, CanMessage, CanSignal},
+    OpcUaConfig, OpcUaNode, CONFIG, DEFAULT_OPCUA_BUS,
+};
+use rand::RngCore;
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, sleep_until, timeout};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const DEFAULT_PORT: u16 = 4840;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CHANNEL_LIFETIME_MS: u32 = 3_600_000;
+// The least session timeout requested, which is at least twice the
+// longest interval otherwise
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+// The longest session timeout a server may revise to. Keep-alives are
+// sent at half of it.
+const MAX_SESSION_TIMEOUT: Duration = Duration::from_secs(3600);
+// Server_ServerStatus_CurrentTime, which every server has
+const KEEP_ALIVE_NODE: &str = "i=2258";
+// Nested variants and diagnostic infos that are read at most
+const MAX_NESTING: u32 = 16;
+const BUFFER_SIZE: u32 = 65536;
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+// Of a response that comes in chunks
+const MAX_RESPONSE_SIZE: usize = 16 << 20;
+// The message header, channel ID and token ID
+const SYMMETRIC_HEADER_LEN: usize = 16;
+
+const SECURITY_POLICY_NONE: &str = "http://opcfoundation.org/UA/SecurityPolicy#None";
+const SECURITY_MODE_NONE: u32 = 1;
+const TOKEN_TYPE_ANONYMOUS: u32 = 0;
+const APPLICATION_TYPE_CLIENT: u32 = 1;
+const ATTRIBUTE_VALUE: u32 = 13;
+const TIMESTAMPS_SOURCE: u32 = 0;
+const STATUS_BAD: u32 = 0x8000_0000;
+// 100 ns intervals from 1601, the epoch of OPC UA, to 1970
+const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+// 9999-12-31 23:59:59, from which on a date time means the largest one
+const MAX_DATE_TIME_TICKS: i64 = 2_650_467_743_990_000_000;
+
+// The binary encodings of the requests, in namespace 0
+const OPEN_SECURE_CHANNEL_REQUEST: u16 = 446;
+const CLOSE_SECURE_CHANNEL_REQUEST: u16 = 452;
+const CREATE_SESSION_REQUEST: u16 = 461;
+const ACTIVATE_SESSION_REQUEST: u16 = 467;
+const CLOSE_SESSION_REQUEST: u16 = 473;
+const READ_REQUEST: u16 = 631;
+const ANONYMOUS_IDENTITY_TOKEN: u16 = 321;
+
+fn now_ticks() -> i64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    UNIX_EPOCH_TICKS + (since_epoch.as_nanos() / 100) as i64
+}
+
+// Servers may send the smallest or the largest date time as a time stamp
+// that is not set
+fn date_time(ticks: i64) -> Option<SystemTime> {
+    if ticks >= MAX_DATE_TIME_TICKS {
+        return None;
+    }
+    let since_epoch = ticks.checked_sub(UNIX_EPOCH_TICKS).filter(|t| *t > 0)?;
+    UNIX_EPOCH.checked_add(Duration::from_micros(since_epoch as u64 / 10))
+}
+
+fn check_status(code: u32) -> Result<(), String> {
+    match code & STATUS_BAD {
+        0 => Ok(()),
+        _ => Err(format!("The OPC UA server returned status {code:#010x}")),
+    }
+}
+
+// Binary encoding, little endian
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.raw(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.raw(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.raw(&v.to_le_bytes())
+    }
+
+    fn i32(&mut self, v: i32) -> &mut Self {
+        self.raw(&v.to_le_bytes())
+    }
+
+    fn i64(&mut self, v: i64) -> &mut Self {
+        self.raw(&v.to_le_bytes())
+    }
+
+    fn f64(&mut self, v: f64) -> &mut Self {
+        self.raw(&v.to_le_bytes())
+    }
+
+    // A null byte string has the length -1
+    fn bytes(&mut self, v: Option<&[u8]>) -> &mut Self {
+        match v {
+            Some(v) => self.i32(v.len() as i32).raw(v),
+            None => self.i32(-1),
+        }
+    }
+
+    fn string(&mut self, v: Option<&str>) -> &mut Self {
+        self.bytes(v.map(str::as_bytes))
+    }
+
+    // The node ID of a type in namespace 0, in the four byte encoding
+    fn type_id(&mut self, id: u16) -> &mut Self {
+        self.u8(1).u8(0).u16(id)
+    }
+}
+
+// Encode a numeric or string node ID, as accepted by lib::is_node_id
+fn encode_node_id(node_id: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("Unsupported OPC UA node ID {node_id}");
+    let (namespace, id) = match node_id.strip_prefix("ns=").and_then(|s| s.split_once(';')) {
+        Some((namespace, id)) => (namespace.parse().map_err(|_| invalid())?, id),
+        None => (0, node_id),
+    };
+    let mut w = Writer::default();
+    match id.split_once('=') {
+        Some(("i", n)) => w
+            .u8(2)
+            .u16(namespace)
+            .u32(n.parse().map_err(|_| invalid())?),
+        Some(("s", s)) if !s.is_empty() => w.u8(3).u16(namespace).string(Some(s)),
+        _ => return Err(invalid()),
+    };
+    Ok(w.0)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    // Of variants and diagnostic infos, which may contain themselves
+    depth: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    // Read a value that may be nested, e.g. a variant in a variant, so
+    // that a malicious server cannot overflow the stack
+    fn nested<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth >= MAX_NESTING {
+            return Err("OPC UA value nested too deeply".to_string());
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or("Truncated OPC UA message")?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        self.array().map(i32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        self.array().map(i64::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        self.array().map(f32::from_le_bytes)
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        self.array().map(f64::from_le_bytes)
+    }
+
+    fn bytes(&mut self) -> Result<Option<&'a [u8]>, String> {
+        match self.i32()? {
+            n if n < 0 => Ok(None),
+            n => self.take(n as usize).map(Some),
+        }
+    }
+
+    fn string(&mut self) -> Result<Option<String>, String> {
+        Ok(self
+            .bytes()?
+            .map(|b| String::from_utf8_lossy(b).into_owned()))
+    }
+
+    // Read the elements of an array with the given function
+    fn each(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        for _ in 0..self.i32()?.max(0) {
+            element(self)?;
+        }
+        Ok(())
+    }
+
+    // Read a node ID or an expanded node ID, and return its encoding
+    fn node_id(&mut self) -> Result<&'a [u8], String> {
+        let start = self.pos;
+        let mask = self.u8()?;
+        match mask & 0x3f {
+            0 => self.take(1).map(drop)?,
+            1 => self.take(3).map(drop)?,
+            2 => self.take(6).map(drop)?,
+            3 | 5 => {
+                self.u16()?;
+                self.bytes()?;
+            }
+            4 => self.take(18).map(drop)?,
+            _ => return Err("Invalid OPC UA node ID".to_string()),
+        }
+        if mask & 0x80 != 0 {
+            self.string()?;
+        }
+        if mask & 0x40 != 0 {
+            self.u32()?;
+        }
+        Ok(&self.buf[start..self.pos])
+    }
+
+    fn localized_text(&mut self) -> Result<(), String> {
+        let mask = self.u8()?;
+        if mask & 0x01 != 0 {
+            self.string()?;
+        }
+        if mask & 0x02 != 0 {
+            self.string()?;
+        }
+        Ok(())
+    }
+
+    fn extension_object(&mut self) -> Result<(), String> {
+        self.node_id()?;
+        match self.u8()? {
+            0 => Ok(()),
+            1 | 2 => self.bytes().map(drop),
+            _ => Err("Invalid OPC UA extension object".to_string()),
+        }
+    }
+
+    fn diagnostic_info(&mut self) -> Result<(), String> {
+        let mask = self.u8()?;
+        // Symbolic ID, namespace URI, localized text and locale
+        for bit in [0x01, 0x02, 0x04, 0x08] {
+            if mask & bit != 0 {
+                self.i32()?;
+            }
+        }
+        if mask & 0x10 != 0 {
+            self.string()?;
+        }
+        if mask & 0x20 != 0 {
+            self.u32()?;
+        }
+        if mask & 0x40 != 0 {
+            self.nested(Self::diagnostic_info)?;
+        }
+        Ok(())
+    }
+
+    // Read the type and header of a response. A service fault is a
+    // response header with a bad status.
+    fn response(&mut self) -> Result<(), String> {
+        self.node_id()?;
+        self.i64()?;
+        self.u32()?;
+        let result = self.u32()?;
+        self.diagnostic_info()?;
+        self.each(|r| r.string().map(drop))?;
+        self.extension_object()?;
+        check_status(result)
+    }
+
+    fn application_description(&mut self) -> Result<(), String> {
+        self.string()?;
+        self.string()?;
+        self.localized_text()?;
+        self.u32()?;
+        self.string()?;
+        self.string()?;
+        self.each(|r| r.string().map(drop))
+    }
+
+    // Read an endpoint description and return the policy ID of anonymous
+    // sessions, if the endpoint is without security and allows them
+    fn anonymous_policy(&mut self) -> Result<Option<String>, String> {
+        self.string()?;
+        self.application_description()?;
+        self.bytes()?;
+        let mode = self.u32()?;
+        let policy = self.string()?;
+        let mut anonymous = None;
+        self.each(|r| {
+            let policy_id = r.string()?;
+            let token_type = r.u32()?;
+            r.string()?;
+            r.string()?;
+            r.string()?;
+            if token_type == TOKEN_TYPE_ANONYMOUS && anonymous.is_none() {
+                anonymous = policy_id;
+            }
+            Ok(())
+        })?;
+        self.string()?;
+        self.u8()?;
+        let matches = mode == SECURITY_MODE_NONE && policy.as_deref() == Some(SECURITY_POLICY_NONE);
+        Ok(anonymous.filter(|_| matches))
+    }
+
+    // Read a value of a built-in type. Values of types that are not
+    // sent as signals are read past and give None.
+    fn builtin(&mut self, type_id: u8) -> Result<Option<can_signal::Value>, String> {
+        use can_signal::Value::*;
+        let value = match type_id {
+            1 => ValU64((self.u8()? != 0) as u64),
+            2 => ValI64(self.u8()? as i8 as i64),
+            3 => ValU64(self.u8()? as u64),
+            4 => ValI64(self.u16()? as i16 as i64),
+            5 => ValU64(self.u16()? as u64),
+            6 => ValI64(self.i32()? as i64),
+            7 => ValU64(self.u32()? as u64),
+            8 => ValI64(self.i64()?),
+            9 => ValU64(self.u64()?),
+            10 => ValF64(self.f32()? as f64),
+            11 => ValF64(self.f64()?),
+            12 => ValStr(self.string()?.unwrap_or_default()),
+            13 => return self.take(8).map(|_| None),
+            14 => return self.take(16).map(|_| None),
+            15 | 16 => return self.bytes().map(|_| None),
+            17 | 18 => return self.node_id().map(|_| None),
+            19 => return self.take(4).map(|_| None),
+            20 => {
+                self.u16()?;
+                return self.string().map(|_| None);
+            }
+            21 => return self.localized_text().map(|_| None),
+            22 => return self.extension_object().map(|_| None),
+            23 => return self.nested(Self::data_value).map(|_| None),
+            24 => return self.nested(Self::variant).map(|_| None),
+            25 => return self.nested(Self::diagnostic_info).map(|_| None),
+            _ => return Err(format!("Unknown OPC UA type {type_id}")),
+        };
+        Ok(Some(value))
+    }
+
+    // Arrays are read past and give None
+    fn variant(&mut self) -> Result<Option<can_signal::Value>, String> {
+        let mask = self.u8()?;
+        let type_id = mask & 0x3f;
+        if mask & 0x80 == 0 {
+            return self.builtin(type_id);
+        }
+        self.each(|r| r.builtin(type_id).map(drop))?;
+        if mask & 0x40 != 0 {
+            self.each(|r| r.i32().map(drop))?;
+        }
+        Ok(None)
+    }
+
+    // Read a data value. The value is None unless it is good, and the
+    // time stamp is the source time stamp, if any.
+    fn data_value(&mut self) -> Result<(Option<can_signal::Value>, Option<SystemTime>), String> {
+        let mask = self.u8()?;
+        let mut value = match mask & 0x01 {
+            0 => None,
+            _ => self.variant()?,
+        };
+        if mask & 0x02 != 0 && self.u32()? & STATUS_BAD != 0 {
+            value = None;
+        }
+        let mut source = None;
+        if mask & 0x04 != 0 {
+            source = date_time(self.i64()?);
+        }
+        if mask & 0x10 != 0 {
+            self.u16()?;
+        }
+        if mask & 0x08 != 0 {
+            self.i64()?;
+        }
+        if mask & 0x20 != 0 {
+            self.u16()?;
+        }
+        Ok((value, source))
+    }
+}
+
+// Write a message that fits in one chunk
+async fn write_message(stream: &mut TcpStream, kind: &[u8; 3], body: &[u8]) -> Result<(), String> {
+    let mut message = Writer::default();
+    message
+        .raw(kind)
+        .u8(b'F')
+        .u32(8 + body.len() as u32)
+        .raw(body);
+    stream
+        .write_all(&message.0)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Read a message chunk, including its header
+async fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+    let mut chunk = vec![0; 8];
+    stream
+        .read_exact(&mut chunk)
+        .await
+        .map_err(|e| e.to_string())?;
+    let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as usize;
+    if !(8..=MAX_MESSAGE_SIZE).contains(&size) {
+        return Err(format!("Invalid OPC UA message size {size}"));
+    }
+    chunk.resize(size, 0);
+    stream
+        .read_exact(&mut chunk[8..])
+        .await
+        .map_err(|e| e.to_string())?;
+    if &chunk[..3] == b"ERR" {
+        let mut r = Reader::new(&chunk[8..]);
+        let code = r.u32()?;
+        let reason = r.string()?.unwrap_or_default();
+        return Err(format!("OPC UA error {code:#010x}: {reason}"));
+    }
+    Ok(chunk)
+}
+
+struct Client {
+    stream: TcpStream,
+    channel_id: u32,
+    token_id: u32,
+    sequence_number: u32,
+    request_id: u32,
+    // The authentication token of the session, a null node ID before
+    // the session is created
+    session: Vec<u8>,
+    // When the secure channel is to be renewed
+    renew_at: Instant,
+    // The session timeout, as revised by the server
+    session_timeout: Duration,
+}
+
+impl Client {
+    async fn connect(config: &OpcUaConfig, session_timeout: Duration) -> Result<Client, String> {
+        let endpoint = config.endpoint.as_str();
+        let address = endpoint
+            .strip_prefix("opc.tcp://")
+            .and_then(|a| a.split('/').next())
+            .ok_or_else(|| format!("Invalid OPC UA endpoint {endpoint}"))?;
+        let address = match address.contains(':') {
+            true => address.to_string(),
+            false => format!("{address}:{DEFAULT_PORT}"),
+        };
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut client = Client {
+            stream,
+            channel_id: 0,
+            token_id: 0,
+            sequence_number: 0,
+            request_id: 0,
+            session: vec![0, 0],
+            renew_at: Instant::now(),
+            session_timeout,
+        };
+        timeout(REQUEST_TIMEOUT, client.open_secure_channel(endpoint))
+            .await
+            .map_err(|_| "Timeout opening the OPC UA secure channel".to_string())??;
+        client.create_session(endpoint, session_timeout).await?;
+        Ok(client)
+    }
+
+    fn sequence(&mut self, w: &mut Writer) {
+        self.sequence_number += 1;
+        self.request_id += 1;
+        w.u32(self.sequence_number).u32(self.request_id);
+    }
+
+    fn request_header(&self, w: &mut Writer) {
+        w.raw(&self.session)
+            .i64(now_ticks())
+            .u32(self.request_id)
+            .u32(0)
+            .string(None)
+            .u32(REQUEST_TIMEOUT.as_millis() as u32)
+            // No additional header
+            .raw(&[0, 0, 0]);
+    }
+
+    async fn open_secure_channel(&mut self, endpoint: &str) -> Result<(), String> {
+        let mut hello = Writer::default();
+        hello
+            .u32(0)
+            .u32(BUFFER_SIZE)
+            .u32(BUFFER_SIZE)
+            .u32(0)
+            .u32(0)
+            .string(Some(endpoint));
+        write_message(&mut self.stream, b"HEL", &hello.0).await?;
+        let ack = read_message(&mut self.stream).await?;
+        if &ack[..3] != b"ACK" {
+            return Err("The OPC UA server did not acknowledge the connection".to_string());
+        }
+
+        let mut w = Writer::default();
+        w.raw(b"OPNF")
+            .u32(0)
+            .u32(0)
+            .string(Some(SECURITY_POLICY_NONE))
+            .bytes(None)
+            .bytes(None);
+        self.sequence(&mut w);
+        w.type_id(OPEN_SECURE_CHANNEL_REQUEST);
+        self.request_header(&mut w);
+        // Protocol version, issue, security mode, nonce and lifetime
+        w.u32(0)
+            .u32(0)
+            .u32(SECURITY_MODE_NONE)
+            .bytes(None)
+            .u32(CHANNEL_LIFETIME_MS);
+        self.send(w.0).await?;
+
+        let chunk = read_message(&mut self.stream).await?;
+        if &chunk[..3] != b"OPN" {
+            return Err("The OPC UA server did not open the secure channel".to_string());
+        }
+        let mut r = Reader::new(&chunk[8..]);
+        r.u32()?;
+        let policy = r.string()?;
+        r.bytes()?;
+        r.bytes()?;
+        if policy.as_deref() != Some(SECURITY_POLICY_NONE) {
+            return Err("The OPC UA server uses another security policy".to_string());
+        }
+        r.u32()?;
+        r.u32()?;
+        r.response()?;
+        r.u32()?;
+        self.channel_id = r.u32()?;
+        self.token_id = r.u32()?;
+        r.i64()?;
+        let lifetime = Duration::from_millis(r.u32()? as u64);
+        self.renew_at = Instant::now() + lifetime * 3 / 4;
+        Ok(())
+    }
+
+    // Set the size of a chunk and send it
+    async fn send(&mut self, mut chunk: Vec<u8>) -> Result<(), String> {
+        let size = chunk.len() as u32;
+        chunk[4..8].copy_from_slice(&size.to_le_bytes());
+        self.stream
+            .write_all(&chunk)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Send a request and return the response, which may come in chunks
+    async fn call(&mut self, type_id: u16, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut w = Writer::default();
+        w.raw(b"MSGF")
+            .u32(0)
+            .u32(self.channel_id)
+            .u32(self.token_id);
+        self.sequence(&mut w);
+        w.type_id(type_id);
+        self.request_header(&mut w);
+        w.raw(body);
+        let request_id = self.request_id;
+
+        let exchange = async {
+            self.send(w.0).await?;
+            let mut response = Vec::new();
+            loop {
+                let chunk = read_message(&mut self.stream).await?;
+                // After the header and sequence number
+                let mut r = Reader::new(&chunk);
+                r.take(SYMMETRIC_HEADER_LEN + 4)?;
+                if r.u32()? != request_id {
+                    return Err("The OPC UA server answered another request".to_string());
+                }
+                let body = &chunk[r.pos..];
+                if response.len() + body.len() > MAX_RESPONSE_SIZE {
+                    return Err("OPC UA response too large".to_string());
+                }
+                response.extend_from_slice(body);
+                match (&chunk[..3], chunk[3]) {
+                    (b"MSG", b'F') => return Ok(response),
+                    (b"MSG", b'C') => continue,
+                    _ => return Err("The OPC UA server aborted the response".to_string()),
+                }
+            }
+        };
+        timeout(REQUEST_TIMEOUT, exchange)
+            .await
+            .map_err(|_| "Timeout waiting for the OPC UA server".to_string())?
+    }
+
+    async fn create_session(
+        &mut self,
+        endpoint: &str,
+        session_timeout: Duration,
+    ) -> Result<(), String> {
+        let mut nonce = [0; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut w = Writer::default();
+        // The client description
+        w.string(Some("urn:hostmobility:host-insight-client"))
+            .string(None)
+            .u8(0x02)
+            .string(Some("HOST Insight Client"))
+            .u32(APPLICATION_TYPE_CLIENT)
+            .string(None)
+            .string(None)
+            .i32(-1);
+        w.string(None)
+            .string(Some(endpoint))
+            .string(Some("host-insight-client"))
+            .bytes(Some(&nonce))
+            .bytes(None)
+            .f64(session_timeout.as_millis() as f64)
+            .u32(0);
+        let response = self.call(CREATE_SESSION_REQUEST, &w.0).await?;
+
+        let mut r = Reader::new(&response);
+        r.response()?;
+        r.node_id()?;
+        let session = r.node_id()?.to_vec();
+        if let Some(revised) = revised_session_timeout(r.f64()?) {
+            self.session_timeout = revised;
+        }
+        // The nonce and certificate of the server
+        r.bytes()?;
+        r.bytes()?;
+        let mut policy = None;
+        r.each(|r| {
+            let anonymous = r.anonymous_policy()?;
+            policy = policy.take().or(anonymous);
+            Ok(())
+        })?;
+        let policy = policy.ok_or("The OPC UA server does not allow anonymous sessions")?;
+        self.session = session;
+
+        let mut token = Writer::default();
+        token.string(Some(&policy));
+        let mut w = Writer::default();
+        // No signature, software certificates or locales
+        w.string(None).bytes(None).i32(-1).i32(-1);
+        w.type_id(ANONYMOUS_IDENTITY_TOKEN)
+            .u8(1)
+            .bytes(Some(&token.0));
+        w.string(None).bytes(None);
+        let response = self.call(ACTIVATE_SESSION_REQUEST, &w.0).await?;
+        Reader::new(&response).response()
+    }
+
+    // Read the values of the given nodes, in the same order
+    async fn read(
+        &mut self,
+        nodes: &[&[u8]],
+    ) -> Result<Vec<(Option<can_signal::Value>, Option<SystemTime>)>, String> {
+        let mut w = Writer::default();
+        w.f64(0.0).u32(TIMESTAMPS_SOURCE).i32(nodes.len() as i32);
+        for node in nodes {
+            // The value attribute, without index range or data encoding
+            w.raw(node)
+                .u32(ATTRIBUTE_VALUE)
+                .string(None)
+                .u16(0)
+                .string(None);
+        }
+        let response = self.call(READ_REQUEST, &w.0).await?;
+
+        let mut r = Reader::new(&response);
+        r.response()?;
+        let mut values = Vec::new();
+        r.each(|r| {
+            values.push(r.data_value()?);
+            Ok(())
+        })?;
+        if values.len() != nodes.len() {
+            return Err("The OPC UA server returned another number of values".to_string());
+        }
+        Ok(values)
+    }
+
+    async fn close(mut self) {
+        // Delete subscriptions, although there are none
+        let _ = self.call(CLOSE_SESSION_REQUEST, &[1]).await;
+        let mut w = Writer::default();
+        w.raw(b"CLOF")
+            .u32(0)
+            .u32(self.channel_id)
+            .u32(self.token_id);
+        self.sequence(&mut w);
+        w.type_id(CLOSE_SECURE_CHANNEL_REQUEST);
+        self.request_header(&mut w);
+        let _ = self.send(w.0).await;
+    }
+}
+
+async fn publish(bus: &str, node: &OpcUaNode, value: can_signal::Value, time: SystemTime) {
+    let unit = node.unit.clone().unwrap_or_else(|| "N/A".to_string());
+    cache::update(bus, &node.name, &unit, value.clone()).await;
+    let message = CanMessage {
+        bus: bus.to_string(),
+        time_stamp: Some(history::unix_millis(time)),
+        signal: vec![CanSignal {
+            signal_name: node.name.clone(),
+            unit,
+            value: Some(value),
+            raw: None,
+            refresh: false,
+        }],
+        composite: String::new(),
+    };
+    queue_can_message(message).await;
+}
+
+async fn read_nodes(config: &OpcUaConfig) -> Result<(), String> {
+    let bus = config.bus.as_deref().unwrap_or(DEFAULT_OPCUA_BUS);
+    let node_ids = config
+        .nodes
+        .iter()
+        .map(|n| encode_node_id(&n.node_id))
+        .collect::<Result<Vec<_>, _>>()?;
+    let keep_alive_node = encode_node_id(KEEP_ALIVE_NODE)?;
+    let session_timeout = requested_session_timeout(config);
+    let mut client = Client::connect(config, session_timeout).await?;
+    println!(
+        "Reading {} OPC UA nodes from {}",
+        config.nodes.len(),
+        config.endpoint
+    );
+
+    let mut due = vec![Instant::now(); config.nodes.len()];
+    let mut last_request = Instant::now();
+    loop {
+        if Instant::now() >= client.renew_at {
+            client.close().await;
+            client = Client::connect(config, session_timeout).await?;
+            last_request = Instant::now();
+        }
+        let keep_alive_at = last_request + client.session_timeout / 2;
+
+        let now = Instant::now();
+        let indices: Vec<_> = (0..due.len()).filter(|i| due[*i] <= now).collect();
+        if indices.is_empty() && now >= keep_alive_at {
+            client.read(&[keep_alive_node.as_slice()]).await?;
+            last_request = now;
+        } else if !indices.is_empty() {
+            let nodes: Vec<_> = indices.iter().map(|i| node_ids[*i].as_slice()).collect();
+            let values = client.read(&nodes).await?;
+            last_request = now;
+            for (i, (value, source)) in indices.into_iter().zip(values) {
+                let node = &config.nodes[i];
+                let interval = node.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS);
+                due[i] = now + Duration::from_millis(interval);
+                if let Some(value) = value {
+                    let time = source.unwrap_or_else(SystemTime::now);
+                    publish(bus, node, value, time).await;
+                }
+            }
+        }
+        let keep_alive_at = last_request + client.session_timeout / 2;
+        let next = due.iter().min().copied().unwrap_or(keep_alive_at);
+        sleep_until(next.min(keep_alive_at).into()).await;
+    }
+}
+
+// Twice the longest interval, so that the nodes keep the session alive
+// if the server accepts it
+// The session timeout in milliseconds that the server revised to, if
+// valid, at most MAX_SESSION_TIMEOUT
+fn revised_session_timeout(revised_ms: f64) -> Option<Duration> {
+    if revised_ms.is_finite() && revised_ms >= 1.0 {
+        let max_ms = MAX_SESSION_TIMEOUT.as_millis() as f64;
+        Some(Duration::from_secs_f64(revised_ms.min(max_ms) / 1000.0))
+    } else {
+        None
+    }
+}
+
+fn requested_session_timeout(config: &OpcUaConfig) -> Duration {
+    let longest = config
+        .nodes
+        .iter()
+        .map(|n| n.interval_ms.unwrap_or(DEFAULT_INTERVAL_MS))
+        .max()
+        .unwrap_or_default();
+    SESSION_TIMEOUT.max(Duration::from_millis(longest.saturating_mul(2)))
+}
+
+pub async fn opcua_reader() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.opcua.as_ref().unwrap();
+    loop {
+        if let Err(e) = read_nodes(config).await {
+            eprintln!("OPC UA: {e}");
+        }
+        sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_ids() {
+        assert_eq!(
+            encode_node_id("i=2258").unwrap(),
+            [2, 0, 0, 0xd2, 0x08, 0, 0]
+        );
+        assert_eq!(
+            encode_node_id("ns=2;s=T=1").unwrap(),
+            [3, 2, 0, 3, 0, 0, 0, b'T', b'=', b'1']
+        );
+        let encoded = encode_node_id("ns=2;s=Temp").unwrap();
+        assert_eq!(Reader::new(&encoded).node_id().unwrap(), encoded);
+        // GUID and opaque node IDs are not supported
+        assert!(encode_node_id("ns=1;g=09087e75-8e5e-499b-954f-f2a9603db28a").is_err());
+        assert!(encode_node_id("b=M/RbKBsRVkePCePcx24oRA==").is_err());
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        // Diagnostic infos, each with an inner diagnostic info
+        let deep = vec![0x40; 100_000];
+        assert!(Reader::new(&deep).diagnostic_info().is_err());
+        // Variants of variants
+        let deep = vec![24; 100_000];
+        assert!(Reader::new(&deep).variant().is_err());
+        let mut w = Writer::default();
+        w.u8(24).u8(24).u8(6).i32(7);
+        assert_eq!(Reader::new(&w.0).variant().unwrap(), None);
+    }
+
+    #[test]
+    fn data_values() {
+        let mut w = Writer::default();
+        // A double with a source time stamp
+        w.u8(0x05)
+            .u8(11)
+            .f64(21.5)
+            .i64(UNIX_EPOCH_TICKS + 10_000_000);
+        // An array of two booleans with a status and a server time stamp
+        w.u8(0x0b).u8(0x81).i32(2).u8(1).u8(0).u32(0).i64(0);
+        // A bad int32
+        w.u8(0x03).u8(6).i32(7).u32(STATUS_BAD);
+        // A string
+        w.u8(0x01).u8(12).string(Some("Running"));
+
+        let mut r = Reader::new(&w.0);
+        let (value, source) = r.data_value().unwrap();
+        assert_eq!(value, Some(can_signal::Value::ValF64(21.5)));
+        assert_eq!(source, Some(UNIX_EPOCH + Duration::from_secs(1)));
+        assert_eq!(r.data_value().unwrap(), (None, None));
+        assert_eq!(r.data_value().unwrap(), (None, None));
+        let (value, _) = r.data_value().unwrap();
+        assert_eq!(
+            value,
+            Some(can_signal::Value::ValStr("Running".to_string()))
+        );
+        assert!(r.data_value().is_err());
+
+        // The largest date time, which some servers send for no time
+        assert_eq!(date_time(i64::MAX), None);
+        assert_eq!(date_time(MAX_DATE_TIME_TICKS), None);
+        assert!(date_time(MAX_DATE_TIME_TICKS - 1).is_some());
+        assert_eq!(date_time(0), None);
+    }
+
+    const ENDPOINT_PATH: &str = "/plc";
+    const OPEN_SECURE_CHANNEL_RESPONSE: u16 = 449;
+    const CREATE_SESSION_RESPONSE: u16 = 464;
+    const ACTIVATE_SESSION_RESPONSE: u16 = 470;
+    const READ_RESPONSE: u16 = 634;
+
+    fn response_header(w: &mut Writer, type_id: u16, request_id: u32) {
+        w.type_id(type_id)
+            .i64(0)
+            .u32(request_id)
+            .u32(0)
+            .u8(0)
+            .i32(-1)
+            .raw(&[0, 0, 0]);
+    }
+
+    // A chunk of a response of the server on channel 5 with token 6
+    fn chunk(kind: &[u8; 4], request_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.raw(kind)
+            .u32(SYMMETRIC_HEADER_LEN as u32 + 8 + body.len() as u32)
+            .u32(5)
+            .u32(6)
+            .u32(request_id)
+            .u32(request_id)
+            .raw(body);
+        w.0
+    }
+
+    // The responses of a server to Hello, OpenSecureChannel,
+    // CreateSession and ActivateSession, with the given channel lifetime
+    fn session_responses(lifetime_ms: u32) -> Vec<Vec<u8>> {
+        let mut ack = Writer::default();
+        ack.raw(b"ACKF")
+            .u32(28)
+            .u32(0)
+            .u32(BUFFER_SIZE)
+            .u32(BUFFER_SIZE)
+            .u32(0)
+            .u32(0);
+
+        let mut opn = Writer::default();
+        opn.raw(b"OPNF")
+            .u32(0)
+            .u32(5)
+            .string(Some(SECURITY_POLICY_NONE))
+            .bytes(None)
+            .bytes(None)
+            .u32(1)
+            .u32(1);
+        response_header(&mut opn, OPEN_SECURE_CHANNEL_RESPONSE, 1);
+        opn.u32(0).u32(5).u32(6).i64(0).u32(lifetime_ms).bytes(None);
+        let size = opn.0.len() as u32;
+        opn.0[4..8].copy_from_slice(&size.to_le_bytes());
+
+        let mut session = Writer::default();
+        response_header(&mut session, CREATE_SESSION_RESPONSE, 2);
+        session
+            .raw(&[2, 1, 0, 1, 0, 0, 0])
+            .raw(&[2, 1, 0, 2, 0, 0, 0])
+            .f64(120_000.0)
+            .bytes(None)
+            .bytes(None);
+        // One endpoint without security that allows anonymous sessions
+        session
+            .i32(1)
+            .string(Some("opc.tcp://plc"))
+            .string(Some("urn:plc"))
+            .string(None)
+            .u8(0)
+            .u32(0)
+            .string(None)
+            .string(None)
+            .i32(-1)
+            .bytes(None)
+            .u32(SECURITY_MODE_NONE)
+            .string(Some(SECURITY_POLICY_NONE))
+            .i32(1)
+            .string(Some("anonymous"))
+            .u32(TOKEN_TYPE_ANONYMOUS)
+            .string(None)
+            .string(None)
+            .string(None)
+            .string(None)
+            .u8(0);
+
+        let mut activate = Writer::default();
+        response_header(&mut activate, ACTIVATE_SESSION_RESPONSE, 3);
+        activate.bytes(None).i32(-1).i32(-1);
+
+        vec![
+            ack.0,
+            opn.0,
+            chunk(b"MSGF", 2, &session.0),
+            chunk(b"MSGF", 3, &activate.0),
+        ]
+    }
+
+    // Serve the responses in turn, each after a request of the client,
+    // and return the endpoint and the requests
+    async fn serve(responses: Vec<Vec<u8>>) -> (String, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!(
+            "opc.tcp://{}{ENDPOINT_PATH}",
+            listener.local_addr().unwrap()
+        );
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut requests = Vec::new();
+            for response in responses {
+                match read_message(&mut stream).await {
+                    Ok(request) => requests.push(request),
+                    Err(_) => break,
+                }
+                if stream.write_all(&response).await.is_err() {
+                    break;
+                }
+            }
+            requests
+        });
+        (endpoint, server)
+    }
+
+    fn config(endpoint: String) -> OpcUaConfig {
+        OpcUaConfig {
+            endpoint,
+            security_policy: None,
+            bus: None,
+            nodes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn revised_session_timeout_is_bounded() {
+        assert_eq!(
+            revised_session_timeout(120_000.0),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(revised_session_timeout(f64::MAX), Some(MAX_SESSION_TIMEOUT));
+        assert_eq!(revised_session_timeout(f64::NAN), None);
+        assert_eq!(revised_session_timeout(0.0), None);
+    }
+
+    #[tokio::test]
+    async fn session_and_read() {
+        let mut read = Writer::default();
+        response_header(&mut read, READ_RESPONSE, 4);
+        read.i32(2);
+        read.u8(0x05)
+            .u8(11)
+            .f64(21.5)
+            .i64(UNIX_EPOCH_TICKS + 10_000_000);
+        read.u8(0x01).u8(6).i32(-7);
+        read.i32(-1);
+        // The response in two chunks
+        let (first, last) = read.0.split_at(20);
+        let mut responses = session_responses(CHANNEL_LIFETIME_MS);
+        let mut chunks = chunk(b"MSGC", 4, first);
+        chunks.extend(chunk(b"MSGF", 4, last));
+        responses.push(chunks);
+
+        let (endpoint, server) = serve(responses).await;
+        let mut client = Client::connect(&config(endpoint.clone()), SESSION_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!((client.channel_id, client.token_id), (5, 6));
+        assert_eq!(client.session, [2, 1, 0, 2, 0, 0, 0]);
+        assert_eq!(client.session_timeout, Duration::from_secs(120));
+
+        let temperature = encode_node_id("ns=1;s=Temp").unwrap();
+        let count = encode_node_id("ns=1;i=7").unwrap();
+        let values = client.read(&[&temperature, &count]).await.unwrap();
+        assert_eq!(
+            values,
+            [
+                (
+                    Some(can_signal::Value::ValF64(21.5)),
+                    Some(UNIX_EPOCH + Duration::from_secs(1))
+                ),
+                (Some(can_signal::Value::ValI64(-7)), None),
+            ]
+        );
+
+        let requests = server.await.unwrap();
+        let kinds: Vec<_> = requests.iter().map(|r| &r[..4]).collect();
+        assert_eq!(kinds, [b"HELF", b"OPNF", b"MSGF", b"MSGF", b"MSGF"]);
+        // The Hello names the endpoint
+        assert!(requests[0].ends_with(endpoint.as_bytes()));
+        // The read request uses the session and names the nodes
+        let read_request = &requests[4];
+        let contains = |part: &[u8]| read_request.windows(part.len()).any(|w| w == part);
+        assert!(contains(&[2, 1, 0, 2, 0, 0, 0]));
+        assert!(contains(&temperature));
+        assert!(contains(&count));
+    }
+
+    #[tokio::test]
+    async fn channel_is_renewed_before_the_token_expires() {
+        let (endpoint, server) = serve(session_responses(20_000)).await;
+        let before = Instant::now();
+        let client = Client::connect(&config(endpoint), SESSION_TIMEOUT)
+            .await
+            .unwrap();
+        assert!(client.renew_at >= before + Duration::from_secs(15));
+        assert!(client.renew_at <= Instant::now() + Duration::from_secs(15));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn response_to_another_request() {
+        let mut responses = session_responses(CHANNEL_LIFETIME_MS);
+        let mut read = Writer::default();
+        response_header(&mut read, READ_RESPONSE, 3);
+        read.i32(0).i32(-1);
+        responses.push(chunk(b"MSGF", 3, &read.0));
+
+        let (endpoint, _server) = serve(responses).await;
+        let mut client = Client::connect(&config(endpoint), SESSION_TIMEOUT)
+            .await
+            .unwrap();
+        let error = client.read(&[&[0, 1]]).await.unwrap_err();
+        assert!(error.contains("another request"));
+    }
+
+    #[tokio::test]
+    async fn response_size_is_limited() {
+        let mut responses = session_responses(CHANNEL_LIFETIME_MS);
+        let mut chunks = Vec::new();
+        for _ in 0..=MAX_RESPONSE_SIZE / (1 << 20) {
+            chunks.extend(chunk(b"MSGC", 4, &vec![0; 1 << 20]));
+        }
+        responses.push(chunks);
+
+        let (endpoint, _server) = serve(responses).await;
+        let mut client = Client::connect(&config(endpoint), SESSION_TIMEOUT)
+            .await
+            .unwrap();
+        let error = client.read(&[&[0, 1]]).await.unwrap_err();
+        assert!(error.contains("too large"));
+    }
+
+    #[test]
+    fn service_faults() {
+        let mut w = Writer::default();
+        w.type_id(397)
+            .i64(0)
+            .u32(1)
+            .u32(0x800e_0000)
+            .u8(0)
+            .i32(-1)
+            .raw(&[0, 0, 0]);
+        let error = Reader::new(&w.0).response().unwrap_err();
+        assert!(error.contains("0x800e0000"));
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Recovery of the outputs after the client stopped during a remote
+// control session. The output commands of the session are found in the
+// command journal at startup, once the outputs have been set to their
+// defaults. The outputs that were set are then either restored or left
+// at their defaults, as configured, and the server is told which
+// commands were applied.
+
+use super::analog::{is_analog_out, set_analog_out};
+use super::gpio::{is_digital_out, set_digital_out};
+use super::journal::{self, Output, MAX_REPORTED};
+use super::local_override;
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{
+        agent_client::AgentClient, OutputCommandRecord, OutputCommandStatus, OutputRecovery,
+    },
+    OutputRecoveryPolicy, CONFIG,
+};
+use std::error::Error;
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+
+lazy_static! {
+    static ref REPORT: Mutex<Option<OutputRecovery>> = Mutex::new(None);
+}
+
+// The last applied state of each output
+fn last_applied(records: &[OutputCommandRecord]) -> Vec<&OutputCommandRecord> {
+    let mut last: Vec<&OutputCommandRecord> = Vec::new();
+    for record in records {
+        if record.status() != OutputCommandStatus::OutputCommandApplied {
+            continue;
+        }
+        last.retain(|r| r.cmd != record.cmd);
+        last.push(record);
+    }
+    last
+}
+
+async fn restore(record: &OutputCommandRecord) -> Result<(), Box<dyn Error>> {
+    let output = match record.setpoint {
+        Some(setpoint) if is_analog_out(&record.cmd) => Output::Analog(setpoint),
+        None if is_digital_out(&record.cmd) => Output::Digital(record.active),
+        _ => return Err("no longer configured".into()),
+    };
+    // Journaled again, so that the outputs are restored after another
+    // restart as well
+    let seq = journal::begin("", &record.cmd, output).await;
+    let result = match output {
+        Output::Digital(active) => set_digital_out(&record.cmd, active).map_err(|e| e.into()),
+        Output::Analog(setpoint) => set_analog_out(&record.cmd, setpoint)
+            .map(|_| ())
+            .map_err(|e| e.into()),
+    };
+    match &result {
+        Ok(()) => journal::applied(seq, "", output).await,
+        Err(_) => journal::failed(seq).await,
+    }
+    result
+}
+
+// Handle a session that was interrupted. Called at startup once the
+// outputs have been set to their defaults. Returns whether the outputs
+// were restored.
+pub async fn recover() -> bool {
+    let mut records = journal::take_interrupted_session().await;
+    if records.is_empty() {
+        return false;
+    }
+
+    let policy = CONFIG
+        .outputs
+        .as_ref()
+        .and_then(|o| o.recovery)
+        .unwrap_or(OutputRecoveryPolicy::Revert);
+    let mut restored = false;
+    if policy == OutputRecoveryPolicy::Restore {
+        if local_override::read_input().await {
+            println!("Local override active, not restoring the outputs");
+        } else {
+            restored = true;
+            for record in last_applied(&records) {
+                match restore(record).await {
+                    Ok(()) => println!("Restored output {}", record.cmd),
+                    Err(e) => eprintln!("Failed to restore output {}: {e}", record.cmd),
+                }
+            }
+        }
+    }
+
+    let pending = records
+        .iter()
+        .filter(|r| r.status() == OutputCommandStatus::OutputCommandPending)
+        .count();
+    println!(
+        "Remote control session was interrupted after {} output commands ({} unconfirmed), outputs {}",
+        records.len(),
+        pending,
+        if restored { "restored" } else { "left at defaults" }
+    );
+
+    let skip = records.len().saturating_sub(MAX_REPORTED);
+    records.drain(..skip);
+    *REPORT.lock().await = Some(OutputRecovery {
+        restored,
+        commands: records,
+    });
+    restored
+}
+
+// Send the outcome of a recovery, if there was one
+pub async fn report_recovery(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let report = match REPORT.lock().await.take() {
+        Some(report) => report,
+        None => return Ok(()),
+    };
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    loop {
+        tap::record("SendOutputRecovery", &report).await;
+        let response = client.send_output_recovery(report.clone()).await;
+        if handle_send_result(response, &mut retry_sleep_s)
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cmd: &str, active: bool, status: OutputCommandStatus) -> OutputCommandRecord {
+        let mut record = OutputCommandRecord {
+            cmd: cmd.to_string(),
+            active,
+            ..Default::default()
+        };
+        record.set_status(status);
+        record
+    }
+
+    #[test]
+    fn restores_last_applied_state_of_each_output() {
+        let records = [
+            record("Pump", true, OutputCommandStatus::OutputCommandApplied),
+            record("Fan", true, OutputCommandStatus::OutputCommandApplied),
+            record("Pump", false, OutputCommandStatus::OutputCommandFailed),
+            record("Horn", true, OutputCommandStatus::OutputCommandPending),
+        ];
+        let last: Vec<_> = last_applied(&records).iter().map(|r| &r.cmd).collect();
+        assert_eq!(last, ["Pump", "Fan"]);
+        assert!(last_applied(&records)[0].active);
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Pairing of the unit with a vehicle during installation. The pair
+// command registers a random code with the server and prints it, both
+// as text and as a QR code with the uid and domain of the unit, so that
+// the installer can bind the physical unit to a vehicle in the backend
+// app. The code proves that the installer has access to the unit and is
+// only valid for a day.
+
+use super::net::{connect, handle_send_result, intercept};
+use super::tap;
+use lib::{
+    history::unix_millis,
+    host_insight::{agent_client::AgentClient, PairingCode},
+    CONFIG, IDENTITY,
+};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use rand::Rng;
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+use tokio::time::timeout;
+
+pub const PAIRING_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+// Time to wait for the server, since a code it does not know is useless
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Without 0, O, 1 and I, which are easily mixed up when typed
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 10;
+
+fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// The code in groups of five, as it is shown to the installer
+fn display_code(code: &str) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let groups: Vec<String> = chars.chunks(5).map(|c| c.iter().collect()).collect();
+    groups.join("-")
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// The link that the QR code encodes
+pub fn pairing_url(uid: &str, domain: &str, code: &str) -> String {
+    format!(
+        "hostinsight://pair?uid={}&domain={}&nonce={}",
+        url_encode(uid),
+        url_encode(domain),
+        code
+    )
+}
+
+async fn register(code: PairingCode) -> Result<(), Box<dyn Error>> {
+    let channel = connect(&IDENTITY.domain).await?;
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    let send = async {
+        loop {
+            tap::record("SendPairingCode", &code).await;
+            let response = client.send_pairing_code(code.clone()).await;
+            if handle_send_result(response, &mut retry_sleep_s)
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+    };
+    timeout(REGISTER_TIMEOUT, send)
+        .await
+        .map_err(|_| format!("{} could not be reached", IDENTITY.domain))?;
+    Ok(())
+}
+
+pub async fn pair() -> Result<(), Box<dyn Error>> {
+    let nonce = new_code();
+    let valid_until = SystemTime::now() + PAIRING_VALIDITY;
+    register(PairingCode {
+        nonce: nonce.clone(),
+        valid_until: unix_millis(valid_until),
+    })
+    .await?;
+
+    let url = pairing_url(&IDENTITY.uid, &IDENTITY.domain, &nonce);
+    // Light on dark, as in most terminals
+    let qr = QrCode::new(url.as_bytes())?
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    println!("{qr}");
+    println!("Unit:         {}", IDENTITY.uid);
+    println!("Server:       {}", IDENTITY.domain);
+    println!("Pairing code: {}", display_code(&nonce));
+    println!("Valid for {} hours", PAIRING_VALIDITY.as_secs() / 3600);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        let code = new_code();
+        assert_eq!(code.len(), CODE_LEN);
+        assert!(code.bytes().all(|b| CODE_ALPHABET.contains(&b)));
+        assert_eq!(display_code("ABCDE23456"), "ABCDE-23456");
+        assert_eq!(
+            pairing_url("00a1 b", "insight.example.com", "ABCDE23456"),
+            "hostinsight://pair?uid=00a1%20b&domain=insight.example.com&nonce=ABCDE23456"
+        );
+    }
+}
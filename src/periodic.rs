@@ -0,0 +1,155 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Periodic reporting of selected CAN signals. Instead of being sent when
+// they change, these signals are sampled from the value cache at a fixed
+// rate, e.g. for integration with control systems.
+
+use super::backpressure;
+use super::can::queue_can_message;
+use futures::future::join_all;
+use lib::{
+    cache, history,
+    host_insight::{CanMessage, CanSignal},
+    ReportingMode, SignalConfig, CONFIG,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::time::{Duration, SystemTime};
+use tokio::time::{interval, MissedTickBehavior};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const MIN_INTERVAL_MS: u64 = 10;
+
+// Names of the signals that are reported periodically instead of on change
+pub fn periodic_signals() -> HashSet<String> {
+    CONFIG
+        .can
+        .as_ref()
+        .and_then(|c| c.signals.as_ref())
+        .map(|signals| {
+            signals
+                .iter()
+                .filter(|s| s.mode == ReportingMode::Periodic)
+                .map(|s| s.name.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// The sampling interval of a periodic signal
+pub fn interval_ms(signal: &SignalConfig) -> u64 {
+    signal
+        .interval_ms
+        .unwrap_or(DEFAULT_INTERVAL_MS)
+        .max(MIN_INTERVAL_MS)
+}
+
+pub fn is_enabled() -> bool {
+    !periodic_signals().is_empty()
+}
+
+pub async fn periodic_reporter() -> Result<(), Box<dyn Error>> {
+    // Signals with the same interval are sampled together
+    let mut groups: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for s in CONFIG.can.as_ref().unwrap().signals.as_ref().unwrap() {
+        if s.mode == ReportingMode::Periodic {
+            groups
+                .entry(interval_ms(s))
+                .or_default()
+                .push(s.name.clone());
+        }
+    }
+
+    join_all(
+        groups
+            .into_iter()
+            .map(|(interval_ms, signals)| report_periodically(interval_ms, signals)),
+    )
+    .await;
+    Ok(())
+}
+
+async fn report_periodically(interval_ms: u64, signals: Vec<String>) {
+    let mut ticks = interval(Duration::from_millis(interval_ms));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut skipped: u32 = 0;
+    let mut sampled: u64 = 0;
+    loop {
+        ticks.tick().await;
+        // Skip ticks under backpressure
+        let factor = backpressure::current()
+            .await
+            .and_then(|l| l.periodic_factor)
+            .unwrap_or(1);
+        skipped += 1;
+        if skipped < factor {
+            continue;
+        }
+        skipped = 0;
+        if let Some(rate) = backpressure::server_rate().await {
+            sampled += 1;
+            if !backpressure::keep_sample(sampled, rate) {
+                continue;
+            }
+        }
+        for message in sample_signals(Some(&signals)).await {
+            queue_can_message(message).await;
+        }
+    }
+}
+
+// Send the latest value of every CAN signal, e.g. as a daily baseline
+pub async fn send_snapshot() {
+    for mut message in sample_signals(None).await {
+        for signal in message.signal.iter_mut() {
+            signal.refresh = true;
+        }
+        queue_can_message(message).await;
+    }
+}
+
+// Build one message per bus with the latest value of each signal, or of
+// all signals if none are given. Signals that have not been received yet
+// are left out.
+async fn sample_signals(signals: Option<&[String]>) -> Vec<CanMessage> {
+    let time_stamp = Some(history::unix_millis(SystemTime::now()));
+    let mut messages: BTreeMap<String, CanMessage> = BTreeMap::new();
+    for (source, name, cached) in cache::snapshot().await {
+        if source == cache::DIGITAL_IN_SOURCE || signals.is_some_and(|s| !s.contains(&name)) {
+            continue;
+        }
+        messages
+            .entry(source.clone())
+            .or_insert_with(|| CanMessage {
+                bus: source,
+                time_stamp,
+                signal: Vec::new(),
+                composite: String::new(),
+            })
+            .signal
+            .push(CanSignal {
+                signal_name: name,
+                unit: cached.unit,
+                value: Some(cached.value),
+                raw: None,
+                refresh: false,
+            });
+    }
+    messages.into_values().collect()
+}
@@ -0,0 +1,250 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Raw mode of CAN ports, for buses that are not described by a DBC yet.
+// The frames are sent undecoded with their ID, DLC, data and time stamp,
+// so that they can be captured and reverse engineered on the server.
+
+use super::can_trace;
+use super::fdstore::{self, CanSocket};
+use super::intrusion;
+use super::log_level::debug;
+use super::net::{handle_send_result, intercept};
+use super::spool;
+use super::stats;
+use super::subsystem::is_enabled;
+use super::tap;
+use super::utils::stream_batch;
+use lazy_static::lazy_static;
+use lib::{
+    error::ClientError,
+    host_insight::{agent_client::AgentClient, RawCanFrame, Subsystem},
+    CanPort, CONFIG,
+};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use tonic::Request;
+
+const MAX_FRAMES_TO_SEND: usize = 500;
+
+// A frame as it is queued, which only becomes a RawCanFrame when it is
+// sent. The port name is shared by the frames of a port.
+struct QueuedFrame {
+    bus: Arc<str>,
+    id: u32,
+    extended: bool,
+    dlc: u8,
+    data: Vec<u8>,
+    time_stamp_us: i64,
+    flags: Vec<&'static str>,
+}
+
+impl From<QueuedFrame> for RawCanFrame {
+    fn from(f: QueuedFrame) -> Self {
+        RawCanFrame {
+            bus: f.bus.to_string(),
+            id: f.id,
+            extended: f.extended,
+            dlc: f.dlc as u32,
+            data: f.data,
+            time_stamp_us: f.time_stamp_us,
+            flags: f.flags.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+lazy_static! {
+    // Up to the spool memory limit of frames are kept while the server
+    // cannot be reached, after which the oldest are dropped. A raw bus
+    // can carry thousands of frames per second.
+    static ref RAW_FRAME_QUEUE: Mutex<VecDeque<QueuedFrame>> = Mutex::new(VecDeque::new());
+}
+
+pub fn is_enabled_for(port: &CanPort) -> bool {
+    port.raw == Some(true)
+}
+
+// Whether a port sends raw frames, always or while the DBC file cannot
+// be loaded
+pub fn is_allowed_for(port: &CanPort) -> bool {
+    is_enabled_for(port) || port.raw_fallback == Some(true)
+}
+
+// Whether any port may send raw frames
+pub fn any_raw() -> bool {
+    CONFIG
+        .can
+        .iter()
+        .flat_map(|c| c.ports.iter().flatten())
+        .any(is_allowed_for)
+}
+
+fn unix_micros(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as i64)
+}
+
+// Add a frame to the queue, dropping the oldest frames if it is full.
+// Returns whether a frame was dropped.
+fn push_frame<T>(queue: &mut VecDeque<T>, frame: T, limit: usize) -> bool {
+    let full = queue.len() >= limit;
+    if full {
+        queue.pop_front();
+    }
+    queue.push_back(frame);
+    full
+}
+
+pub async fn raw_monitor(port: &CanPort) -> Result<(), Box<dyn Error>> {
+    let socket_rx =
+        fdstore::open_can(&port.name, &port.name).map_err(|source| ClientError::Can {
+            port: port.name.clone(),
+            source,
+        })?;
+    if port.fd == Some(true) {
+        socket_rx.enable_fd().map_err(|source| ClientError::Can {
+            port: port.name.clone(),
+            source,
+        })?;
+    }
+    eprintln!("Start reading raw frames from {}", &port.name);
+    read_frames(port, &socket_rx).await
+}
+
+// Queue the frames read from the socket of a port as raw frames. Also
+// used by a port with a DBC while the DBC file cannot be loaded.
+pub async fn read_frames(port: &CanPort, socket_rx: &CanSocket) -> Result<(), Box<dyn Error>> {
+    let bus: Arc<str> = port.name.as_str().into();
+
+    // Whether frames are being dropped, to only log when it starts
+    let mut dropping = false;
+    loop {
+        let frame = socket_rx.read_fd_frame().await;
+        if !is_enabled(Subsystem::Can).await {
+            continue;
+        }
+        let f = match frame {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+        stats::record_frame(&port.name, f.id()).await;
+        if can_trace::is_enabled() {
+            can_trace::trace(&port.name, || {
+                can_trace::format_frame(&port.name, f.id(), &f.flags(), f.data(), &[])
+            })
+            .await;
+        }
+        if intrusion::is_enabled() {
+            intrusion::inspect(&port.name, f.id(), f.data().len(), None).await;
+        }
+
+        let raw = QueuedFrame {
+            bus: bus.clone(),
+            id: f.id(),
+            extended: f.is_extended(),
+            dlc: f.dlc(),
+            data: f.data().to_vec(),
+            time_stamp_us: unix_micros(f.received()),
+            flags: f.flags(),
+        };
+        let limit = spool::memory_limit();
+        let dropped = push_frame(&mut *RAW_FRAME_QUEUE.lock().await, raw, limit);
+        if dropped && !dropping {
+            eprintln!("Raw CAN frame queue is full, dropping the oldest frames");
+        }
+        dropping = dropped;
+    }
+}
+
+pub async fn raw_frame_sender(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    loop {
+        let frames: Vec<RawCanFrame> = {
+            let mut queue = RAW_FRAME_QUEUE.lock().await;
+            let n = queue.len().min(MAX_FRAMES_TO_SEND);
+            queue.drain(..n).map(RawCanFrame::from).collect()
+        };
+        if frames.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+        debug!("Sending {} raw CAN frames", frames.len());
+
+        let frames = Arc::new(frames);
+        let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+        loop {
+            tap::record_all("SendRawCanFrames", frames.as_slice()).await;
+            let request = Request::new(stream_batch(&frames));
+            let response = client.send_raw_can_frames(request).await;
+            if handle_send_result(response, &mut retry_sleep_s)
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let frame = |id| RawCanFrame {
+            id,
+            ..Default::default()
+        };
+        let mut queue = VecDeque::new();
+        assert!(!push_frame(&mut queue, frame(1), 2));
+        assert!(!push_frame(&mut queue, frame(2), 2));
+        assert!(push_frame(&mut queue, frame(3), 2));
+        let ids: Vec<_> = queue.iter().map(|f| f.id).collect();
+        assert_eq!(ids, [2, 3]);
+    }
+
+    // The queue holds up to the spool memory limit of frames, which has to
+    // fit the gateways with 128 MB of RAM even for CAN FD frames
+    const QUEUE_BUDGET: usize = 2 * 1024 * 1024;
+
+    #[test]
+    fn queued_frames_fit_memory_budget() {
+        let frame = QueuedFrame {
+            bus: "can0".into(),
+            id: 0x18FEF100,
+            extended: true,
+            dlc: 15,
+            data: vec![0; 64],
+            time_stamp_us: 0,
+            flags: vec!["FD", "BRS"],
+        };
+        let in_memory = std::mem::size_of::<QueuedFrame>()
+            + frame.data.capacity()
+            + frame.flags.capacity() * std::mem::size_of::<&str>();
+        assert!(
+            in_memory * spool::DEFAULT_MEMORY_LIMIT <= QUEUE_BUDGET,
+            "{in_memory} bytes per frame"
+        );
+    }
+}
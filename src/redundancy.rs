@@ -0,0 +1,68 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Deduplication of frames from redundant CAN buses. Some vehicles send
+// the same messages on two buses. The ports of such a redundancy group
+// are reported as one bus, named after the group, and a frame with the
+// same ID and payload as one received on another port of the group
+// within the window is dropped.
+
+use lazy_static::lazy_static;
+use lib::{CanPort, RedundancyGroup, CONFIG};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const MAX_RECENT_FRAMES: usize = 4096;
+
+type FrameKey = (String, u32, Vec<u8>);
+
+lazy_static! {
+    // The port and time of the latest frame per group, ID and payload
+    static ref RECENT_FRAMES: Mutex<HashMap<FrameKey, (String, Instant)>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn group_of(port: &CanPort) -> Option<&'static RedundancyGroup> {
+    CONFIG
+        .can
+        .as_ref()?
+        .redundant
+        .as_ref()?
+        .iter()
+        .find(|g| g.ports.contains(&port.name))
+}
+
+pub async fn is_duplicate(group: &RedundancyGroup, port: &str, id: u32, data: &[u8]) -> bool {
+    let window = Duration::from_millis(group.window_ms);
+    let now = Instant::now();
+    let mut recent = RECENT_FRAMES.lock().await;
+
+    let key = (group.name.clone(), id, data.to_vec());
+    if let Some((seen_on, seen)) = recent.get(&key) {
+        if seen_on != port && now.duration_since(*seen) < window {
+            return true;
+        }
+    }
+
+    if recent.len() >= MAX_RECENT_FRAMES {
+        recent.retain(|_, (_, seen)| now.duration_since(*seen) < window);
+    }
+    recent.insert(key, (port.to_string(), now));
+    false
+}
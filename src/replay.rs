@@ -0,0 +1,154 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Replay of a spool dir to a given endpoint, e.g. to migrate the data
+// after a switch of backend or to recover the data of a returned unit.
+// Replies are not acted on, since the endpoint may not be the one that
+// controls the unit.
+
+use super::gpio::to_values_batch;
+use super::net::{connect_to, intercept};
+use super::spool;
+use super::tap;
+use super::utils::stream_batch;
+use lib::{
+    host_insight::{agent_client::AgentClient, CanMessage, Value},
+    CONFIG,
+};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tonic::transport::Uri;
+use tonic::{Request, Status};
+
+// The URL and the domain of an endpoint given as URL or domain
+pub fn parse_endpoint(endpoint: &str) -> Result<(String, String), String> {
+    let url = match endpoint.contains("://") {
+        true => endpoint.to_string(),
+        false => format!("https://{}", endpoint),
+    };
+    let uri: Uri = url.parse().map_err(|e| format!("{}", e))?;
+    if uri.scheme_str() != Some("https") {
+        return Err("only https is supported".to_string());
+    }
+    match uri.host() {
+        Some(host) => Ok((url.clone(), host.to_string())),
+        None => Err("no host".to_string()),
+    }
+}
+
+pub async fn replay(
+    endpoint: &str,
+    dir: Option<&String>,
+    remove: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (url, domain) = parse_endpoint(endpoint)?;
+    let channel = connect_to(&url, &domain).await?;
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    for kind in [spool::CAN_SPOOL, spool::VALUE_SPOOL] {
+        let dir = match dir {
+            Some(dir) => Path::new(dir).join(kind),
+            None if spool::is_enabled() => spool::spool_dir(kind),
+            None => return Err("No spool dir given or configured".into()),
+        };
+        let segments = spool::segments(&dir);
+        println!(
+            "Replaying {} segments from {:?} to {}",
+            segments.len(),
+            dir,
+            url
+        );
+
+        for segment in segments {
+            let mut retry_sleep_s = CONFIG.time.sleep_min_s;
+            if kind == spool::CAN_SPOOL {
+                let messages = match spool::read_segment::<CanMessage>(&segment) {
+                    Ok(messages) => Arc::new(messages),
+                    Err(e) => {
+                        eprintln!("Skipping spool segment {:?}: {}", segment, e);
+                        continue;
+                    }
+                };
+                tap::record_all("SendCanMessageStream", messages.as_slice()).await;
+                while let Err(e) = client
+                    .send_can_message_stream(Request::new(stream_batch(&messages)))
+                    .await
+                {
+                    backoff(&segment, e, &mut retry_sleep_s).await?;
+                }
+            } else {
+                let values = match spool::read_segment::<Value>(&segment) {
+                    Ok(values) => Arc::new(to_values_batch(values)),
+                    Err(e) => {
+                        eprintln!("Skipping spool segment {:?}: {}", segment, e);
+                        continue;
+                    }
+                };
+                tap::record_all("SendValuesStream", values.as_slice()).await;
+                while let Err(e) = client
+                    .send_values_stream(Request::new(stream_batch(&values)))
+                    .await
+                {
+                    backoff(&segment, e, &mut retry_sleep_s).await?;
+                }
+            }
+            if remove {
+                spool::remove_segment(&segment);
+            }
+        }
+    }
+    println!("Replay done");
+    Ok(())
+}
+
+async fn backoff(segment: &Path, e: Status, retry_sleep_s: &mut u64) -> Result<(), String> {
+    if *retry_sleep_s > CONFIG.time.sleep_max_s {
+        return Err(format!("Failed to replay {:?}: {}", segment, e));
+    }
+    eprintln!("Failed to replay {:?}: {}", segment, e);
+    sleep(Duration::from_secs(*retry_sleep_s)).await;
+    *retry_sleep_s *= 2;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            parse_endpoint("backend.example.com"),
+            Ok((
+                "https://backend.example.com".to_string(),
+                "backend.example.com".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_endpoint("https://backend.example.com:8443"),
+            Ok((
+                "https://backend.example.com:8443".to_string(),
+                "backend.example.com".to_string()
+            ))
+        );
+        assert!(parse_endpoint("http://backend.example.com").is_err());
+    }
+}
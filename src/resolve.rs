@@ -0,0 +1,220 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Connections to the servers with a fallback to the last address that
+// worked, as DNS is a common point of failure on flaky cellular links.
+// TLS is set up on top of the connection with the server name, so the
+// certificate is still checked against the domain when the cached
+// address is used.
+//
+// The resolved IPv6 and IPv4 addresses are tried in parallel with a
+// small head start for the preferred family (happy eyeballs), for
+// carriers with IPv6-only or broken IPv6 access. When no address can be
+// reached, the error lists the failure for each of them.
+
+use super::log_level::debug;
+use super::utils::write_atomic_async;
+use futures::stream::{FuturesUnordered, StreamExt};
+use lazy_static::lazy_static;
+use lib::conf_dir;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tonic::transport::Uri;
+
+const DEFAULT_PORT: u16 = 443;
+// Time to wait for a connection attempt before the next address is
+// tried in parallel, as recommended by RFC 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// Limit of a connection to the last known address
+const CACHED_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref ADDRESSES: Mutex<HashMap<String, SocketAddr>> = Mutex::new(load());
+}
+
+fn cache_path() -> PathBuf {
+    PathBuf::from(format!("{}/server-addresses", conf_dir()))
+}
+
+// Each line is "<host> <address>"
+fn parse(content: &str) -> HashMap<String, SocketAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (host, addr) = line.split_once(' ')?;
+            Some((host.to_string(), addr.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+fn load() -> HashMap<String, SocketAddr> {
+    parse(&fs::read_to_string(cache_path()).unwrap_or_default())
+}
+
+async fn remember(host: &str, addr: SocketAddr) {
+    let mut addresses = ADDRESSES.lock().await;
+    if addresses.get(host) == Some(&addr) {
+        return;
+    }
+    addresses.insert(host.to_string(), addr);
+    let mut content = String::new();
+    for (host, addr) in addresses.iter() {
+        content.push_str(&format!("{host} {addr}\n"));
+    }
+    if let Err(e) = write_atomic_async(cache_path(), content.into_bytes()).await {
+        eprintln!("Failed to save server addresses: {e}");
+    }
+}
+
+fn family(addr: &SocketAddr) -> &'static str {
+    if addr.is_ipv6() {
+        "IPv6"
+    } else {
+        "IPv4"
+    }
+}
+
+// Alternate between the address families, starting with the family
+// that the resolver put first
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut interleaved = Vec::new();
+    first.reverse();
+    second.reverse();
+    while !first.is_empty() || !second.is_empty() {
+        interleaved.extend(first.pop());
+        interleaved.extend(second.pop());
+    }
+    interleaved
+}
+
+async fn attempt(addr: SocketAddr) -> (SocketAddr, io::Result<TcpStream>) {
+    (addr, TcpStream::connect(addr).await)
+}
+
+// Connect to whichever address answers first. The next address is tried
+// when the previous attempt fails or has not succeeded within
+// ATTEMPT_DELAY, without cancelling the attempts in progress, so that a
+// broken IPv6 or IPv4 path only costs a short delay.
+async fn connect_any(addrs: &[SocketAddr]) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let mut addrs = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut failures = Vec::new();
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => break,
+            }
+        }
+        let finished = if addrs.len() > 0 {
+            match timeout(ATTEMPT_DELAY, attempts.next()).await {
+                Ok(finished) => finished,
+                Err(_) => {
+                    attempts.extend(addrs.next().map(attempt));
+                    continue;
+                }
+            }
+        } else {
+            attempts.next().await
+        };
+        match finished {
+            Some((addr, Ok(stream))) => {
+                debug!("Connected to {addr}");
+                return Ok(stream);
+            }
+            Some((addr, Err(e))) => {
+                debug!("Failed to connect to {addr}: {e}");
+                failures.push(format!("{} {addr}: {e}", family(&addr)));
+                attempts.extend(addrs.next().map(attempt));
+            }
+            None => {}
+        }
+    }
+    if failures.is_empty() {
+        return Err("No addresses".into());
+    }
+    Err(failures.join(", ").into())
+}
+
+// Connector for the channels. The address is only cached once a
+// connection to it has been made.
+pub async fn connect(uri: Uri) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+    let host = uri.host().ok_or("No host in server URL")?;
+    let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+
+    let stream = match lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs: Vec<_> = addrs.collect();
+            let stream = connect_any(&addrs).await?;
+            remember(host, stream.peer_addr()?).await;
+            stream
+        }
+        Err(e) => {
+            let cached = ADDRESSES.lock().await.get(host).copied();
+            match cached {
+                Some(addr) => {
+                    eprintln!("Failed to resolve {host}: {e}, using last known address {addr}");
+                    timeout(CACHED_CONNECT_TIMEOUT, TcpStream::connect(addr))
+                        .await
+                        .map_err(|_| format!("Timed out connecting to {addr}"))??
+                }
+                None => return Err(e.into()),
+            }
+        }
+    };
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:443", "[2001:db8::2]:443", "192.0.2.1:443"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        assert_eq!(interleave(&addrs), [addrs[0], addrs[2], addrs[1]]);
+        assert_eq!(interleave(&addrs[2..]), [addrs[2]]);
+        assert!(interleave(&[]).is_empty());
+    }
+
+    #[test]
+    fn parses_cache() {
+        let addresses =
+            parse("a.example.com 192.0.2.1:443\nb.example.com [2001:db8::1]:443\nbad\n");
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(
+            addresses["b.example.com"],
+            "[2001:db8::1]:443".parse().unwrap()
+        );
+    }
+}
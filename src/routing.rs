@@ -0,0 +1,198 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Routing of selected data to secondary endpoints, e.g. for delivery to
+// both the vehicle manufacturer and the operator from one client. Each
+// route has its own domain and uid, and gets a copy of the CAN signals
+// and digital input values that match its sources and signals. The data
+// is still sent to the server as well.
+//
+// A route endpoint only receives data. Actions in its replies are
+// ignored, so that it cannot control the unit.
+
+use super::can::restamp_message;
+use super::gpio::restamp_value;
+use super::spool;
+use super::tap;
+use super::transport;
+use super::utils::stream_batch;
+use lazy_static::lazy_static;
+use lib::{
+    cache::DIGITAL_IN_SOURCE,
+    host_insight::{agent_client::AgentClient, CanMessage, Value, Values},
+    RouteConfig, CONFIG,
+};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+const MAX_MSG_TO_SEND: usize = 100;
+
+#[derive(Default)]
+struct RouteQueue {
+    can_messages: VecDeque<CanMessage>,
+    values: VecDeque<Value>,
+}
+
+lazy_static! {
+    static ref ROUTE_QUEUES: Mutex<HashMap<String, RouteQueue>> = Mutex::new(HashMap::new());
+}
+
+fn routes() -> &'static [RouteConfig] {
+    CONFIG.routes.as_deref().unwrap_or_default()
+}
+
+fn matches(route: &RouteConfig, source: &str, signal: &str) -> bool {
+    contains(&route.sources, source) && contains(&route.signals, signal)
+}
+
+// A list that is not configured contains everything
+fn contains(list: &Option<Vec<String>>, name: &str) -> bool {
+    match list {
+        Some(list) => list.iter().any(|n| n == name),
+        None => true,
+    }
+}
+
+// Queue the signals of a CAN message that match each route
+pub async fn route_can_message(message: &CanMessage) {
+    for route in routes() {
+        let signal: Vec<_> = message
+            .signal
+            .iter()
+            .filter(|s| matches(route, &message.bus, &s.signal_name))
+            .cloned()
+            .collect();
+        if signal.is_empty() {
+            continue;
+        }
+        let mut queues = ROUTE_QUEUES.lock().await;
+        let queue = &mut queues.entry(route.name.clone()).or_default().can_messages;
+        if queue.len() >= spool::memory_limit() {
+            queue.pop_front();
+        }
+        queue.push_back(CanMessage {
+            signal,
+            ..message.clone()
+        });
+    }
+}
+
+pub async fn route_value(value: &Value) {
+    for route in routes() {
+        if !matches(route, DIGITAL_IN_SOURCE, &value.name) {
+            continue;
+        }
+        let mut queues = ROUTE_QUEUES.lock().await;
+        let queue = &mut queues.entry(route.name.clone()).or_default().values;
+        if queue.len() >= spool::memory_limit() {
+            queue.pop_front();
+        }
+        queue.push_back(value.clone());
+    }
+}
+
+pub async fn restamp_queues(correct: impl Fn(i64) -> Option<i64>) {
+    for queue in ROUTE_QUEUES.lock().await.values_mut() {
+        for message in queue.can_messages.iter_mut() {
+            restamp_message(message, &correct);
+        }
+        for value in queue.values.iter_mut() {
+            restamp_value(value, &correct);
+        }
+    }
+}
+
+pub async fn route_sender(route: &RouteConfig, channel: Channel) -> Result<(), Box<dyn Error>> {
+    let uid: MetadataValue<_> = route.uid.parse()?;
+    #[allow(clippy::result_large_err)]
+    let mut client = AgentClient::with_interceptor(channel, move |mut req: Request<()>| {
+        req.metadata_mut().insert("uid", uid.clone());
+        Ok::<_, Status>(req)
+    });
+    println!("Routing data to {} at {}", route.name, route.domain);
+
+    loop {
+        let (can_messages, values) = {
+            let mut queues = ROUTE_QUEUES.lock().await;
+            let queue = queues.entry(route.name.clone()).or_default();
+            let n = queue.can_messages.len().min(MAX_MSG_TO_SEND);
+            let m = queue.values.len().min(MAX_MSG_TO_SEND);
+            (
+                Arc::new(queue.can_messages.drain(..n).collect::<Vec<_>>()),
+                queue.values.drain(..m).collect::<Vec<_>>(),
+            )
+        };
+        if can_messages.is_empty() && values.is_empty() {
+            sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+
+        let mut retry_sleep_s = CONFIG.time.sleep_min_s;
+        // The CAN messages that the endpoint has acknowledged, which are
+        // not sent again on a retry
+        let mut sent = 0;
+        loop {
+            let streams = transport::use_streams().await;
+            let result = async {
+                if sent < can_messages.len() {
+                    let unsent = &can_messages[sent..];
+                    tap::record_all(&route.name, unsent).await;
+                    if streams {
+                        let batch = match sent {
+                            0 => can_messages.clone(),
+                            _ => Arc::new(unsent.to_vec()),
+                        };
+                        let request = Request::new(stream_batch(&batch));
+                        client.send_can_message_stream(request).await?;
+                        sent = can_messages.len();
+                    } else {
+                        for m in unsent {
+                            client.send_can_message(m.clone()).await?;
+                            sent += 1;
+                        }
+                    }
+                }
+                if !values.is_empty() {
+                    let measurements = Values {
+                        measurements: values.clone(),
+                    };
+                    tap::record(&route.name, &measurements).await;
+                    client.send_values(measurements).await?;
+                }
+                Ok::<_, Status>(())
+            }
+            .await;
+
+            match result {
+                Ok(()) => break,
+                Err(e) => {
+                    eprintln!("Failed to send to {}: {}", route.name, e);
+                    sleep(Duration::from_secs(retry_sleep_s)).await;
+                    retry_sleep_s = (retry_sleep_s * 2).min(CONFIG.time.sleep_max_s);
+                }
+            }
+        }
+    }
+}
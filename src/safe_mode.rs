@@ -0,0 +1,107 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Safe mode after crash loops. Every start is recorded, and the record
+// is removed again when the client exits in an orderly way, e.g. to
+// apply a config update or when stopped by systemd. If the client has
+// started too many times without exiting cleanly within the window, e.g.
+// because a bad config makes it crash, it starts in safe mode: with the
+// fallback config and only what is needed to let the server recover the
+// device. The limits are given on the command line, since the config
+// may be the cause of the crashes.
+
+use super::config_update::config_update_monitor;
+use super::control::control_monitor;
+use super::net::{heartbeat, send_state, set_status, setup_network};
+use futures::future::{try_join_all, FutureExt};
+use lib::{conf_dir, history, set_safe_mode, StatusCodes};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tonic::transport::Channel;
+
+fn starts_path() -> PathBuf {
+    PathBuf::from(format!("{}/starts", conf_dir()))
+}
+
+fn read_starts() -> Vec<i64> {
+    fs::read_to_string(starts_path())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().parse().ok())
+        .collect()
+}
+
+fn write_starts(starts: &[i64]) {
+    let s: String = starts.iter().map(|t| format!("{t}\n")).collect();
+    if let Err(e) = fs::write(starts_path(), s) {
+        eprintln!("Failed to record start: {e}");
+    }
+}
+
+// Record this start and enter safe mode if the client has already
+// started max_starts times within the window without exiting cleanly
+pub fn check_crash_loop(max_starts: usize, window: Duration) -> bool {
+    let now = history::unix_millis(SystemTime::now());
+    let window_ms = window.as_millis() as i64;
+    let mut starts: Vec<i64> = read_starts()
+        .into_iter()
+        .filter(|t| now - t < window_ms)
+        .collect();
+    // A pending config, e.g. a fix pushed while in safe mode, is tried.
+    // If it crashes too, the next start is in safe mode again.
+    let pending = Path::new(&format!("{}/conf-new.toml", conf_dir())).exists();
+    let crash_loop = starts.len() >= max_starts && !pending;
+    starts.push(now);
+    write_starts(&starts);
+
+    if crash_loop {
+        eprintln!(
+            "Started {} times in {:?} without exiting cleanly, entering safe mode",
+            starts.len(),
+            window
+        );
+        set_safe_mode();
+    }
+    crash_loop
+}
+
+// Called when the client exits in an orderly way
+pub fn record_clean_exit() {
+    let mut starts = read_starts();
+    if starts.pop().is_some() {
+        write_starts(&starts);
+    }
+}
+
+// Only report the state and keep in contact with the server, so that it
+// can e.g. push a new config or software, or use remote control
+pub async fn run_safe_mode(channel: Channel) -> Result<(), Box<dyn Error>> {
+    set_status(StatusCodes::SafeMode).await;
+    send_state(channel.clone()).await;
+
+    let heartbeat_channel = setup_network().await?;
+    let futures = vec![
+        config_update_monitor(channel.clone()).boxed(),
+        control_monitor(channel).boxed(),
+        heartbeat(heartbeat_channel).boxed(),
+    ];
+    try_join_all(futures).await?;
+    Ok(())
+}
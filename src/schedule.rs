@@ -0,0 +1,158 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Schedule-scoped config overrides, e.g. higher CAN reporting rates
+// during working hours and lower at night. The overrides of the
+// schedules that are active in local time when the config is loaded are
+// merged into the config, and the client restarts to reload it when the
+// set of active schedules changes.
+
+use std::collections::BTreeMap;
+use toml::value::Table;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Clone, Copy)]
+pub struct LocalTime {
+    pub weekday: u32, // Days since Sunday
+    pub minute: u32,  // Minutes since midnight
+}
+
+pub fn local_time() -> LocalTime {
+    // SAFETY: localtime_r only writes to the given struct
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        LocalTime {
+            weekday: tm.tm_wday as u32,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u32,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Schedule {
+    pub name: String,
+    start: u32,
+    end: u32,
+    days: Vec<u32>,
+    overrides: Table,
+}
+
+impl Schedule {
+    // A schedule that ends before it starts runs past midnight, and
+    // then belongs to the day it started
+    pub fn is_active(&self, t: LocalTime) -> bool {
+        let on = |day: u32| self.days.is_empty() || self.days.contains(&day);
+        if self.start <= self.end {
+            on(t.weekday) && t.minute >= self.start && t.minute < self.end
+        } else {
+            (on(t.weekday) && t.minute >= self.start)
+                || (on((t.weekday + 6) % 7) && t.minute < self.end)
+        }
+    }
+}
+
+// Parse "HH:MM" into minutes since midnight
+pub fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    (h < 24 && m < 60).then(|| h * 60 + m)
+}
+
+// Parse a day such as "mon" into days since Sunday
+pub fn parse_day(s: &str) -> Option<u32> {
+    DAYS.iter().position(|d| *d == s).map(|i| i as u32)
+}
+
+fn parse_schedule(name: &str, value: &toml::Value) -> Result<Schedule, String> {
+    let time = |key: &str| {
+        value
+            .get(key)
+            .and_then(|v| v.as_str())
+            .and_then(parse_time)
+            .ok_or(format!("Schedule {name} requires {key} as HH:MM"))
+    };
+    let mut days = Vec::new();
+    for day in value
+        .get("days")
+        .and_then(|d| d.as_array())
+        .into_iter()
+        .flatten()
+    {
+        match day.as_str().and_then(parse_day) {
+            Some(day) => days.push(day),
+            None => return Err(format!("Schedule {name} has an invalid day {day}")),
+        }
+    }
+    let overrides = match value.get("overrides") {
+        Some(toml::Value::Table(t)) => t.clone(),
+        _ => Table::new(),
+    };
+    Ok(Schedule {
+        name: name.to_string(),
+        start: time("start")?,
+        end: time("end")?,
+        days,
+        overrides,
+    })
+}
+
+// Merge the overrides into the config, so that only the given keys of a
+// block are replaced
+pub fn merge(base: &mut Table, overrides: &Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+// Take the schedules out of the config and apply the selected ones,
+// usually those that are active at the current time
+pub fn apply(
+    root: &mut Table,
+    selected: impl Fn(&Schedule) -> bool,
+) -> Result<Vec<Schedule>, String> {
+    let tables: BTreeMap<String, toml::Value> = match root.remove("schedules") {
+        Some(toml::Value::Table(tables)) => tables.into_iter().collect(),
+        _ => BTreeMap::new(),
+    };
+    let mut schedules = Vec::new();
+    for (name, value) in &tables {
+        let schedule = parse_schedule(name, value)?;
+        if selected(&schedule) {
+            merge(root, &schedule.overrides);
+        }
+        schedules.push(schedule);
+    }
+    Ok(schedules)
+}
+
+// The names of the schedules that are active at the given time
+pub fn active(schedules: &[Schedule], t: LocalTime) -> Vec<String> {
+    schedules
+        .iter()
+        .filter(|s| s.is_active(t))
+        .map(|s| s.name.clone())
+        .collect()
+}
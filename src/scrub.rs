@@ -0,0 +1,173 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Scrubbing of personal data before it leaves the unit, e.g. to reduce
+// the precision of positions or to pseudonymize driver IDs. The rules
+// are applied to everything that is sent, spooled or routed, while the
+// local logic such as alerts and composites sees the real values.
+//
+// Hashed values are keyed with HMAC-SHA256, so that they cannot be
+// reversed by hashing all possible IDs without the key.
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{can_signal, CanMessage, CanSignal},
+    ScrubAction, ScrubRule, CONFIG,
+};
+use sha2::Sha256;
+
+// Length in bytes of the hashes that are sent
+const HASH_LEN: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+lazy_static! {
+    static ref KEY: Option<HmacSha256> = CONFIG
+        .scrub
+        .as_ref()
+        .and_then(|s| s.key.as_ref())
+        .map(|k| new_key(k.as_bytes()));
+}
+
+fn round(value: f64, decimals: u32) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    (value * factor).round() / factor
+}
+
+// HMAC takes keys of any length
+fn new_key(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC accepts any key length")
+}
+
+fn hash(key: &HmacSha256, value: &can_signal::Value) -> String {
+    let data = match value {
+        can_signal::Value::ValF64(v) => v.to_string(),
+        can_signal::Value::ValStr(v) => v.clone(),
+        can_signal::Value::ValI64(v) => v.to_string(),
+        can_signal::Value::ValU64(v) => v.to_string(),
+    };
+    let mut mac = key.clone();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes()[..HASH_LEN]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn apply(rule: &ScrubRule, key: Option<&HmacSha256>, signal: &mut CanSignal) {
+    // The raw value would give the real value away
+    signal.raw = None;
+    signal.value = match (rule.action, signal.value.take()) {
+        (ScrubAction::Round, Some(can_signal::Value::ValF64(v))) => Some(
+            can_signal::Value::ValF64(round(v, rule.decimals.unwrap_or(0))),
+        ),
+        (ScrubAction::Hash, Some(v)) => key.map(|k| can_signal::Value::ValStr(hash(k, &v))),
+        (ScrubAction::Round, v) => v,
+        _ => None,
+    };
+}
+
+fn find<'a>(rules: &'a [ScrubRule], name: &str) -> Option<&'a ScrubRule> {
+    rules.iter().find(|r| r.signal == name)
+}
+
+fn scrub_with(rules: &[ScrubRule], key: Option<&HmacSha256>, signals: &mut Vec<CanSignal>) {
+    signals.retain(
+        |s| !matches!(find(rules, &s.signal_name), Some(r) if r.action == ScrubAction::Drop),
+    );
+    for signal in signals.iter_mut() {
+        if let Some(rule) = find(rules, &signal.signal_name) {
+            apply(rule, key, signal);
+        }
+    }
+}
+
+// Apply the rules to signals that are about to be sent
+pub fn scrub_signals(signals: &mut Vec<CanSignal>) {
+    if let Some(scrub) = &CONFIG.scrub {
+        scrub_with(&scrub.rules, KEY.as_ref(), signals);
+    }
+}
+
+pub fn scrub_message(message: &mut CanMessage) {
+    scrub_signals(&mut message.signal);
+}
+
+// Whether a digital input value may be sent. Only dropping applies to
+// digital inputs.
+pub fn keep_value(name: &str) -> bool {
+    let rule = CONFIG.scrub.as_ref().and_then(|s| find(&s.rules, name));
+    !matches!(rule, Some(r) if r.action == ScrubAction::Drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(name: &str, value: can_signal::Value) -> CanSignal {
+        CanSignal {
+            signal_name: name.to_string(),
+            unit: "N/A".to_string(),
+            value: Some(value),
+            raw: Some(1),
+            refresh: false,
+        }
+    }
+
+    #[test]
+    fn applies_rules() {
+        let rules = [
+            ScrubRule {
+                signal: "Latitude".to_string(),
+                action: ScrubAction::Round,
+                decimals: Some(2),
+            },
+            ScrubRule {
+                signal: "DriverId".to_string(),
+                action: ScrubAction::Hash,
+                decimals: None,
+            },
+            ScrubRule {
+                signal: "Vin".to_string(),
+                action: ScrubAction::Drop,
+                decimals: None,
+            },
+        ];
+        let key = new_key(b"secret");
+        let mut signals = vec![
+            signal("Latitude", can_signal::Value::ValF64(57.70887)),
+            signal("DriverId", can_signal::Value::ValU64(1234)),
+            signal("Vin", can_signal::Value::ValStr("YV2".to_string())),
+            signal("Speed", can_signal::Value::ValF64(80.5)),
+        ];
+        scrub_with(&rules, Some(&key), &mut signals);
+
+        assert_eq!(signals.len(), 3);
+        assert_eq!(signals[0].value, Some(can_signal::Value::ValF64(57.71)));
+        assert_eq!(signals[0].raw, None);
+        let hashed = match &signals[1].value {
+            Some(can_signal::Value::ValStr(hashed)) => hashed,
+            _ => panic!("DriverId was not hashed"),
+        };
+        assert_eq!(hashed.len(), 2 * HASH_LEN);
+        assert_eq!(hashed, "55124a287e8ddc58a97eb3eea634a4d3");
+        assert_eq!(signals[2].value, Some(can_signal::Value::ValF64(80.5)));
+        assert_eq!(signals[2].raw, Some(1));
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Key algorithms for UDS SecurityAccess. The algorithms are specific to
+// the vehicle manufacturer, and usually come as a seed/key library with
+// the GenerateKeyEx function of the Vector interface, which is loaded
+// from the configuration directory. Simple algorithms are embedded.
+// Libraries are loaded once per port and called from a blocking thread,
+// since a key computation may take a while.
+
+use lazy_static::lazy_static;
+use lib::{conf_dir, is_plain_file_name, KeyAlgorithmKind, SecurityAccessConfig};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// Largest key that a library may return
+const MAX_KEY_LEN: usize = 256;
+
+lazy_static! {
+    // The key algorithms that are in use, by port
+    static ref ALGORITHMS: Mutex<HashMap<String, Arc<dyn KeyAlgorithm>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Something that computes the key for a seed
+pub trait KeyAlgorithm: Send + Sync {
+    fn key(&self, level: u8, seed: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+// The key algorithm of a port, which is loaded on first use. Blocks, so
+// it is called from a blocking thread.
+pub fn key_algorithm(config: &SecurityAccessConfig) -> Result<Arc<dyn KeyAlgorithm>, String> {
+    let mut algorithms = ALGORITHMS.lock().unwrap();
+    if let Some(algorithm) = algorithms.get(&config.port) {
+        return Ok(algorithm.clone());
+    }
+    let algorithm: Arc<dyn KeyAlgorithm> = match config.algorithm {
+        KeyAlgorithmKind::Xor => Arc::new(XorKey {
+            mask: config.mask.clone().unwrap_or_default(),
+        }),
+        KeyAlgorithmKind::Library => {
+            // Only libraries in the configuration directory are loaded
+            let library = config.library.as_deref().unwrap_or_default();
+            if !is_plain_file_name(library) {
+                return Err(format!("Invalid seed/key library {library:?}"));
+            }
+            Arc::new(LibraryKey::open(
+                &Path::new(&conf_dir()).join(library),
+                config.variant.as_deref().unwrap_or_default(),
+            )?)
+        }
+    };
+    algorithms.insert(config.port.clone(), algorithm.clone());
+    Ok(algorithm)
+}
+
+// The seed XOR a mask, which is repeated for seeds longer than the mask
+pub struct XorKey {
+    pub mask: Vec<u8>,
+}
+
+impl KeyAlgorithm for XorKey {
+    fn key(&self, _level: u8, seed: &[u8]) -> Result<Vec<u8>, String> {
+        if self.mask.is_empty() {
+            return Err("No mask".to_string());
+        }
+        Ok(seed
+            .iter()
+            .zip(self.mask.iter().cycle())
+            .map(|(s, m)| s ^ m)
+            .collect())
+    }
+}
+
+type GenerateKeyEx = unsafe extern "C" fn(
+    seed: *const u8,
+    seed_len: c_uint,
+    level: c_uint,
+    variant: *const c_char,
+    key: *mut u8,
+    max_key_len: c_uint,
+    key_len: *mut c_uint,
+) -> c_int;
+
+// A seed/key library with the Vector GenerateKeyEx function. Libraries
+// are not required to be thread safe, so the calls are serialized.
+pub struct LibraryKey {
+    handle: *mut c_void,
+    generate: GenerateKeyEx,
+    variant: CString,
+    calls: Mutex<()>,
+}
+
+// SAFETY: the handle is only passed to dlclose when the library is
+// dropped, and GenerateKeyEx is only called while holding the lock
+unsafe impl Send for LibraryKey {}
+unsafe impl Sync for LibraryKey {}
+
+fn dl_error() -> String {
+    // SAFETY: dlerror returns null or a C string that is valid until the
+    // next call of a dl function on this thread, and it is copied at once
+    unsafe {
+        let error = libc::dlerror();
+        if error.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(error).to_string_lossy().into_owned()
+        }
+    }
+}
+
+impl LibraryKey {
+    pub fn open(path: &Path, variant: &str) -> Result<LibraryKey, String> {
+        let c_path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| format!("Invalid library path {path:?}"))?;
+        let variant = CString::new(variant).map_err(|_| "Invalid variant".to_string())?;
+        // SAFETY: the path is a C string, and loading runs the
+        // initializers of the library, which is trusted like the config
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(format!("Failed to load {path:?}: {}", dl_error()));
+        }
+        let name = CString::new("GenerateKeyEx").unwrap();
+        // SAFETY: the handle was returned by dlopen and the name is a C
+        // string
+        let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+        if symbol.is_null() {
+            // SAFETY: the handle was returned by dlopen and is not used
+            // after this
+            unsafe { libc::dlclose(handle) };
+            return Err(format!("No GenerateKeyEx in {path:?}"));
+        }
+        // SAFETY: the symbol is not null, and GenerateKeyEx has this
+        // signature in the Vector seed/key interface. It stays valid
+        // until the handle is closed on drop.
+        let generate = unsafe { std::mem::transmute::<*mut c_void, GenerateKeyEx>(symbol) };
+        Ok(LibraryKey {
+            handle,
+            generate,
+            variant,
+            calls: Mutex::new(()),
+        })
+    }
+}
+
+impl KeyAlgorithm for LibraryKey {
+    fn key(&self, level: u8, seed: &[u8]) -> Result<Vec<u8>, String> {
+        let mut key = vec![0; MAX_KEY_LEN];
+        let mut key_len: c_uint = 0;
+        let _call = self.calls.lock().unwrap();
+        // SAFETY: the seed and the key buffer are valid for the given
+        // lengths, the variant is a C string, and the library has not been
+        // closed since self holds the handle
+        let result = unsafe {
+            (self.generate)(
+                seed.as_ptr(),
+                seed.len() as c_uint,
+                level as c_uint,
+                self.variant.as_ptr(),
+                key.as_mut_ptr(),
+                key.len() as c_uint,
+                &mut key_len,
+            )
+        };
+        if result != 0 {
+            return Err(format!("GenerateKeyEx failed with {result}"));
+        }
+        key.truncate((key_len as usize).min(MAX_KEY_LEN));
+        Ok(key)
+    }
+}
+
+impl Drop for LibraryKey {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by dlopen, and GenerateKeyEx is
+        // not called after this
+        unsafe { libc::dlclose(self.handle) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xor_key() {
+        let xor = XorKey {
+            mask: vec![0xff, 0x00],
+        };
+        assert_eq!(xor.key(1, &[0x12, 0x34, 0x56]).unwrap(), [0xed, 0x34, 0xa9]);
+        assert!(XorKey { mask: Vec::new() }.key(1, &[0x12]).is_err());
+    }
+
+    #[test]
+    fn library_outside_the_conf_dir() {
+        let config = |library: &str| SecurityAccessConfig {
+            port: format!("can-{library}"),
+            algorithm: KeyAlgorithmKind::Library,
+            level: None,
+            session: None,
+            library: Some(library.to_string()),
+            variant: None,
+            mask: None,
+        };
+        for library in ["/usr/lib/keygen.so", "../keygen.so", "lib/keygen.so"] {
+            let error = key_algorithm(&config(library)).err().unwrap();
+            assert!(error.contains("Invalid seed/key library"));
+        }
+    }
+
+    #[test]
+    fn missing_library() {
+        let error = LibraryKey::open(Path::new("/nonexistent/keygen.so"), "")
+            .err()
+            .unwrap();
+        assert!(error.contains("Failed to load"));
+    }
+}
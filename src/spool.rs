@@ -0,0 +1,442 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// On-disk spool for messages that could not be sent yet. When an
+// in-memory send queue grows beyond its limit, the oldest messages are
+// written to a segment file. The senders always send spooled segments
+// before the in-memory queue, so the order of the data is kept.
+//
+// Segments are optionally encrypted and authenticated with
+// ChaCha20-Poly1305 using a device key. This is transparent to the
+// senders since segments are decrypted when read.
+//
+// Segments are written on the blocking pool, one at a time, and only get
+// their final name once they are synced, so that a crash never leaves a
+// partial segment behind. They are named by a sequence number, which
+// keeps their order when the wall clock steps, and the time they were
+// spooled. A segment that cannot be read, e.g. after the key was lost,
+// is moved to the quarantine dir rather than deleted.
+//
+// If a segment cannot be written, e.g. since the key is invalid or the
+// disk is full, the messages are put back in the send queue and the
+// failure is reported in the heartbeat status. Spooling is then not
+// tried again for a while, so the queue grows in memory meanwhile.
+
+use super::log_level::debug;
+use super::net::{clear_status, set_status};
+use super::utils::sync_dir;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use lazy_static::lazy_static;
+use lib::{conf_dir, instance, StatusCodes, CONFIG};
+use prost::Message;
+use rand::RngCore;
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+type SpoolError = Box<dyn Error + Send + Sync>;
+
+pub const CAN_SPOOL: &str = "can";
+pub const VALUE_SPOOL: &str = "values";
+
+pub const DEFAULT_MEMORY_LIMIT: usize = 10000;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SEGMENT_PLAIN: u8 = 0;
+const SEGMENT_ENCRYPTED: u8 = 1;
+const QUARANTINE_DIR: &str = "quarantine";
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref SPOOL_CIPHER: Result<Option<ChaCha20Poly1305>, String> = load_cipher();
+    // Keeps the segments in the order they are written
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+    // Continues after the segments that are already spooled
+    static ref SEQUENCE: AtomicU64 = AtomicU64::new(last_sequence() + 1);
+    // When the last write failed
+    static ref WRITE_FAILED_AT: std::sync::Mutex<Option<Instant>> = std::sync::Mutex::new(None);
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG.spool.is_some()
+}
+
+// Number of messages kept in memory before the oldest are spooled
+pub fn memory_limit() -> usize {
+    CONFIG
+        .spool
+        .as_ref()
+        .and_then(|s| s.memory_limit)
+        .unwrap_or(DEFAULT_MEMORY_LIMIT)
+}
+
+// Whether the oldest messages of a send queue of the given length are
+// to be spooled
+pub fn should_spool(queue_len: usize) -> bool {
+    is_enabled()
+        && queue_len >= memory_limit()
+        && retry_due(*WRITE_FAILED_AT.lock().unwrap(), Instant::now())
+}
+
+fn retry_due(failed_at: Option<Instant>, now: Instant) -> bool {
+    failed_at.map_or(true, |t| now.duration_since(t) >= WRITE_RETRY_INTERVAL)
+}
+
+// Report the result of write_segment. On failure, the caller has put the
+// messages back in its queue.
+pub async fn report_write(kind: &str, result: Result<(), SpoolError>) {
+    match result {
+        Ok(()) => {
+            if WRITE_FAILED_AT.lock().unwrap().take().is_some() {
+                clear_status(StatusCodes::SpoolFailed).await;
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to spool {kind}, keeping the messages in memory: {e}");
+            *WRITE_FAILED_AT.lock().unwrap() = Some(Instant::now());
+            set_status(StatusCodes::SpoolFailed).await;
+        }
+    }
+}
+
+// Instances share the spool dir but have their own segments
+pub fn spool_dir(kind: &str) -> PathBuf {
+    let dir = PathBuf::from(&CONFIG.spool.as_ref().unwrap().dir);
+    match instance() {
+        Some(instance) => dir.join(instance).join(kind),
+        None => dir.join(kind),
+    }
+}
+
+// Read the device key, or create one on first use
+fn load_cipher() -> Result<Option<ChaCha20Poly1305>, String> {
+    let spool = match CONFIG.spool.as_ref() {
+        Some(spool) => spool,
+        None => return Ok(None),
+    };
+    if !spool.encrypt.unwrap_or(false) {
+        return Ok(None);
+    }
+    let key_file = spool
+        .key_file
+        .clone()
+        .unwrap_or_else(|| format!("{}/spool.key", conf_dir()));
+
+    let key = match fs::read(&key_file) {
+        Ok(key) => key,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut key = vec![0; KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&key_file)
+                .and_then(|mut f| f.write_all(&key))
+                .map_err(|e| format!("Could not create the spool key {key_file}: {e}"))?;
+            key
+        }
+        Err(e) => return Err(format!("Could not read the spool key {key_file}: {e}")),
+    };
+    if key.len() != KEY_LEN {
+        return Err(format!("Invalid spool key in {key_file}"));
+    }
+    Ok(Some(ChaCha20Poly1305::new(Key::from_slice(&key))))
+}
+
+fn cipher() -> Result<Option<&'static ChaCha20Poly1305>, SpoolError> {
+    match SPOOL_CIPHER.as_ref() {
+        Ok(cipher) => Ok(cipher.as_ref()),
+        Err(e) => Err(e.clone().into()),
+    }
+}
+
+// Write messages to a new segment
+pub async fn write_segment<M: Message + Send + 'static>(
+    kind: &str,
+    messages: Vec<M>,
+) -> Result<(), SpoolError> {
+    let dir = spool_dir(kind);
+    let _lock = WRITE_LOCK.lock().await;
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let path = segment_path(&dir, sequence, millis);
+        debug!("Spooling {} messages to {:?}", messages.len(), path);
+        write_segment_file(&path, &messages)
+    })
+    .await?
+}
+
+fn write_segment_file<M: Message>(path: &Path, messages: &[M]) -> Result<(), SpoolError> {
+    let mut plain = Vec::new();
+    for m in messages {
+        m.encode_length_delimited(&mut plain)?;
+    }
+
+    let mut segment = Vec::new();
+    match cipher()? {
+        Some(cipher) => {
+            let mut nonce = [0; NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let ciphertext = cipher
+                .encrypt(Nonce::from_slice(&nonce), plain.as_slice())
+                .map_err(|_| "Failed to encrypt spool segment")?;
+            segment.push(SEGMENT_ENCRYPTED);
+            segment.extend_from_slice(&nonce);
+            segment.extend(ciphertext);
+        }
+        None => {
+            segment.push(SEGMENT_PLAIN);
+            segment.extend(plain);
+        }
+    }
+
+    let partial = path.with_extension("part");
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)?;
+    f.write_all(&segment)?;
+    f.sync_all()?;
+    fs::rename(&partial, path)?;
+    sync_dir(path)?;
+    Ok(())
+}
+
+// Segment names sort in the order they were written
+fn segment_path(dir: &Path, sequence: u64, millis: i64) -> PathBuf {
+    dir.join(format!("{sequence:020}-{millis:020}.seg"))
+}
+
+// The sequence number of a segment and when it was spooled
+fn parse_segment_name(path: &Path) -> Option<(u64, i64)> {
+    let (sequence, millis) = path.file_stem()?.to_str()?.split_once('-')?;
+    Some((sequence.parse().ok()?, millis.parse().ok()?))
+}
+
+fn last_sequence() -> u64 {
+    [CAN_SPOOL, VALUE_SPOOL]
+        .iter()
+        .flat_map(|kind| segments(&spool_dir(kind)))
+        .filter_map(|segment| parse_segment_name(&segment))
+        .map(|(sequence, _)| sequence)
+        .max()
+        .unwrap_or(0)
+}
+
+// Correct the time stamps of the spooled messages after a step of the
+// wall clock. The restamp function corrects a message and returns true
+// if it was changed, and correct corrects a time in milliseconds. A
+// segment with changed messages is rewritten under its sequence number
+// with the corrected time.
+pub fn restamp_segments<M: Message + Default>(
+    kind: &str,
+    restamp: impl Fn(&mut M) -> bool,
+    correct: impl Fn(i64) -> Option<i64>,
+) {
+    let dir = spool_dir(kind);
+    for path in segments(&dir) {
+        let mut messages = match read_segment::<M>(&path) {
+            Ok(messages) => messages,
+            Err(_) => continue,
+        };
+        let mut changed = false;
+        for m in messages.iter_mut() {
+            changed |= restamp(m);
+        }
+        if !changed {
+            continue;
+        }
+
+        let (sequence, millis) = match parse_segment_name(&path) {
+            Some(name) => name,
+            None => continue,
+        };
+        let new_path = segment_path(&dir, sequence, correct(millis).unwrap_or(millis).max(0));
+        match write_segment_file(&new_path, &messages) {
+            Ok(()) if new_path != path => remove_segment(&path),
+            Ok(()) => {}
+            Err(e) => eprintln!("Failed to restamp spool segment {:?}: {}", path, e),
+        }
+    }
+}
+
+// Remove the partial segments of writes that were interrupted, e.g. by a
+// power cut, which would otherwise be left behind. Called at startup,
+// before anything is spooled.
+pub fn remove_partial_segments() {
+    for kind in [CAN_SPOOL, VALUE_SPOOL] {
+        let entries = match fs::read_dir(spool_dir(kind)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_some_and(|e| e == "part") {
+                match fs::remove_file(&path) {
+                    Ok(()) => println!("Removed partial segment {}", path.display()),
+                    Err(e) => eprintln!("Failed to remove {}: {e}", path.display()),
+                }
+            }
+        }
+    }
+}
+
+// The segments in a dir, oldest first
+pub fn segments(dir: &Path) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "seg"))
+                .collect()
+        })
+        .unwrap_or_default();
+    segments.sort();
+    segments
+}
+
+pub fn oldest_segment(kind: &str) -> Option<PathBuf> {
+    segments(&spool_dir(kind)).into_iter().next()
+}
+
+// When the oldest segment of a kind was written, in milliseconds since
+// the epoch as in its name
+pub fn oldest_segment_millis(kind: &str) -> Option<i64> {
+    parse_segment_name(&oldest_segment(kind)?).map(|(_, millis)| millis)
+}
+
+// Total size in bytes of the segments of all kinds
+pub fn size_bytes() -> u64 {
+    [CAN_SPOOL, VALUE_SPOOL]
+        .iter()
+        .flat_map(|kind| segments(&spool_dir(kind)))
+        .filter_map(|segment| fs::metadata(segment).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+pub fn segment_count(kind: &str) -> usize {
+    fs::read_dir(spool_dir(kind))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|e| e.path().extension().is_some_and(|e| e == "seg"))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+pub fn read_segment<M: Message + Default>(path: &Path) -> Result<Vec<M>, SpoolError> {
+    let segment = fs::read(path)?;
+    let plain = match segment.split_first() {
+        Some((&SEGMENT_PLAIN, rest)) => rest.to_vec(),
+        Some((&SEGMENT_ENCRYPTED, rest)) if rest.len() >= NONCE_LEN => {
+            let cipher =
+                cipher()?.ok_or("Encrypted spool segment but encryption is not configured")?;
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| "Spool segment failed authentication")?
+        }
+        _ => return Err("Invalid spool segment".into()),
+    };
+
+    let mut buf = plain.as_slice();
+    let mut messages = Vec::new();
+    while !buf.is_empty() {
+        messages.push(M::decode_length_delimited(&mut buf)?);
+    }
+    Ok(messages)
+}
+
+pub fn remove_segment(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        eprintln!("Failed to remove spool segment {:?}: {}", path, e);
+    }
+}
+
+// The quarantine dir of a segment, next to the dirs of the kinds, so
+// that it can be replayed like a spool dir once the cause is fixed
+fn quarantine_path(segment: &Path) -> Option<PathBuf> {
+    let kind_dir = segment.parent()?;
+    Some(
+        kind_dir
+            .parent()?
+            .join(QUARANTINE_DIR)
+            .join(kind_dir.file_name()?)
+            .join(segment.file_name()?),
+    )
+}
+
+// Move a segment that cannot be read out of the way of the sender. It
+// is only removed if it cannot be moved, since the sender would
+// otherwise try it again and again.
+pub fn quarantine_segment(path: &Path) {
+    let result = match quarantine_path(path) {
+        Some(target) => target
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(path, &target)),
+        None => Err(ErrorKind::NotFound.into()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to quarantine spool segment {:?}: {}", path, e);
+        remove_segment(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_sort_by_sequence() {
+        let dir = Path::new("/spool/can");
+        let path = segment_path(dir, 2, 1_700_000_000_000);
+        assert_eq!(parse_segment_name(&path), Some((2, 1_700_000_000_000)));
+        // After a clock step backwards, sequence 3 still sorts after 2
+        assert!(segment_path(dir, 3, 1_600_000_000_000) > path);
+        assert_eq!(
+            quarantine_path(&path).unwrap(),
+            Path::new("/spool/quarantine/can").join(path.file_name().unwrap())
+        );
+    }
+
+    #[test]
+    fn failed_write_is_retried_after_an_interval() {
+        let now = Instant::now();
+        assert!(retry_due(None, now));
+        assert!(!retry_due(Some(now), now + Duration::from_secs(1)));
+        assert!(retry_due(Some(now), now + WRITE_RETRY_INTERVAL));
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Periodic statistics report. Frames are counted per bus and message ID
+// so that the messages responsible for the data volume can be found
+// without a capture.
+//
+// The report also has percentiles of the latency of CAN messages from
+// the reception of the frame, through the send queue, until the server
+// acknowledged them, to quantify the effect of batching settings.
+// Messages read from the spool are not included.
+//
+// When several instances run on one unit, the report names the instance
+// it is from.
+//
+// The status of the configured VPN tunnels is included as well.
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use super::vpn;
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{agent_client::AgentClient, FrameRate, LatencyPercentiles, StatsReport},
+    instance, CONFIG,
+};
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+
+const DEFAULT_TOP_TALKERS: usize = 10;
+const MAX_LATENCY_SAMPLES: usize = 10000;
+
+// Latency stages, from the reception of a frame until the ack
+const STAGE_DECODE: &str = "decode"; // Reception to queued
+const STAGE_QUEUE: &str = "queue"; // Queued to sent
+const STAGE_SEND: &str = "send"; // Sent to acknowledged, including retries
+const STAGE_TOTAL: &str = "total"; // Reception to acknowledged
+
+// Random sample of the latencies in milliseconds of the last interval
+#[derive(Default)]
+struct LatencySamples {
+    count: u64,
+    samples: Vec<f64>,
+}
+
+lazy_static! {
+    static ref FRAME_COUNTERS: Mutex<HashMap<(String, u32), u64>> = Mutex::new(HashMap::new());
+    static ref LATENCIES: Mutex<BTreeMap<&'static str, LatencySamples>> =
+        Mutex::new(BTreeMap::new());
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG.stats.is_some()
+}
+
+// Count a received frame, whether it is in the DBC or not
+pub async fn record_frame(bus: &str, id: u32) {
+    if !is_enabled() {
+        return;
+    }
+    let mut counters = FRAME_COUNTERS.lock().await;
+    match counters.get_mut(&(bus.to_string(), id)) {
+        Some(count) => *count += 1,
+        None => {
+            counters.insert((bus.to_string(), id), 1);
+        }
+    }
+}
+
+// Record the latencies of messages that were acknowledged by the
+// server. The timings are when the frame of each message was received
+// and when the message was queued.
+pub async fn record_latencies(timings: &[(Instant, Instant)], sent: Instant, acked: Instant) {
+    if !is_enabled() {
+        return;
+    }
+    let mut latencies = LATENCIES.lock().await;
+    let mut rng = rand::thread_rng();
+    for (received, queued) in timings {
+        for (stage, latency) in [
+            (STAGE_DECODE, queued.duration_since(*received)),
+            (STAGE_QUEUE, sent.duration_since(*queued)),
+            (STAGE_SEND, acked.duration_since(sent)),
+            (STAGE_TOTAL, acked.duration_since(*received)),
+        ] {
+            let l = latencies.entry(stage).or_default();
+            let ms = latency.as_secs_f64() * 1000.0;
+            // Reservoir sampling keeps the memory use bounded
+            l.count += 1;
+            if l.samples.len() < MAX_LATENCY_SAMPLES {
+                l.samples.push(ms);
+            } else {
+                let i = rng.gen_range(0..l.count) as usize;
+                if i < MAX_LATENCY_SAMPLES {
+                    l.samples[i] = ms;
+                }
+            }
+        }
+    }
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+async fn take_latencies() -> Vec<LatencyPercentiles> {
+    let latencies = std::mem::take(&mut *LATENCIES.lock().await);
+    latencies
+        .into_iter()
+        .map(|(stage, mut l)| {
+            l.samples
+                .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            LatencyPercentiles {
+                stage: stage.to_string(),
+                messages: l.count,
+                p50_ms: percentile(&l.samples, 50.0),
+                p90_ms: percentile(&l.samples, 90.0),
+                p99_ms: percentile(&l.samples, 99.0),
+                max_ms: *l.samples.last().unwrap(),
+            }
+        })
+        .collect()
+}
+
+// Take the counters of the last interval and return the message IDs
+// with the most frames
+async fn take_top_talkers(interval_s: u64, limit: usize) -> Vec<FrameRate> {
+    let counters: Vec<_> = FRAME_COUNTERS.lock().await.drain().collect();
+    let mut talkers: Vec<FrameRate> = counters
+        .into_iter()
+        .map(|((bus, id), frames)| FrameRate {
+            bus,
+            id,
+            frames,
+            rate: frames as f64 / interval_s as f64,
+        })
+        .collect();
+    talkers.sort_by(|a, b| b.frames.cmp(&a.frames).then(a.id.cmp(&b.id)));
+    talkers.truncate(limit);
+    talkers
+}
+
+pub async fn stats_reporter(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.stats.as_ref().unwrap();
+    let interval_s = config.interval_s.max(1);
+    let limit = config.top_talkers.unwrap_or(DEFAULT_TOP_TALKERS);
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+
+    loop {
+        sleep(Duration::from_secs(interval_s)).await;
+        let report = StatsReport {
+            interval_s,
+            top_talkers: take_top_talkers(interval_s, limit).await,
+            latency: take_latencies().await,
+            instance: instance().unwrap_or_default().to_string(),
+            vpn: vpn::peers(),
+        };
+
+        let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+        loop {
+            tap::record("SendStats", &report).await;
+            let response = client.send_stats(report.clone()).await;
+            if handle_send_result(response, &mut retry_sleep_s)
+                .await
+                .is_ok()
+            {
+                break;
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 50.0), 50.0);
+        assert_eq!(percentile(&sorted, 99.0), 99.0);
+        assert_eq!(percentile(&sorted, 100.0), 100.0);
+        assert_eq!(percentile(&[7.0], 0.0), 7.0);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0], 90.0), 3.0);
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Storage manager for all directories owned by the client (spool, logs,
+// captures). Each directory has a quota and a priority. Files are
+// evicted oldest first when a directory exceeds its quota, and from the
+// lowest priority directories first when the filesystem is nearly full.
+// Eviction for a full filesystem only frees what is needed to get below
+// the limit, and keeps the reserve of each directory. If the client's
+// own files cannot free enough, e.g. since something else filled the
+// filesystem, nothing is evicted.
+
+use super::net::{clear_status, set_status};
+use lib::{StatusCodes, StorageDir, CONFIG};
+use nix::sys::statvfs::statvfs;
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+
+const DEFAULT_CHECK_INTERVAL_S: u64 = 60;
+const DEFAULT_NEAR_FULL_PERCENT: u64 = 90;
+const MB: u64 = 1024 * 1024;
+
+struct StoredFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+// The outcome of a check of the directories
+#[derive(Default)]
+struct Check {
+    near_full: bool,
+    evicted_files: usize,
+    evicted_bytes: u64,
+}
+
+pub async fn storage_manager() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.storage.as_ref().unwrap();
+    let interval = config.check_interval_s.unwrap_or(DEFAULT_CHECK_INTERVAL_S);
+    let near_full_percent = config
+        .near_full_percent
+        .unwrap_or(DEFAULT_NEAR_FULL_PERCENT);
+
+    // Evict from the least important directories first
+    let mut dirs = config.dirs.clone();
+    dirs.sort_by_key(|d| d.priority);
+    let dirs = Arc::new(dirs);
+
+    loop {
+        let check = {
+            let dirs = dirs.clone();
+            tokio::task::spawn_blocking(move || check(&dirs, near_full_percent)).await?
+        };
+
+        if check.near_full {
+            set_status(StatusCodes::StorageNearFull).await;
+        } else if check.evicted_files > 0 {
+            clear_status(StatusCodes::StorageNearFull).await;
+            set_status(StatusCodes::StorageEvicted).await;
+        } else {
+            clear_status(StatusCodes::StorageNearFull).await;
+            clear_status(StatusCodes::StorageEvicted).await;
+        }
+        if check.evicted_files > 0 {
+            eprintln!(
+                "Storage: evicted {} files ({} bytes) to free filesystem space",
+                check.evicted_files, check.evicted_bytes
+            );
+        }
+
+        sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+fn check(dirs: &[StorageDir], near_full_percent: u64) -> Check {
+    for dir in dirs {
+        enforce_quota(dir);
+    }
+
+    let mut check = Check::default();
+    for dir in dirs {
+        let needed = bytes_over_limit(&dir.path, near_full_percent);
+        if needed == 0 {
+            continue;
+        }
+        for f in free_filesystem_space(dirs, &dir.path, needed) {
+            check.evicted_files += 1;
+            check.evicted_bytes += f.size;
+        }
+        check.near_full |= bytes_over_limit(&dir.path, near_full_percent) > 0;
+    }
+    check
+}
+
+// Remove the oldest files in the directory until it is within its quota
+fn enforce_quota(dir: &StorageDir) {
+    let quota = dir.quota_mb * MB;
+    let mut files = list_files(Path::new(&dir.path));
+    let mut usage: u64 = files.iter().map(|f| f.size).sum();
+
+    files.sort_by_key(|f| f.modified);
+    for f in files {
+        if usage <= quota {
+            break;
+        }
+        if remove_file(&f) {
+            usage -= f.size;
+        }
+    }
+}
+
+// Evict files from the directories on the same filesystem as path,
+// lowest priority and oldest first, to free the needed bytes. Returns
+// the evicted files, which are none if the directories do not hold
+// enough above their reserves.
+fn free_filesystem_space(dirs: &[StorageDir], path: &str, needed: u64) -> Vec<StoredFile> {
+    let device = match fs::metadata(path) {
+        Ok(m) => m.dev(),
+        Err(_) => return Vec::new(),
+    };
+    let same_device = |dir: &&StorageDir| {
+        fs::metadata(&dir.path)
+            .map(|m| m.dev() == device)
+            .unwrap_or(false)
+    };
+
+    let candidates = dirs.iter().filter(same_device).flat_map(evictable_files);
+    plan_eviction(candidates, needed)
+        .into_iter()
+        .filter(remove_file)
+        .collect()
+}
+
+// The files of a directory that may be evicted for a full filesystem,
+// oldest first, leaving at least its reserve
+fn evictable_files(dir: &StorageDir) -> Vec<StoredFile> {
+    let reserve = dir.reserve_mb.unwrap_or(0) * MB;
+    let mut files = list_files(Path::new(&dir.path));
+    let mut usage: u64 = files.iter().map(|f| f.size).sum();
+
+    files.sort_by_key(|f| f.modified);
+    files
+        .into_iter()
+        .take_while(|f| {
+            let keep = usage - f.size >= reserve;
+            usage -= f.size;
+            keep
+        })
+        .collect()
+}
+
+// The first candidates that free the needed bytes, or none if all of
+// them would not be enough
+fn plan_eviction(candidates: impl Iterator<Item = StoredFile>, needed: u64) -> Vec<StoredFile> {
+    let mut freed = 0;
+    let mut plan = Vec::new();
+    for f in candidates {
+        if freed >= needed {
+            break;
+        }
+        freed += f.size;
+        plan.push(f);
+    }
+    match freed >= needed {
+        true => plan,
+        false => Vec::new(),
+    }
+}
+
+// How many bytes the usage of the filesystem is above the limit. Block
+// counts are 32 bit on some of the supported targets.
+#[allow(clippy::unnecessary_cast)]
+fn bytes_over_limit(path: &str, limit_percent: u64) -> u64 {
+    match statvfs(path) {
+        Ok(s) => {
+            let block_size = s.fragment_size() as u64;
+            let total = s.blocks() as u64 * block_size;
+            let used = total - s.blocks_available() as u64 * block_size;
+            used.saturating_sub(total / 100 * limit_percent)
+        }
+        Err(_) => 0,
+    }
+}
+
+fn list_files(dir: &Path) -> Vec<StoredFile> {
+    let mut files = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+
+    for entry in entries.flatten() {
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            files.extend(list_files(&entry.path()));
+        } else {
+            files.push(StoredFile {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+    }
+    files
+}
+
+fn remove_file(f: &StoredFile) -> bool {
+    match fs::remove_file(&f.path) {
+        Ok(_) => {
+            eprintln!("Storage: evicted {:?} ({} bytes)", f.path, f.size);
+            true
+        }
+        Err(e) => {
+            eprintln!("Storage: failed to evict {:?}: {}", f.path, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::time::TimeVal;
+
+    fn test_dir(name: &str, files: &[(&str, u64)]) -> StorageDir {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Older files first
+        for (age, (file, size)) in files.iter().enumerate() {
+            let path = dir.join(file);
+            fs::write(&path, vec![0; *size as usize]).unwrap();
+            let mtime = TimeVal::new(1_700_000_000 + age as i64, 0);
+            nix::sys::stat::utimes(&path, &mtime, &mtime).unwrap();
+        }
+        StorageDir {
+            path: dir.to_str().unwrap().to_string(),
+            quota_mb: 1,
+            priority: 1,
+            reserve_mb: None,
+        }
+    }
+
+    fn remaining(dir: &StorageDir) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(&dir.path)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_str().unwrap().to_string())
+            .collect();
+        files.sort();
+        fs::remove_dir_all(&dir.path).unwrap();
+        files
+    }
+
+    #[test]
+    fn quota_evicts_oldest_first() {
+        let dir = test_dir("quota", &[("a", MB / 2), ("b", MB / 2), ("c", MB / 2)]);
+        enforce_quota(&dir);
+        assert_eq!(remaining(&dir), ["b", "c"]);
+    }
+
+    #[test]
+    fn full_filesystem_evicts_lowest_priority_first() {
+        let low = test_dir("low", &[("a", 100), ("b", 100)]);
+        let high = StorageDir {
+            priority: 2,
+            ..test_dir("high", &[("c", 100)])
+        };
+        let evicted = free_filesystem_space(&[low.clone(), high.clone()], &high.path, 150);
+        assert_eq!(evicted.len(), 2);
+        assert!(remaining(&low).is_empty());
+        assert_eq!(remaining(&high), ["c"]);
+    }
+
+    #[test]
+    fn full_filesystem_keeps_the_reserve() {
+        let low = StorageDir {
+            reserve_mb: Some(1),
+            ..test_dir("reserve", &[("a", MB / 2), ("b", MB / 2), ("c", MB / 2)])
+        };
+        let evicted = free_filesystem_space(std::slice::from_ref(&low), &low.path, MB / 4);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(remaining(&low), ["b", "c"]);
+    }
+
+    #[test]
+    fn nothing_is_evicted_if_it_would_not_help() {
+        let low = test_dir("nothing", &[("a", 100), ("b", 100)]);
+        let evicted = free_filesystem_space(std::slice::from_ref(&low), &low.path, 1000);
+        assert!(evicted.is_empty());
+        assert_eq!(remaining(&low), ["a", "b"]);
+    }
+}
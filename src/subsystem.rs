@@ -0,0 +1,81 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+use super::gpio::{read_all_digital_in, send_value};
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{Subsystem, SubsystemControl},
+    CONFIG,
+};
+use std::collections::HashSet;
+use tokio::sync::Mutex;
+
+lazy_static! {
+    static ref PAUSED_SUBSYSTEMS: Mutex<HashSet<Subsystem>> = Mutex::new(HashSet::new());
+}
+
+pub async fn is_enabled(subsystem: Subsystem) -> bool {
+    !PAUSED_SUBSYSTEMS.lock().await.contains(&subsystem)
+}
+
+// Resume a subsystem without reporting the current state, which needs
+// the config, so that a test leaves the subsystems as it found them
+#[cfg(test)]
+pub(crate) async fn resume(subsystem: Subsystem) {
+    PAUSED_SUBSYSTEMS.lock().await.remove(&subsystem);
+}
+
+// Pause or resume reporting from a subsystem, as requested by the server.
+// The monitors keep running while paused, but nothing is reported.
+pub async fn control_subsystem(msg: SubsystemControl) {
+    let subsystem = match Subsystem::from_i32(msg.subsystem) {
+        Some(Subsystem::Gnss) if CONFIG.gnss.is_none() => {
+            eprintln!("GNSS is not configured on this unit.");
+            return;
+        }
+        Some(s) => s,
+        None => {
+            eprintln!("Unknown subsystem: {}", msg.subsystem);
+            return;
+        }
+    };
+
+    let mut paused = PAUSED_SUBSYSTEMS.lock().await;
+    if msg.enabled {
+        if !paused.remove(&subsystem) {
+            return;
+        }
+        drop(paused);
+        println!("Resuming {:?}", subsystem);
+
+        // Edges that happened while paused were never reported, so
+        // report the current state of all inputs.
+        if subsystem == Subsystem::DigitalIn {
+            match read_all_digital_in().await {
+                Ok(values) => {
+                    for (key, val) in values {
+                        send_value(&key, val, None).await;
+                    }
+                }
+                Err(e) => eprintln!("Failed to read the digital ins: {e}"),
+            }
+        }
+    } else if paused.insert(subsystem) {
+        println!("Pausing {:?}", subsystem);
+    }
+}
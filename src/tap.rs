@@ -0,0 +1,223 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Debug tap that mirrors the messages sent to the server as JSON, one
+// message per line, to a local file or UDP port. This shows exactly what
+// is sent, without access to the backend or stripping TLS.
+
+use lazy_static::lazy_static;
+use lib::history::unix_millis;
+use lib::host_insight::DebugTapRequest;
+use lib::{instance, run_dir, CONFIG};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Mutex;
+
+// A remote request without a duration enables the tap this long
+const DEFAULT_REMOTE_DURATION_S: u64 = 600;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    File(String),
+    Udp(String),
+}
+
+enum Sink {
+    File(File),
+    Udp(UdpSocket),
+}
+
+struct Tap {
+    sink: Sink,
+    until: Option<Instant>,
+}
+
+// Checked first, so that the tap costs nothing when disabled
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TAP: Mutex<Option<Tap>> = Mutex::new(None);
+}
+
+// A target is either udp://host:port or the path of a file
+pub fn parse_target(target: &str) -> Result<Target, String> {
+    match target.strip_prefix("udp://") {
+        Some(addr)
+            if addr
+                .rsplit_once(':')
+                .is_some_and(|(h, p)| !h.is_empty() && p.parse::<u16>().is_ok()) =>
+        {
+            Ok(Target::Udp(addr.to_string()))
+        }
+        Some(_) => Err("expected udp://host:port".to_string()),
+        None if target.is_empty() => Err("empty target".to_string()),
+        None => Ok(Target::File(target.to_string())),
+    }
+}
+
+// The server may only write the tap to a file in the tap directory of
+// the run directory, and send it to the UDP sinks in the config.
+// Instances share the run directory but have their own tap directory.
+fn remote_target(target: Target) -> Result<Target, String> {
+    match target {
+        Target::File(name) => {
+            if name.is_empty() || name.starts_with('.') || name.contains('/') {
+                return Err("expected a file name without a directory".to_string());
+            }
+            let dir = match instance() {
+                Some(instance) => Path::new(run_dir()).join("tap").join(instance),
+                None => Path::new(run_dir()).join("tap"),
+            };
+            std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+            Ok(Target::File(dir.join(name).to_string_lossy().into_owned()))
+        }
+        Target::Udp(addr) => {
+            let allowed = CONFIG
+                .debug_tap
+                .as_ref()
+                .is_some_and(|t| t.udp_sinks.contains(&addr));
+            if allowed {
+                Ok(Target::Udp(addr))
+            } else {
+                Err(format!("{addr} is not in debug_tap.udp_sinks"))
+            }
+        }
+    }
+}
+
+fn open(target: &Target) -> io::Result<Sink> {
+    match target {
+        Target::File(path) => Ok(Sink::File(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        Target::Udp(addr) => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(addr)?;
+            Ok(Sink::Udp(socket))
+        }
+    }
+}
+
+pub async fn enable(target: &Target, duration: Option<Duration>) -> io::Result<()> {
+    let sink = open(target)?;
+    *TAP.lock().await = Some(Tap {
+        sink,
+        until: duration.map(|d| Instant::now() + d),
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    println!("Debug tap to {:?} enabled", target);
+    Ok(())
+}
+
+pub async fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    if TAP.lock().await.take().is_some() {
+        println!("Debug tap disabled");
+    }
+}
+
+// Enable the tap on request of the server. An empty target disables it.
+pub async fn request_tap(request: DebugTapRequest) {
+    if request.target.is_empty() {
+        disable().await;
+        return;
+    }
+    let duration_s = match request.duration_s {
+        0 => DEFAULT_REMOTE_DURATION_S,
+        d => d as u64,
+    };
+    let result = match parse_target(&request.target).and_then(remote_target) {
+        Ok(target) => enable(&target, Some(Duration::from_secs(duration_s)))
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to enable debug tap to {}: {}", request.target, e);
+    }
+}
+
+// Mirror a message sent with the given RPC
+pub async fn record<M: Serialize>(rpc: &str, message: &M) {
+    record_all(rpc, std::slice::from_ref(message)).await;
+}
+
+// Mirror the messages of a stream
+pub async fn record_all<M: Serialize>(rpc: &str, messages: &[M]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut tap = TAP.lock().await;
+    let t = match tap.as_mut() {
+        Some(t) => t,
+        None => return,
+    };
+    if t.until.is_some_and(|until| Instant::now() >= until) {
+        drop(tap);
+        disable().await;
+        return;
+    }
+
+    let time_stamp = unix_millis(SystemTime::now());
+    for message in messages {
+        let line = json!({ "time_stamp": time_stamp, "rpc": rpc, "message": message });
+        let result = match &mut t.sink {
+            Sink::File(f) => writeln!(f, "{}", line),
+            Sink::Udp(socket) => socket.send(line.to_string().as_bytes()).map(|_| ()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write to debug tap: {}", e);
+            drop(tap);
+            disable().await;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("udp://127.0.0.1:5140"),
+            Ok(Target::Udp("127.0.0.1:5140".to_string()))
+        );
+        assert_eq!(
+            parse_target("/tmp/tap.json"),
+            Ok(Target::File("/tmp/tap.json".to_string()))
+        );
+        assert!(parse_target("udp://127.0.0.1").is_err());
+        assert!(parse_target("udp://:5140").is_err());
+        assert!(parse_target("").is_err());
+    }
+
+    #[test]
+    fn remote_files_stay_in_the_tap_dir() {
+        for name in ["/etc/passwd", "../conf.toml", "tap/../../x", ".hidden"] {
+            assert!(remote_target(Target::File(name.to_string())).is_err());
+        }
+    }
+}
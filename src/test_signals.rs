@@ -0,0 +1,117 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Built-in generator of synthetic signals. The values are queued like
+// decoded CAN signals, so that the server ingestion can be load tested
+// from real units without a vehicle.
+
+use super::can::queue_can_message;
+use futures::future::join_all;
+use lib::{
+    cache, history,
+    host_insight::{can_signal, CanMessage, CanSignal},
+    TestSignal, Waveform, CONFIG, DEFAULT_TEST_SIGNALS_BUS,
+};
+use std::error::Error;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::{interval, MissedTickBehavior};
+
+const DEFAULT_PERIOD_S: f64 = 60.0;
+const DEFAULT_MIN: f64 = 0.0;
+const DEFAULT_MAX: f64 = 100.0;
+
+// The value of a signal the given time after the generator started
+pub fn value(signal: &TestSignal, elapsed_s: f64) -> f64 {
+    let period = signal.period_s.unwrap_or(DEFAULT_PERIOD_S);
+    let min = signal.min.unwrap_or(DEFAULT_MIN);
+    let max = signal.max.unwrap_or(DEFAULT_MAX);
+    let phase = (elapsed_s % period) / period;
+    match signal.waveform {
+        Waveform::Ramp => min + (max - min) * phase,
+        Waveform::Sine => (min + max) / 2.0 + (max - min) / 2.0 * (2.0 * PI * phase).sin(),
+        Waveform::Step if phase < 0.5 => min,
+        Waveform::Step => max,
+    }
+}
+
+pub async fn test_signal_generator() -> Result<(), Box<dyn Error>> {
+    let config = CONFIG.test_signals.as_ref().unwrap();
+    let bus = config.bus.as_deref().unwrap_or(DEFAULT_TEST_SIGNALS_BUS);
+    eprintln!("Generating {} test signals on {bus}", config.signals.len());
+    join_all(config.signals.iter().map(|s| generate(bus, s))).await;
+    Ok(())
+}
+
+async fn generate(bus: &str, signal: &TestSignal) {
+    let start = Instant::now();
+    let mut ticks = interval(Duration::from_millis(signal.interval_ms));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let value = can_signal::Value::ValF64(value(signal, start.elapsed().as_secs_f64()));
+        cache::update(bus, &signal.name, "N/A", value.clone()).await;
+        let message = CanMessage {
+            bus: bus.to_string(),
+            time_stamp: Some(history::unix_millis(SystemTime::now())),
+            signal: vec![CanSignal {
+                signal_name: signal.name.clone(),
+                unit: "N/A".to_string(),
+                value: Some(value),
+                raw: None,
+                refresh: false,
+            }],
+            composite: String::new(),
+        };
+        queue_can_message(message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(waveform: Waveform) -> TestSignal {
+        TestSignal {
+            name: "Test".to_string(),
+            waveform,
+            interval_ms: 100,
+            period_s: Some(10.0),
+            min: Some(-10.0),
+            max: Some(10.0),
+        }
+    }
+
+    #[test]
+    fn waveforms() {
+        let ramp = signal(Waveform::Ramp);
+        assert_eq!(value(&ramp, 0.0), -10.0);
+        assert_eq!(value(&ramp, 5.0), 0.0);
+        assert_eq!(value(&ramp, 12.5), -5.0);
+
+        let sine = signal(Waveform::Sine);
+        assert!(value(&sine, 0.0).abs() < 1e-9);
+        assert!((value(&sine, 2.5) - 10.0).abs() < 1e-9);
+        assert!((value(&sine, 7.5) + 10.0).abs() < 1e-9);
+
+        let step = signal(Waveform::Step);
+        assert_eq!(value(&step, 4.9), -10.0);
+        assert_eq!(value(&step, 5.0), 10.0);
+        assert_eq!(value(&step, 10.0), -10.0);
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Chunked and resumable upload of large files, e.g. DDD files, log
+// bundles and captures. Every chunk carries a CRC32 of its data. After
+// a disconnect the server is asked how much it has received so that the
+// upload continues from there instead of starting over. The file is read
+// on a blocking thread, chunk by chunk.
+
+use super::net::{handle_send_result, intercept};
+use super::tap;
+use futures::stream;
+use lazy_static::lazy_static;
+use lib::{
+    host_insight::{agent_client::AgentClient, FileChunk, FileInfo, UploadRequest},
+    CONFIG,
+};
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use tonic::Request;
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_STALLED_ATTEMPTS: u32 = 3;
+
+lazy_static! {
+    // Requests with the canonical path of the file
+    static ref UPLOAD_REQUESTS: Mutex<Vec<(UploadRequest, PathBuf)>> = Mutex::new(Vec::new());
+}
+
+// Queue a file for upload. Only files in one of the configured upload
+// directories are accepted, and the file is read from the path that was
+// checked, so that a symlink swapped in later is not followed.
+pub async fn request_upload(request: UploadRequest) {
+    match allowed_path(&request.path).await {
+        Some(path) => UPLOAD_REQUESTS.lock().await.push((request, path)),
+        None => eprintln!("Upload of {} is not allowed.", request.path),
+    }
+}
+
+async fn allowed_path(path: &str) -> Option<PathBuf> {
+    let path = tokio::fs::canonicalize(path).await.ok()?;
+    let dirs = &CONFIG.transfer.as_ref()?.upload_dirs;
+    dirs.iter().any(|dir| path.starts_with(dir)).then_some(path)
+}
+
+pub async fn upload_monitor(channel: Channel) -> Result<(), Box<dyn Error>> {
+    loop {
+        let requests: Vec<_> = UPLOAD_REQUESTS.lock().await.drain(..).collect();
+        for (request, path) in requests {
+            if let Err(e) =
+                upload_file(channel.clone(), &request.path, path, request.compress).await
+            {
+                eprintln!("Failed to upload {}: {}", request.path, e);
+            }
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Upload the file at path under the given name
+pub async fn upload_file(
+    channel: Channel,
+    name: &str,
+    path: PathBuf,
+    compress: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let size = tokio::fs::metadata(&path).await?.len();
+    let chunk_size = CONFIG
+        .transfer
+        .as_ref()
+        .and_then(|t| t.chunk_size)
+        .unwrap_or(DEFAULT_CHUNK_SIZE);
+    let info = FileInfo {
+        file_name: name.to_string(),
+        size,
+    };
+
+    let mut retry_sleep_s: u64 = CONFIG.time.sleep_min_s;
+    let mut last_offset = None;
+    let mut stalled_attempts = 0;
+    loop {
+        // Ask the server where to continue
+        tap::record("GetUploadOffset", &info).await;
+        let offset = match client.get_upload_offset(info.clone()).await {
+            Ok(r) => r.into_inner().offset,
+            Err(e) => {
+                let _ = handle_send_result(Err(e), &mut retry_sleep_s).await;
+                continue;
+            }
+        };
+        if offset >= size {
+            println!("Uploaded {name}");
+            return Ok(());
+        }
+        if last_offset == Some(offset) {
+            stalled_attempts += 1;
+            if stalled_attempts >= MAX_STALLED_ATTEMPTS {
+                return Err(format!("No progress after {stalled_attempts} attempts").into());
+            }
+        } else {
+            stalled_attempts = 0;
+        }
+        last_offset = Some(offset);
+
+        let reader = ChunkReader {
+            file: open_at(path.clone(), offset).await?,
+            file_name: name.to_string(),
+            offset,
+            size,
+            chunk_size,
+            compress,
+        };
+        let chunks = stream::unfold(reader, |mut reader| async move {
+            let (chunk, reader) = spawn_blocking(move || (reader.next(), reader)).await.ok()?;
+            chunk.map(|chunk| (chunk, reader))
+        });
+
+        let response = client.upload_file(Request::new(chunks)).await;
+        let _ = handle_send_result(response, &mut retry_sleep_s).await;
+    }
+}
+
+async fn open_at(path: PathBuf, offset: u64) -> std::io::Result<fs::File> {
+    spawn_blocking(move || {
+        let mut f = fs::File::open(path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        Ok(f)
+    })
+    .await?
+}
+
+struct ChunkReader {
+    file: fs::File,
+    file_name: String,
+    offset: u64,
+    size: u64,
+    chunk_size: usize,
+    compress: bool,
+}
+
+impl Iterator for ChunkReader {
+    type Item = FileChunk;
+
+    fn next(&mut self) -> Option<FileChunk> {
+        let mut buf = vec![0; self.chunk_size];
+        let n = match self.file.read(&mut buf) {
+            Ok(0) => return None,
+            Ok(n) => n,
+            Err(e) => {
+                // The upload is resumed from the server's offset later
+                eprintln!("Failed to read {}: {}", self.file_name, e);
+                return None;
+            }
+        };
+        buf.truncate(n);
+
+        let (data, compressed) = compress_chunk(buf, self.compress);
+        let chunk = FileChunk {
+            file_name: self.file_name.clone(),
+            offset: self.offset,
+            crc32: crc32fast::hash(&data),
+            data,
+            compressed,
+            size: self.size,
+        };
+        self.offset += n as u64;
+        Some(chunk)
+    }
+}
+
+// Chunks are compressed independently so that an upload can be resumed
+// at any chunk boundary. The offset always refers to the uncompressed file.
+#[cfg(feature = "zstd")]
+fn compress_chunk(buf: Vec<u8>, compress: bool) -> (Vec<u8>, bool) {
+    if !compress {
+        return (buf, false);
+    }
+    match zstd::bulk::compress(&buf, 3) {
+        Ok(data) => (data, true),
+        Err(_) => (buf, false),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_chunk(buf: Vec<u8>, _compress: bool) -> (Vec<u8>, bool) {
+    (buf, false)
+}
+
+const PARTIAL_SUFFIX: &str = ".part";
+// Holds the URL of a partial download, so that it is only resumed for
+// the same URL
+const PARTIAL_URL_SUFFIX: &str = ".part.url";
+// Partial downloads that have not been resumed for this long are
+// removed at startup
+const PARTIAL_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(suffix);
+    PathBuf::from(p)
+}
+
+// Download a file with curl into a partial file that is resumed if the
+// download is interrupted, and only moved into place once complete. A
+// partial file of another URL is started over.
+pub fn download_file(url: &str, dst: &Path) -> Result<(), std::io::Error> {
+    let partial = with_suffix(dst, PARTIAL_SUFFIX);
+    let url_file = with_suffix(dst, PARTIAL_URL_SUFFIX);
+    if fs::read_to_string(&url_file).ok().as_deref() != Some(url) {
+        let _ = fs::remove_file(&partial);
+        fs::write(&url_file, url)?;
+    }
+
+    let status = std::process::Command::new("curl")
+        .arg("--fail")
+        .arg("--location")
+        .arg("--retry")
+        .arg("5")
+        .arg("--continue-at")
+        .arg("-")
+        .arg("-o")
+        .arg(&partial)
+        .arg(url)
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "curl failed to download {url}: {status}"
+        )));
+    }
+    fs::rename(&partial, dst)?;
+    let _ = fs::remove_file(&url_file);
+    Ok(())
+}
+
+// Whether a partial file left at startup cannot be resumed: it has no
+// URL, e.g. it is not a download, or it has not been resumed for
+// PARTIAL_MAX_AGE
+fn is_stale(partial: &Path, now: SystemTime) -> bool {
+    let url_file = with_suffix(partial, ".url");
+    if !url_file.is_file() {
+        return true;
+    }
+    fs::metadata(partial)
+        .and_then(|m| m.modified())
+        .map_or(true, |t| {
+            now.duration_since(t).unwrap_or_default() > PARTIAL_MAX_AGE
+        })
+}
+
+// Remove the partial files of interrupted downloads that are stale, and
+// the URLs of downloads without a partial file. Called at startup, after
+// an interrupted certificate renewal has been completed. Other partial
+// downloads are resumed when they are requested again.
+pub fn remove_partial_downloads(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let now = SystemTime::now();
+    for path in entries.flatten().map(|e| e.path()) {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(dst) = name.strip_suffix(PARTIAL_URL_SUFFIX) {
+            if !path
+                .with_file_name(format!("{dst}{PARTIAL_SUFFIX}"))
+                .exists()
+            {
+                let _ = fs::remove_file(&path);
+            }
+        } else if name.ends_with(PARTIAL_SUFFIX) && is_stale(&path, now) {
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Removed partial download {}", path.display()),
+                Err(e) => eprintln!("Failed to remove {}: {e}", path.display()),
+            }
+            let _ = fs::remove_file(with_suffix(&path, ".url"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::time::TimeVal;
+
+    fn touch(path: &Path, mtime: i64) {
+        fs::write(path, b"data").unwrap();
+        let mtime = TimeVal::new(mtime, 0);
+        nix::sys::stat::utimes(path, &mtime, &mtime).unwrap();
+    }
+
+    // Recent downloads are kept for --continue-at, stale ones and
+    // partial files that are not downloads are removed
+    #[test]
+    fn only_stale_partial_downloads_are_removed() {
+        let dir = std::env::temp_dir().join(format!("partial-downloads-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let old = now - PARTIAL_MAX_AGE.as_secs() as i64 - 60;
+
+        touch(&dir.join("recent.part"), now);
+        fs::write(dir.join("recent.part.url"), "https://example.com/recent").unwrap();
+        touch(&dir.join("old.part"), old);
+        fs::write(dir.join("old.part.url"), "https://example.com/old").unwrap();
+        touch(&dir.join("cert.pem.part"), now);
+        fs::write(dir.join("done.part.url"), "https://example.com/done").unwrap();
+
+        remove_partial_downloads(&dir);
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["recent.part", "recent.part.url"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
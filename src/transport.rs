@@ -0,0 +1,116 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Some APNs and proxies break long-lived HTTP/2 streams. When streaming
+// RPCs fail repeatedly, the senders fall back to unary RPCs and remote
+// control falls back to polling. Streams are tried again now and then,
+// and are used again as soon as they work.
+
+use super::health::since_contact;
+use super::log_level::debug;
+use lazy_static::lazy_static;
+use lib::CONFIG;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tonic::{Code, Status};
+
+const MAX_STREAM_FAILURES: u32 = 3;
+const STREAM_PROBE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+struct StreamState {
+    failures: u32,
+    // Set while falling back, to the time streams were last tried
+    fallback_since: Option<Instant>,
+}
+
+lazy_static! {
+    static ref STREAM_STATE: Mutex<StreamState> = Mutex::new(StreamState {
+        failures: 0,
+        fallback_since: None,
+    });
+}
+
+// Whether to use a streaming RPC. While falling back, this is true once
+// every probe interval to find out if streams work again.
+pub async fn use_streams() -> bool {
+    let mut state = STREAM_STATE.lock().await;
+    match state.fallback_since {
+        Some(since) if since.elapsed() >= STREAM_PROBE_INTERVAL => {
+            state.fallback_since = Some(Instant::now());
+            true
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+pub async fn stream_succeeded() {
+    let mut state = STREAM_STATE.lock().await;
+    if state.fallback_since.is_some() {
+        println!("Streaming RPCs work again");
+    }
+    state.failures = 0;
+    state.fallback_since = None;
+}
+
+// Whether a failed streaming RPC points at broken streams rather than
+// at an outage of the network or the server, which unary RPCs would
+// not get through either. A reset stream only counts while the server
+// is otherwise reachable.
+fn is_stream_failure(code: Code, reachable: bool) -> bool {
+    match code {
+        Code::Unimplemented => true,
+        Code::Internal | Code::Unknown | Code::Cancelled => reachable,
+        _ => false,
+    }
+}
+
+pub async fn stream_failed(status: &Status) {
+    let reachable = since_contact().await < Duration::from_secs(2 * CONFIG.time.heartbeat_s);
+    if !is_stream_failure(status.code(), reachable) {
+        return;
+    }
+    let mut state = STREAM_STATE.lock().await;
+    state.failures += 1;
+    debug!("Streaming RPC failed, {} in a row", state.failures);
+    if state.failures >= MAX_STREAM_FAILURES && state.fallback_since.is_none() {
+        eprintln!(
+            "Streaming RPCs failed {} times, falling back to unary RPCs",
+            state.failures
+        );
+        state.fallback_since = Some(Instant::now());
+    }
+}
+
+pub async fn is_fallback() -> bool {
+    STREAM_STATE.lock().await.fallback_since.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outages_are_not_stream_failures() {
+        assert!(is_stream_failure(Code::Unimplemented, false));
+        assert!(is_stream_failure(Code::Internal, true));
+        assert!(!is_stream_failure(Code::Internal, false));
+        assert!(!is_stream_failure(Code::Unavailable, true));
+        assert!(!is_stream_failure(Code::DeadlineExceeded, true));
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Support tunnel opened on request of the server. The client connects to
+// the configured jump host with SSH and forwards a port there back to
+// the local SSH server, so that support can reach a unit behind carrier
+// NAT without a permanent VPN. The tunnel is closed after a while, and
+// is open while the heartbeat reports StatusCodes::TunnelOpen. It is
+// also closed when the client exits, so that it never outlives the
+// requested duration.
+
+use super::net::{clear_status, set_status};
+use lazy_static::lazy_static;
+use lib::{conf_dir, host_insight::TunnelRequest, StatusCodes, TunnelConfig, CONFIG};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+use tokio::time::{sleep, Instant};
+
+const DEFAULT_SSH_PORT: u16 = 22;
+const DEFAULT_DURATION_S: u64 = 600;
+const DEFAULT_MAX_DURATION_S: u64 = 3600;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Changed on every request, so that only the latest tunnel is watched
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TUNNEL: Mutex<Option<Child>> = Mutex::new(None);
+}
+
+fn ssh_command(tunnel: &TunnelConfig, remote_port: u16) -> Command {
+    let mut command = Command::new("ssh");
+    command
+        .args(["-N", "-T"])
+        .args(["-o", "BatchMode=yes"])
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .args(["-o", "ServerAliveInterval=30"])
+        .args(["-o", "ServerAliveCountMax=3"])
+        .args(["-o", "StrictHostKeyChecking=yes"])
+        .arg("-i")
+        .arg(format!("{}/{}", conf_dir(), tunnel.key_file))
+        .arg("-p")
+        .arg(tunnel.port.unwrap_or(DEFAULT_SSH_PORT).to_string())
+        .arg("-R")
+        .arg(format!(
+            "{}:localhost:{}",
+            remote_port,
+            tunnel.local_port.unwrap_or(DEFAULT_SSH_PORT)
+        ));
+    if let Some(known_hosts) = &tunnel.known_hosts_file {
+        command
+            .arg("-o")
+            .arg(format!("UserKnownHostsFile={}/{}", conf_dir(), known_hosts));
+    }
+    command
+        .arg(format!("{}@{}", tunnel.user, tunnel.host))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+    command
+}
+
+async fn close() {
+    let child = TUNNEL.lock().unwrap().take();
+    if let Some(mut child) = child {
+        let _ = child.kill().await;
+        println!("Support tunnel closed");
+    }
+    clear_status(StatusCodes::TunnelOpen).await;
+}
+
+// Stop ssh before the client exits, which skips the destructors
+pub fn kill() {
+    if let Some(child) = TUNNEL.lock().unwrap().as_mut() {
+        let _ = child.start_kill();
+    }
+}
+
+// Open or close the tunnel on request of the server. Remote port 0
+// closes the tunnel.
+pub async fn request_tunnel(request: TunnelRequest) {
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    close().await;
+    if request.remote_port == 0 {
+        return;
+    }
+    let remote_port = match u16::try_from(request.remote_port) {
+        Ok(port) => port,
+        Err(_) => {
+            eprintln!("Ignoring tunnel request for port {}", request.remote_port);
+            return;
+        }
+    };
+    let tunnel = match &CONFIG.tunnel {
+        Some(tunnel) => tunnel,
+        None => {
+            eprintln!("Ignoring tunnel request, no tunnel configured");
+            return;
+        }
+    };
+
+    let max_duration_s = tunnel.max_duration_s.unwrap_or(DEFAULT_MAX_DURATION_S);
+    let duration_s = match request.duration_s {
+        0 => DEFAULT_DURATION_S,
+        s => s as u64,
+    }
+    .min(max_duration_s);
+
+    let child = match ssh_command(tunnel, remote_port).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to open support tunnel: {e}");
+            return;
+        }
+    };
+    *TUNNEL.lock().unwrap() = Some(child);
+    set_status(StatusCodes::TunnelOpen).await;
+    println!(
+        "Support tunnel to {} port {} open for {} s",
+        tunnel.host, remote_port, duration_s
+    );
+
+    tokio::spawn(watch(generation, Duration::from_secs(duration_s)));
+}
+
+// Close the tunnel when it expires, and notice if ssh exits by itself
+async fn watch(generation: u64, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        sleep(CHECK_INTERVAL).await;
+        if GENERATION.load(Ordering::Relaxed) != generation {
+            return;
+        }
+        let exited = {
+            let mut tunnel = TUNNEL.lock().unwrap();
+            match tunnel.as_mut().map(|c| c.try_wait()) {
+                Some(Ok(Some(status))) => {
+                    *tunnel = None;
+                    Some(status)
+                }
+                _ => None,
+            }
+        };
+        if let Some(status) = exited {
+            eprintln!("Support tunnel exited: {status}");
+            clear_status(StatusCodes::TunnelOpen).await;
+            return;
+        }
+    }
+    if GENERATION.load(Ordering::Relaxed) == generation {
+        close().await;
+    }
+}
@@ -16,71 +16,119 @@
 // along with this program; if not, write to the Free Software Foundation,
 // Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
 
+use super::analog::set_all_analog_out_to_defaults;
 use super::gpio::set_all_digital_out_to_defaults;
-use anyhow::Error;
-use lib::{CONFIG, CONF_DIR, GIT_COMMIT_DESCRIBE};
+use super::safe_mode::record_clean_exit;
+use super::transfer::download_file;
+use super::tunnel;
+use futures::stream::{self, Stream};
+use lib::{conf_dir, error::ClientError, run_dir, CONFIG, GIT_COMMIT_DESCRIBE};
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-
-static CLIENT_UPGRADE_PATH: &str = "/tmp/host-insight/client_upgrade";
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
 pub fn fetch_resource(url: &str, dst: Option<String>) -> Result<(), std::io::Error> {
-    if dst.is_some() {
-        let mut process = Command::new("curl")
-            .arg("-o")
-            .arg(format!("{}/{}", CONF_DIR, dst.unwrap()))
-            .arg(url)
-            .spawn()
-            .expect("Failed to execute curl.");
-        process.wait()?;
-    } else {
-        let url_components: Vec<&str> = url.split('/').collect();
-        let file_name = url_components[url_components.len() - 1];
-        let mut process = Command::new("curl")
-            .arg("-o")
-            .arg(format!("{}/{}", CONF_DIR, file_name))
-            .arg(url)
-            .spawn()
-            .expect("Failed to execute curl.");
-        process.wait()?;
-    }
+    let file_name = match dst {
+        Some(dst) => dst,
+        None => {
+            let url_components: Vec<&str> = url.split('/').collect();
+            url_components[url_components.len() - 1].to_string()
+        }
+    };
 
-    Ok(())
+    download_file(url, &PathBuf::from(format!("{}/{}", conf_dir(), file_name)))
 }
 
-pub fn update_client(version: &str) -> Result<(), Error> {
-    let current_version_components: Vec<&str> = GIT_COMMIT_DESCRIBE.split('.').collect();
-    let required_version_components: Vec<&str> = version.split('.').collect();
+// The major version of e.g. v0.5.1-3-gabcdef
+fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.replace('v', "").parse().ok()
+}
 
-    let current_major: u32 = current_version_components[0]
-        .replace('v', "")
-        .parse()
-        .unwrap();
-    let required_major: u32 = required_version_components[0]
-        .replace('v', "")
-        .parse()
-        .unwrap();
+pub fn update_client(version: &str) -> Result<(), ClientError> {
+    let unsupported = |reason: &str| ClientError::Unsupported(format!("{reason}: {version}"));
+    let current_major = major_version(GIT_COMMIT_DESCRIBE)
+        .ok_or_else(|| unsupported("Unknown version of this client"))?;
+    let required_major =
+        major_version(version).ok_or_else(|| unsupported("Invalid required version"))?;
 
     if current_major < required_major {
         // Write the requested upgrade to file for use by Host Insight helper
-        if let Some(parent_dir) = Path::new(CLIENT_UPGRADE_PATH).parent() {
-            fs::create_dir_all(parent_dir)?;
-        }
-        fs::write(CLIENT_UPGRADE_PATH, format!("{}", required_major))?;
+        let path = Path::new(run_dir()).join("client_upgrade");
+        let file_error = |source| ClientError::File {
+            path: path.display().to_string(),
+            source,
+        };
+        fs::create_dir_all(run_dir()).map_err(file_error)?;
+        fs::write(&path, format!("{}", required_major)).map_err(file_error)?;
         Ok(())
     } else {
-        Err(Error::msg(
-            "Required major version is not greater than the current major version.",
+        Err(unsupported(
+            "Required major version is not greater than the current major version",
         ))
     }
 }
 
+// Write a file so that it has either its old or its new contents after a
+// crash or power loss. The data is written to a temporary file that is
+// synced and renamed over the file, and the rename is synced too.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+// Sync the directory of a file, so that a new or renamed entry is durable
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::File::open(dir)?.sync_all(),
+        _ => fs::File::open(".")?.sync_all(),
+    }
+}
+
+// Write a file atomically on the blocking pool, so that a slow storage
+// device does not stall the monitors
+pub async fn write_atomic_async(path: PathBuf, data: Vec<u8>) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic(&path, &data)).await?
+}
+
+// Stream a batch that is kept for retries. The items are cloned one at a
+// time as they are sent, so an attempt never duplicates the whole batch.
+pub fn stream_batch<T: Clone>(batch: &Arc<Vec<T>>) -> impl Stream<Item = T> {
+    let batch = batch.clone();
+    stream::iter((0..batch.len()).map(move |i| batch[i].clone()))
+}
+
 pub fn clean_up() {
+    record_clean_exit();
+    tunnel::kill();
     if CONFIG.digital_out.is_some() {
         set_all_digital_out_to_defaults()
             .expect("Failed to set all digital outs to their default values.");
     }
+    set_all_analog_out_to_defaults();
+}
+
+// Exit in an orderly way when stopped, e.g. by systemd for a reboot, so
+// that the stop is not taken for a crash
+pub async fn exit_on_termination() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            eprintln!("Failed to handle SIGTERM: {e}");
+            return;
+        }
+    };
+    terminate.recv().await;
+    println!("Terminated");
+    clean_up();
+    std::process::exit(0);
 }
 
 // TODO: Make this function return Result<String, Error> Right now, it
@@ -94,3 +142,26 @@ pub fn get_md5sum(path: &str) -> Option<String> {
         Err(_) => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, StreamExt};
+
+    #[test]
+    fn parses_major_version() {
+        assert_eq!(major_version("v0.5.1-3-gabcdef"), Some(0));
+        assert_eq!(major_version("2.0"), Some(2));
+        assert_eq!(major_version("latest"), None);
+    }
+
+    #[test]
+    fn stream_batch_keeps_the_batch_for_retries() {
+        let batch = Arc::new(vec![1, 2, 3]);
+        for _ in 0..2 {
+            let sent: Vec<i32> = block_on(stream_batch(&batch).collect());
+            assert_eq!(sent, [1, 2, 3]);
+        }
+        assert_eq!(Arc::strong_count(&batch), 1);
+    }
+}
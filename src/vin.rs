@@ -0,0 +1,205 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Detection of the VIN at startup, so that the server can link the unit
+// to the vehicle it is installed in. The VIN is requested on the CAN
+// ports that may transmit: with OBD-II mode 09 and UDS DID F190 over
+// ISO-TP, or on J1939 ports with a request for the Vehicle
+// Identification PGN, which is answered with a TP.BAM broadcast. The
+// requests are repeated with a backoff until a VIN is found, e.g. once
+// the ignition is turned on, and the state is sent again once it is
+// known.
+
+use super::isotp::{positive, transact, IsoTpSocket, SID_READ_DATA_BY_IDENTIFIER};
+use super::j1939::{self, Packet};
+use super::log_level::debug;
+use super::net::send_state;
+use futures::stream::StreamExt;
+use lazy_static::lazy_static;
+use lib::{CanPort, CONFIG};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout_at};
+use tokio_socketcan::{CANFrame, CANSocket};
+use tonic::transport::Channel;
+
+const VIN_LEN: usize = 17;
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// OBD-II request and response IDs of the first ECU. The response to a
+// functional request (0x7DF) would have to be flow controlled from
+// 0x7E0, which an ISO-TP socket bound to 0x7DF cannot do, so the engine
+// ECU is asked directly.
+const OBD_PHYSICAL_ID: u32 = 0x7e0;
+const OBD_RESPONSE_ID: u32 = 0x7e8;
+const OBD_VEHICLE_INFORMATION: u8 = 0x09;
+const OBD_PID_VIN: u8 = 0x02;
+const DID_VIN: u16 = 0xf190;
+
+// J1939 request for the Vehicle Identification PGN, sent to all nodes
+// from the off-board diagnostics address
+const PGN_REQUEST: u32 = 0xea00;
+const PGN_VEHICLE_IDENTIFICATION: u32 = 0xfeec;
+const J1939_PRIORITY: u32 = 6;
+const J1939_SOURCE: u32 = 0xf9;
+const J1939_GLOBAL: u32 = 0xff;
+
+lazy_static! {
+    static ref VIN: Mutex<Option<String>> = Mutex::new(None);
+}
+
+// The detected VIN, for the state
+pub async fn vin() -> Option<String> {
+    VIN.lock().await.clone()
+}
+
+// A VIN from a response, which may be padded or, on J1939, delimited
+// with an asterisk. VINs use the digits and the capital letters except
+// I, O and Q.
+pub fn parse_vin(data: &[u8]) -> Option<String> {
+    let end = data.iter().position(|b| *b == b'*').unwrap_or(data.len());
+    let vin = std::str::from_utf8(&data[..end]).ok()?;
+    let vin = vin.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    let valid = vin.len() == VIN_LEN
+        && vin
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase() && !"IOQ".contains(c));
+    valid.then(|| vin.to_string())
+}
+
+async fn request_isotp(port: &str, tx_id: u32, request: &[u8]) -> Option<Vec<u8>> {
+    let socket = match IsoTpSocket::open(port, tx_id, OBD_RESPONSE_ID) {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("{port}: no ISO-TP socket for the VIN: {e}");
+            return None;
+        }
+    };
+    let outcome = transact(&socket, request, RESPONSE_TIMEOUT).await.ok()?;
+    positive(outcome, "VIN request").ok()
+}
+
+// OBD-II mode 09 PID 02, whose response starts with the number of
+// data items
+async fn obd_vin(port: &str) -> Option<String> {
+    let request = [OBD_VEHICLE_INFORMATION, OBD_PID_VIN];
+    let data = request_isotp(port, OBD_PHYSICAL_ID, &request).await?;
+    parse_vin(data.get(1..)?)
+}
+
+async fn uds_vin(port: &str) -> Option<String> {
+    let [hi, lo] = DID_VIN.to_be_bytes();
+    let request = [SID_READ_DATA_BY_IDENTIFIER, hi, lo];
+    parse_vin(&request_isotp(port, OBD_PHYSICAL_ID, &request).await?)
+}
+
+async fn j1939_vin(port: &str) -> Option<String> {
+    let mut socket = CANSocket::open(port).ok()?;
+    let id = J1939_PRIORITY << 26 | (PGN_REQUEST | J1939_GLOBAL) << 8 | J1939_SOURCE;
+    let pgn = PGN_VEHICLE_IDENTIFICATION.to_le_bytes();
+    let frame = CANFrame::new(id, &pgn[..3], false, false).ok()?;
+    socket.write_frame(frame).ok()?.await.ok()?;
+
+    let mut transport = j1939::Reassembler::new();
+    let deadline = tokio::time::Instant::now() + RESPONSE_TIMEOUT;
+    while let Ok(Some(Ok(frame))) = timeout_at(deadline, socket.next()).await {
+        let id = j1939::parse_id(frame.id() | libc::CAN_EFF_FLAG);
+        let data = match transport.handle(&id, frame.data(), Instant::now()) {
+            Packet::Single if id.pgn == PGN_VEHICLE_IDENTIFICATION => frame.data().to_vec(),
+            Packet::Complete(PGN_VEHICLE_IDENTIFICATION, data) => data,
+            _ => continue,
+        };
+        if let Some(vin) = parse_vin(&data) {
+            return Some(vin);
+        }
+    }
+    None
+}
+
+async fn port_vin(port: &CanPort) -> Option<String> {
+    if port.j1939 == Some(true) {
+        return j1939_vin(&port.name).await;
+    }
+    match obd_vin(&port.name).await {
+        Some(vin) => Some(vin),
+        None => uds_vin(&port.name).await,
+    }
+}
+
+pub fn is_enabled() -> bool {
+    CONFIG
+        .can
+        .as_ref()
+        .is_some_and(|c| c.detect_vin != Some(false))
+}
+
+async fn detect_vin() -> Option<String> {
+    let ports = CONFIG.can.iter().flat_map(|c| c.ports.iter().flatten());
+    for port in ports.filter(|p| p.listen_only == Some(false) && p.raw != Some(true)) {
+        if let Some(vin) = port_vin(port).await {
+            println!("VIN {} detected on {}", vin, port.name);
+            return Some(vin);
+        }
+    }
+    None
+}
+
+// Request the VIN on the ports that may transmit until one answers. The
+// vehicle may not answer yet, e.g. with the ignition off, so the
+// requests are repeated with a growing interval.
+pub async fn vin_detector(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let mut interval = MIN_RETRY_INTERVAL;
+    loop {
+        if let Some(vin) = detect_vin().await {
+            *VIN.lock().await = Some(vin);
+            send_state(channel).await;
+            return Ok(());
+        }
+        debug!("No VIN found, trying again in {} s", interval.as_secs());
+        sleep(interval).await;
+        interval = (interval * 2).min(MAX_RETRY_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vins() {
+        assert_eq!(
+            parse_vin(b"YV2A4C2A1VA123456").as_deref(),
+            Some("YV2A4C2A1VA123456")
+        );
+        // Delimited on J1939 and padded by some ECUs
+        assert_eq!(
+            parse_vin(b"YV2A4C2A1VA123456*").as_deref(),
+            Some("YV2A4C2A1VA123456")
+        );
+        assert_eq!(
+            parse_vin(b"\0YV2A4C2A1VA123456\0").as_deref(),
+            Some("YV2A4C2A1VA123456")
+        );
+        assert_eq!(parse_vin(b"YV2A4C2A1VA12345"), None);
+        assert_eq!(parse_vin(b"YV2A4C2A1VO123456"), None);
+        assert_eq!(parse_vin(b"yv2a4c2a1va123456"), None);
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Status of WireGuard tunnels that the unit already uses, reported with
+// the statistics so that a unit that lost its VPN can be found. The
+// server can also push new settings for a tunnel if management is
+// enabled. The settings are in the format of wg setconf, without the
+// wg-quick additions such as Address.
+
+use lib::{host_insight::VpnPeer, host_insight::VpnSettings, CONFIG};
+use std::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn wg(args: &[&str]) -> Result<String, Box<dyn Error + Send + Sync>> {
+    wg_with_input(args, None)
+}
+
+// Run wg with the given input on stdin, e.g. a config that holds a
+// private key and so must not be written to a file
+fn wg_with_input(
+    args: &[&str],
+    input: Option<&str>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut child = Command::new("wg")
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "wg {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+// Parse the peers from the output of wg show <interface> dump. The first
+// line is the interface itself, and each following line is a peer with
+// tab separated public key, preshared key, endpoint, allowed IPs, latest
+// handshake, received bytes, sent bytes and keepalive.
+fn parse_dump(interface: &str, dump: &str, now: u64) -> Vec<VpnPeer> {
+    dump.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split('\t').collect();
+            let (public_key, endpoint, handshake, rx, tx) = match fields[..] {
+                [public_key, _, endpoint, _, handshake, rx, tx, ..] => {
+                    (public_key, endpoint, handshake, rx, tx)
+                }
+                _ => return None,
+            };
+            let handshake: u64 = handshake.parse().ok()?;
+            Some(VpnPeer {
+                interface: interface.to_string(),
+                public_key: public_key.to_string(),
+                endpoint: match endpoint {
+                    "(none)" => String::new(),
+                    e => e.to_string(),
+                },
+                handshake_age_s: (handshake > 0).then(|| now.saturating_sub(handshake)),
+                rx_bytes: rx.parse().ok()?,
+                tx_bytes: tx.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+// Peers of the configured interfaces. An interface that is down is
+// left out.
+pub fn peers() -> Vec<VpnPeer> {
+    let vpn = match &CONFIG.vpn {
+        Some(vpn) => vpn,
+        None => return Vec::new(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut peers = Vec::new();
+    for interface in &vpn.interfaces {
+        match wg(&["show", interface, "dump"]) {
+            Ok(dump) => peers.extend(parse_dump(interface, &dump, now)),
+            Err(e) => eprintln!("Failed to read VPN status: {e}"),
+        }
+    }
+    peers
+}
+
+// Apply settings pushed by the server. The running settings are kept
+// first and restored if the new ones cannot be applied. Both hold the
+// private key, so they are only passed to wg on stdin and never written
+// to disk.
+fn apply(settings: &VpnSettings) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let interface = settings.interface.as_str();
+    let previous = wg(&["showconf", interface])?;
+    let syncconf = ["syncconf", interface, "/dev/stdin"];
+    if let Err(e) = wg_with_input(&syncconf, Some(&settings.config)) {
+        wg_with_input(&syncconf, Some(&previous))?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub fn request_vpn_settings(settings: VpnSettings) {
+    let managed = CONFIG.vpn.as_ref().is_some_and(|vpn| {
+        vpn.manage.unwrap_or(false) && vpn.interfaces.contains(&settings.interface)
+    });
+    if !managed {
+        eprintln!(
+            "Ignoring VPN settings for {}, the interface is not managed",
+            settings.interface
+        );
+        return;
+    }
+    match apply(&settings) {
+        Ok(()) => println!("VPN settings for {} applied", settings.interface),
+        Err(e) => eprintln!(
+            "Failed to apply VPN settings for {}: {e}",
+            settings.interface
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_wg_dump() {
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t51820\toff\n\
+                    cGVlcjE=\t(none)\t203.0.113.5:51820\t10.0.0.0/24\t1000\t2048\t4096\t25\n\
+                    cGVlcjI=\t(none)\t(none)\t10.0.1.0/24\t0\t0\t0\toff\n";
+        let peers = parse_dump("wg0", dump, 1060);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].interface, "wg0");
+        assert_eq!(peers[0].endpoint, "203.0.113.5:51820");
+        assert_eq!(peers[0].handshake_age_s, Some(60));
+        assert_eq!(peers[0].rx_bytes, 2048);
+        assert_eq!(peers[0].tx_bytes, 4096);
+        assert_eq!(peers[1].endpoint, "");
+        assert_eq!(peers[1].handshake_age_s, None);
+    }
+}
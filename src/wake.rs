@@ -0,0 +1,132 @@
+// Copyright (C) 2023  Host Mobility AB
+
+// This file is part of HOST Insight Client
+
+// HOST Insight Client is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// HOST Insight Client is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program; if not, write to the Free Software Foundation,
+// Inc., 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301  USA
+
+// Check-ins while parked, for battery powered units. While the unit is
+// parked, a heartbeat is sent and the unit is kept awake for a while, so
+// that the queued data is sent and the server gets a chance to reply.
+// Then the RTC wake alarm is set for the next check-in, and the unit is
+// either suspended or left to the power management to suspend.
+
+use super::net::{current_status, handle_send_result, intercept};
+use super::tap;
+use lib::{
+    cache::{self, DIGITAL_IN_SOURCE},
+    host_insight::{agent_client::AgentClient, can_signal},
+    WakeConfig, CONFIG,
+};
+use nix::time::{clock_gettime, ClockId};
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+use tokio::time::{sleep, sleep_until, Instant};
+use tonic::transport::Channel;
+
+const DEFAULT_AWAKE_S: u64 = 60;
+const DEFAULT_RTC: &str = "rtc0";
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+async fn is_parked(wake: &WakeConfig) -> bool {
+    // Not parked until the input is known
+    cache::get(DIGITAL_IN_SOURCE, &wake.parked_input)
+        .await
+        .is_some_and(|v| v.value == can_signal::Value::ValU64(0))
+}
+
+fn set_wake_alarm(wake: &WakeConfig, seconds: Option<u64>) {
+    let path = format!(
+        "/sys/class/rtc/{}/wakealarm",
+        wake.rtc.as_deref().unwrap_or(DEFAULT_RTC)
+    );
+    // A set alarm has to be cleared before it can be changed
+    let result = fs::write(&path, "0").and_then(|_| match seconds {
+        Some(s) => fs::write(&path, format!("+{}", s)),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to set wake alarm in {}: {}", path, e);
+    }
+}
+
+// Time since boot, including time suspended
+fn boot_time() -> Duration {
+    clock_gettime(ClockId::CLOCK_BOOTTIME)
+        .map(Duration::from)
+        .unwrap_or_default()
+}
+
+async fn check_in(channel: Channel, awake: Duration) {
+    let mut client = AgentClient::with_interceptor(channel, intercept);
+    let deadline = Instant::now() + awake;
+    let mut retry_sleep_s = CONFIG.time.sleep_min_s;
+    let status = current_status().await;
+    while Instant::now() < deadline {
+        tap::record("HeartBeat", &status).await;
+        match client.heart_beat(status.clone()).await {
+            Ok(response) => {
+                let _ = handle_send_result(Ok(response), &mut retry_sleep_s).await;
+                break;
+            }
+            Err(e) => {
+                eprintln!("Check-in failed: {e}");
+                sleep(Duration::from_secs(retry_sleep_s)).await;
+                retry_sleep_s = (retry_sleep_s * 2).min(CONFIG.time.sleep_max_s);
+            }
+        }
+    }
+    sleep_until(deadline).await;
+}
+
+pub async fn wake_scheduler(channel: Channel) -> Result<(), Box<dyn Error>> {
+    let wake = CONFIG.wake.as_ref().unwrap();
+    let awake = Duration::from_secs(wake.awake_s.unwrap_or(DEFAULT_AWAKE_S));
+    let interval = Duration::from_secs(wake.interval_s);
+    let mut alarm_set = false;
+
+    loop {
+        if !is_parked(wake).await {
+            if alarm_set {
+                set_wake_alarm(wake, None);
+                alarm_set = false;
+            }
+            sleep(CHECK_INTERVAL).await;
+            continue;
+        }
+
+        check_in(channel.clone(), awake).await;
+        if !is_parked(wake).await {
+            continue;
+        }
+
+        println!("Parked, next check-in in {} s", wake.interval_s);
+        set_wake_alarm(wake, Some(wake.interval_s));
+        alarm_set = true;
+        if wake.suspend.unwrap_or(false) {
+            if let Err(e) = Command::new("systemctl").arg("suspend").status() {
+                eprintln!("Failed to suspend: {e}");
+            }
+        }
+
+        // The boot time includes the time suspended, so this ends soon
+        // after the unit is woken by the alarm
+        let next = boot_time() + interval;
+        while boot_time() < next && is_parked(wake).await {
+            sleep(CHECK_INTERVAL).await;
+        }
+    }
+}